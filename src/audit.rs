@@ -0,0 +1,75 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+#[derive(Serialize, Debug, Clone, Copy)]
+pub enum AuditAction {
+    CreateImage,
+    MakeFilesystem,
+    CreateDirectory,
+    AttachLoopDevice,
+    DetachLoopDevice,
+    Mount,
+    Unmount,
+    CopyIntoFilesystem,
+    WriteFile,
+    Symlink,
+    CopyImage,
+}
+
+#[derive(Serialize)]
+struct AuditEntry<'a> {
+    timestamp_ms: u128,
+    run_id: &'a str,
+    action: AuditAction,
+    path: &'a Path,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    args: &'a [String],
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AuditLog {
+    log_path: Option<PathBuf>,
+    run_id: String,
+}
+
+impl AuditLog {
+    pub fn new(log_path: Option<PathBuf>, run_id: String) -> Self {
+        Self { log_path, run_id }
+    }
+
+    pub fn record(&self, action: AuditAction, path: &Path) {
+        self.record_with_args(action, path, &[]);
+    }
+
+    pub fn record_with_args(&self, action: AuditAction, path: &Path, args: &[String]) {
+        let Some(ref log_path) = self.log_path else {
+            return;
+        };
+
+        let entry = AuditEntry {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("System time is before the UNIX epoch")
+                .as_millis(),
+            run_id: &self.run_id,
+            action,
+            path,
+            args,
+        };
+        let mut line = serde_json::to_vec(&entry).expect("Could not encode audit log entry into JSON");
+        line.push(b'\n');
+
+        // a single append-mode write keeps concurrent entries from interleaving
+        std::fs::File::options()
+            .create(true)
+            .append(true)
+            .open(log_path)
+            .and_then(|mut file| file.write_all(&line))
+            .expect("Could not append entry to audit log");
+    }
+}
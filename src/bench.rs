@@ -0,0 +1,256 @@
+use std::{
+    fs::File,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::process::Command;
+use uuid::Uuid;
+
+use crate::{
+    extract::{extract_tar, ExtractOptions},
+    minimize::get_tree_size,
+    BenchArgs,
+};
+
+#[derive(Serialize, Debug)]
+pub struct BenchResult {
+    pub phase: &'static str,
+    pub bytes: u64,
+    pub min_ms: f64,
+    pub mean_ms: f64,
+    pub throughput_mib_s: f64,
+}
+
+pub async fn bench_command(bench_args: BenchArgs) {
+    let work_path = bench_args.work_dir.join(format!("buildfs-bench-{}", Uuid::new_v4()));
+    let tree_path = work_path.join("tree");
+    let tar_path = work_path.join("tree.tar");
+    let image_path = work_path.join("image");
+    tokio::fs::create_dir_all(&work_path)
+        .await
+        .expect("Could not create benchmark work directory");
+
+    let (file_count, file_size_kib) = (bench_args.files, bench_args.file_size_kib);
+    let synthetic_tree_path = tree_path.clone();
+    tokio::task::spawn_blocking(move || create_synthetic_tree(&synthetic_tree_path, file_count, file_size_kib))
+        .await
+        .expect("Join on blocking task failed");
+    let tree_bytes = get_tree_size(&tree_path);
+    let image_bytes = bench_args.image_size_mib * 1024 * 1024;
+    log::info!(
+        "Created synthetic tree of {} file(s) totalling {} MiB at {tree_path:?}",
+        bench_args.files,
+        tree_bytes / 1024 / 1024
+    );
+
+    let mut results = Vec::new();
+
+    results.push(
+        measure("tar-create", tree_bytes, bench_args.iterations, || {
+            let (tree_path, tar_path) = (tree_path.clone(), tar_path.clone());
+            async move {
+                run_blocking(move || {
+                    let mut builder =
+                        tar::Builder::new(File::create(&tar_path).expect("Could not create benchmark tarball"));
+                    builder
+                        .append_dir_all(".", &tree_path)
+                        .expect("Could not append synthetic tree to tarball");
+                    builder.finish().expect("Could not finish benchmark tarball");
+                })
+                .await;
+            }
+        })
+        .await,
+    );
+
+    results.push(
+        measure("tar-unpack", tree_bytes, bench_args.iterations, || {
+            let (tar_path, unpack_path) = (tar_path.clone(), work_path.join("unpacked"));
+            async move {
+                run_blocking(move || {
+                    let _ = std::fs::remove_dir_all(&unpack_path);
+                    let extract_options = ExtractOptions {
+                        confine_symlinks: false,
+                        same_filesystem: true,
+                    };
+                    extract_tar(
+                        File::open(&tar_path).expect("Could not open benchmark tarball"),
+                        &unpack_path,
+                        extract_options,
+                    )
+                    .expect("Could not unpack benchmark tarball");
+                })
+                .await;
+            }
+        })
+        .await,
+    );
+
+    results.push(
+        measure("cp-recursive", tree_bytes, bench_args.iterations, || {
+            let (tree_path, copy_path) = (tree_path.clone(), work_path.join("cp-copy"));
+            async move {
+                let _ = tokio::fs::remove_dir_all(&copy_path).await;
+                let exit_status = Command::new(which::which("cp").expect("Could not locate \"cp\" binary in PATH"))
+                    .arg("-r")
+                    .arg("-p")
+                    .arg("--preserve=links")
+                    .arg(&tree_path)
+                    .arg(&copy_path)
+                    .status()
+                    .await
+                    .expect("Could not fork \"cp\" to perform recursive copy");
+                if !exit_status.success() {
+                    panic!("\"cp\" exited with non-zero exit status: {exit_status}");
+                }
+            }
+        })
+        .await,
+    );
+
+    results.push(
+        measure("file-copy", tree_bytes, bench_args.iterations, || {
+            let (tree_path, copy_path) = (tree_path.clone(), work_path.join("file-copy"));
+            async move {
+                run_blocking(move || {
+                    let _ = std::fs::remove_dir_all(&copy_path);
+                    copy_tree_by_file(&tree_path, &copy_path);
+                })
+                .await;
+            }
+        })
+        .await,
+    );
+
+    results.push(
+        measure("dd", image_bytes, bench_args.iterations, || {
+            let image_path = image_path.clone();
+            async move {
+                let exit_status = Command::new(which::which("dd").expect("Could not locate \"dd\" binary in PATH"))
+                    .arg("if=/dev/zero")
+                    .arg(format!("of={}", image_path.to_string_lossy()))
+                    .arg("bs=1M")
+                    .arg(format!("count={}", image_bytes / 1024 / 1024))
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .status()
+                    .await
+                    .expect("Failed to fork \"dd\" process");
+                if !exit_status.success() {
+                    panic!("\"dd\" invocation failed with exit status: {exit_status}");
+                }
+            }
+        })
+        .await,
+    );
+
+    results.push(
+        measure("fallocate", image_bytes, bench_args.iterations, || {
+            let image_path = image_path.clone();
+            async move {
+                run_blocking(move || {
+                    let _ = std::fs::remove_file(&image_path);
+                    let image_file = File::create(&image_path).expect("Could not create benchmark image");
+                    let result = unsafe { libc::fallocate(image_file.as_raw_fd(), 0, 0, image_bytes as libc::off_t) };
+                    if result != 0 {
+                        panic!("fallocate failed: {}", std::io::Error::last_os_error());
+                    }
+                })
+                .await;
+            }
+        })
+        .await,
+    );
+
+    tokio::fs::remove_dir_all(&work_path)
+        .await
+        .expect("Could not remove benchmark work directory");
+
+    if bench_args.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&results).expect("Could not encode benchmark results into JSON")
+        );
+        return;
+    }
+
+    println!(
+        "{:<14} {:>10} {:>12} {:>12} {:>12}",
+        "phase", "MiB", "min (ms)", "mean (ms)", "MiB/s"
+    );
+    for result in &results {
+        println!(
+            "{:<14} {:>10} {:>12.1} {:>12.1} {:>12.1}",
+            result.phase,
+            result.bytes / 1024 / 1024,
+            result.min_ms,
+            result.mean_ms,
+            result.throughput_mib_s
+        );
+    }
+}
+
+async fn measure<F, Fut>(phase: &'static str, bytes: u64, iterations: u32, mut iteration: F) -> BenchResult
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = ()>,
+{
+    let mut durations = Vec::with_capacity(iterations as usize);
+    for _ in 0..iterations.max(1) {
+        let start = Instant::now();
+        iteration().await;
+        durations.push(start.elapsed());
+    }
+
+    let min = durations.iter().min().copied().unwrap_or_default();
+    let mean = durations.iter().sum::<Duration>() / durations.len() as u32;
+    log::info!("Benchmarked {phase}: min {min:?}, mean {mean:?}");
+
+    BenchResult {
+        phase,
+        bytes,
+        min_ms: min.as_secs_f64() * 1000.0,
+        mean_ms: mean.as_secs_f64() * 1000.0,
+        throughput_mib_s: bytes as f64 / 1024.0 / 1024.0 / min.as_secs_f64().max(f64::EPSILON),
+    }
+}
+
+async fn run_blocking(function: impl FnOnce() + Send + 'static) {
+    tokio::task::spawn_blocking(function)
+        .await
+        .expect("Join on blocking task failed");
+}
+
+fn create_synthetic_tree(tree_path: &Path, file_count: u64, file_size_kib: u64) {
+    let contents = (0..file_size_kib * 1024)
+        .map(|index| (index % 251) as u8)
+        .collect::<Vec<_>>();
+
+    for index in 0..file_count {
+        let dir_path = tree_path.join(format!("dir-{}", index % 32));
+        std::fs::create_dir_all(&dir_path).expect("Could not create synthetic tree directory");
+        std::fs::write(dir_path.join(format!("file-{index}")), &contents).expect("Could not write synthetic tree file");
+    }
+}
+
+fn copy_tree_by_file(source_path: &Path, destination_path: &PathBuf) {
+    std::fs::create_dir_all(destination_path).expect("Could not create copy destination directory");
+
+    for entry in std::fs::read_dir(source_path).expect("Could not read synthetic tree directory") {
+        let entry = entry.expect("Could not read synthetic tree directory entry");
+        let entry_destination_path = destination_path.join(entry.file_name());
+        if entry
+            .file_type()
+            .expect("Could not inspect synthetic tree entry")
+            .is_dir()
+        {
+            copy_tree_by_file(&entry.path(), &entry_destination_path);
+        } else {
+            std::fs::copy(entry.path(), entry_destination_path).expect("Could not copy synthetic tree file");
+        }
+    }
+}
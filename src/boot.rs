@@ -0,0 +1,144 @@
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::{
+    audit::{AuditAction, AuditLog},
+    dry_run::AdjoinAbsolute,
+    error::BuildfsError,
+    run::get_mount_fstype,
+    schema::FilesystemType,
+    tools::{get_tool_command, get_tool_path, ToolsConfig},
+};
+
+// Firecracker attaches the root drive as the first virtio-blk device and has no PCI bus or keyboard controller
+static ROOT_DEVICE: &str = "/dev/vda";
+static BASE_KERNEL_CMDLINE: &str = "console=ttyS0 reboot=k panic=1 pci=off";
+static INIT_PROBES: [&str; 4] = ["/sbin/init", "/usr/sbin/init", "/usr/lib/systemd/systemd", "/init"];
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct BootMetadata {
+    pub kernel_cmdline: String,
+    pub root_device: String,
+    pub read_only: bool,
+    pub filesystem_type: String,
+    pub filesystem_uuid: Option<String>,
+    pub filesystem_label: Option<String>,
+    pub init_path: Option<String>,
+}
+
+pub async fn detect_init_path(mount_path: &PathBuf) -> Option<String> {
+    for init_probe in INIT_PROBES {
+        if tokio::fs::symlink_metadata(mount_path.adjoin_absolute(&PathBuf::from(init_probe)))
+            .await
+            .is_ok()
+        {
+            return Some(init_probe.to_string());
+        }
+    }
+
+    log::warn!("Could not find an init binary inside the filesystem, so the boot metadata recommends no init path");
+    None
+}
+
+pub async fn get_boot_metadata(
+    filesystem_type: &FilesystemType,
+    output_path: &Path,
+    init_path: Option<String>,
+    tools: &ToolsConfig,
+) -> BootMetadata {
+    let read_only = matches!(filesystem_type, FilesystemType::Squashfs);
+    let (filesystem_uuid, filesystem_label) = probe_filesystem_ids(output_path, tools).await;
+
+    BootMetadata {
+        kernel_cmdline: get_kernel_cmdline(filesystem_type, read_only, init_path.as_deref()),
+        root_device: ROOT_DEVICE.to_string(),
+        read_only,
+        filesystem_type: get_mount_fstype(filesystem_type).to_string(),
+        filesystem_uuid,
+        filesystem_label,
+        init_path,
+    }
+}
+
+pub async fn write_boot_metadata(
+    boot_metadata: &BootMetadata,
+    output_path: &Path,
+    audit_log: &AuditLog,
+) -> Result<PathBuf, BuildfsError> {
+    let mut boot_metadata_path = output_path.to_path_buf();
+    boot_metadata_path.as_mut_os_string().push(".boot.json");
+
+    let boot_metadata_json =
+        serde_json::to_string_pretty(boot_metadata).expect("Could not encode boot metadata into JSON");
+    audit_log.record(AuditAction::WriteFile, &boot_metadata_path);
+    tokio::fs::write(&boot_metadata_path, boot_metadata_json)
+        .await
+        .map_err(BuildfsError::io("Could not write boot metadata file"))?;
+    Ok(boot_metadata_path)
+}
+
+fn get_kernel_cmdline(filesystem_type: &FilesystemType, read_only: bool, init_path: Option<&str>) -> String {
+    let mut kernel_cmdline = format!(
+        "{BASE_KERNEL_CMDLINE} root={ROOT_DEVICE} {} rootfstype={}",
+        if read_only { "ro" } else { "rw" },
+        get_mount_fstype(filesystem_type)
+    );
+    if let Some(init_path) = init_path {
+        kernel_cmdline.push_str(&format!(" init={init_path}"));
+    }
+    kernel_cmdline
+}
+
+async fn probe_filesystem_ids(output_path: &Path, tools: &ToolsConfig) -> (Option<String>, Option<String>) {
+    if get_tool_path(tools, "blkid").is_none() && tools.container_image.is_none() {
+        log::warn!("Could not locate \"blkid\" binary in PATH, so the boot metadata lists no filesystem UUID or label");
+        return (None, None);
+    }
+
+    let output = match get_tool_command(tools, "blkid", &[output_path.parent().unwrap_or(Path::new("/"))]) {
+        Ok(mut command) => command.args(["-o", "export"]).arg(output_path).output().await.ok(),
+        Err(_) => None,
+    };
+    let Some(output) = output else {
+        log::warn!("Could not run \"blkid\", so the boot metadata lists no filesystem UUID or label");
+        return (None, None);
+    };
+    parse_blkid_export(&String::from_utf8_lossy(&output.stdout))
+}
+
+fn parse_blkid_export(output: &str) -> (Option<String>, Option<String>) {
+    let mut ids = (None, None);
+    for line in output.lines() {
+        match line.trim().split_once('=') {
+            Some(("UUID", uuid)) => ids.0 = Some(uuid.to_string()),
+            Some(("LABEL", label)) => ids.1 = Some(label.to_string()),
+            _ => {}
+        }
+    }
+    ids
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schema::FilesystemType;
+
+    use super::{get_kernel_cmdline, parse_blkid_export};
+
+    #[test]
+    fn squashfs_images_boot_read_only() {
+        assert_eq!(
+            get_kernel_cmdline(&FilesystemType::Squashfs, true, Some("/sbin/init")),
+            "console=ttyS0 reboot=k panic=1 pci=off root=/dev/vda ro rootfstype=squashfs init=/sbin/init"
+        );
+        assert_eq!(
+            parse_blkid_export(
+                "DEVNAME=rootfs.ext4\nLABEL=rootfs\nUUID=0b4c2f6e-5a6d-4f1e-9d3c-7a1e2b3c4d5e\nTYPE=ext4\n"
+            ),
+            (
+                Some("0b4c2f6e-5a6d-4f1e-9d3c-7a1e2b3c4d5e".to_string()),
+                Some("rootfs".to_string())
+            )
+        );
+    }
+}
@@ -0,0 +1,91 @@
+use std::{collections::HashMap, path::PathBuf, sync::Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::RunArgs;
+
+static ACTIVE_CHECKPOINT: Mutex<Option<(PathBuf, RunState)>> = Mutex::new(None);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RunState {
+    pub run_id: String,
+    pub run_args: RunArgs,
+    pub checkpoint: RunCheckpoint,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "stage")]
+pub enum RunCheckpoint {
+    Started,
+    RunningCommands {
+        container_id: String,
+        container_name: String,
+        inline_mount_paths: HashMap<String, (PathBuf, PathBuf)>,
+        completed_commands: usize,
+    },
+    Exported {
+        staging_path: PathBuf,
+    },
+}
+
+pub async fn load_run_state(state_path: &PathBuf) -> RunState {
+    let state_json = tokio::fs::read_to_string(state_path)
+        .await
+        .expect("Could not read run state file");
+    serde_json::from_str(&state_json).expect("Could not decode run state file")
+}
+
+pub fn begin_checkpoint(state_path: PathBuf, run_state: RunState) {
+    write_run_state(&state_path, &run_state);
+    *lock_checkpoint() = Some((state_path, run_state));
+}
+
+pub fn save_checkpoint(checkpoint: RunCheckpoint) {
+    let mut active_checkpoint = lock_checkpoint();
+    if let Some((ref state_path, ref mut run_state)) = *active_checkpoint {
+        run_state.checkpoint = checkpoint;
+        write_run_state(state_path, run_state);
+    }
+}
+
+pub fn complete_command() {
+    let mut active_checkpoint = lock_checkpoint();
+    if let Some((ref state_path, ref mut run_state)) = *active_checkpoint {
+        if let RunCheckpoint::RunningCommands {
+            ref mut completed_commands,
+            ..
+        } = run_state.checkpoint
+        {
+            *completed_commands += 1;
+            write_run_state(state_path, run_state);
+        }
+    }
+}
+
+pub fn end_checkpoint() {
+    if let Some((state_path, _)) = lock_checkpoint().take() {
+        std::fs::remove_file(&state_path).expect("Could not remove run state file");
+        log::debug!("Removed run state file at {state_path:?}");
+    }
+}
+
+pub fn is_checkpointing() -> bool {
+    // the checkpoint can still be held by a thread that panicked with it, in which case the state file was kept
+    match ACTIVE_CHECKPOINT.try_lock() {
+        Ok(active_checkpoint) => active_checkpoint.is_some(),
+        Err(_) => true,
+    }
+}
+
+fn write_run_state(state_path: &PathBuf, run_state: &RunState) {
+    let state_json = serde_json::to_string_pretty(run_state).expect("Could not encode run state into JSON");
+    // the state is written to a sibling file and renamed over, so a kill mid-write never leaves a torn state file
+    let mut tmp_state_path = state_path.clone();
+    tmp_state_path.as_mut_os_string().push(".tmp");
+    std::fs::write(&tmp_state_path, state_json).expect("Could not write run state file");
+    std::fs::rename(&tmp_state_path, state_path).expect("Could not move run state file into place");
+}
+
+fn lock_checkpoint() -> std::sync::MutexGuard<'static, Option<(PathBuf, RunState)>> {
+    ACTIVE_CHECKPOINT.lock().expect("Checkpoint state was poisoned")
+}
@@ -0,0 +1,190 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use sys_mount::UnmountFlags;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{
+    checkpoint::is_checkpointing,
+    container_engine::get_container_engine,
+    epilogue::report_failure,
+    fuse::unmount_fuse_lazily,
+    loop_device::detach_loop_device,
+    privilege::unmount_via_helper,
+    schema::{BuildScriptContainer, ContainerEngineType},
+};
+
+static CLEANUP_STATE: Mutex<CleanupState> = Mutex::new(CleanupState {
+    container: None,
+    mounts: Vec::new(),
+    loop_devices: Vec::new(),
+    paths: Vec::new(),
+});
+
+#[derive(Default)]
+struct CleanupState {
+    container: Option<CleanupContainer>,
+    mounts: Vec<(PathBuf, MountKind)>,
+    loop_devices: Vec<PathBuf>,
+    paths: Vec<PathBuf>,
+}
+
+#[derive(Clone, Copy)]
+pub enum MountKind {
+    Kernel,
+    Helper,
+    Fuse,
+}
+
+struct CleanupContainer {
+    engine: ContainerEngineType,
+    connection_uri: Option<String>,
+    container_name: String,
+    wait_timeout_s: Option<u64>,
+}
+
+pub fn register_container(container: &BuildScriptContainer, connection_uri: Option<String>, container_name: &str) {
+    if container.attach_to.is_some() {
+        return;
+    }
+
+    lock_state().container = Some(CleanupContainer {
+        engine: container.engine.clone(),
+        connection_uri,
+        container_name: container_name.to_string(),
+        wait_timeout_s: container.wait_timeout_s,
+    });
+}
+
+pub fn release_container() {
+    lock_state().container = None;
+}
+
+pub fn register_path(path: &Path) {
+    lock_state().paths.push(path.to_path_buf());
+}
+
+pub fn release_path(path: &Path) {
+    lock_state().paths.retain(|registered_path| registered_path != path);
+}
+
+pub fn register_mount(mount_path: &Path, kind: MountKind) {
+    lock_state().mounts.push((mount_path.to_path_buf(), kind));
+}
+
+pub fn release_mount(mount_path: &Path) {
+    lock_state()
+        .mounts
+        .retain(|(registered_path, _)| registered_path != mount_path);
+}
+
+pub fn register_loop_device(device_path: &Path) {
+    lock_state().loop_devices.push(device_path.to_path_buf());
+}
+
+pub fn release_loop_device(device_path: &Path) {
+    lock_state()
+        .loop_devices
+        .retain(|registered_path| registered_path != device_path);
+}
+
+pub fn clean_up() {
+    let CleanupState {
+        container,
+        mounts,
+        loop_devices,
+        paths,
+    } = std::mem::take(&mut *lock_state());
+    if container.is_none() && mounts.is_empty() && loop_devices.is_empty() && paths.is_empty() {
+        return;
+    }
+
+    // nothing is resumed from a mounted image, so it is released even when checkpointing, innermost mount first
+    for (mount_path, kind) in mounts.into_iter().rev() {
+        let result = match kind {
+            MountKind::Kernel => sys_mount::unmount(&mount_path, UnmountFlags::DETACH),
+            MountKind::Helper => unmount_via_helper(&mount_path, true),
+            MountKind::Fuse => unmount_fuse_lazily(&mount_path),
+        };
+        match result {
+            Ok(()) => log::info!("Lazily unmounted leftover mount at {mount_path:?}"),
+            // the mount may already be gone if its owner was dropped while unwinding
+            Err(err) if err.raw_os_error() == Some(libc::EINVAL) => {}
+            Err(err) => log::warn!("Could not unmount {mount_path:?} during cleanup: {err}"),
+        }
+    }
+    for device_path in loop_devices {
+        match detach_loop_device(&device_path) {
+            Ok(()) => log::info!("Detached leftover loop device {device_path:?}"),
+            Err(err) => log::warn!("Could not detach loop device {device_path:?} during cleanup: {err}"),
+        }
+    }
+
+    if is_checkpointing() {
+        log::warn!(
+            "Left the build container and temporary files in place, so that the run can be resumed from its state file"
+        );
+        return;
+    }
+
+    let mut unique_paths = HashSet::new();
+    for path in paths.into_iter().filter(|path| unique_paths.insert(path.clone())) {
+        let result = match path.is_dir() {
+            true => std::fs::remove_dir_all(&path),
+            false => std::fs::remove_file(&path),
+        };
+        if let Err(err) = result {
+            log::warn!("Could not remove temporary path {path:?} during cleanup: {err}");
+        }
+    }
+    log::info!("Removed {} leftover temporary path(s)", unique_paths.len());
+
+    if let Some(container) = container {
+        // the engine is reconnected on its own thread, since this can run inside the runtime or from the panic hook
+        let container_name = container.container_name.clone();
+        let result = std::thread::spawn(move || {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("Could not start Tokio runtime for cleanup")
+                .block_on(async {
+                    get_container_engine(&container.engine, container.connection_uri)?
+                        .remove_container(&container.container_name, container.wait_timeout_s)
+                        .await
+                })
+        })
+        .join();
+
+        match result {
+            Ok(Ok(())) => log::info!("Stopped and removed leftover container {container_name}"),
+            Ok(Err(err)) => log::error!(
+                "Could not remove leftover container {container_name}, it has to be removed manually: {err}"
+            ),
+            Err(_) => {
+                log::error!("Could not remove leftover container {container_name}, it has to be removed manually")
+            }
+        }
+    }
+}
+
+pub async fn handle_termination_signals() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Could not listen for SIGTERM");
+    let (signal_name, exit_code) = tokio::select! {
+        _ = tokio::signal::ctrl_c() => ("SIGINT", 130),
+        _ = sigterm.recv() => ("SIGTERM", 143),
+    };
+
+    report_failure(&format!("Interrupted by {signal_name}"));
+    tokio::task::spawn_blocking(clean_up)
+        .await
+        .expect("Join on blocking task failed");
+    std::process::exit(exit_code);
+}
+
+fn lock_state() -> std::sync::MutexGuard<'static, CleanupState> {
+    // cleanup also runs from the panic hook, so a poisoned state is still worth cleaning up
+    CLEANUP_STATE.lock().unwrap_or_else(|error| error.into_inner())
+}
@@ -0,0 +1,170 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    policy::Policy,
+    schema::{BuildScript, ContainerEngineType},
+    tools::ToolsConfig,
+    FsBackend, RunArgs, RuntimeSettings,
+};
+
+pub static DEFAULT_CONFIG_PATH: &str = "/etc/buildfs/config.toml";
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub wasm_plugins: Vec<PathBuf>,
+    #[serde(default)]
+    pub policy: Option<Policy>,
+    #[serde(default)]
+    pub audit_log: Option<PathBuf>,
+    #[serde(default)]
+    pub tools: ToolsConfig,
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+    #[serde(skip)]
+    pub variable_overrides: HashMap<String, String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct EffectiveConfig {
+    pub config_path: Option<PathBuf>,
+    pub engine: EffectiveEngineConfig,
+    pub runtime: RuntimeSettings,
+    pub no_exec_logs: bool,
+    pub fs_backend: FsBackend,
+    pub unpack_path: PathBuf,
+    pub tmp_path: PathBuf,
+    pub keep_staging: bool,
+    pub staging_dir: Option<PathBuf>,
+    pub output_path: PathBuf,
+    pub copy_paths: Vec<PathBuf>,
+    pub report_path: Option<PathBuf>,
+    pub manifest_path: Option<PathBuf>,
+    pub state_path: Option<PathBuf>,
+    pub audit_log: Option<PathBuf>,
+    pub policy_source: Option<String>,
+    pub hermetic: bool,
+    pub offline: bool,
+    pub build_id: Option<String>,
+    pub image_version: Option<String>,
+    pub vcs_commit: Option<String>,
+    pub wasm_plugins: Vec<PathBuf>,
+    pub tools: EffectiveToolsConfig,
+}
+
+#[derive(Serialize, Debug)]
+pub struct EffectiveEngineConfig {
+    pub engine_type: String,
+    pub connection_uri: Option<String>,
+    pub connection_source: String,
+    pub tunneled: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct EffectiveToolsConfig {
+    pub container_image: Option<String>,
+    pub container_cli: Option<String>,
+    pub paths: Vec<(String, PathBuf)>,
+}
+
+pub async fn load_config(config_path: Option<PathBuf>) -> Config {
+    let config_path = match config_path {
+        Some(config_path) => config_path,
+        None => {
+            let default_config_path = PathBuf::from(DEFAULT_CONFIG_PATH);
+            if !tokio::fs::try_exists(&default_config_path).await.unwrap_or(false) {
+                log::debug!("No config file found at {default_config_path:?}, using defaults");
+                return Config::default();
+            }
+            default_config_path
+        }
+    };
+
+    let config_toml = tokio::fs::read_to_string(&config_path)
+        .await
+        .expect("Could not read config file");
+    let mut config = toml::from_str::<Config>(&config_toml).expect("Could not decode config file from TOML");
+    log::debug!("Loaded config file from {config_path:?}");
+    config.path = Some(config_path);
+
+    config
+}
+
+pub fn get_effective_config(
+    run_args: &RunArgs,
+    no_exec_logs: bool,
+    config: &Config,
+    runtime_settings: RuntimeSettings,
+    build_script: &BuildScript,
+    unpack_path: &Path,
+) -> EffectiveConfig {
+    let (connection_uri, connection_source) = match build_script.container.connection_uri {
+        Some(ref connection_uri) if run_args.dry_run_args.connection_uri.is_some() => {
+            (Some(connection_uri.clone()), "command line or BUILDFS_CONNECTION_URI")
+        }
+        Some(ref connection_uri) => (Some(connection_uri.clone()), "build script"),
+        None => match build_script.container.engine {
+            // bollard falls back to DOCKER_HOST before the default socket, the other engines ignore the environment
+            ContainerEngineType::Docker => match std::env::var("DOCKER_HOST") {
+                Ok(docker_host) => (Some(docker_host), "DOCKER_HOST"),
+                Err(_) => (None, "engine default"),
+            },
+            _ => (None, "engine default"),
+        },
+    };
+
+    let dry_run_args = &run_args.dry_run_args;
+    let policy_source = match (&dry_run_args.policy_path, &config.policy) {
+        (Some(policy_path), _) => Some(format!("command line ({policy_path:?})")),
+        (None, Some(_)) => Some("config file".to_string()),
+        (None, None) => None,
+    };
+
+    let mut tool_paths = config
+        .tools
+        .paths
+        .iter()
+        .map(|(key, path)| (key.clone(), path.clone()))
+        .collect::<Vec<_>>();
+    tool_paths.sort();
+
+    EffectiveConfig {
+        config_path: config.path.clone(),
+        engine: EffectiveEngineConfig {
+            engine_type: build_script.container.engine.to_string(),
+            connection_uri,
+            connection_source: connection_source.to_string(),
+            tunneled: build_script.container.is_remote(),
+        },
+        runtime: runtime_settings,
+        no_exec_logs,
+        fs_backend: run_args.fs_backend,
+        unpack_path: unpack_path.to_path_buf(),
+        tmp_path: PathBuf::from("/tmp"),
+        keep_staging: run_args.keep_staging || run_args.staging_dir.is_some(),
+        staging_dir: run_args.staging_dir.clone(),
+        output_path: run_args.output_path.clone(),
+        copy_paths: run_args.copy_paths.clone(),
+        report_path: run_args.report_path.clone(),
+        manifest_path: run_args.manifest_path.clone(),
+        state_path: run_args.state_path.clone(),
+        audit_log: config.audit_log.clone(),
+        policy_source,
+        hermetic: dry_run_args.hermetic,
+        offline: dry_run_args.offline,
+        build_id: run_args.provenance_args.build_id.clone(),
+        image_version: run_args.provenance_args.image_version.clone(),
+        vcs_commit: run_args.provenance_args.vcs_commit.clone(),
+        wasm_plugins: config.wasm_plugins.clone(),
+        tools: EffectiveToolsConfig {
+            container_image: config.tools.container_image.clone(),
+            container_cli: config.tools.container_cli.clone(),
+            paths: tool_paths,
+        },
+    }
+}
@@ -1,26 +1,41 @@
-use std::{collections::HashMap, path::PathBuf, pin::Pin};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    pin::Pin,
+};
 
 use async_trait::async_trait;
 use bollard::{
-    container::{Config, CreateContainerOptions, LogOutput, RemoveContainerOptions, StopContainerOptions},
+    container::{
+        Config, CreateContainerOptions, LogOutput, LogsOptions, RemoveContainerOptions, StatsOptions,
+        StopContainerOptions, UploadToContainerOptions,
+    },
     exec::{CreateExecOptions, StartExecResults},
-    secret::HostConfig,
+    image::{CommitContainerOptions, PushImageOptions},
+    secret::{ChangeType, HostConfig},
     ClientVersion, Docker,
 };
 use futures_util::{Stream, StreamExt, TryStreamExt};
 use tokio::io::AsyncWriteExt;
 use uuid::Uuid;
 
-use crate::schema::{BuildScriptContainer, BuildScriptContainerImage};
+use crate::{
+    error::BuildfsError,
+    registry::get_registry_credentials,
+    schema::{BuildScriptContainer, BuildScriptContainerImage, ContainerEngineType},
+};
 
-use super::{format_uid_gid_string, ContainerEngine, ExecParams, ExecReader, StreamType};
+use super::{
+    format_uid_gid_string, get_exec_args, resolve_container_process, ContainerChange, ContainerChangeKind,
+    ContainerEngine, ContainerInspection, ContainerStats, ExecParams, ExecReader, StreamType,
+};
 
 pub struct DockerContainerEngine {
     client: Docker,
 }
 
 impl DockerContainerEngine {
-    pub fn new(connection_uri: Option<String>) -> Self {
+    pub fn new(connection_uri: Option<String>) -> Result<Self, BuildfsError> {
         let client = match connection_uri {
             Some(connection_uri) => {
                 let client_version = ClientVersion {
@@ -30,33 +45,50 @@ impl DockerContainerEngine {
 
                 if connection_uri.starts_with("http://") {
                     Docker::connect_with_http(&connection_uri, 5, &client_version)
+                } else if connection_uri.starts_with("npipe://") && !cfg!(windows) {
+                    return Err(BuildfsError::InvalidArguments(
+                        "Named pipe connection URIs can only be used to reach Docker from a Windows host".to_string(),
+                    ));
                 } else {
                     Docker::connect_with_local(&connection_uri, 5, &client_version)
                 }
             }
             None => Docker::connect_with_defaults(),
         }
-        .expect("Could not connect to Docker daemon");
+        .map_err(BuildfsError::engine("Could not connect to Docker daemon"))?;
 
-        Self { client }
+        Ok(Self { client })
     }
 }
 
 #[async_trait]
 impl ContainerEngine for DockerContainerEngine {
-    async fn ping(&self) {
-        let response = self.client.ping().await.expect("Pinging Docker daemon failed");
+    fn engine_type(&self) -> ContainerEngineType {
+        ContainerEngineType::Docker
+    }
+
+    async fn ping(&self) -> Result<(), BuildfsError> {
+        let response = self
+            .client
+            .ping()
+            .await
+            .map_err(BuildfsError::engine("Pinging Docker daemon failed"))?;
 
         if !response.contains("OK") {
-            panic!("Ping response from Docker daemon is not OK: {response}");
+            return Err(BuildfsError::Engine {
+                context: "Ping response from Docker daemon is not OK",
+                message: response,
+            });
         }
+        Ok(())
     }
 
-    async fn pull_image(&self, image: &BuildScriptContainerImage) {
+    async fn pull_image(&self, image: &BuildScriptContainerImage) -> Result<(), BuildfsError> {
         let mut stream = self.client.create_image(
             Some(bollard::image::CreateImageOptions {
                 from_image: image.full_name(),
-                tag: image.tag.clone(),
+                // the daemon treats a digest passed as the tag as a pin, which a plain tag would override
+                tag: image.digest.clone().unwrap_or_else(|| image.tag.clone()),
                 ..Default::default()
             }),
             None,
@@ -64,22 +96,46 @@ impl ContainerEngine for DockerContainerEngine {
         );
 
         while let Some(result) = stream.next().await {
-            result.expect("Could not pull image via Docker daemon");
+            result.map_err(BuildfsError::engine("Could not pull image via Docker daemon"))?;
+        }
+
+        Ok(())
+    }
+
+    async fn load_image(&self, archive_path: &Path) -> Result<(), BuildfsError> {
+        let archive = tokio::fs::read(archive_path)
+            .await
+            .map_err(BuildfsError::io("Could not read image archive"))?;
+        let mut stream =
+            self.client
+                .import_image(bollard::image::ImportImageOptions { quiet: true }, archive.into(), None);
+
+        while let Some(result) = stream.next().await {
+            result.map_err(BuildfsError::engine("Could not load image via Docker daemon"))?;
         }
+        Ok(())
+    }
+
+    async fn image_exists(&self, image: &BuildScriptContainerImage) -> bool {
+        self.client.inspect_image(&image.full_name()).await.is_ok()
     }
 
     async fn start_container(
         &self,
         container: BuildScriptContainer,
         mut extra_volumes: HashMap<PathBuf, PathBuf>,
-    ) -> (String, String) {
+    ) -> Result<(String, String), BuildfsError> {
+        let process = resolve_container_process(&container);
         extra_volumes.extend(container.volumes);
+        let can_fall_back_to_host_userns = container.rootful && container.userns.is_none();
 
         let container_name = Uuid::new_v4().to_string();
-        let config = Config {
+        let mut config = Config {
             image: Some(container.image.full_name()),
-            tty: Some(true),
+            tty: Some(process.tty),
             hostname: container.hostname,
+            entrypoint: process.entrypoint,
+            cmd: process.cmd,
             env: Some(
                 container
                     .env
@@ -97,33 +153,98 @@ impl ContainerEngine for DockerContainerEngine {
                 runtime: container.oci_runtime,
                 cap_add: container.cap_add,
                 cap_drop: container.cap_drop,
+                network_mode: container.network_mode,
                 privileged: Some(container.rootful),
+                init: process.init,
+                userns_mode: container.userns,
                 ..Default::default()
             }),
             ..Default::default()
         };
 
-        let response = self
+        let create_options = CreateContainerOptions {
+            name: container_name.as_str(),
+            platform: None,
+        };
+        let response = match self
             .client
-            .create_container(
-                Some(CreateContainerOptions {
-                    name: &container_name,
-                    platform: None,
-                }),
-                config,
-            )
+            .create_container(Some(create_options.clone()), config.clone())
             .await
-            .expect("Could not create container via Docker daemon");
+        {
+            // daemons with userns-remap refuse privileged containers unless they opt out of the remapping
+            Err(err) if can_fall_back_to_host_userns && is_userns_conflict(&err) => {
+                log::warn!(
+                    "The Docker daemon remaps user namespaces, which rootful containers can't use, retrying with userns = \"host\". Set it explicitly to silence this warning"
+                );
+                if let Some(ref mut host_config) = config.host_config {
+                    host_config.userns_mode = Some("host".to_string());
+                }
+                self.client.create_container(Some(create_options), config).await
+            }
+            result => result,
+        }
+        .map_err(BuildfsError::engine("Could not create container via Docker daemon"))?;
 
         self.client
             .start_container::<String>(&container_name, None)
             .await
-            .expect("Could not start container via Docker daemon");
+            .map_err(BuildfsError::engine("Could not start container via Docker daemon"))?;
+
+        Ok((response.id, container_name))
+    }
+
+    async fn resolve_attach_target(&self, target: &str) -> Result<(String, String), BuildfsError> {
+        let inspection = self
+            .client
+            .inspect_container(target, None)
+            .await
+            .map_err(BuildfsError::engine(
+                "Could not find the container to attach to via Docker daemon",
+            ))?;
+
+        Ok((
+            inspection.id.unwrap_or_else(|| target.to_string()),
+            inspection
+                .name
+                .map(|name| name.trim_start_matches('/').to_string())
+                .unwrap_or_else(|| target.to_string()),
+        ))
+    }
+
+    async fn upload_file(
+        &self,
+        container_name: &str,
+        host_path: &Path,
+        container_path: &Path,
+    ) -> Result<(), BuildfsError> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let tar_path = container_path.strip_prefix("/").unwrap_or(container_path);
+        if host_path.is_dir() {
+            builder.append_dir_all(tar_path, host_path)
+        } else {
+            builder.append_path_with_name(host_path, tar_path)
+        }
+        .map_err(BuildfsError::io("Could not append path to upload tarball"))?;
+        let tar = builder
+            .into_inner()
+            .map_err(BuildfsError::io("Could not finish upload tarball"))?;
 
-        (response.id, container_name)
+        self.client
+            .upload_to_container(
+                container_name,
+                Some(UploadToContainerOptions {
+                    path: "/",
+                    ..Default::default()
+                }),
+                tar.into(),
+            )
+            .await
+            .map_err(BuildfsError::engine(
+                "Could not upload file into container via Docker daemon",
+            ))
     }
 
-    async fn exec_in_container(&self, exec_params: ExecParams<'_>) -> Box<dyn ExecReader> {
+    async fn exec_in_container(&self, exec_params: ExecParams<'_>) -> Result<Box<dyn ExecReader>, BuildfsError> {
         let response = self
             .client
             .create_exec(
@@ -132,7 +253,7 @@ impl ContainerEngine for DockerContainerEngine {
                     attach_stdin: Some(false),
                     attach_stdout: Some(true),
                     attach_stderr: Some(true),
-                    tty: Some(true),
+                    tty: Some(false),
                     env: Some(
                         exec_params
                             .env
@@ -140,7 +261,7 @@ impl ContainerEngine for DockerContainerEngine {
                             .map(|(key, value)| format!("{key}={value}"))
                             .collect(),
                     ),
-                    cmd: Some(exec_params.cmd.split_whitespace().map(|s| s.to_owned()).collect()),
+                    cmd: Some(get_exec_args(&exec_params.cmd, exec_params.shell.as_deref())?),
                     privileged: exec_params.privileged,
                     user: format_uid_gid_string(exec_params.uid, exec_params.gid),
                     working_dir: exec_params
@@ -150,22 +271,81 @@ impl ContainerEngine for DockerContainerEngine {
                 },
             )
             .await
-            .expect("Could not create exec via Docker daemon");
+            .map_err(BuildfsError::engine("Could not create exec via Docker daemon"))?;
 
         let stream = match self
             .client
             .start_exec(&response.id, None)
             .await
-            .expect("Could not start exec via Docker daemon")
+            .map_err(BuildfsError::engine("Could not start exec via Docker daemon"))?
         {
             StartExecResults::Attached { output, input: _ } => output,
-            StartExecResults::Detached => panic!("Attaching to Docker daemon exec failed"),
+            StartExecResults::Detached => {
+                return Err(BuildfsError::Engine {
+                    context: "Attaching to Docker daemon exec failed",
+                    message: "the exec was started detached".to_string(),
+                })
+            }
         };
 
-        Box::new(DockerExecReader { stream })
+        Ok(Box::new(DockerExecReader {
+            stream,
+            exec_id: response.id,
+        }))
     }
 
-    async fn export_container(&self, container_name: &str, tar_path: &PathBuf) {
+    async fn inspect_exec(&self, exec_id: &str) -> Result<Option<i64>, BuildfsError> {
+        Ok(self
+            .client
+            .inspect_exec(exec_id)
+            .await
+            .map_err(BuildfsError::engine("Could not inspect exec via Docker daemon"))?
+            .exit_code)
+    }
+
+    async fn commit_container(
+        &self,
+        container_name: &str,
+        image: &BuildScriptContainerImage,
+    ) -> Result<(), BuildfsError> {
+        self.client
+            .commit_container(
+                CommitContainerOptions {
+                    container: container_name,
+                    repo: &image.name,
+                    tag: &image.tag,
+                    pause: true,
+                    ..Default::default()
+                },
+                Config::<String>::default(),
+            )
+            .await
+            .map_err(BuildfsError::engine("Could not commit container via Docker daemon"))?;
+        Ok(())
+    }
+
+    async fn push_image(&self, image: &BuildScriptContainerImage) -> Result<(), BuildfsError> {
+        let mut stream = self.client.push_image(
+            &image.name,
+            Some(PushImageOptions {
+                tag: image.tag.as_str(),
+            }),
+            get_registry_credentials(image),
+        );
+
+        while let Some(result) = stream.next().await {
+            let push_info = result.map_err(BuildfsError::engine("Could not push image via Docker daemon"))?;
+            if let Some(error) = push_info.error {
+                return Err(BuildfsError::Engine {
+                    context: "Could not push image via Docker daemon",
+                    message: format!("{}: {error}", image.full_name()),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    async fn export_container(&self, container_name: &str, tar_path: &PathBuf) -> Result<(), BuildfsError> {
         let mut stream = self.client.export_container(container_name);
         let mut file = tokio::fs::File::options()
             .write(true)
@@ -173,21 +353,108 @@ impl ContainerEngine for DockerContainerEngine {
             .create(true)
             .open(tar_path)
             .await
-            .expect("Could not open tarball file");
+            .map_err(BuildfsError::io("Could not open tarball file"))?;
 
         while let Some(result) = stream.next().await {
-            let bytes = result.expect("Could not stream contents of tarball while exporting Docker container");
+            let bytes = result.map_err(BuildfsError::engine(
+                "Could not stream contents of tarball while exporting Docker container",
+            ))?;
             file.write_all(&bytes)
                 .await
-                .expect("Could not write streamed-in content to tarball");
+                .map_err(BuildfsError::io("Could not write streamed-in content to tarball"))?;
         }
+        Ok(())
+    }
+
+    async fn diff_container(&self, container_name: &str) -> Result<Vec<ContainerChange>, BuildfsError> {
+        Ok(self
+            .client
+            .container_changes(container_name)
+            .await
+            .map_err(BuildfsError::engine(
+                "Could not retrieve container changes via Docker daemon",
+            ))?
+            .unwrap_or_default()
+            .into_iter()
+            .map(|change| ContainerChange {
+                path: PathBuf::from(change.path),
+                kind: match change.kind {
+                    ChangeType::_0 => ContainerChangeKind::Modified,
+                    ChangeType::_1 => ContainerChangeKind::Added,
+                    ChangeType::_2 => ContainerChangeKind::Deleted,
+                },
+            })
+            .collect())
+    }
+
+    async fn inspect_container(&self, container_name: &str) -> Option<ContainerInspection> {
+        let state = self
+            .client
+            .inspect_container(container_name, None)
+            .await
+            .ok()?
+            .state
+            .unwrap_or_default();
+
+        Some(ContainerInspection {
+            status: state.status.map(|status| status.to_string()).unwrap_or_default(),
+            running: state.running.unwrap_or_default(),
+            exit_code: state.exit_code,
+            oom_killed: state.oom_killed.unwrap_or_default(),
+            error: state.error.filter(|error| !error.is_empty()),
+        })
+    }
+
+    async fn container_stats(&self, container_name: &str) -> Option<ContainerStats> {
+        let stats = self
+            .client
+            .stats(
+                container_name,
+                Some(StatsOptions {
+                    stream: false,
+                    one_shot: true,
+                }),
+            )
+            .next()
+            .await?
+            .ok()?;
+
+        Some(ContainerStats {
+            cpu_usage_ns: stats.cpu_stats.cpu_usage.total_usage,
+            memory_bytes: stats.memory_stats.usage.unwrap_or_default(),
+        })
     }
 
-    async fn remove_container(&self, container_name: &str, timeout: Option<u64>) {
+    async fn container_logs(&self, container_name: &str, tail: usize) -> String {
+        let mut stream = self.client.logs(
+            container_name,
+            Some(LogsOptions::<String> {
+                stdout: true,
+                stderr: true,
+                tail: tail.to_string(),
+                ..Default::default()
+            }),
+        );
+        let mut logs = String::new();
+
+        while let Some(result) = stream.next().await {
+            match result {
+                Ok(log_output) => logs.push_str(&String::from_utf8_lossy(&log_output.into_bytes())),
+                Err(err) => {
+                    log::debug!("Could not stream container logs via Docker daemon: {err}");
+                    break;
+                }
+            }
+        }
+
+        logs
+    }
+
+    async fn remove_container(&self, container_name: &str, timeout: Option<u64>) -> Result<(), BuildfsError> {
         self.client
             .stop_container(container_name, timeout.map(|t| StopContainerOptions { t: t as i64 }))
             .await
-            .expect("Could not stop container via Docker daemon");
+            .map_err(BuildfsError::engine("Could not stop container via Docker daemon"))?;
 
         self.client
             .remove_container(
@@ -198,12 +465,13 @@ impl ContainerEngine for DockerContainerEngine {
                 }),
             )
             .await
-            .expect("Could not remove container via Docker daemon");
+            .map_err(BuildfsError::engine("Could not remove container via Docker daemon"))
     }
 }
 
 struct DockerExecReader {
     stream: Pin<Box<dyn Stream<Item = Result<LogOutput, bollard::errors::Error>> + Send>>,
+    exec_id: String,
 }
 
 #[async_trait]
@@ -218,4 +486,62 @@ impl ExecReader for DockerExecReader {
 
         Some((String::from_utf8_lossy(&bytes).into_owned(), stream_type))
     }
+
+    fn exec_id(&self) -> &str {
+        &self.exec_id
+    }
+}
+
+fn is_userns_conflict(err: &bollard::errors::Error) -> bool {
+    err.to_string().contains("incompatible with user namespaces")
+}
+
+#[cfg(test)]
+mod tests {
+    use bollard::container::LogOutput;
+    use hyper::body::Bytes;
+
+    use crate::container_engine::{ExecReader, StreamType};
+
+    use super::{is_userns_conflict, DockerExecReader};
+
+    #[tokio::test]
+    async fn exec_output_keeps_its_stream_type() {
+        let mut exec_reader = DockerExecReader {
+            stream: Box::pin(futures_util::stream::iter([
+                Ok(LogOutput::StdOut {
+                    message: Bytes::from_static(b"building\n"),
+                }),
+                Ok(LogOutput::StdErr {
+                    message: Bytes::from_static(b"warning\n"),
+                }),
+            ])),
+            exec_id: "exec".to_string(),
+        };
+
+        assert_eq!(
+            exec_reader.read().await,
+            Some(("building\n".to_string(), StreamType::Stdout))
+        );
+        assert_eq!(
+            exec_reader.read().await,
+            Some(("warning\n".to_string(), StreamType::Stderr))
+        );
+        assert_eq!(exec_reader.read().await, None);
+    }
+
+    #[test]
+    fn userns_conflicts_are_recognized() {
+        let conflict = bollard::errors::Error::DockerResponseServerError {
+            status_code: 500,
+            message: "privileged mode is incompatible with user namespaces.  You must run the container in the host namespace when running privileged mode".to_string(),
+        };
+        assert!(is_userns_conflict(&conflict));
+
+        let other = bollard::errors::Error::DockerResponseServerError {
+            status_code: 404,
+            message: "No such image: debian:bookworm".to_string(),
+        };
+        assert!(!is_userns_conflict(&other));
+    }
 }
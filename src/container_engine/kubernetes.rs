@@ -0,0 +1,501 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    process::Command,
+    sync::mpsc,
+};
+use uuid::Uuid;
+
+use crate::{
+    error::BuildfsError,
+    schema::{BuildScriptContainer, BuildScriptContainerImage, ContainerEngineType},
+};
+
+use super::{
+    get_exec_args, resolve_container_process, ContainerChange, ContainerEngine, ContainerInspection, ContainerStats,
+    ExecParams, ExecReader, StreamType,
+};
+
+static POD_CONTAINER_NAME: &str = "build";
+static POD_READY_TIMEOUT_S: u64 = 300;
+
+pub struct KubernetesContainerEngine {
+    kubectl_path: PathBuf,
+    context: Option<String>,
+    exit_codes: Arc<Mutex<HashMap<String, i64>>>,
+    config_maps: Mutex<HashMap<String, Vec<String>>>,
+}
+
+impl KubernetesContainerEngine {
+    pub fn new(connection_uri: Option<String>) -> Result<Self, BuildfsError> {
+        Ok(Self {
+            kubectl_path: which::which("kubectl")
+                .map_err(BuildfsError::engine("Could not locate the \"kubectl\" binary in PATH"))?,
+            context: connection_uri,
+            exit_codes: Arc::new(Mutex::new(HashMap::new())),
+            config_maps: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn kubectl(&self) -> Command {
+        let mut command = Command::new(&self.kubectl_path);
+        if let Some(ref context) = self.context {
+            command.arg("--context").arg(context);
+        }
+        command
+    }
+
+    async fn run_kubectl(&self, args: &[&str], stdin: Option<String>) -> Result<Vec<u8>, BuildfsError> {
+        let mut command = self.kubectl();
+        command
+            .args(args)
+            .stdin(if stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let mut child = command.spawn().map_err(BuildfsError::io("Could not invoke kubectl"))?;
+
+        if let Some(stdin) = stdin {
+            let mut child_stdin = child.stdin.take().expect("Could not take stdin of kubectl");
+            tokio::io::AsyncWriteExt::write_all(&mut child_stdin, stdin.as_bytes())
+                .await
+                .map_err(BuildfsError::io("Could not write to stdin of kubectl"))?;
+        }
+
+        let output = child
+            .wait_with_output()
+            .await
+            .map_err(BuildfsError::io("Could not wait on kubectl"))?;
+        if !output.status.success() {
+            return Err(BuildfsError::Engine {
+                context: "kubectl failed",
+                message: format!(
+                    "kubectl {}: {}",
+                    args.first().unwrap_or(&""),
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ),
+            });
+        }
+
+        Ok(output.stdout)
+    }
+
+    async fn get_pod(&self, pod_name: &str) -> Option<Value> {
+        let output = self
+            .kubectl()
+            .args(["get", "pod", pod_name, "-o", "json"])
+            .output()
+            .await
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+
+        serde_json::from_slice(&output.stdout).ok()
+    }
+}
+
+#[async_trait]
+impl ContainerEngine for KubernetesContainerEngine {
+    fn engine_type(&self) -> ContainerEngineType {
+        ContainerEngineType::Kubernetes
+    }
+
+    async fn ping(&self) -> Result<(), BuildfsError> {
+        let output = self
+            .kubectl()
+            .args(["auth", "can-i", "create", "pods"])
+            .output()
+            .await
+            .map_err(BuildfsError::io(
+                "Could not invoke kubectl to ping the Kubernetes cluster",
+            ))?;
+
+        if String::from_utf8_lossy(&output.stdout).trim() != "yes" {
+            return Err(BuildfsError::Engine {
+                context: "Pinging the Kubernetes cluster failed",
+                message: "the cluster does not allow the current user to create pods".to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    async fn pull_image(&self, _image: &BuildScriptContainerImage) -> Result<(), BuildfsError> {
+        // the kubelet pulls the image when the pod is scheduled
+        Ok(())
+    }
+
+    async fn load_image(&self, _archive_path: &Path) -> Result<(), BuildfsError> {
+        Err(BuildfsError::InvalidArguments(
+            "Images cannot be loaded into a Kubernetes cluster, push the image to a registry the cluster can access instead"
+                .to_string(),
+        ))
+    }
+
+    async fn image_exists(&self, _image: &BuildScriptContainerImage) -> bool {
+        // images are cached on whichever node the pod lands on, which can't be checked up front
+        false
+    }
+
+    async fn start_container(
+        &self,
+        container: BuildScriptContainer,
+        extra_volumes: HashMap<PathBuf, PathBuf>,
+    ) -> Result<(String, String), BuildfsError> {
+        let process = resolve_container_process(&container);
+        let pod_name = Uuid::new_v4().to_string();
+        let mut config_map_names = Vec::new();
+        let mut volumes = Vec::new();
+        let mut volume_mounts = Vec::new();
+
+        // each bind-mounted file is shipped to the pod as a single-key ConfigMap
+        for (index, (host_path, container_path)) in extra_volumes.iter().enumerate() {
+            if host_path.is_dir() {
+                return Err(BuildfsError::InvalidArguments(format!(
+                    "The directory {host_path:?} cannot be mounted into a Kubernetes pod, only files are supported"
+                )));
+            }
+
+            let config_map_name = format!("{pod_name}-{index}");
+            self.run_kubectl(
+                &[
+                    "create",
+                    "configmap",
+                    &config_map_name,
+                    &format!("--from-file=content={}", host_path.to_string_lossy()),
+                ],
+                None,
+            )
+            .await?;
+
+            volumes.push(json!({
+                "name": format!("file-{index}"),
+                "configMap": { "name": config_map_name, "defaultMode": 0o555 },
+            }));
+            volume_mounts.push(json!({
+                "name": format!("file-{index}"),
+                "mountPath": container_path,
+                "subPath": "content",
+            }));
+            config_map_names.push(config_map_name);
+        }
+
+        self.config_maps
+            .lock()
+            .expect("Kubernetes engine state was poisoned")
+            .insert(pod_name.clone(), config_map_names);
+
+        let pod = json!({
+            "apiVersion": "v1",
+            "kind": "Pod",
+            "metadata": {
+                "name": pod_name,
+                "labels": { "app.kubernetes.io/managed-by": "buildfs" },
+            },
+            "spec": {
+                "restartPolicy": "Never",
+                "hostname": container.hostname,
+                "hostNetwork": container.network_mode.as_deref() == Some("host"),
+                "runtimeClassName": container.oci_runtime,
+                "containers": [{
+                    "name": POD_CONTAINER_NAME,
+                    "image": container.image.full_name(),
+                    "command": process.entrypoint,
+                    "args": process.cmd,
+                    "tty": process.tty,
+                    "env": container
+                        .env
+                        .iter()
+                        .map(|(key, value)| json!({ "name": key, "value": value }))
+                        .collect::<Vec<_>>(),
+                    "securityContext": {
+                        "privileged": container.rootful,
+                        "capabilities": { "add": container.cap_add, "drop": container.cap_drop },
+                    },
+                    "volumeMounts": volume_mounts,
+                }],
+                "volumes": volumes,
+            },
+        });
+
+        self.run_kubectl(&["create", "-f", "-"], Some(pod.to_string())).await?;
+        self.run_kubectl(
+            &[
+                "wait",
+                "--for=condition=Ready",
+                &format!("pod/{pod_name}"),
+                &format!("--timeout={}s", container.timeout.unwrap_or(POD_READY_TIMEOUT_S)),
+            ],
+            None,
+        )
+        .await?;
+
+        let pod_uid = self
+            .get_pod(&pod_name)
+            .await
+            .and_then(|pod| pod["metadata"]["uid"].as_str().map(|uid| uid.to_string()))
+            .unwrap_or_else(|| pod_name.clone());
+        Ok((pod_uid, pod_name))
+    }
+
+    async fn resolve_attach_target(&self, target: &str) -> Result<(String, String), BuildfsError> {
+        let pod = self.get_pod(target).await.ok_or_else(|| BuildfsError::Engine {
+            context: "Could not find the pod to attach to via kubectl",
+            message: target.to_string(),
+        })?;
+
+        Ok((
+            pod["metadata"]["uid"].as_str().unwrap_or(target).to_string(),
+            target.to_string(),
+        ))
+    }
+
+    async fn upload_file(
+        &self,
+        container_name: &str,
+        host_path: &Path,
+        container_path: &Path,
+    ) -> Result<(), BuildfsError> {
+        if let Some(parent_path) = container_path.parent() {
+            self.run_kubectl(
+                &[
+                    "exec",
+                    container_name,
+                    "--",
+                    "mkdir",
+                    "-p",
+                    &parent_path.to_string_lossy(),
+                ],
+                None,
+            )
+            .await?;
+        }
+
+        self.run_kubectl(
+            &[
+                "cp",
+                &host_path.to_string_lossy(),
+                &format!("{container_name}:{}", container_path.to_string_lossy()),
+            ],
+            None,
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn exec_in_container(&self, exec_params: ExecParams<'_>) -> Result<Box<dyn ExecReader>, BuildfsError> {
+        let mut args = Vec::new();
+        // kubectl exec has no notion of a working directory, so it is entered via a shell
+        if let Some(working_dir) = exec_params.working_dir {
+            args.extend([
+                "sh".to_string(),
+                "-c".to_string(),
+                "cd \"$0\" && exec \"$@\"".to_string(),
+                working_dir.to_string_lossy().to_string(),
+            ]);
+        }
+        args.push("env".to_string());
+        args.extend(exec_params.env.into_iter().map(|(key, value)| format!("{key}={value}")));
+        args.extend(get_exec_args(&exec_params.cmd, exec_params.shell.as_deref())?);
+
+        let mut child = self
+            .kubectl()
+            .arg("exec")
+            .arg(exec_params.container_name)
+            .arg("--")
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(BuildfsError::io("Could not invoke kubectl to exec in pod"))?;
+
+        let exec_id = Uuid::new_v4().to_string();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let stdout_task = tokio::spawn(forward_lines(
+            child.stdout.take().expect("Could not take stdout of kubectl"),
+            StreamType::Stdout,
+            sender.clone(),
+        ));
+        let stderr_task = tokio::spawn(forward_lines(
+            child.stderr.take().expect("Could not take stderr of kubectl"),
+            StreamType::Stderr,
+            sender.clone(),
+        ));
+
+        let exit_codes = self.exit_codes.clone();
+        let task_exec_id = exec_id.clone();
+        tokio::spawn(async move {
+            let _ = stdout_task.await;
+            let _ = stderr_task.await;
+            if let Some(exit_code) = child.wait().await.ok().and_then(|status| status.code()) {
+                exit_codes
+                    .lock()
+                    .expect("Kubernetes engine state was poisoned")
+                    .insert(task_exec_id, exit_code as i64);
+            }
+            drop(sender);
+        });
+
+        Ok(Box::new(KubernetesExecReader { receiver, exec_id }))
+    }
+
+    async fn inspect_exec(&self, exec_id: &str) -> Result<Option<i64>, BuildfsError> {
+        Ok(self
+            .exit_codes
+            .lock()
+            .expect("Kubernetes engine state was poisoned")
+            .remove(exec_id))
+    }
+
+    async fn commit_container(
+        &self,
+        _container_name: &str,
+        _image: &BuildScriptContainerImage,
+    ) -> Result<(), BuildfsError> {
+        Err(BuildfsError::InvalidArguments(
+            "Pods cannot be committed into an image, post_build.commit_image is unsupported with Kubernetes"
+                .to_string(),
+        ))
+    }
+
+    async fn push_image(&self, _image: &BuildScriptContainerImage) -> Result<(), BuildfsError> {
+        Err(BuildfsError::InvalidArguments(
+            "Images cannot be pushed from a Kubernetes cluster".to_string(),
+        ))
+    }
+
+    async fn export_container(&self, container_name: &str, tar_path: &PathBuf) -> Result<(), BuildfsError> {
+        let file = std::fs::File::create(tar_path).map_err(BuildfsError::io("Could not open export tarball file"))?;
+        // the pod has no export API, so tar inside the pod streams out everything but other mounts
+        let output = self
+            .kubectl()
+            .args([
+                "exec",
+                container_name,
+                "--",
+                "tar",
+                "-C",
+                "/",
+                "-cf",
+                "-",
+                "--one-file-system",
+                ".",
+            ])
+            .stdout(file)
+            .stderr(Stdio::piped())
+            .output()
+            .await
+            .map_err(BuildfsError::io("Could not invoke kubectl to export pod rootfs"))?;
+
+        if !output.status.success() {
+            return Err(BuildfsError::Engine {
+                context: "Could not export pod rootfs via tar inside the pod",
+                message: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    async fn diff_container(&self, _container_name: &str) -> Result<Vec<ContainerChange>, BuildfsError> {
+        // callers check EngineCapability::ContainerDiff first, so this is only reached by mistake
+        Err(BuildfsError::Engine {
+            context: "Could not diff the pod's filesystem",
+            message: "container changes cannot be retrieved from a Kubernetes pod".to_string(),
+        })
+    }
+
+    async fn inspect_container(&self, container_name: &str) -> Option<ContainerInspection> {
+        let pod = self.get_pod(container_name).await?;
+        let state = &pod["status"]["containerStatuses"][0]["state"];
+        let terminated = &state["terminated"];
+
+        Some(ContainerInspection {
+            status: pod["status"]["phase"].as_str().unwrap_or_default().to_string(),
+            running: state["running"].is_object(),
+            exit_code: terminated["exitCode"].as_i64(),
+            oom_killed: terminated["reason"].as_str() == Some("OOMKilled"),
+            error: terminated["message"].as_str().map(|message| message.to_string()),
+        })
+    }
+
+    async fn container_stats(&self, _container_name: &str) -> Option<ContainerStats> {
+        // the metrics API only reports instantaneous usage and depends on metrics-server being installed
+        None
+    }
+
+    async fn container_logs(&self, container_name: &str, tail: usize) -> String {
+        match self
+            .kubectl()
+            .args(["logs", "--tail", &tail.to_string(), container_name])
+            .output()
+            .await
+        {
+            Ok(output) => {
+                String::from_utf8_lossy(&output.stdout).into_owned() + &String::from_utf8_lossy(&output.stderr)
+            }
+            Err(err) => format!("Container logs are unavailable: {err}"),
+        }
+    }
+
+    async fn remove_container(&self, container_name: &str, timeout: Option<u64>) -> Result<(), BuildfsError> {
+        self.run_kubectl(
+            &[
+                "delete",
+                "pod",
+                container_name,
+                &format!("--grace-period={}", timeout.unwrap_or(30)),
+            ],
+            None,
+        )
+        .await?;
+
+        let config_map_names = self
+            .config_maps
+            .lock()
+            .expect("Kubernetes engine state was poisoned")
+            .remove(container_name)
+            .unwrap_or_default();
+        for config_map_name in config_map_names {
+            self.run_kubectl(&["delete", "configmap", &config_map_name], None)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+async fn forward_lines(
+    reader: impl AsyncRead + Unpin,
+    stream_type: StreamType,
+    sender: mpsc::UnboundedSender<(String, StreamType)>,
+) {
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if sender.send((line + "\n", stream_type)).is_err() {
+            break;
+        }
+    }
+}
+
+struct KubernetesExecReader {
+    receiver: mpsc::UnboundedReceiver<(String, StreamType)>,
+    exec_id: String,
+}
+
+#[async_trait]
+impl ExecReader for KubernetesExecReader {
+    async fn read(&mut self) -> Option<(String, StreamType)> {
+        self.receiver.recv().await
+    }
+
+    fn exec_id(&self) -> &str {
+        &self.exec_id
+    }
+}
@@ -0,0 +1,299 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+
+use crate::{
+    error::BuildfsError,
+    schema::{BuildScriptContainer, BuildScriptContainerImage, ContainerEngineType},
+};
+
+use super::{
+    ContainerChange, ContainerEngine, ContainerInspection, ContainerStats, ExecParams, ExecReader, StreamType,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MockCall {
+    Ping,
+    PullImage(String),
+    LoadImage(PathBuf),
+    ImageExists(String),
+    StartContainer {
+        image: String,
+        volumes: HashMap<PathBuf, PathBuf>,
+    },
+    AttachContainer(String),
+    UploadFile(PathBuf),
+    Exec(String),
+    InspectExec(String),
+    CommitContainer(String),
+    PushImage(String),
+    ExportContainer,
+    DiffContainer,
+    InspectContainer,
+    ContainerStats,
+    ContainerLogs(usize),
+    RemoveContainer,
+}
+
+pub struct MockExecResponse {
+    pub output: Vec<(String, StreamType)>,
+    pub exit_code: i64,
+}
+
+#[derive(Default)]
+struct MockState {
+    calls: Vec<MockCall>,
+    exec_responses: VecDeque<MockExecResponse>,
+    exec_exit_codes: HashMap<String, i64>,
+    inspections: VecDeque<Option<ContainerInspection>>,
+    changes: VecDeque<Vec<ContainerChange>>,
+    stats: VecDeque<ContainerStats>,
+    rootfs_files: Vec<(PathBuf, Vec<u8>)>,
+    local_images: Vec<String>,
+    pull_errors: VecDeque<String>,
+    logs: String,
+}
+
+#[derive(Clone, Default)]
+pub struct MockContainerEngine {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockContainerEngine {
+    pub fn with_exec(self, output: &str, exit_code: i64) -> Self {
+        self.lock().exec_responses.push_back(MockExecResponse {
+            output: vec![(output.to_string(), StreamType::Stdout)],
+            exit_code,
+        });
+        self
+    }
+
+    pub fn with_inspection(self, inspection: Option<ContainerInspection>) -> Self {
+        self.lock().inspections.push_back(inspection);
+        self
+    }
+
+    pub fn with_changes(self, changes: Vec<ContainerChange>) -> Self {
+        self.lock().changes.push_back(changes);
+        self
+    }
+
+    pub fn with_stats(self, cpu_usage_ns: u64, memory_bytes: u64) -> Self {
+        self.lock().stats.push_back(ContainerStats {
+            cpu_usage_ns,
+            memory_bytes,
+        });
+        self
+    }
+
+    pub fn with_pull_error(self, error: &str) -> Self {
+        self.lock().pull_errors.push_back(error.to_string());
+        self
+    }
+
+    pub fn with_local_image(self, image: &str) -> Self {
+        self.lock().local_images.push(image.to_string());
+        self
+    }
+
+    pub fn with_rootfs_file(self, path: impl Into<PathBuf>, contents: impl Into<Vec<u8>>) -> Self {
+        self.lock().rootfs_files.push((path.into(), contents.into()));
+        self
+    }
+
+    pub fn with_logs(self, logs: &str) -> Self {
+        self.lock().logs = logs.to_string();
+        self
+    }
+
+    pub fn calls(&self) -> Vec<MockCall> {
+        self.lock().calls.clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MockState> {
+        self.state.lock().expect("Mock container engine state was poisoned")
+    }
+
+    fn record(&self, call: MockCall) {
+        self.lock().calls.push(call);
+    }
+}
+
+pub fn running_inspection() -> ContainerInspection {
+    ContainerInspection {
+        status: "running".to_string(),
+        running: true,
+        ..Default::default()
+    }
+}
+
+#[async_trait]
+impl ContainerEngine for MockContainerEngine {
+    fn engine_type(&self) -> ContainerEngineType {
+        ContainerEngineType::Mock
+    }
+
+    async fn ping(&self) -> Result<(), BuildfsError> {
+        self.record(MockCall::Ping);
+        Ok(())
+    }
+
+    async fn pull_image(&self, image: &BuildScriptContainerImage) -> Result<(), BuildfsError> {
+        let mut state = self.lock();
+        state.calls.push(MockCall::PullImage(image.full_name()));
+        match state.pull_errors.pop_front() {
+            Some(message) => Err(BuildfsError::Engine {
+                context: "Could not pull image via mock engine",
+                message,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    async fn load_image(&self, archive_path: &Path) -> Result<(), BuildfsError> {
+        self.record(MockCall::LoadImage(archive_path.to_path_buf()));
+        Ok(())
+    }
+
+    async fn image_exists(&self, image: &BuildScriptContainerImage) -> bool {
+        let mut state = self.lock();
+        state.calls.push(MockCall::ImageExists(image.full_name()));
+        state.local_images.contains(&image.full_name())
+    }
+
+    async fn start_container(
+        &self,
+        container: BuildScriptContainer,
+        mut extra_volumes: HashMap<PathBuf, PathBuf>,
+    ) -> Result<(String, String), BuildfsError> {
+        extra_volumes.extend(container.volumes);
+        self.record(MockCall::StartContainer {
+            image: container.image.full_name(),
+            volumes: extra_volumes,
+        });
+        Ok(("mock-container-id".to_string(), "mock-container".to_string()))
+    }
+
+    async fn resolve_attach_target(&self, target: &str) -> Result<(String, String), BuildfsError> {
+        self.record(MockCall::AttachContainer(target.to_string()));
+        Ok(("mock-container-id".to_string(), target.to_string()))
+    }
+
+    async fn upload_file(
+        &self,
+        _container_name: &str,
+        _host_path: &Path,
+        container_path: &Path,
+    ) -> Result<(), BuildfsError> {
+        self.record(MockCall::UploadFile(container_path.to_path_buf()));
+        Ok(())
+    }
+
+    async fn exec_in_container(&self, exec_params: ExecParams<'_>) -> Result<Box<dyn ExecReader>, BuildfsError> {
+        let mut state = self.lock();
+        state.calls.push(MockCall::Exec(exec_params.cmd));
+
+        let exec_id = format!("mock-exec-{}", state.exec_exit_codes.len());
+        let response = state.exec_responses.pop_front().unwrap_or(MockExecResponse {
+            output: Vec::new(),
+            exit_code: 0,
+        });
+        state.exec_exit_codes.insert(exec_id.clone(), response.exit_code);
+
+        Ok(Box::new(MockExecReader {
+            output: response.output.into(),
+            exec_id,
+        }))
+    }
+
+    async fn inspect_exec(&self, exec_id: &str) -> Result<Option<i64>, BuildfsError> {
+        let mut state = self.lock();
+        state.calls.push(MockCall::InspectExec(exec_id.to_string()));
+        Ok(state.exec_exit_codes.get(exec_id).copied())
+    }
+
+    async fn commit_container(
+        &self,
+        _container_name: &str,
+        image: &BuildScriptContainerImage,
+    ) -> Result<(), BuildfsError> {
+        self.record(MockCall::CommitContainer(image.full_name()));
+        Ok(())
+    }
+
+    async fn push_image(&self, image: &BuildScriptContainerImage) -> Result<(), BuildfsError> {
+        self.record(MockCall::PushImage(image.full_name()));
+        Ok(())
+    }
+
+    async fn export_container(&self, _container_name: &str, tar_path: &PathBuf) -> Result<(), BuildfsError> {
+        let mut state = self.lock();
+        state.calls.push(MockCall::ExportContainer);
+
+        let tar_file = std::fs::File::create(tar_path).expect("Could not create mock rootfs tarball");
+        let mut builder = tar::Builder::new(tar_file);
+        for (path, contents) in &state.rootfs_files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path.strip_prefix("/").unwrap_or(path), contents.as_slice())
+                .expect("Could not append file to mock rootfs tarball");
+        }
+        builder.finish().expect("Could not finish mock rootfs tarball");
+        Ok(())
+    }
+
+    async fn diff_container(&self, _container_name: &str) -> Result<Vec<ContainerChange>, BuildfsError> {
+        let mut state = self.lock();
+        state.calls.push(MockCall::DiffContainer);
+        Ok(state.changes.pop_front().unwrap_or_default())
+    }
+
+    async fn inspect_container(&self, _container_name: &str) -> Option<ContainerInspection> {
+        let mut state = self.lock();
+        state.calls.push(MockCall::InspectContainer);
+        state
+            .inspections
+            .pop_front()
+            .unwrap_or_else(|| Some(running_inspection()))
+    }
+
+    async fn container_stats(&self, _container_name: &str) -> Option<ContainerStats> {
+        let mut state = self.lock();
+        state.calls.push(MockCall::ContainerStats);
+        state.stats.pop_front()
+    }
+
+    async fn container_logs(&self, _container_name: &str, tail: usize) -> String {
+        let mut state = self.lock();
+        state.calls.push(MockCall::ContainerLogs(tail));
+        state.logs.clone()
+    }
+
+    async fn remove_container(&self, _container_name: &str, _timeout: Option<u64>) -> Result<(), BuildfsError> {
+        self.record(MockCall::RemoveContainer);
+        Ok(())
+    }
+}
+
+struct MockExecReader {
+    output: VecDeque<(String, StreamType)>,
+    exec_id: String,
+}
+
+#[async_trait]
+impl ExecReader for MockExecReader {
+    async fn read(&mut self) -> Option<(String, StreamType)> {
+        self.output.pop_front()
+    }
+
+    fn exec_id(&self) -> &str {
+        &self.exec_id
+    }
+}
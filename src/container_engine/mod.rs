@@ -1,31 +1,206 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+    time::Instant,
+};
 
 use async_trait::async_trait;
+use serde::Serialize;
 
-use crate::schema::{BuildScriptContainer, BuildScriptContainerImage};
+use crate::{
+    error::BuildfsError,
+    schema::{BuildScriptContainer, BuildScriptContainerImage, ContainerEngineType, KeepAlivePolicy},
+};
 
 pub mod docker;
+pub mod kubernetes;
+#[cfg(any(test, feature = "mock-engine"))]
+pub mod mock;
 pub mod podman;
 
 #[async_trait]
 pub trait ContainerEngine {
-    async fn ping(&self);
+    fn engine_type(&self) -> ContainerEngineType;
 
-    async fn pull_image(&self, image: &BuildScriptContainerImage);
+    async fn ping(&self) -> Result<(), BuildfsError>;
+
+    async fn pull_image(&self, image: &BuildScriptContainerImage) -> Result<(), BuildfsError>;
+
+    async fn load_image(&self, archive_path: &Path) -> Result<(), BuildfsError>;
+
+    async fn image_exists(&self, image: &BuildScriptContainerImage) -> bool;
 
     async fn start_container(
         &self,
         container: BuildScriptContainer,
         extra_volumes: HashMap<PathBuf, PathBuf>,
-    ) -> (String, String);
+    ) -> Result<(String, String), BuildfsError>;
+
+    async fn resolve_attach_target(&self, target: &str) -> Result<(String, String), BuildfsError>;
+
+    async fn upload_file(
+        &self,
+        container_name: &str,
+        host_path: &Path,
+        container_path: &Path,
+    ) -> Result<(), BuildfsError>;
+
+    async fn exec_in_container(&self, exec_params: ExecParams<'_>) -> Result<Box<dyn ExecReader>, BuildfsError>;
+
+    async fn inspect_exec(&self, exec_id: &str) -> Result<Option<i64>, BuildfsError>;
+
+    async fn commit_container(
+        &self,
+        container_name: &str,
+        image: &BuildScriptContainerImage,
+    ) -> Result<(), BuildfsError>;
+
+    async fn push_image(&self, image: &BuildScriptContainerImage) -> Result<(), BuildfsError>;
+
+    async fn export_container(&self, container_name: &str, tar_path: &PathBuf) -> Result<(), BuildfsError>;
+
+    async fn diff_container(&self, container_name: &str) -> Result<Vec<ContainerChange>, BuildfsError>;
+
+    async fn inspect_container(&self, container_name: &str) -> Option<ContainerInspection>;
+
+    async fn container_stats(&self, container_name: &str) -> Option<ContainerStats>;
+
+    async fn container_logs(&self, container_name: &str, tail: usize) -> String;
+
+    async fn remove_container(&self, container_name: &str, timeout: Option<u64>) -> Result<(), BuildfsError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EngineCapability {
+    ContainerTimeout,
+    HttpConnection,
+    UnixConnection,
+    NamedPipeConnection,
+    SshConnection,
+    OciRuntime,
+    Init,
+    EntrypointOverride,
+    HostVolumes,
+    UserNamespace,
+    IdMapping,
+    ContainerDiff,
+    ResourceStats,
+    ImageCache,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapabilitySupport {
+    Supported,
+    Ignored,
+    Unsupported,
+}
 
-    async fn exec_in_container(&self, exec_params: ExecParams<'_>) -> Box<dyn ExecReader>;
+impl Display for EngineCapability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EngineCapability::ContainerTimeout => write!(f, "container timeout"),
+            EngineCapability::HttpConnection => write!(f, "HTTP connection URI"),
+            EngineCapability::UnixConnection => write!(f, "Unix socket connection URI"),
+            EngineCapability::NamedPipeConnection => write!(f, "named pipe connection URI"),
+            EngineCapability::SshConnection => write!(f, "SSH connection URI"),
+            EngineCapability::OciRuntime => write!(f, "OCI runtime selection"),
+            EngineCapability::Init => write!(f, "init process"),
+            EngineCapability::EntrypointOverride => write!(f, "entrypoint and cmd overrides"),
+            EngineCapability::HostVolumes => write!(f, "host volumes"),
+            EngineCapability::UserNamespace => write!(f, "user namespace mode"),
+            EngineCapability::IdMapping => write!(f, "UID/GID mapping"),
+            EngineCapability::ContainerDiff => write!(f, "container filesystem diff"),
+            EngineCapability::ResourceStats => write!(f, "container resource statistics"),
+            EngineCapability::ImageCache => write!(f, "local image cache lookup"),
+        }
+    }
+}
+
+pub fn get_container_engine(
+    engine_type: &ContainerEngineType,
+    connection_uri: Option<String>,
+) -> Result<Box<dyn ContainerEngine + Send + Sync>, BuildfsError> {
+    Ok(match engine_type {
+        ContainerEngineType::Docker => Box::new(docker::DockerContainerEngine::new(connection_uri)?),
+        ContainerEngineType::Podman => Box::new(podman::PodmanContainerEngine::new(connection_uri)?),
+        ContainerEngineType::Kubernetes => Box::new(kubernetes::KubernetesContainerEngine::new(connection_uri)?),
+        #[cfg(any(test, feature = "mock-engine"))]
+        ContainerEngineType::Mock => Box::new(mock::MockContainerEngine::default()),
+    })
+}
+
+impl EngineCapability {
+    pub fn support(self, engine_type: &ContainerEngineType) -> CapabilitySupport {
+        match (engine_type, self) {
+            (ContainerEngineType::Docker, EngineCapability::ContainerTimeout) => CapabilitySupport::Ignored,
+            (ContainerEngineType::Podman, EngineCapability::HttpConnection) => CapabilitySupport::Unsupported,
+            (ContainerEngineType::Podman, EngineCapability::NamedPipeConnection) => CapabilitySupport::Unsupported,
+            (ContainerEngineType::Kubernetes, EngineCapability::NamedPipeConnection) => CapabilitySupport::Unsupported,
+            (ContainerEngineType::Kubernetes, EngineCapability::HttpConnection) => CapabilitySupport::Unsupported,
+            (ContainerEngineType::Kubernetes, EngineCapability::SshConnection) => CapabilitySupport::Unsupported,
+            (ContainerEngineType::Kubernetes, EngineCapability::Init) => CapabilitySupport::Ignored,
+            (ContainerEngineType::Kubernetes, EngineCapability::HostVolumes) => CapabilitySupport::Unsupported,
+            (ContainerEngineType::Docker, EngineCapability::IdMapping) => CapabilitySupport::Unsupported,
+            (ContainerEngineType::Kubernetes, EngineCapability::UserNamespace) => CapabilitySupport::Unsupported,
+            (ContainerEngineType::Kubernetes, EngineCapability::IdMapping) => CapabilitySupport::Unsupported,
+            (ContainerEngineType::Kubernetes, EngineCapability::ContainerDiff) => CapabilitySupport::Unsupported,
+            (ContainerEngineType::Kubernetes, EngineCapability::ResourceStats) => CapabilitySupport::Unsupported,
+            (ContainerEngineType::Kubernetes, EngineCapability::ImageCache) => CapabilitySupport::Unsupported,
+            _ => CapabilitySupport::Supported,
+        }
+    }
+
+    pub fn used_by(container: &BuildScriptContainer) -> Vec<EngineCapability> {
+        let mut capabilities = Vec::new();
+
+        if container.timeout.is_some() {
+            capabilities.push(EngineCapability::ContainerTimeout);
+        }
+
+        if let Some(ref connection_uri) = container.connection_uri {
+            if connection_uri.starts_with("http://") {
+                capabilities.push(EngineCapability::HttpConnection);
+            } else if connection_uri.starts_with("ssh://") {
+                capabilities.push(EngineCapability::SshConnection);
+            } else if connection_uri.starts_with("npipe://") {
+                capabilities.push(EngineCapability::NamedPipeConnection);
+            } else {
+                capabilities.push(EngineCapability::UnixConnection);
+            }
+        }
+
+        if container.oci_runtime.is_some() {
+            capabilities.push(EngineCapability::OciRuntime);
+        }
+
+        if container.init.is_some() {
+            capabilities.push(EngineCapability::Init);
+        }
 
-    async fn export_container(&self, container_name: &str, tar_path: &PathBuf);
+        if container.entrypoint.is_some() || container.cmd.is_some() {
+            capabilities.push(EngineCapability::EntrypointOverride);
+        }
 
-    async fn remove_container(&self, container_name: &str, timeout: Option<u64>);
+        if !container.volumes.is_empty() {
+            capabilities.push(EngineCapability::HostVolumes);
+        }
+
+        if container.userns.is_some() {
+            capabilities.push(EngineCapability::UserNamespace);
+        }
+
+        if !container.uidmap.is_empty() || !container.gidmap.is_empty() || container.keep_id {
+            capabilities.push(EngineCapability::IdMapping);
+        }
+
+        capabilities
+    }
 }
 
+// execs never get a TTY, so every engine demultiplexes stdout and stderr, and Unknown is only reported for output
+// that an engine can't attribute to either stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum StreamType {
     Stdout,
     Stdin,
@@ -33,15 +208,104 @@ pub enum StreamType {
     Unknown,
 }
 
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ContainerInspection {
+    pub status: String,
+    pub running: bool,
+    pub exit_code: Option<i64>,
+    pub oom_killed: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ContainerStats {
+    pub cpu_usage_ns: u64,
+    pub memory_bytes: u64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ContainerChange {
+    pub path: PathBuf,
+    pub kind: ContainerChangeKind,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ContainerChangeKind {
+    Modified,
+    Added,
+    Deleted,
+}
+
+impl From<i64> for ContainerChangeKind {
+    fn from(value: i64) -> Self {
+        match value {
+            1 => ContainerChangeKind::Added,
+            2 => ContainerChangeKind::Deleted,
+            _ => ContainerChangeKind::Modified,
+        }
+    }
+}
+
 #[async_trait]
 pub trait ExecReader {
     async fn read(&mut self) -> Option<(String, StreamType)>;
+
+    fn exec_id(&self) -> &str;
+}
+
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExecOutcome {
+    pub exit_code: Option<i64>,
+    pub duration_ms: u64,
+    pub stdout_bytes: u64,
+    pub stderr_bytes: u64,
+}
+
+pub struct ExecSession {
+    exec_reader: Box<dyn ExecReader>,
+    started_at: Instant,
+    stdout_bytes: u64,
+    stderr_bytes: u64,
+}
+
+impl ExecSession {
+    pub fn new(exec_reader: Box<dyn ExecReader>) -> Self {
+        Self {
+            exec_reader,
+            started_at: Instant::now(),
+            stdout_bytes: 0,
+            stderr_bytes: 0,
+        }
+    }
+
+    pub async fn read(&mut self) -> Option<(String, StreamType)> {
+        let (output, stream_type) = self.exec_reader.read().await?;
+        match stream_type {
+            StreamType::Stdout => self.stdout_bytes += output.len() as u64,
+            StreamType::Stderr => self.stderr_bytes += output.len() as u64,
+            StreamType::Stdin | StreamType::Unknown => {}
+        }
+
+        Some((output, stream_type))
+    }
+
+    pub async fn finish(self, container_engine: &dyn ContainerEngine) -> Result<ExecOutcome, BuildfsError> {
+        // the output streams closing is what marks the end of the exec, so the wall time is taken before inspecting
+        let duration_ms = self.started_at.elapsed().as_millis() as u64;
+        Ok(ExecOutcome {
+            exit_code: container_engine.inspect_exec(self.exec_reader.exec_id()).await?,
+            duration_ms,
+            stdout_bytes: self.stdout_bytes,
+            stderr_bytes: self.stderr_bytes,
+        })
+    }
 }
 
 pub struct ExecParams<'a> {
     pub container_name: &'a str,
     pub container_id: &'a str,
     pub cmd: String,
+    pub shell: Option<String>,
     pub uid: Option<u32>,
     pub gid: Option<u32>,
     pub working_dir: Option<PathBuf>,
@@ -49,6 +313,101 @@ pub struct ExecParams<'a> {
     pub env: HashMap<String, String>,
 }
 
+pub(super) fn get_exec_args(cmd: &str, shell: Option<&str>) -> Result<Vec<String>, BuildfsError> {
+    let split = |text: &str| {
+        split_shell_words(text)
+            .map_err(|err| BuildfsError::Validation(format!("\"{text}\" can't be split into arguments, since {err}")))
+    };
+    Ok(match shell {
+        // the shell receives the whole command as one argument, so quoting, pipes and redirects work as written
+        Some(shell) => split(shell)?
+            .into_iter()
+            .chain(["-c".to_string(), cmd.to_string()])
+            .collect(),
+        None => split(cmd)?,
+    })
+}
+
+pub fn split_shell_words(text: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    let mut characters = text.chars();
+    while let Some(character) = characters.next() {
+        match (quote, character) {
+            // nothing, not even a backslash, is special inside single quotes
+            (Some('\''), '\'') => quote = None,
+            (Some('"'), '"') => quote = None,
+            (Some('"'), '\\') => match characters.next() {
+                Some('\n') => {}
+                Some(escaped @ ('"' | '\\' | '$' | '`')) => word.get_or_insert_with(String::new).push(escaped),
+                Some(other) => word.get_or_insert_with(String::new).extend(['\\', other]),
+                None => return Err("it has an unterminated quote".to_string()),
+            },
+            // a backslash before a line break continues the line, like in a shell
+            (None, '\\') => match characters.next() {
+                Some('\n') => {}
+                Some(escaped) => word.get_or_insert_with(String::new).push(escaped),
+                None => return Err("it ends with a lone backslash".to_string()),
+            },
+            (None, '\'' | '"') => {
+                quote = Some(character);
+                word.get_or_insert_with(String::new);
+            }
+            (None, _) if character.is_whitespace() => words.extend(word.take()),
+            _ => word.get_or_insert_with(String::new).push(character),
+        }
+    }
+
+    if quote.is_some() {
+        return Err("it has an unterminated quote".to_string());
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+pub fn quote_shell_word(word: &str) -> String {
+    format!("'{}'", word.replace('\'', "'\\''"))
+}
+
+pub(super) struct ContainerProcess {
+    pub entrypoint: Option<Vec<String>>,
+    pub cmd: Option<Vec<String>>,
+    pub init: Option<bool>,
+    pub tty: bool,
+}
+
+pub(super) fn resolve_container_process(container: &BuildScriptContainer) -> ContainerProcess {
+    if container.entrypoint.is_some() || container.cmd.is_some() {
+        return ContainerProcess {
+            entrypoint: container.entrypoint.clone(),
+            cmd: container.cmd.clone(),
+            init: container.init,
+            tty: true,
+        };
+    }
+
+    match container.keep_alive {
+        KeepAlivePolicy::Pause => ContainerProcess {
+            entrypoint: Some(
+                container
+                    .pause_cmd
+                    .clone()
+                    .unwrap_or_else(|| vec!["sleep".to_string(), "infinity".to_string()]),
+            ),
+            cmd: Some(Vec::new()),
+            init: Some(container.init.unwrap_or(true)),
+            tty: false,
+        },
+        KeepAlivePolicy::Tty => ContainerProcess {
+            entrypoint: None,
+            cmd: None,
+            init: container.init,
+            tty: true,
+        },
+    }
+}
+
 pub(super) fn format_uid_gid_string(uid: Option<u32>, gid: Option<u32>) -> Option<String> {
     match uid {
         Some(uid) => match gid {
@@ -58,3 +417,47 @@ pub(super) fn format_uid_gid_string(uid: Option<u32>, gid: Option<u32>) -> Optio
         None => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::schema::parse_build_script;
+
+    use super::{get_exec_args, quote_shell_word, resolve_container_process, split_shell_words};
+
+    #[test]
+    fn image_process_is_kept_unless_pause_is_opted_into() {
+        let build_script_toml = "schema_version = 1\n[filesystem]\nsize_mib = 64\n[container]\nimage = { name = \"debian\", tag = \"bookworm\" }\n";
+        let process = resolve_container_process(&parse_build_script(build_script_toml).container);
+        assert!(process.tty && process.entrypoint.is_none() && process.cmd.is_none());
+
+        let build_script = parse_build_script(&format!("{build_script_toml}keep_alive = \"Pause\"\n"));
+        let process = resolve_container_process(&build_script.container);
+        assert_eq!(
+            process.entrypoint,
+            Some(vec!["sleep".to_string(), "infinity".to_string()])
+        );
+        assert!(!process.tty);
+    }
+
+    #[test]
+    fn commands_are_split_like_a_shell_would() {
+        assert_eq!(
+            get_exec_args("sh -c 'echo a b'", None).unwrap(),
+            vec!["sh".to_string(), "-c".to_string(), "echo a b".to_string()]
+        );
+        assert_eq!(
+            split_shell_words("apt-get install -y \\\n  curl \"a \\\"b\\\"\" ''").unwrap(),
+            vec!["apt-get", "install", "-y", "curl", "a \"b\"", ""]
+        );
+        assert_eq!(
+            get_exec_args("echo $HOME", Some("/bin/bash -eo pipefail")).unwrap(),
+            vec!["/bin/bash", "-eo", "pipefail", "-c", "echo $HOME"]
+        );
+        assert!(split_shell_words("echo 'a").is_err());
+        assert!(split_shell_words("echo \\").is_err());
+        assert_eq!(
+            split_shell_words(&format!("find {}", quote_shell_word("/srv/it's here"))).unwrap(),
+            vec!["find", "/srv/it's here"]
+        );
+    }
+}
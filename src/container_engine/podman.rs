@@ -1,37 +1,52 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use async_trait::async_trait;
 use futures_util::StreamExt;
-use hyper::upgrade::Upgraded;
-use hyper_util::rt::TokioIo;
 use podman_rest_client::{
     v5::{
-        apis::{Containers, Exec, Images, System},
-        models::{BindOptions, ContainerExecLibpodBody, ExecStartLibpodBody, Mount, SpecGenerator},
-        params::{ContainerStopLibpod, ImagePullLibpod},
+        apis::{Containers, ContainersCompat, Exec, ExecCompat, Images, Pods, System},
+        models::{
+            BindOptions, ContainerExecLibpodBody, ExecStartLibpodBody, IdMap, IdMappingOptions, Mount, Namespace,
+            SpecGenerator,
+        },
+        params::{ContainerStats as ContainerStatsParams, ContainerStopLibpod, ImageCommitLibpod, ImagePullLibpod},
     },
     AttachFrame, AttachFrameStream, PodmanRestClient,
 };
-use tokio::io::AsyncWriteExt;
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncWriteExt};
 use uuid::Uuid;
 
 use crate::{
-    container_engine::format_uid_gid_string,
-    schema::{BuildScriptContainer, BuildScriptContainerImage},
+    container_engine::{format_uid_gid_string, get_exec_args, resolve_container_process},
+    error::BuildfsError,
+    privilege,
+    schema::{BuildScriptContainer, BuildScriptContainerImage, BuildScriptIdMap, ContainerEngineType},
+};
+
+use super::{
+    ContainerChange, ContainerEngine, ContainerInspection, ContainerStats, ExecParams, ExecReader, StreamType,
 };
 
-use super::{ContainerEngine, ExecParams, ExecReader, StreamType};
+static EXEC_INSPECT_ATTEMPTS: u32 = 20;
 
 pub struct PodmanContainerEngine {
     client: PodmanRestClient,
+    cli_url: Option<String>,
 }
 
 impl PodmanContainerEngine {
-    pub fn new(connection_uri: Option<String>) -> Self {
+    pub fn new(connection_uri: Option<String>) -> Result<Self, BuildfsError> {
+        let cli_url = connection_uri.clone();
         let connection_uri = match connection_uri {
             Some(uri) => uri,
             None => {
-                let uid = unsafe { libc::geteuid() };
+                // inside the user namespace of --privilege-helper, the socket still belongs to the invoking user
+                let uid = privilege::get_host_uid();
                 let resolved_uri = match uid {
                     0 => "unix:///run/podman/podman.sock".to_string(),
                     other => format!("unix:///run/user/{other}/podman/podman.sock"),
@@ -42,47 +57,97 @@ impl PodmanContainerEngine {
         };
 
         if !connection_uri.starts_with("unix://") {
-            panic!("A Podman connection can only use a Unix socket and must be unix://M where M is the socket path");
+            return Err(BuildfsError::InvalidArguments(
+                "A Podman connection can only use a Unix socket and must be unix://M where M is the socket path"
+                    .to_string(),
+            ));
         }
 
         let socket_path = connection_uri.trim_start_matches("unix://");
-        Self {
+        Ok(Self {
             client: PodmanRestClient::new_unix(socket_path),
+            cli_url,
+        })
+    }
+
+    fn podman_cli(&self, podman_path: &Path) -> tokio::process::Command {
+        let mut command = tokio::process::Command::new(podman_path);
+        if let Some(ref cli_url) = self.cli_url {
+            command.arg("--url").arg(cli_url);
         }
+        command
+    }
+
+    fn locate_podman(&self) -> Result<PathBuf, BuildfsError> {
+        which::which("podman").map_err(BuildfsError::engine("Could not locate the \"podman\" binary in PATH"))
     }
 }
 
 #[async_trait]
 impl ContainerEngine for PodmanContainerEngine {
-    async fn ping(&self) {
+    fn engine_type(&self) -> ContainerEngineType {
+        ContainerEngineType::Podman
+    }
+
+    async fn ping(&self) -> Result<(), BuildfsError> {
         self.client
             .system_version_libpod()
             .await
-            .expect("Pinging libpod failed");
+            .map_err(BuildfsError::engine("Pinging libpod failed"))?;
+        Ok(())
     }
 
-    async fn pull_image(&self, image: &BuildScriptContainerImage) {
+    async fn pull_image(&self, image: &BuildScriptContainerImage) -> Result<(), BuildfsError> {
         self.client
             .image_pull_libpod(Some(ImagePullLibpod {
                 reference: Some(image.full_name().as_str()),
                 ..Default::default()
             }))
             .await
-            .expect("Could not pull image via libpod");
+            .map(|_| ())
+            .map_err(BuildfsError::engine("Could not pull image via libpod"))
+    }
+
+    async fn load_image(&self, archive_path: &Path) -> Result<(), BuildfsError> {
+        // the libpod client only accepts the uploaded archive as a string, so the podman CLI is used instead
+        let podman_path = self.locate_podman()?;
+        let output = self
+            .podman_cli(&podman_path)
+            .arg("load")
+            .arg("--input")
+            .arg(archive_path)
+            .output()
+            .await
+            .map_err(BuildfsError::io("Could not invoke podman to load image"))?;
+
+        if !output.status.success() {
+            return Err(BuildfsError::Engine {
+                context: "Could not load image via podman",
+                message: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    async fn image_exists(&self, image: &BuildScriptContainerImage) -> bool {
+        self.client.image_exists_libpod(&image.full_name()).await.is_ok()
     }
 
     async fn start_container(
         &self,
         container: BuildScriptContainer,
         mut extra_volumes: HashMap<PathBuf, PathBuf>,
-    ) -> (String, String) {
+    ) -> Result<(String, String), BuildfsError> {
         let container_name = Uuid::new_v4().to_string();
+        let process = resolve_container_process(&container);
+        let userns = get_userns(&container);
+        let idmappings = get_idmappings(&container);
         extra_volumes.extend(container.volumes);
 
         let spec_generator = SpecGenerator {
             image: Some(container.image.full_name()),
             privileged: Some(container.rootful),
-            terminal: Some(true),
+            terminal: Some(process.tty),
             remove: Some(true),
             env: Some(container.env),
             hostname: container.hostname,
@@ -90,6 +155,15 @@ impl ContainerEngine for PodmanContainerEngine {
             timeout: container.timeout,
             cap_add: container.cap_add,
             cap_drop: container.cap_drop,
+            netns: container.network_mode.map(|network_mode| Namespace {
+                nsmode: Some(network_mode),
+                value: None,
+            }),
+            entrypoint: process.entrypoint,
+            command: process.cmd,
+            init: process.init,
+            userns,
+            idmappings,
             name: Some(container_name.clone()),
             mounts: Some(
                 extra_volumes
@@ -113,22 +187,90 @@ impl ContainerEngine for PodmanContainerEngine {
             .client
             .container_create_libpod(spec_generator)
             .await
-            .expect("Could not create container via libpod");
+            .map_err(BuildfsError::engine("Could not create container via libpod"))?;
 
         self.client
             .container_start_libpod(&container_name, None)
             .await
-            .expect("Could not start container via libpod");
+            .map_err(BuildfsError::engine("Could not start container via libpod"))?;
+
+        Ok((response.id, container_name))
+    }
+
+    async fn resolve_attach_target(&self, target: &str) -> Result<(String, String), BuildfsError> {
+        if let Ok(inspection) = self.client.container_inspect_libpod(target, None).await {
+            return Ok((
+                inspection.id.unwrap_or_else(|| target.to_string()),
+                inspection.name.unwrap_or_else(|| target.to_string()),
+            ));
+        }
+
+        // a pod is attached to via its first container that isn't the infra container
+        let pod = self
+            .client
+            .pod_inspect_libpod(target)
+            .await
+            .map_err(BuildfsError::engine(
+                "Could not find the container or pod to attach to via libpod",
+            ))?;
+        pod.containers
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|container| container.id != pod.infra_container_id)
+            .find_map(|container| Some((container.id?, container.name?)))
+            .ok_or_else(|| BuildfsError::Engine {
+                context: "Could not find a non-infra container inside the pod to attach to",
+                message: target.to_string(),
+            })
+    }
 
-        (response.id, container_name)
+    async fn upload_file(
+        &self,
+        container_name: &str,
+        host_path: &Path,
+        container_path: &Path,
+    ) -> Result<(), BuildfsError> {
+        // the libpod client only accepts the uploaded archive as a string, so the podman CLI is used instead
+        let podman_path = self.locate_podman()?;
+
+        if let Some(parent_path) = container_path.parent() {
+            let status = self
+                .podman_cli(&podman_path)
+                .arg("exec")
+                .arg(container_name)
+                .arg("mkdir")
+                .arg("-p")
+                .arg(parent_path)
+                .status()
+                .await
+                .map_err(BuildfsError::io("Could not invoke podman to create upload directory"))?;
+            if !status.success() {
+                return Err(BuildfsError::Engine {
+                    context: "Could not create upload directory inside container via podman",
+                    message: format!("{parent_path:?}: {status}"),
+                });
+            }
+        }
+
+        let status = self
+            .podman_cli(&podman_path)
+            .arg("cp")
+            .arg(host_path)
+            .arg(format!("{container_name}:{}", container_path.to_string_lossy()))
+            .status()
+            .await
+            .map_err(BuildfsError::io("Could not invoke podman to upload file"))?;
+        if !status.success() {
+            return Err(BuildfsError::Engine {
+                context: "Could not upload file into container via podman",
+                message: format!("{host_path:?}: {status}"),
+            });
+        }
+        Ok(())
     }
 
-    async fn exec_in_container(&self, exec_params: ExecParams<'_>) -> Box<dyn ExecReader> {
-        let cmd_parts = exec_params
-            .cmd
-            .split_whitespace()
-            .map(|slice| slice.to_owned())
-            .collect::<Vec<_>>();
+    async fn exec_in_container(&self, exec_params: ExecParams<'_>) -> Result<Box<dyn ExecReader>, BuildfsError> {
+        let cmd_parts = get_exec_args(&exec_params.cmd, exec_params.shell.as_deref())?;
 
         let exec_id = self
             .client
@@ -138,6 +280,7 @@ impl ContainerEngine for PodmanContainerEngine {
                     attach_stdout: Some(true),
                     attach_stdin: Some(false),
                     attach_stderr: Some(true),
+                    tty: Some(false),
                     cmd: Some(cmd_parts),
                     user: format_uid_gid_string(exec_params.uid, exec_params.gid),
                     working_dir: exec_params
@@ -149,7 +292,7 @@ impl ContainerEngine for PodmanContainerEngine {
                 },
             )
             .await
-            .expect("Could not create exec via libpod")
+            .map_err(BuildfsError::engine("Could not create exec via libpod"))?
             .id;
 
         let exec_io = self
@@ -158,35 +301,176 @@ impl ContainerEngine for PodmanContainerEngine {
                 &exec_id,
                 ExecStartLibpodBody {
                     detach: Some(false),
+                    tty: Some(false),
                     ..Default::default()
                 },
             )
             .await
-            .expect("Could not start exec via libpod");
+            .map_err(BuildfsError::engine("Could not start exec via libpod"))?;
         let stream = AttachFrameStream::new(exec_io);
 
-        Box::new(PodmanExecReader { stream })
+        Ok(Box::new(PodmanExecReader { stream, exec_id }))
+    }
+
+    async fn inspect_exec(&self, exec_id: &str) -> Result<Option<i64>, BuildfsError> {
+        // the attach stream can close before libpod has reaped the exec, in which case it reports no exit code yet
+        for _ in 0..EXEC_INSPECT_ATTEMPTS {
+            let exec_session = self
+                .client
+                .exec_inspect(exec_id)
+                .await
+                .map_err(BuildfsError::engine("Could not inspect exec via libpod"))?;
+            if exec_session.running != Some(true) {
+                return Ok(exec_session.exit_code);
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        log::warn!("Exec {exec_id} was still running after its output ended, so its exit code is unknown");
+        Ok(None)
+    }
+
+    async fn commit_container(
+        &self,
+        container_name: &str,
+        image: &BuildScriptContainerImage,
+    ) -> Result<(), BuildfsError> {
+        self.client
+            .image_commit_libpod(Some(ImageCommitLibpod {
+                container: container_name,
+                repo: Some(&image.name),
+                tag: Some(&image.tag),
+                pause: Some(true),
+                ..Default::default()
+            }))
+            .await
+            .map_err(BuildfsError::engine("Could not commit container via libpod"))?;
+        Ok(())
     }
 
-    async fn export_container(&self, container_name: &str, tar_path: &PathBuf) {
+    async fn push_image(&self, image: &BuildScriptContainerImage) -> Result<(), BuildfsError> {
+        // the podman CLI picks up the credentials stored by "podman login", which the libpod client does not
+        let podman_path = self.locate_podman()?;
+        let output = self
+            .podman_cli(&podman_path)
+            .arg("push")
+            .arg(image.full_name())
+            .output()
+            .await
+            .map_err(BuildfsError::io("Could not invoke podman to push image"))?;
+
+        if !output.status.success() {
+            return Err(BuildfsError::Engine {
+                context: "Could not push image via podman",
+                message: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    async fn export_container(&self, container_name: &str, tar_path: &PathBuf) -> Result<(), BuildfsError> {
         let mut file = tokio::fs::File::options()
             .write(true)
             .create(true)
             .append(true)
             .open(tar_path)
             .await
-            .expect("Could not open export tarball file");
+            .map_err(BuildfsError::io("Could not open export tarball file"))?;
         let mut stream = self.client.container_export_libpod(container_name);
 
         while let Some(bytes_result) = stream.next().await {
-            let bytes = bytes_result.expect("Could not receive bytes streamed-in from libpod");
+            let bytes =
+                bytes_result.map_err(BuildfsError::engine("Could not receive bytes streamed-in from libpod"))?;
             file.write_all(&bytes)
                 .await
-                .expect("Could not write streamed-in tar contents to file");
+                .map_err(BuildfsError::io("Could not write streamed-in tar contents to file"))?;
         }
+        Ok(())
+    }
+
+    async fn diff_container(&self, container_name: &str) -> Result<Vec<ContainerChange>, BuildfsError> {
+        let changes_json = self
+            .client
+            .container_changes_libpod(container_name, None)
+            .await
+            .map_err(BuildfsError::engine("Could not retrieve container changes via libpod"))?;
+
+        Ok(
+            serde_json::from_str::<Option<Vec<PodmanContainerChange>>>(&changes_json)
+                .map_err(BuildfsError::engine(
+                    "Could not decode container changes returned by libpod",
+                ))?
+                .unwrap_or_default()
+                .into_iter()
+                .map(|change| ContainerChange {
+                    path: PathBuf::from(change.path),
+                    kind: change.kind.into(),
+                })
+                .collect(),
+        )
+    }
+
+    async fn inspect_container(&self, container_name: &str) -> Option<ContainerInspection> {
+        let state = self
+            .client
+            .container_inspect_libpod(container_name, None)
+            .await
+            .ok()?
+            .state
+            .unwrap_or_default();
+
+        Some(ContainerInspection {
+            status: state.status.unwrap_or_default(),
+            running: state.running.unwrap_or_default(),
+            exit_code: state.exit_code.map(i64::from),
+            oom_killed: state.oom_killed.unwrap_or_default(),
+            error: state.error.filter(|error| !error.is_empty()),
+        })
     }
 
-    async fn remove_container(&self, container_name: &str, timeout: Option<u64>) {
+    async fn container_stats(&self, container_name: &str) -> Option<ContainerStats> {
+        // the libpod stats model doesn't match what libpod actually returns, so the Docker-compatible endpoint is used
+        let stats = self
+            .client
+            .container_stats(
+                container_name,
+                Some(ContainerStatsParams {
+                    stream: Some(false),
+                    one_shot: Some(true),
+                }),
+            )
+            .await
+            .ok()?;
+
+        Some(ContainerStats {
+            cpu_usage_ns: stats["cpu_stats"]["cpu_usage"]["total_usage"].as_u64()?,
+            memory_bytes: stats["memory_stats"]["usage"].as_u64().unwrap_or_default(),
+        })
+    }
+
+    async fn container_logs(&self, container_name: &str, tail: usize) -> String {
+        // the libpod client discards the response body of the logs endpoint, so the podman CLI is used instead
+        let Ok(podman_path) = which::which("podman") else {
+            return "Container logs are unavailable: the \"podman\" binary could not be located in PATH".to_string();
+        };
+
+        match self
+            .podman_cli(&podman_path)
+            .arg("logs")
+            .arg("--tail")
+            .arg(tail.to_string())
+            .arg(container_name)
+            .output()
+            .await
+        {
+            Ok(output) => {
+                String::from_utf8_lossy(&output.stdout).into_owned() + &String::from_utf8_lossy(&output.stderr)
+            }
+            Err(err) => format!("Container logs are unavailable: {err}"),
+        }
+    }
+
+    async fn remove_container(&self, container_name: &str, timeout: Option<u64>) -> Result<(), BuildfsError> {
         self.client
             .container_stop_libpod(
                 container_name,
@@ -196,16 +480,26 @@ impl ContainerEngine for PodmanContainerEngine {
                 }),
             )
             .await
-            .expect("Could not stop container via libpod");
+            .map_err(BuildfsError::engine("Could not stop container via libpod"))?;
+        Ok(())
     }
 }
 
-struct PodmanExecReader {
-    stream: AttachFrameStream<TokioIo<Upgraded>>,
+#[derive(Deserialize)]
+struct PodmanContainerChange {
+    #[serde(rename = "Path")]
+    path: String,
+    #[serde(rename = "Kind")]
+    kind: i64,
+}
+
+struct PodmanExecReader<R> {
+    stream: AttachFrameStream<R>,
+    exec_id: String,
 }
 
 #[async_trait]
-impl ExecReader for PodmanExecReader {
+impl<R: AsyncRead + Unpin + Send> ExecReader for PodmanExecReader<R> {
     async fn read(&mut self) -> Option<(String, StreamType)> {
         let attach_frame = self.stream.next().await?.ok()?;
         let (bytes, stream_type) = match attach_frame {
@@ -216,4 +510,81 @@ impl ExecReader for PodmanExecReader {
 
         Some((String::from_utf8_lossy(&bytes).into_owned(), stream_type))
     }
+
+    fn exec_id(&self) -> &str {
+        &self.exec_id
+    }
+}
+
+fn get_userns(container: &BuildScriptContainer) -> Option<Namespace> {
+    // explicit mappings need a private user namespace to be applied to
+    let nsmode = match container.userns {
+        Some(ref userns) => userns.clone(),
+        None if container.keep_id => "keep-id".to_string(),
+        None if !container.uidmap.is_empty() || !container.gidmap.is_empty() => "private".to_string(),
+        None => return None,
+    };
+
+    Some(Namespace {
+        nsmode: Some(nsmode),
+        value: None,
+    })
+}
+
+fn get_idmappings(container: &BuildScriptContainer) -> Option<IdMappingOptions> {
+    if container.uidmap.is_empty() && container.gidmap.is_empty() {
+        return None;
+    }
+
+    let to_id_maps = |id_maps: &[BuildScriptIdMap]| {
+        id_maps
+            .iter()
+            .map(|id_map| IdMap {
+                container_id: Some(id_map.container_id.into()),
+                host_id: Some(id_map.host_id.into()),
+                size: Some(id_map.size.into()),
+            })
+            .collect::<Vec<_>>()
+    };
+    Some(IdMappingOptions {
+        uid_map: Some(to_id_maps(&container.uidmap)),
+        gid_map: Some(to_id_maps(&container.gidmap)),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use podman_rest_client::AttachFrameStream;
+    use tokio::io::AsyncWriteExt;
+
+    use crate::container_engine::{ExecReader, StreamType};
+
+    use super::PodmanExecReader;
+
+    #[tokio::test]
+    async fn exec_output_is_demultiplexed_into_stdout_and_stderr() {
+        let (mut writer, reader) = tokio::io::duplex(64);
+        for (frame_type, payload) in [(1u8, "building\n"), (2u8, "warning\n")] {
+            let mut frame = vec![frame_type, 0, 0, 0];
+            frame.extend((payload.len() as u32).to_be_bytes());
+            frame.extend(payload.as_bytes());
+            writer.write_all(&frame).await.unwrap();
+        }
+        drop(writer);
+
+        let mut exec_reader = PodmanExecReader {
+            stream: AttachFrameStream::new(reader),
+            exec_id: "exec".to_string(),
+        };
+        assert_eq!(
+            exec_reader.read().await,
+            Some(("building\n".to_string(), StreamType::Stdout))
+        );
+        assert_eq!(
+            exec_reader.read().await,
+            Some(("warning\n".to_string(), StreamType::Stderr))
+        );
+        assert_eq!(exec_reader.read().await, None);
+    }
 }
@@ -1,59 +1,189 @@
-use std::path::{Path, PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+};
 
+use regex::Regex;
 use uuid::Uuid;
 
 use crate::{
-    container_engine::{docker::DockerContainerEngine, podman::PodmanContainerEngine, ContainerEngine},
-    package::{get_package_type, unpack_command, BUILD_SCRIPT_FILENAME},
-    schema::{BuildScript, ContainerEngineType},
-    DryRunArgs, PackageType, UnpackArgs,
+    config::Config,
+    container_engine::{get_container_engine, split_shell_words, CapabilitySupport, ContainerEngine, EngineCapability},
+    epilogue::{enter_phase, BuildPhase},
+    error::BuildfsError,
+    image_reference::parse_image_reference,
+    library::splice_libraries,
+    minimize::get_tree_size,
+    package::{find_build_script, get_package_type, unpack_command},
+    package_ignore::{is_ignored, load_package_ignore, IGNORE_FILENAME},
+    plugin::get_plugin_binary_name,
+    policy::{enforce_hermetic, enforce_offline, enforce_policy, load_policy, Policy},
+    run::{exec_and_collect, pull_image},
+    schema::{
+        try_parse_build_script, BuildScript, BuildScriptContainer, BuildScriptFormat, ContainerEngineType,
+        FilesystemType, ResolvConfPolicy,
+    },
+    squashfs::validate_squashfs,
+    ssh::{open_ssh_tunnel, SshTunnel},
+    step_mount::{parse_step_mount, StepMountKind},
+    warnings::{WarningCollector, WarningKind},
+    wasm::apply_wasm_plugins,
+    DryRunArgs, PackageType, UnpackArgs, ValidateArgs,
 };
 
-pub async fn dry_run_command(dry_run_args: DryRunArgs) {
-    let (_, container_engine, _, _) = prepare_for_run(&dry_run_args).await;
-    container_engine.ping().await;
+static SHELL_SYNTAX_CHARACTERS: &[char] = &['|', '&', ';', '<', '>', '$', '`', '*', '?'];
+
+pub struct PreparedRun {
+    pub build_script: BuildScript,
+    pub container_engine: Box<dyn ContainerEngine>,
+    pub unpack_path: PathBuf,
+    pub can_delete_unpack_path: bool,
+    pub warnings: WarningCollector,
+    pub ssh_tunnel: Option<SshTunnel>,
+    pub connection_uri: Option<String>,
+    pub policy: Option<Policy>,
+}
+
+pub async fn dry_run_command(dry_run_args: DryRunArgs, deep: bool, config: &Config) -> Result<(), BuildfsError> {
+    let prepared_run = prepare_for_run(&dry_run_args, config).await?;
+    prepared_run.container_engine.ping().await?;
+    if deep {
+        check_container(&prepared_run.build_script, prepared_run.container_engine.as_ref()).await?;
+    }
+    prepared_run.warnings.surface(dry_run_args.json_warnings);
     log::info!("Dry run completed successfully");
+    Ok(())
 }
 
-pub async fn prepare_for_run(dry_run_args: &DryRunArgs) -> (BuildScript, Box<dyn ContainerEngine>, PathBuf, bool) {
-    let package_type = get_package_type(&dry_run_args.package).await;
-    let mut can_delete = false;
+pub async fn validate_command(validate_args: ValidateArgs, config: &Config) -> Result<(), BuildfsError> {
+    enter_phase(BuildPhase::Validating);
+    let (mut build_script, package_type, unpack_path, _) = load_package(&validate_args.package, config).await?;
+    if let Some(engine) = validate_args.engine {
+        log::info!("Overriding the container engine of the build script with {engine}");
+        build_script.container.engine = engine;
+    }
+    let mut warnings = WarningCollector::default();
 
-    let (unpack_path, build_script_path) = match package_type {
-        PackageType::BuildScript => (dry_run_args.package.clone(), dry_run_args.package.clone()),
-        PackageType::Directory => (
-            dry_run_args.package.clone(),
-            dry_run_args.package.join(BUILD_SCRIPT_FILENAME),
-        ),
-        _ => {
-            can_delete = false;
-            let tmp_path = PathBuf::from(format!("/tmp/{}", Uuid::new_v4()));
-            unpack_command(UnpackArgs {
-                source_path: dry_run_args.package.clone(),
-                destination_path: tmp_path.clone(),
-            })
-            .await;
-            (tmp_path.clone(), tmp_path.join(BUILD_SCRIPT_FILENAME))
+    let policy = match validate_args.policy_path {
+        Some(ref policy_path) => Some(load_policy(policy_path).await?),
+        None => config.policy.clone(),
+    };
+    let result = async {
+        if let Some(ref policy) = policy {
+            enforce_policy(policy, &build_script)?;
+        }
+        if validate_args.hermetic {
+            enforce_hermetic(&build_script)?;
         }
+        validate_build_script(&build_script, package_type, &unpack_path, &mut warnings).await
+    }
+    .await;
+
+    if matches!(package_type, PackageType::Tar | PackageType::TarGz) {
+        tokio::fs::remove_dir_all(&unpack_path)
+            .await
+            .map_err(BuildfsError::io("Could not remove temporary unpacked package"))?;
+    }
+    result?;
+
+    warnings.surface(validate_args.json_warnings);
+    if validate_args.strict && !warnings.warnings().is_empty() {
+        return Err(BuildfsError::Validation(format!(
+            "{} warning(s) were collected, which --strict turns into a failure",
+            warnings.warnings().len()
+        )));
+    }
+    log::info!("Validation completed successfully");
+    Ok(())
+}
+
+pub async fn prepare_for_run(dry_run_args: &DryRunArgs, config: &Config) -> Result<PreparedRun, BuildfsError> {
+    enter_phase(BuildPhase::Validating);
+    let (mut build_script, package_type, unpack_path, can_delete) = load_package(&dry_run_args.package, config).await?;
+    // overrides are applied before anything else so that policies and engine checks see the engine that will be used
+    if let Some(ref engine) = dry_run_args.engine {
+        log::info!("Overriding the container engine of the build script with {engine}");
+        build_script.container.engine = engine.clone();
+    }
+    if let Some(ref connection_uri) = dry_run_args.connection_uri {
+        log::info!("Overriding the connection URI of the build script with {connection_uri}");
+        build_script.container.connection_uri = Some(connection_uri.clone());
+    }
+    let mut warnings = WarningCollector::default();
+
+    let policy = match dry_run_args.policy_path {
+        Some(ref policy_path) => Some(load_policy(policy_path).await?),
+        None => config.policy.clone(),
     };
-    log::info!("Unpacked package into {unpack_path:?} with build script located at {build_script_path:?}");
+    if let Some(ref policy) = policy {
+        enforce_policy(policy, &build_script)?;
+    }
+    if dry_run_args.hermetic {
+        enforce_hermetic(&build_script)?;
+    }
 
-    let build_script_json = tokio::fs::read_to_string(&build_script_path)
-        .await
-        .expect("Could not read build script from temporary location");
-    let build_script =
-        toml::from_str::<BuildScript>(&build_script_json).expect("Could not decode build script from TOML");
-    log::debug!("Read build script at {build_script_path:?}");
+    validate_build_script(&build_script, package_type, &unpack_path, &mut warnings).await?;
 
-    let container_engine: Box<dyn ContainerEngine> = match build_script.container.engine {
-        ContainerEngineType::Docker => Box::new(DockerContainerEngine::new(
-            build_script.container.connection_uri.clone(),
-        )),
-        ContainerEngineType::Podman => Box::new(PodmanContainerEngine::new(
-            build_script.container.connection_uri.clone(),
-        )),
+    let (ssh_tunnel, connection_uri) = match build_script.container.connection_uri {
+        Some(ref connection_uri) if build_script.container.is_remote() => {
+            let (ssh_tunnel, local_uri) = open_ssh_tunnel(connection_uri, &build_script.container.engine).await;
+            (Some(ssh_tunnel), Some(local_uri))
+        }
+        ref connection_uri => (None, connection_uri.clone()),
     };
+
+    let container_engine: Box<dyn ContainerEngine> =
+        get_container_engine(&build_script.container.engine, connection_uri.clone())?;
     log::info!("Connected to container engine {}", build_script.container.engine);
+    if dry_run_args.offline {
+        enforce_offline(&build_script, container_engine.as_ref()).await?;
+    }
+
+    Ok(PreparedRun {
+        build_script,
+        container_engine,
+        unpack_path,
+        can_delete_unpack_path: can_delete,
+        warnings,
+        ssh_tunnel,
+        connection_uri,
+        policy,
+    })
+}
+
+pub async fn validate_build_script(
+    build_script: &BuildScript,
+    package_type: PackageType,
+    unpack_path: &PathBuf,
+    warnings: &mut WarningCollector,
+) -> Result<(), BuildfsError> {
+    for (field, replacement) in &build_script.deprecated_fields {
+        warnings.warn(
+            WarningKind::Deprecated,
+            format!("{field} is deprecated and will be removed in a future schema version, use {replacement} instead"),
+        );
+    }
+
+    for capability in EngineCapability::used_by(&build_script.container) {
+        match capability.support(&build_script.container.engine) {
+            CapabilitySupport::Supported => {}
+            CapabilitySupport::Ignored => warnings.warn(
+                WarningKind::EngineSpecific,
+                format!(
+                    "The {capability} used by the build script is not supported by {} and will be ignored",
+                    build_script.container.engine
+                ),
+            ),
+            CapabilitySupport::Unsupported => {
+                return Err(BuildfsError::Validation(format!(
+                    "the {capability} used by the build script is not supported by {}",
+                    build_script.container.engine
+                )))
+            }
+        }
+    }
+
+    validate_user_namespace(&build_script.container)?;
 
     let references = build_script
         .commands
@@ -65,6 +195,13 @@ pub async fn prepare_for_run(dry_run_args: &DryRunArgs) -> (BuildScript, Box<dyn
                 .iter()
                 .filter_map(|overlay| overlay.source.as_ref()),
         )
+        .chain(
+            build_script
+                .first_boot
+                .iter()
+                .filter_map(|first_boot| first_boot.script_path.as_ref()),
+        )
+        .chain(build_script.guest.agent.as_ref().map(|agent| &agent.source))
         .chain(
             build_script
                 .container
@@ -76,26 +213,42 @@ pub async fn prepare_for_run(dry_run_args: &DryRunArgs) -> (BuildScript, Box<dyn
 
     if let PackageType::BuildScript = package_type {
         if !references.is_empty() {
-            panic!(
-                "Build script validation failed: A non-packaged script contains {} reference(s) to outside resources",
+            return Err(BuildfsError::Validation(format!(
+                "A non-packaged script contains {} reference(s) to outside resources",
                 references.len()
-            )
+            )));
         }
     } else {
+        let package_ignore = load_package_ignore(unpack_path)?;
         for reference_path in &references {
             if !reference_path.is_absolute() {
-                panic!(
-                    "Build script validation failed: {} reference isn't absolute (relative to package root)",
+                return Err(BuildfsError::Validation(format!(
+                    "{} reference isn't absolute (relative to package root)",
                     reference_path.to_string_lossy()
-                );
+                )));
             }
 
             let full_path = unpack_path.adjoin_absolute(&reference_path);
-            if !tokio::fs::metadata(&full_path).await.is_ok() {
-                panic!(
-                    "Build script validation failed: {} reference doesn't exist",
+            let (Ok(canonical_path), Ok(canonical_unpack_path)) = (
+                tokio::fs::canonicalize(&full_path).await,
+                tokio::fs::canonicalize(&unpack_path).await,
+            ) else {
+                return Err(BuildfsError::Validation(format!(
+                    "{} reference doesn't exist",
                     reference_path.to_string_lossy()
-                );
+                )));
+            };
+            if escapes_root(reference_path) || !canonical_path.starts_with(canonical_unpack_path) {
+                return Err(BuildfsError::Validation(format!(
+                    "{} reference resolves outside of the package",
+                    reference_path.to_string_lossy()
+                )));
+            }
+            if is_ignored(&package_ignore, reference_path, full_path.is_dir()) {
+                return Err(BuildfsError::Validation(format!(
+                    "{} reference is excluded by {IGNORE_FILENAME}",
+                    reference_path.to_string_lossy()
+                )));
             }
         }
     }
@@ -106,7 +259,224 @@ pub async fn prepare_for_run(dry_run_args: &DryRunArgs) -> (BuildScript, Box<dyn
         .filter(|command| command.script_inline.is_none() && command.script_path.is_none() && command.command.is_none())
         .count();
     if empty_commands > 0 {
-        panic!("Build script validation failed: {empty_commands} command(s) contain no reference to a script, a script path or an inline command");
+        return Err(BuildfsError::Validation(format!(
+            "{empty_commands} command(s) contain no reference to a script, a script path or an inline command"
+        )));
+    }
+
+    for reference in build_script.commands.iter().flat_map(|command| &command.mounts) {
+        let step_mount = match parse_step_mount(reference) {
+            Some((StepMountKind::Cache, name)) => build_script.container.caches.get(name),
+            Some((StepMountKind::Secret, name)) => build_script.container.secrets.get(name),
+            None => {
+                return Err(BuildfsError::Validation(format!(
+                    "step mount \"{reference}\" must be of the form \"cache:<name>\" or \"secret:<name>\""
+                )))
+            }
+        };
+        if step_mount.is_none() {
+            return Err(BuildfsError::Validation(format!(
+                "step mount \"{reference}\" does not refer to any of the container's caches or secrets"
+            )));
+        }
+    }
+
+    for (name, step_mount) in build_script
+        .container
+        .caches
+        .iter()
+        .chain(&build_script.container.secrets)
+    {
+        // the mounts are set up with exec-ed commands that are split on whitespace
+        if name.contains(['/', ':'])
+            || name.contains(char::is_whitespace)
+            || !step_mount.destination.is_absolute()
+            || step_mount.destination.to_string_lossy().contains(char::is_whitespace)
+        {
+            return Err(BuildfsError::Validation(format!(
+                "cache or secret \"{name}\" must have a plain name and an absolute destination without whitespace"
+            )));
+        }
+    }
+
+    if !build_script.container.caches.is_empty()
+        && (build_script.container.attach_to.is_some() || build_script.container.is_remote())
+    {
+        return Err(BuildfsError::Validation(
+            "caches are bind-mounted into the container, which is impossible for attached or remote containers"
+                .to_string(),
+        ));
+    }
+
+    if let Some(ref context) = build_script.context {
+        if build_script.container.attach_to.is_some() || build_script.container.is_remote() {
+            return Err(BuildfsError::Validation(
+                "the build context is bind-mounted into the container, which is impossible for attached or remote containers"
+                    .to_string(),
+            ));
+        }
+        let (destination, host_path) = (context.get_destination(), context.get_host_path());
+        if !destination.is_absolute() || destination.parent().is_none() {
+            return Err(BuildfsError::Validation(format!(
+                "build context destination {destination:?} must be an absolute path below the root"
+            )));
+        }
+        if host_path.is_absolute() || escapes_root(&host_path) {
+            return Err(BuildfsError::Validation(format!(
+                "build context host_path {host_path:?} must be relative to the output directory and stay inside of it"
+            )));
+        }
+    }
+
+    let early_export_commands = build_script
+        .commands
+        .iter()
+        .filter(|command| command.early_export)
+        .count();
+    if early_export_commands > 1 {
+        return Err(BuildfsError::Validation(format!(
+            "{early_export_commands} commands are marked for early export, but at most one can be"
+        )));
+    }
+    // a secret is uploaded into the container's writable layer for its step, which an export running alongside it
+    // could capture, and its removal afterwards leaves no trace for the staleness check to notice
+    let late_secret_commands = build_script
+        .commands
+        .iter()
+        .skip_while(|command| !command.early_export)
+        .skip(1)
+        .filter(|command| {
+            command
+                .mounts
+                .iter()
+                .any(|reference| matches!(parse_step_mount(reference), Some((StepMountKind::Secret, _))))
+        })
+        .count();
+    if late_secret_commands > 0 {
+        return Err(BuildfsError::Validation(format!(
+            "{late_secret_commands} command(s) after the early export mount secrets, which the export could capture"
+        )));
+    }
+
+    let ownership = &build_script.export.ownership;
+    if let Some(rule) = ownership.rules.iter().find(|rule| !rule.path.is_absolute()) {
+        return Err(BuildfsError::Validation(format!(
+            "ownership rule path {:?} isn't absolute",
+            rule.path
+        )));
+    }
+    if ownership
+        .map
+        .iter()
+        .any(|id_map| id_map.size == 0 || id_map.to.checked_add(id_map.size - 1).is_none())
+    {
+        return Err(BuildfsError::Validation(
+            "every ownership ID map must cover at least one ID and stay below 2^32".to_string(),
+        ));
+    }
+
+    for package in build_script
+        .packages
+        .iter()
+        .flat_map(|package_set| &package_set.install)
+    {
+        if package.is_empty()
+            || !package
+                .chars()
+                .all(|character| character.is_ascii_alphanumeric() || ".+-_:=~@/".contains(character))
+        {
+            return Err(BuildfsError::Validation(format!(
+                "package {package:?} is not a valid package name"
+            )));
+        }
+    }
+    if let Some(index) = build_script
+        .packages
+        .iter()
+        .position(|package_set| package_set.install.is_empty())
+    {
+        return Err(BuildfsError::Validation(format!(
+            "[[packages]] entry #{} installs nothing",
+            index + 1
+        )));
+    }
+
+    for command in &build_script.commands {
+        if let Some(ref interpreter) = command.interpreter {
+            if command.command.is_some() {
+                return Err(BuildfsError::Validation(format!(
+                    "interpreter {interpreter:?} is set on a simple command, but only applies to scripts"
+                )));
+            }
+            if interpreter.is_empty() || interpreter.contains(char::is_whitespace) {
+                return Err(BuildfsError::Validation(format!(
+                    "interpreter {interpreter:?} must be a single binary name or path without arguments"
+                )));
+            }
+            if command
+                .script_inline
+                .as_ref()
+                .is_some_and(|script| script.starts_with("#!"))
+            {
+                return Err(BuildfsError::Validation(format!(
+                    "an inline script with interpreter {interpreter:?} already starts with a shebang"
+                )));
+            }
+        }
+
+        // commands without a shell, and shells themselves, are split into arguments by buildfs before the exec
+        let split_texts = [
+            (command.shell.as_deref(), "shell"),
+            (
+                command
+                    .command
+                    .as_deref()
+                    .filter(|_| command.shell.as_deref() == Some("")),
+                "command",
+            ),
+        ];
+        for (text, kind) in split_texts.into_iter().filter_map(|(text, kind)| Some((text?, kind))) {
+            if let Err(err) = split_shell_words(text) {
+                return Err(BuildfsError::Validation(format!(
+                    "{kind} \"{text}\" can't be split into arguments, since {err}"
+                )));
+            }
+        }
+
+        match (&command.command, command.shell.as_deref()) {
+            (None, Some(shell)) => {
+                return Err(BuildfsError::Validation(format!("shell {shell:?} is set on a script, but only applies to simple commands")))
+            }
+            (Some(command_text), Some("")) if command_text.contains(SHELL_SYNTAX_CHARACTERS) => warnings.warn(
+                WarningKind::SuspectValue,
+                format!("Command \"{command_text}\" contains shell syntax, but runs without a shell, so it will be passed on verbatim"),
+            ),
+            (Some(command_text), _) if has_unterminated_quote(command_text) => warnings.warn(
+                WarningKind::SuspectValue,
+                format!("Command \"{command_text}\" has an unterminated quote and will likely fail in the shell"),
+            ),
+            _ => {}
+        }
+
+        if let Some(ref expect_output_regex) = command.expect_output_regex {
+            if let Err(err) = Regex::new(expect_output_regex) {
+                return Err(BuildfsError::Validation(format!(
+                    "expected output regex {expect_output_regex:?} is invalid: {err}"
+                )));
+            }
+        }
+
+        if let Some(ref save_output_to) = command.save_output_to {
+            if save_output_to.is_absolute()
+                || save_output_to
+                    .components()
+                    .any(|component| component == Component::ParentDir)
+            {
+                return Err(BuildfsError::Validation(format!(
+                    "output save path {save_output_to:?} must be relative and stay inside the output directory"
+                )));
+            }
+        }
     }
 
     let empty_overlays = build_script
@@ -115,7 +485,20 @@ pub async fn prepare_for_run(dry_run_args: &DryRunArgs) -> (BuildScript, Box<dyn
         .filter(|overlay| overlay.source.is_none() && overlay.source_inline.is_none())
         .count();
     if empty_overlays > 0 {
-        panic!("Build script validation failed: {empty_overlays} overlay(s) contain no references to a source path or an inline source");
+        return Err(BuildfsError::Validation(format!(
+            "{empty_overlays} overlay(s) contain no references to a source path or an inline source"
+        )));
+    }
+
+    let invalid_payloads = build_script
+        .overlays
+        .iter()
+        .filter(|overlay| {
+            overlay.payload.is_encoded() && (overlay.source.is_none() || overlay.is_directory || overlay.mounted)
+        })
+        .count();
+    if invalid_payloads > 0 {
+        return Err(BuildfsError::Validation(format!("{invalid_payloads} compressed or encrypted overlay payload(s) must be non-mounted files with a source path")));
     }
 
     let conflicting_overlays = build_script
@@ -124,29 +507,625 @@ pub async fn prepare_for_run(dry_run_args: &DryRunArgs) -> (BuildScript, Box<dyn
         .filter(|overlay| overlay.is_directory && overlay.source_inline.is_some())
         .count();
     if conflicting_overlays > 0 {
-        panic!(
-            "Build script validation failed: {conflicting_overlays} overlay(s) are inline but are marked as directories"
-        );
+        return Err(BuildfsError::Validation(format!(
+            "{conflicting_overlays} overlay(s) are inline but are marked as directories"
+        )));
+    }
+
+    let escaping_destinations = build_script
+        .overlays
+        .iter()
+        .map(|overlay| &overlay.destination)
+        .chain(&build_script.export.files.include)
+        .chain(&build_script.export.files.create)
+        .chain(&build_script.export.directories.include)
+        .chain(&build_script.export.directories.create)
+        .chain(build_script.export.ownership.rules.iter().map(|rule| &rule.path))
+        .filter(|destination_path| escapes_root(destination_path))
+        .map(|destination_path| format!("{destination_path:?}"))
+        .collect::<Vec<_>>();
+    if !escaping_destinations.is_empty() {
+        return Err(BuildfsError::Validation(format!(
+            "overlay and export path(s) {} resolve outside of the mounted filesystem",
+            escaping_destinations.join(", ")
+        )));
+    }
+
+    let invalid_first_boot = build_script
+        .first_boot
+        .iter()
+        .filter(|first_boot| first_boot.script_inline.is_some() == first_boot.script_path.is_some())
+        .count();
+    if invalid_first_boot > 0 {
+        return Err(BuildfsError::Validation(format!(
+            "{invalid_first_boot} first-boot script(s) must specify exactly one of an inline script or a script path"
+        )));
+    }
+
+    for first_boot in &build_script.first_boot {
+        if first_boot.name.is_empty()
+            || !first_boot
+                .name
+                .chars()
+                .all(|character| character.is_ascii_alphanumeric() || matches!(character, '-' | '_' | '.'))
+        {
+            return Err(BuildfsError::Validation(format!(
+                "first-boot script name {:?} must be a non-empty file name of letters, digits, '-', '_' and '.'",
+                first_boot.name
+            )));
+        }
+    }
+
+    if let Some(ref network) = build_script.guest.network {
+        if network.resolv_conf_inline.is_some() && !matches!(network.resolv_conf, ResolvConfPolicy::Inline) {
+            return Err(BuildfsError::Validation(
+                "inline resolv.conf contents are specified, but the resolv.conf policy is not Inline".to_string(),
+            ));
+        }
+        if network.resolv_conf_inline.is_none() && matches!(network.resolv_conf, ResolvConfPolicy::Inline) {
+            return Err(BuildfsError::Validation(
+                "the resolv.conf policy is Inline, but no inline resolv.conf contents are specified".to_string(),
+            ));
+        }
+    }
+
+    if let Some(ref strip_locales) = build_script.minimize.strip_locales {
+        let invalid_entries = strip_locales.iter().filter(|entry| !entry.starts_with("keep:")).count();
+        if invalid_entries > 0 {
+            return Err(BuildfsError::Validation(format!(
+                "{invalid_entries} locale stripping entry(ies) are not of the form \"keep:<prefix>\""
+            )));
+        }
+    }
+
+    if build_script.container.attach_to.is_some() {
+        if !build_script.container.volumes.is_empty() {
+            return Err(BuildfsError::Validation(
+                "volumes cannot be bind-mounted into an attached container".to_string(),
+            ));
+        }
+
+        let mounted_overlays = build_script.overlays.iter().filter(|overlay| overlay.mounted).count();
+        if mounted_overlays > 0 {
+            return Err(BuildfsError::Validation(format!(
+                "{mounted_overlays} mounted overlay(s) cannot be bind-mounted into an attached container"
+            )));
+        }
+    }
+
+    if build_script.post_build.push && build_script.post_build.commit_image.is_none() {
+        return Err(BuildfsError::Validation(
+            "post_build.push is set, but there is no post_build.commit_image to push".to_string(),
+        ));
+    }
+
+    if let Some(ref commit_image) = build_script.post_build.commit_image {
+        match parse_image_reference(commit_image) {
+            Ok(image_reference) if image_reference.digest.is_some() => return Err(BuildfsError::Validation(format!(
+                "post_build.commit_image {commit_image:?} cannot carry a digest, which is only known once the image is committed"
+            ))),
+            Ok(_) => {}
+            Err(err) => return Err(BuildfsError::Validation(format!(
+                "post_build.commit_image {commit_image:?} is not a valid image reference: {err}"
+            ))),
+        }
+    }
+
+    if let ContainerEngineType::Kubernetes = build_script.container.engine {
+        if build_script.post_build.commit_image.is_some() {
+            return Err(BuildfsError::Validation(
+                "post_build.commit_image is set, but Kubernetes pods cannot be committed into an image".to_string(),
+            ));
+        }
+
+        let unsupported_exec_options = build_script
+            .commands
+            .iter()
+            .filter(|command| command.uid.is_some() || command.gid.is_some() || command.privileged.is_some())
+            .count();
+        if unsupported_exec_options > 0 {
+            warnings.warn(
+                WarningKind::EngineSpecific,
+                format!("{unsupported_exec_options} command(s) set a uid, gid or privileged mode, which Kubernetes does not support per exec and will be ignored"),
+            );
+        }
+    }
+
+    if build_script.container.rootful {
+        let redundant_privileged_commands = build_script
+            .commands
+            .iter()
+            .filter(|command| command.privileged == Some(true))
+            .count();
+        if redundant_privileged_commands > 0 {
+            warnings.warn(
+                WarningKind::SuspectValue,
+                format!("{redundant_privileged_commands} command(s) are marked as privileged, but the container is already rootful"),
+            );
+        }
+    }
+
+    if !matches!(package_type, PackageType::BuildScript) {
+        let mut overlay_bytes = 0;
+        for source_path in build_script
+            .overlays
+            .iter()
+            .filter_map(|overlay| overlay.source.as_ref())
+        {
+            let full_path = unpack_path.adjoin_absolute(source_path);
+            overlay_bytes += tokio::task::spawn_blocking(move || get_tree_size(&full_path))
+                .await
+                .expect("Join on blocking task failed");
+        }
+
+        if overlay_bytes > build_script.filesystem.size_mib as u64 * 1024 * 1024 {
+            warnings.warn(
+                WarningKind::SuspectValue,
+                format!(
+                    "Overlays alone take up {} MiB, which exceeds the filesystem size of {} MiB",
+                    overlay_bytes / 1024 / 1024,
+                    build_script.filesystem.size_mib
+                ),
+            );
+        }
+    }
+
+    if let Some(ref vfat) = build_script.filesystem.vfat {
+        if !matches!(build_script.filesystem.filesystem_type, FilesystemType::Vfat) {
+            return Err(BuildfsError::Validation(
+                "Vfat options are specified, but the filesystem type is not Vfat".to_string(),
+            ));
+        }
+
+        if let Some(ref label) = vfat.label {
+            if label.len() > 11 || !label.is_ascii() {
+                return Err(BuildfsError::Validation(format!(
+                    "the FAT label {label:?} must be at most 11 ASCII characters"
+                )));
+            }
+        }
+    }
+
+    if let Some(ref squashfs) = build_script.filesystem.squashfs {
+        if !matches!(build_script.filesystem.filesystem_type, FilesystemType::Squashfs) {
+            return Err(BuildfsError::Validation(
+                "Squashfs options are specified, but the filesystem type is not Squashfs".to_string(),
+            ));
+        }
+        validate_squashfs(squashfs)?;
+    }
+
+    for plugin in &build_script.plugins {
+        let binary_name = get_plugin_binary_name(plugin);
+        if which::which(&binary_name).is_err() {
+            return Err(BuildfsError::Validation(format!(
+                "plugin binary \"{binary_name}\" could not be located in PATH"
+            )));
+        }
     }
 
     log::debug!("Validated the build script: {} reference(s) found", references.len());
 
+    if build_script.filesystem.size_mib == 0 {
+        return Err(BuildfsError::Validation(
+            "filesystem size must be greater than 0 MiB".to_string(),
+        ));
+    }
+    let export = &build_script.export;
+    if export.files.include.is_empty()
+        && export.files.create.is_empty()
+        && export.directories.include.is_empty()
+        && export.directories.create.is_empty()
+        && build_script.overlays.is_empty()
+    {
+        warnings.warn(
+            WarningKind::SuspectValue,
+            "Nothing is exported from the container and no overlays are set, so the image will be empty".to_string(),
+        );
+    }
+
     if let Some(block_size_mib) = build_script.filesystem.block_size_mib {
         if build_script.filesystem.size_mib % block_size_mib != 0 {
-            panic!("Build script validation failed: filesystem size (MB) must be divisible by dd block size (MB), and is not");
+            return Err(BuildfsError::Validation(
+                "filesystem size (MB) must be divisible by dd block size (MB), and is not".to_string(),
+            ));
         }
     }
 
-    (build_script, container_engine, unpack_path, can_delete)
+    Ok(())
+}
+
+pub async fn load_package(
+    package: &PathBuf,
+    config: &Config,
+) -> Result<(BuildScript, PackageType, PathBuf, bool), BuildfsError> {
+    let package_type = get_package_type(package).await?;
+    let mut can_delete = false;
+
+    let (unpack_path, build_script_path) = match package_type {
+        PackageType::BuildScript => (package.clone(), package.clone()),
+        PackageType::Directory => (package.clone(), find_build_script(package)),
+        _ => {
+            can_delete = false;
+            let tmp_path = PathBuf::from(format!("/tmp/{}", Uuid::new_v4()));
+            unpack_command(UnpackArgs {
+                source_path: package.clone(),
+                destination_path: tmp_path.clone(),
+                same_filesystem: true,
+            })
+            .await?;
+            (tmp_path.clone(), find_build_script(&tmp_path))
+        }
+    };
+    log::info!("Unpacked package into {unpack_path:?} with build script located at {build_script_path:?}");
+
+    let build_script_json = tokio::fs::read_to_string(&build_script_path)
+        .await
+        .map_err(BuildfsError::io("Could not read build script from temporary location"))?;
+    let build_script_format = BuildScriptFormat::from_path(&build_script_path).unwrap_or_default();
+    let mut build_script = try_parse_build_script(&build_script_json, build_script_format, &config.variable_overrides)
+        .map_err(BuildfsError::Validation)?;
+    log::debug!("Read build script at {build_script_path:?}");
+    splice_libraries(&mut build_script, &unpack_path).await?;
+
+    if !config.wasm_plugins.is_empty() {
+        build_script = apply_wasm_plugins(&config.wasm_plugins, build_script).await;
+    }
+
+    Ok((build_script, package_type, unpack_path, can_delete))
 }
 
 pub trait AdjoinAbsolute {
     fn adjoin_absolute(&self, other: &Path) -> PathBuf;
 }
 
+pub async fn check_interpreters(
+    build_script: &BuildScript,
+    container_engine: &dyn ContainerEngine,
+    container_id: &str,
+    container_name: &str,
+) -> Result<(), BuildfsError> {
+    let mut interpreters = build_script
+        .commands
+        .iter()
+        .filter_map(|command| command.interpreter.as_deref())
+        .collect::<Vec<_>>();
+    interpreters.sort();
+    interpreters.dedup();
+
+    let mut missing_interpreters = Vec::new();
+    for interpreter in interpreters {
+        // not every interpreter exits cleanly on --version, but a missing binary always fails with 126 or 127
+        let (exit_code, _) = exec_and_collect(
+            container_engine,
+            container_id,
+            container_name,
+            &format!("{interpreter} --version"),
+        )
+        .await?;
+        if matches!(exit_code, None | Some(126) | Some(127)) {
+            missing_interpreters.push(interpreter);
+        }
+    }
+
+    if !missing_interpreters.is_empty() {
+        return Err(BuildfsError::Validation(format!(
+            "interpreter(s) {} could not be found in the image",
+            missing_interpreters.join(", ")
+        )));
+    }
+
+    Ok(())
+}
+
+async fn check_container(
+    build_script: &BuildScript,
+    container_engine: &dyn ContainerEngine,
+) -> Result<(), BuildfsError> {
+    let (container_id, container_name) = match build_script.container.attach_to {
+        Some(ref target) => container_engine.resolve_attach_target(target).await?,
+        None => {
+            let mut container = build_script.container.clone();
+            container.image = pull_image(container_engine, &build_script.container).await?;
+            container_engine.start_container(container, HashMap::new()).await?
+        }
+    };
+
+    let result = check_interpreters(build_script, container_engine, &container_id, &container_name).await;
+    if build_script.container.attach_to.is_none() {
+        container_engine
+            .remove_container(&container_name, build_script.container.wait_timeout_s)
+            .await?;
+    }
+    result?;
+
+    log::info!("Container started and all command interpreters were found in the image");
+    Ok(())
+}
+
+fn validate_user_namespace(container: &BuildScriptContainer) -> Result<(), BuildfsError> {
+    let has_id_maps = !container.uidmap.is_empty() || !container.gidmap.is_empty();
+    if container.keep_id && (container.userns.is_some() || has_id_maps) {
+        return Err(BuildfsError::Validation(
+            "keep_id is shorthand for userns = \"keep-id\" and can't be combined with userns, uidmap or gidmap"
+                .to_string(),
+        ));
+    }
+    if has_id_maps && container.userns.as_deref().is_some_and(|userns| userns != "private") {
+        return Err(BuildfsError::Validation(
+            "uidmap and gidmap can only be applied to a private user namespace".to_string(),
+        ));
+    }
+    if container
+        .uidmap
+        .iter()
+        .chain(container.gidmap.iter())
+        .any(|id_map| id_map.size == 0)
+    {
+        return Err(BuildfsError::Validation(
+            "every uidmap and gidmap entry must map at least one ID".to_string(),
+        ));
+    }
+
+    // the Docker daemon remaps user namespaces globally, so a container can only opt out of it
+    if let (ContainerEngineType::Docker, Some(userns)) = (&container.engine, &container.userns) {
+        if userns != "host" {
+            return Err(BuildfsError::Validation(format!(
+                "Docker only accepts userns = \"host\", but {userns:?} was set"
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+fn has_unterminated_quote(command_text: &str) -> bool {
+    let mut quote = None;
+    let mut characters = command_text.chars();
+    while let Some(character) = characters.next() {
+        match (quote, character) {
+            // nothing, not even a backslash, is special inside single quotes
+            (Some('\''), '\'') => quote = None,
+            (Some('\''), _) => {}
+            (_, '\\') => {
+                characters.next();
+            }
+            (Some('"'), '"') => quote = None,
+            (None, '\'' | '"') => quote = Some(character),
+            _ => {}
+        }
+    }
+
+    quote.is_some()
+}
+
 impl AdjoinAbsolute for PathBuf {
     fn adjoin_absolute(&self, other: &Path) -> PathBuf {
         let other = other.to_string_lossy();
         self.join(other.trim_start_matches("/"))
     }
 }
+
+pub fn escapes_root(path: &Path) -> bool {
+    let mut depth = 0usize;
+    for component in path.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::ParentDir if depth == 0 => return true,
+            Component::ParentDir => depth -= 1,
+            _ => {}
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        panic::AssertUnwindSafe,
+        path::{Path, PathBuf},
+    };
+
+    use futures_util::FutureExt;
+    use uuid::Uuid;
+
+    use crate::{config::Config, schema::ContainerEngineType, DryRunArgs, ValidateArgs};
+
+    use super::{escapes_root, has_unterminated_quote, prepare_for_run, validate_command};
+
+    async fn prepare_script(build_script_toml: &str) {
+        prepare_script_with_engine(build_script_toml, None).await;
+    }
+
+    async fn prepare_script_with_engine(build_script_toml: &str, engine: Option<ContainerEngineType>) {
+        let package = PathBuf::from(format!("/tmp/{}.toml", Uuid::new_v4()));
+        tokio::fs::write(
+            &package,
+            format!(
+                "schema_version = 1\n[container]\nengine = \"Mock\"\nimage = {{ name = \"debian\", tag = \"bookworm\" }}\n{build_script_toml}"
+            ),
+        )
+        .await
+        .unwrap();
+
+        let dry_run_args = DryRunArgs {
+            package: package.clone(),
+            json_warnings: false,
+            policy_path: None,
+            hermetic: false,
+            offline: false,
+            engine,
+            connection_uri: None,
+        };
+        let result = AssertUnwindSafe(prepare_for_run(&dry_run_args, &Config::default()))
+            .catch_unwind()
+            .await;
+        tokio::fs::remove_file(package).await.unwrap();
+
+        match result {
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) => panic!("{err}"),
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+
+    #[tokio::test]
+    async fn valid_script_passes() {
+        prepare_script("[filesystem]\nsize_mib = 64\n[[commands]]\ncommand = \"true\"\n").await;
+    }
+
+    #[tokio::test]
+    async fn validate_reports_toml_errors_with_their_line() {
+        let package = PathBuf::from(format!("/tmp/{}.toml", Uuid::new_v4()));
+        tokio::fs::write(&package, "schema_version = 1\n[filesystem]\nsize_mib = \n")
+            .await
+            .unwrap();
+        let validate_args = ValidateArgs {
+            package: package.clone(),
+            json_warnings: false,
+            policy_path: None,
+            hermetic: false,
+            engine: None,
+            strict: false,
+        };
+        let result = validate_command(validate_args, &Config::default()).await;
+        tokio::fs::remove_file(package).await.unwrap();
+
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("could not decode build script from TOML"));
+        assert!(err.contains("line 3"));
+    }
+
+    #[tokio::test]
+    async fn deprecated_size_fields_are_warned_about() {
+        for (size_field, should_warn) in [("size = \"64 MiB\"", false), ("size_mib = 64", true)] {
+            let package = PathBuf::from(format!("/tmp/{}.toml", Uuid::new_v4()));
+            tokio::fs::write(
+                &package,
+                format!("schema_version = 1\n[container]\nimage = {{ name = \"debian\", tag = \"bookworm\" }}\n[filesystem]\n{size_field}\n[[overlays]]\nsource_inline = \"x\"\ndestination = \"/x\"\n"),
+            )
+            .await
+            .unwrap();
+            let validate_args = ValidateArgs {
+                package: package.clone(),
+                json_warnings: false,
+                policy_path: None,
+                hermetic: false,
+                engine: None,
+                strict: true,
+            };
+            let result = validate_command(validate_args, &Config::default()).await;
+            tokio::fs::remove_file(package).await.unwrap();
+
+            assert_eq!(result.is_err(), should_warn, "{size_field}: {result:?}");
+        }
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "contain no reference to a script")]
+    async fn empty_command_fails() {
+        prepare_script("[filesystem]\nsize_mib = 64\n[[commands]]\nuid = 0\n").await;
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "reference(s) to outside resources")]
+    async fn non_packaged_script_with_references_fails() {
+        prepare_script("[filesystem]\nsize_mib = 64\n[[commands]]\nscript_path = \"/build.sh\"\n").await;
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "resolve outside of the mounted filesystem")]
+    async fn escaping_overlay_destination_fails() {
+        prepare_script(
+            "[filesystem]\nsize_mib = 64\n[[overlays]]\nsource_inline = \"x\"\ndestination = \"/etc/../../host\"\n",
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "must be relative to the output directory")]
+    async fn escaping_context_host_path_fails() {
+        prepare_script("[filesystem]\nsize_mib = 64\n[context]\nhost_path = \"../artifacts\"\n").await;
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "can't be split into arguments")]
+    async fn unsplittable_command_without_shell_fails() {
+        prepare_script("[filesystem]\nsize_mib = 64\n[[commands]]\ncommand = \"echo 'a\"\nshell = \"\"\n").await;
+    }
+
+    #[test]
+    fn escaping_paths_are_detected() {
+        assert!(escapes_root(Path::new("/etc/../../host")));
+        assert!(!escapes_root(Path::new("/etc/../opt")));
+    }
+
+    #[test]
+    fn unterminated_quotes_are_detected() {
+        assert!(!has_unterminated_quote("echo 'it''s' \"a \\\"b\\\"\" | grep a"));
+        assert!(!has_unterminated_quote("echo it\\'s"));
+        assert!(has_unterminated_quote("echo 'it's'"));
+        assert!(has_unterminated_quote("echo \"a"));
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "already starts with a shebang")]
+    async fn inline_script_with_interpreter_and_shebang_fails() {
+        prepare_script(
+            "[filesystem]\nsize_mib = 64\n[[commands]]\nscript_inline = \"#!/bin/sh\\ntrue\"\ninterpreter = \"python3\"\n",
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "must be divisible by dd block size")]
+    async fn indivisible_block_size_fails() {
+        prepare_script("[filesystem]\nsize_mib = 64\nblock_size_mib = 3\n").await;
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "cannot be bind-mounted into an attached container")]
+    async fn mounted_overlay_in_attached_container_fails() {
+        prepare_script(
+            "attach_to = \"sidecar\"\n[filesystem]\nsize_mib = 64\n[[overlays]]\nsource_inline = \"x\"\ndestination = \"/x\"\nmounted = true\n",
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "after the early export mount secrets")]
+    async fn secret_mount_after_early_export_fails() {
+        prepare_script(
+            "secrets = { token = { source = \"/tmp/token\", destination = \"/run/token\" } }\n[filesystem]\nsize_mib = 64\n[[commands]]\ncommand = \"true\"\nearly_export = true\n[[commands]]\ncommand = \"true\"\nmounts = [\"secret:token\"]\n",
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "host volumes used by the build script is not supported by Kubernetes")]
+    async fn engine_override_is_validated_instead_of_the_build_script_engine() {
+        prepare_script_with_engine(
+            "volumes = { \"/data\" = \"/data\" }\n[filesystem]\nsize_mib = 64\n",
+            Some(ContainerEngineType::Kubernetes),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "keep_id is shorthand for userns")]
+    async fn keep_id_with_explicit_uidmap_fails() {
+        prepare_script(
+            "keep_id = true\nuidmap = [{ container_id = 0, host_id = 1000, size = 1 }]\n[filesystem]\nsize_mib = 64\n",
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "are inline but are marked as directories")]
+    async fn inline_directory_overlay_fails() {
+        prepare_script(
+            "[filesystem]\nsize_mib = 64\n[[overlays]]\nsource_inline = \"x\"\ndestination = \"/x\"\nis_directory = true\n",
+        )
+        .await;
+    }
+}
@@ -0,0 +1,183 @@
+use std::{collections::VecDeque, fmt::Display, sync::Mutex};
+
+use colored::Colorize;
+
+use crate::cleanup::clean_up;
+
+static EPILOGUE_OUTPUT_LINES: usize = 20;
+
+static FAILURE_CONTEXT: Mutex<FailureContext> = Mutex::new(FailureContext {
+    phase: None,
+    step: None,
+    output: VecDeque::new(),
+});
+
+static HINTS: &[(&str, &str)] = &[
+    (
+        "user namespace",
+        "The daemon has user namespace remapping enabled, which conflicts with rootful containers. Set \"rootful = false\", opt out of it with userns = \"host\" or disable userns-remap in the daemon",
+    ),
+    (
+        "SocketNotFoundError",
+        "The container engine's socket could not be found. Start the daemon or point \"container.connection_uri\" at its socket",
+    ),
+    (
+        "Permission denied",
+        "Access was denied. Check that your user may access the container engine's socket, or run buildfs as root for the mount phase",
+    ),
+    (
+        "manifest unknown",
+        "The image could not be found in the registry. Check \"container.image.name\" and \"container.image.tag\"",
+    ),
+    (
+        "No such image",
+        "The image could not be found. Check \"container.image.name\" and \"container.image.tag\"",
+    ),
+    (
+        "OOM-killed: true",
+        "The container ran out of memory. Raise the memory limit of the container engine or reduce the build's memory usage",
+    ),
+    (
+        "No space left on device",
+        "The filesystem ran out of space. Raise \"filesystem.size_mib\" or enable [minimize] options",
+    ),
+    (
+        "The image is too small",
+        "Raise \"filesystem.size\" by at least the missing amount plus some headroom for filesystem metadata, or enable [minimize] options to shrink the exported content",
+    ),
+    (
+        "Could not mount rootfs",
+        "Mounting requires root privileges and kernel support for the chosen filesystem type",
+    ),
+    (
+        "or tools.container_image",
+        "Install the tools for the chosen filesystem type, e.g. e2fsprogs, btrfs-progs, squashfs-tools, dosfstools or xfsprogs, or run them from a utility image with \"tools.container_image\" in the config",
+    ),
+    (
+        "Build script validation failed",
+        "Run \"buildfs explain\" on the package to inspect the resolved build plan",
+    ),
+];
+
+struct FailureContext {
+    phase: Option<BuildPhase>,
+    step: Option<String>,
+    output: VecDeque<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum BuildPhase {
+    Validating,
+    StartingContainer,
+    RunningCommands,
+    CommittingImage,
+    ExportingContainer,
+    Minimizing,
+    CreatingFilesystem,
+    Finalizing,
+    Unmounting,
+    Packing,
+    Verifying,
+    Copying,
+}
+
+impl Display for BuildPhase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildPhase::Validating => write!(f, "validating the build script"),
+            BuildPhase::StartingContainer => write!(f, "starting the container"),
+            BuildPhase::RunningCommands => write!(f, "running commands in the container"),
+            BuildPhase::CommittingImage => write!(f, "committing the container into an image"),
+            BuildPhase::ExportingContainer => write!(f, "exporting the container"),
+            BuildPhase::Minimizing => write!(f, "minimizing the container rootfs"),
+            BuildPhase::CreatingFilesystem => write!(f, "creating the filesystem"),
+            BuildPhase::Finalizing => write!(f, "finalizing the filesystem"),
+            BuildPhase::Unmounting => write!(f, "unmounting the filesystem"),
+            BuildPhase::Packing => write!(f, "packing the filesystem image"),
+            BuildPhase::Verifying => write!(f, "verifying the filesystem"),
+            BuildPhase::Copying => write!(f, "copying the image to additional outputs"),
+        }
+    }
+}
+
+pub fn enter_phase(phase: BuildPhase) {
+    let mut context = lock_context();
+    context.phase = Some(phase);
+    context.step = None;
+    context.output.clear();
+}
+
+pub fn current_phase() -> Option<BuildPhase> {
+    lock_context().phase
+}
+
+pub fn enter_step(step: &str) {
+    let mut context = lock_context();
+    context.step = Some(step.to_string());
+    context.output.clear();
+}
+
+pub fn record_output(output: &str) {
+    let mut context = lock_context();
+    for line in output.lines().filter(|line| !line.trim().is_empty()) {
+        if context.output.len() == EPILOGUE_OUTPUT_LINES {
+            context.output.pop_front();
+        }
+        context.output.push_back(line.to_string());
+    }
+}
+
+pub fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+
+    std::panic::set_hook(Box::new(move |panic_info| {
+        if std::env::var_os("RUST_BACKTRACE").is_some() {
+            default_hook(panic_info);
+        }
+
+        let message = match panic_info.payload().downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => panic_info
+                .payload()
+                .downcast_ref::<String>()
+                .cloned()
+                .unwrap_or_default(),
+        };
+        report_failure(&message);
+        clean_up();
+    }));
+}
+
+pub fn report_failure(message: &str) {
+    // the context is only read here, so recover it even if a panic happened while it was held
+    let context = FAILURE_CONTEXT.lock().unwrap_or_else(|error| error.into_inner());
+
+    eprintln!();
+    match context.phase {
+        Some(phase) => eprintln!(
+            "{} {}",
+            "Build failed while".red().bold(),
+            phase.to_string().red().bold()
+        ),
+        None => eprintln!("{}", "Build failed".red().bold()),
+    }
+    if let Some(ref step) = context.step {
+        eprintln!("{} {step}", "step:".bold());
+    }
+    eprintln!("{} {message}", "error:".bold());
+
+    if !context.output.is_empty() {
+        eprintln!("{}", format!("last {} line(s) of output:", context.output.len()).bold());
+        for line in &context.output {
+            eprintln!("  {} {line}", "|".bright_black());
+        }
+    }
+
+    for (_, hint) in HINTS.iter().filter(|(pattern, _)| message.contains(pattern)) {
+        eprintln!("{} {hint}", "hint:".yellow().bold());
+    }
+}
+
+fn lock_context() -> std::sync::MutexGuard<'static, FailureContext> {
+    FAILURE_CONTEXT.lock().expect("Failure context was poisoned")
+}
@@ -0,0 +1,68 @@
+use std::{fmt::Display, process::ExitCode};
+
+#[derive(Debug, thiserror::Error)]
+pub enum BuildfsError {
+    #[error("Build script validation failed: {0}")]
+    Validation(String),
+    #[error("{0}")]
+    InvalidArguments(String),
+    #[error("{0}")]
+    CommandFailed(String),
+    #[error("{0}")]
+    Resume(String),
+    #[error("{context}: {source}")]
+    Io {
+        context: &'static str,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("{context}: {message}")]
+    Engine { context: &'static str, message: String },
+    #[error("{0}")]
+    Filesystem(String),
+}
+
+impl BuildfsError {
+    pub fn io(context: &'static str) -> impl FnOnce(std::io::Error) -> BuildfsError {
+        move |source| BuildfsError::Io { context, source }
+    }
+
+    pub fn engine<E: Display>(context: &'static str) -> impl FnOnce(E) -> BuildfsError {
+        move |err| BuildfsError::Engine {
+            context,
+            message: err.to_string(),
+        }
+    }
+
+    // panics that were not turned into errors keep exiting with 101, so every code here stays below that
+    pub fn exit_code(&self) -> ExitCode {
+        ExitCode::from(match self {
+            BuildfsError::InvalidArguments(_) => 2,
+            BuildfsError::Validation(_) => 3,
+            BuildfsError::CommandFailed(_) => 4,
+            BuildfsError::Resume(_) => 5,
+            BuildfsError::Io { .. } => 6,
+            BuildfsError::Engine { .. } => 7,
+            BuildfsError::Filesystem(_) => 8,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::ExitCode;
+
+    use super::BuildfsError;
+
+    #[test]
+    fn validation_errors_keep_their_prefix() {
+        let error =
+            BuildfsError::Validation("filesystem size (MB) must be divisible by dd block size (MB)".to_string());
+
+        assert_eq!(
+            error.to_string(),
+            "Build script validation failed: filesystem size (MB) must be divisible by dd block size (MB)"
+        );
+        assert_eq!(error.exit_code(), ExitCode::from(3));
+    }
+}
@@ -0,0 +1,486 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use serde::Serialize;
+
+use crate::{
+    config::Config,
+    dry_run::{load_package, AdjoinAbsolute},
+    error::BuildfsError,
+    guest::DEFAULT_AGENT_VSOCK_PORT,
+    schema::{BuildScript, FilesystemType},
+    squashfs::get_mksquashfs_args,
+    ExplainArgs, PackageType,
+};
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "phase")]
+pub enum PlanPhase {
+    PullImage {
+        image: String,
+    },
+    StartContainer {
+        engine: String,
+        rootful: bool,
+        keep_alive: String,
+        hostname: Option<String>,
+        network_mode: Option<String>,
+        env: HashMap<String, String>,
+        mounts: Vec<PlanMount>,
+    },
+    AttachContainer {
+        engine: String,
+        target: String,
+        uploads: Vec<PlanMount>,
+    },
+    WaitUntilReady {
+        command: String,
+        retries: u32,
+        interval_s: u64,
+    },
+    InstallPackages {
+        manager: Option<String>,
+        packages: Vec<String>,
+    },
+    Exec {
+        cmd: String,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        working_dir: Option<PathBuf>,
+        privileged: bool,
+        env: HashMap<String, String>,
+    },
+    CommitImage {
+        image: String,
+        push: bool,
+    },
+    ExportContainer {
+        remove_container: bool,
+    },
+    Minimize {
+        strip_docs: bool,
+        strip_locales: Option<Vec<String>>,
+        clean_package_cache: bool,
+        strip_binaries: bool,
+        dedup_min_size_kib: Option<u64>,
+    },
+    CreateFilesystem {
+        filesystem_type: String,
+        size_mib: u32,
+        block_size_mib: u32,
+        dd_args: Vec<String>,
+        mkfs_args: Vec<String>,
+    },
+    PackSquashfs {
+        mksquashfs_args: Vec<String>,
+    },
+    ApplyOverlays {
+        mounted: bool,
+        destinations: Vec<PathBuf>,
+    },
+    InstallFirstBoot {
+        names: Vec<String>,
+    },
+    CopyExports {
+        include_directories: Vec<PathBuf>,
+        create_directories: Vec<PathBuf>,
+        include_files: Vec<PathBuf>,
+        create_files: Vec<PathBuf>,
+    },
+    ConfigureGuestNetwork {
+        hostname: Option<String>,
+        resolv_conf: String,
+    },
+    InstallGuestAgent {
+        source: PathBuf,
+        vsock_port: u32,
+    },
+}
+
+#[derive(Serialize, Debug)]
+pub struct PlanMount {
+    pub source: String,
+    pub destination: PathBuf,
+}
+
+pub async fn explain_command(explain_args: ExplainArgs, config: &Config) -> Result<(), BuildfsError> {
+    let (build_script, package_type, unpack_path, _) = load_package(&explain_args.package, config).await?;
+    let plan = build_plan(&build_script);
+
+    if explain_args.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&plan).expect("Could not encode build plan into JSON")
+        );
+    } else {
+        for (index, phase) in plan.iter().enumerate() {
+            print_phase(index + 1, phase);
+        }
+    }
+
+    if matches!(package_type, PackageType::Tar | PackageType::TarGz) {
+        tokio::fs::remove_dir_all(&unpack_path)
+            .await
+            .map_err(BuildfsError::io("Could not remove temporary unpacked package"))?;
+    }
+
+    Ok(())
+}
+
+pub fn build_plan(build_script: &BuildScript) -> Vec<PlanPhase> {
+    let base_script_path = PathBuf::from("/__scripts");
+    let container = &build_script.container;
+    let mut plan = Vec::new();
+    if container.attach_to.is_none() {
+        plan.push(PlanPhase::PullImage {
+            image: container.image.full_name(),
+        });
+    }
+
+    let mut mounts = build_script
+        .commands
+        .iter()
+        .filter_map(|command| command.script_path.as_ref())
+        .map(|script_path| PlanMount {
+            source: script_path.to_string_lossy().to_string(),
+            destination: base_script_path.adjoin_absolute(script_path),
+        })
+        .collect::<Vec<_>>();
+    mounts.extend(
+        build_script
+            .commands
+            .iter()
+            .filter(|command| command.script_inline.is_some())
+            .enumerate()
+            .map(|(index, _)| PlanMount {
+                source: format!("<inline script #{}>", index + 1),
+                destination: base_script_path.join(format!("<inline script #{}>", index + 1)),
+            }),
+    );
+    mounts.extend(
+        build_script
+            .overlays
+            .iter()
+            .filter(|overlay| overlay.mounted)
+            .map(|overlay| PlanMount {
+                source: match overlay.source {
+                    Some(ref source) => source.to_string_lossy().to_string(),
+                    None => "<inline overlay>".to_string(),
+                },
+                destination: overlay.destination.clone(),
+            }),
+    );
+    mounts.extend(container.volumes.iter().map(|(source, destination)| PlanMount {
+        source: source.to_string_lossy().to_string(),
+        destination: destination.clone(),
+    }));
+
+    match container.attach_to {
+        Some(ref target) => plan.push(PlanPhase::AttachContainer {
+            engine: container.engine.to_string(),
+            target: target.clone(),
+            uploads: mounts,
+        }),
+        None => plan.push(PlanPhase::StartContainer {
+            engine: container.engine.to_string(),
+            rootful: container.rootful,
+            keep_alive: format!("{:?}", container.keep_alive),
+            hostname: container.hostname.clone(),
+            network_mode: container.network_mode.clone(),
+            env: container.env.clone(),
+            mounts,
+        }),
+    }
+
+    if let Some(ref ready_check) = container.ready_check {
+        plan.push(PlanPhase::WaitUntilReady {
+            command: ready_check.command.clone(),
+            retries: ready_check.retries.unwrap_or(10),
+            interval_s: ready_check.interval_s.unwrap_or(1),
+        });
+    }
+
+    for package_set in &build_script.packages {
+        plan.push(PlanPhase::InstallPackages {
+            manager: package_set.manager.map(|manager| format!("{manager:?}")),
+            packages: package_set.install.clone(),
+        });
+    }
+
+    let mut inline_index = 0;
+    for command in &build_script.commands {
+        let cmd = if command.script_inline.is_some() {
+            inline_index += 1;
+            base_script_path
+                .join(format!("<inline script #{inline_index}>"))
+                .to_string_lossy()
+                .to_string()
+        } else if let Some(ref script_path) = command.script_path {
+            base_script_path
+                .adjoin_absolute(script_path)
+                .to_string_lossy()
+                .to_string()
+        } else {
+            command.command.clone().unwrap_or_default()
+        };
+
+        plan.push(PlanPhase::Exec {
+            cmd,
+            uid: command.uid,
+            gid: command.gid,
+            working_dir: command.working_dir.clone(),
+            privileged: command.privileged.unwrap_or_default(),
+            env: command.env.clone(),
+        });
+    }
+
+    if let Some(commit_image) = build_script.post_build.get_commit_image() {
+        plan.push(PlanPhase::CommitImage {
+            image: commit_image.full_name(),
+            push: build_script.post_build.push,
+        });
+    }
+
+    plan.push(PlanPhase::ExportContainer {
+        remove_container: container.attach_to.is_none(),
+    });
+
+    let minimize = &build_script.minimize;
+    if minimize.is_enabled() || minimize.dedup.is_some() {
+        plan.push(PlanPhase::Minimize {
+            strip_docs: minimize.strip_docs,
+            strip_locales: minimize.strip_locales.clone(),
+            clean_package_cache: minimize.clean_package_cache,
+            strip_binaries: minimize.strip_binaries,
+            dedup_min_size_kib: minimize.dedup.as_ref().map(|dedup| dedup.get_min_size_kib()),
+        });
+    }
+
+    let filesystem = &build_script.filesystem;
+    let is_squashfs = matches!(filesystem.filesystem_type, FilesystemType::Squashfs);
+    if !is_squashfs {
+        plan.push(PlanPhase::CreateFilesystem {
+            filesystem_type: filesystem.filesystem_type.to_string(),
+            size_mib: filesystem.size_mib,
+            block_size_mib: filesystem.block_size_mib.unwrap_or(1),
+            dd_args: filesystem.dd_args.clone(),
+            mkfs_args: filesystem.mkfs_args.clone(),
+        });
+    }
+
+    plan.push(PlanPhase::ApplyOverlays {
+        mounted: false,
+        destinations: overlay_destinations(build_script, false),
+    });
+
+    let export = &build_script.export;
+    plan.push(PlanPhase::CopyExports {
+        include_directories: export.directories.include.clone(),
+        create_directories: export.directories.create.clone(),
+        include_files: export.files.include.clone(),
+        create_files: export.files.create.clone(),
+    });
+
+    if let Some(ref network) = build_script.guest.network {
+        plan.push(PlanPhase::ConfigureGuestNetwork {
+            hostname: network.hostname.clone(),
+            resolv_conf: format!("{:?}", network.resolv_conf),
+        });
+    }
+
+    if let Some(ref agent) = build_script.guest.agent {
+        plan.push(PlanPhase::InstallGuestAgent {
+            source: agent.source.clone(),
+            vsock_port: agent.vsock_port.unwrap_or(DEFAULT_AGENT_VSOCK_PORT),
+        });
+    }
+
+    plan.push(PlanPhase::ApplyOverlays {
+        mounted: true,
+        destinations: overlay_destinations(build_script, true),
+    });
+
+    if !build_script.first_boot.is_empty() {
+        plan.push(PlanPhase::InstallFirstBoot {
+            names: build_script
+                .first_boot
+                .iter()
+                .map(|first_boot| first_boot.name.clone())
+                .collect(),
+        });
+    }
+
+    if is_squashfs {
+        let mut mksquashfs_args = get_mksquashfs_args(&filesystem.squashfs.clone().unwrap_or_default());
+        mksquashfs_args.extend(filesystem.mkfs_args.iter().cloned());
+        plan.push(PlanPhase::PackSquashfs { mksquashfs_args });
+    }
+
+    plan
+}
+
+fn overlay_destinations(build_script: &BuildScript, mounted: bool) -> Vec<PathBuf> {
+    build_script
+        .overlays
+        .iter()
+        .filter(|overlay| overlay.mounted == mounted)
+        .map(|overlay| overlay.destination.clone())
+        .collect()
+}
+
+fn print_phase(number: usize, phase: &PlanPhase) {
+    match phase {
+        PlanPhase::PullImage { image } => println!("{number}. Pull image {image}"),
+        PlanPhase::StartContainer {
+            engine,
+            rootful,
+            keep_alive,
+            hostname,
+            network_mode,
+            env,
+            mounts,
+        } => {
+            println!("{number}. Start container via {engine} (rootful: {rootful}, keep-alive: {keep_alive})");
+            if let Some(hostname) = hostname {
+                println!("   hostname: {hostname}");
+            }
+            if let Some(network_mode) = network_mode {
+                println!("   network mode: {network_mode}");
+            }
+            for (key, value) in env {
+                println!("   env: {key}={value}");
+            }
+            for mount in mounts {
+                println!("   mount: {} -> {:?}", mount.source, mount.destination);
+            }
+        }
+        PlanPhase::AttachContainer {
+            engine,
+            target,
+            uploads,
+        } => {
+            println!("{number}. Attach to existing container or pod \"{target}\" via {engine}");
+            for upload in uploads {
+                println!("   upload: {} -> {:?}", upload.source, upload.destination);
+            }
+        }
+        PlanPhase::WaitUntilReady {
+            command,
+            retries,
+            interval_s,
+        } => println!("{number}. Wait until \"{command}\" succeeds ({retries} retries, {interval_s}s interval)"),
+        PlanPhase::InstallPackages { manager, packages } => println!(
+            "{number}. Install {} via {}",
+            packages.join(", "),
+            manager
+                .as_deref()
+                .unwrap_or("the package manager detected in the image")
+        ),
+        PlanPhase::Exec {
+            cmd,
+            uid,
+            gid,
+            working_dir,
+            privileged,
+            env,
+        } => {
+            println!("{number}. Exec \"{cmd}\"");
+            if uid.is_some() || gid.is_some() {
+                println!("   user: {uid:?}:{gid:?}");
+            }
+            if let Some(working_dir) = working_dir {
+                println!("   working dir: {working_dir:?}");
+            }
+            if *privileged {
+                println!("   privileged");
+            }
+            for (key, value) in env {
+                println!("   env: {key}={value}");
+            }
+        }
+        PlanPhase::CommitImage { image, push } => match push {
+            true => println!("{number}. Commit the container into image {image} and push it"),
+            false => println!("{number}. Commit the container into image {image}"),
+        },
+        PlanPhase::ExportContainer { remove_container } => match remove_container {
+            true => println!("{number}. Export, unpack and remove the container"),
+            false => println!("{number}. Export and unpack the container, leaving it running"),
+        },
+        PlanPhase::Minimize {
+            strip_docs,
+            strip_locales,
+            clean_package_cache,
+            strip_binaries,
+            dedup_min_size_kib,
+        } => {
+            println!("{number}. Minimize the exported rootfs");
+            println!("   strip docs: {strip_docs}, strip binaries: {strip_binaries}, clean package cache: {clean_package_cache}");
+            if let Some(strip_locales) = strip_locales {
+                println!("   strip locales: {strip_locales:?}");
+            }
+            if let Some(dedup_min_size_kib) = dedup_min_size_kib {
+                println!("   dedup files of at least {dedup_min_size_kib} KiB into hardlinks");
+            }
+        }
+        PlanPhase::CreateFilesystem {
+            filesystem_type,
+            size_mib,
+            block_size_mib,
+            dd_args,
+            mkfs_args,
+        } => {
+            println!("{number}. Create {filesystem_type} filesystem of {size_mib} MiB ({block_size_mib} MiB blocks)");
+            if !dd_args.is_empty() {
+                println!("   dd args: {dd_args:?}");
+            }
+            if !mkfs_args.is_empty() {
+                println!("   mkfs args: {mkfs_args:?}");
+            }
+        }
+        PlanPhase::PackSquashfs { mksquashfs_args } => {
+            println!("{number}. Pack the staged filesystem into a squashfs image");
+            println!("   mksquashfs args: {mksquashfs_args:?}");
+        }
+        PlanPhase::ApplyOverlays { mounted, destinations } => {
+            let kind = if *mounted { "mounted" } else { "non-mounted" };
+            println!("{number}. Apply {} {kind} overlay(s)", destinations.len());
+            for destination in destinations {
+                println!("   overlay: {destination:?}");
+            }
+        }
+        PlanPhase::CopyExports {
+            include_directories,
+            create_directories,
+            include_files,
+            create_files,
+        } => {
+            println!("{number}. Copy exports into the filesystem");
+            for path in include_directories {
+                println!("   include directory: {path:?}");
+            }
+            for path in create_directories {
+                println!("   create directory: {path:?}");
+            }
+            for path in include_files {
+                println!("   include file: {path:?}");
+            }
+            for path in create_files {
+                println!("   create file: {path:?}");
+            }
+        }
+        PlanPhase::ConfigureGuestNetwork { hostname, resolv_conf } => {
+            println!("{number}. Configure guest network (resolv.conf: {resolv_conf})");
+            if let Some(hostname) = hostname {
+                println!("   hostname: {hostname}");
+            }
+        }
+        PlanPhase::InstallGuestAgent { source, vsock_port } => {
+            println!("{number}. Install the guest agent from {source:?} on vsock port {vsock_port}")
+        }
+        PlanPhase::InstallFirstBoot { names } => {
+            println!("{number}. Install first-boot scripts that run once on the guest's first boot");
+            for name in names {
+                println!("   script: {name}");
+            }
+        }
+    }
+}
@@ -0,0 +1,636 @@
+use std::{
+    collections::HashMap,
+    ffi::{CString, OsString},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{MetadataExt, PermissionsExt},
+    },
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    dry_run::AdjoinAbsolute,
+    error::BuildfsError,
+    schema::{BuildScriptOwnership, OwnershipPolicy, SymlinkPolicy},
+};
+
+static MAX_SYMLINK_HOPS: usize = 40;
+static MAX_FAT_FILE_SIZE: u64 = u32::MAX as u64;
+
+pub struct ExportCopier {
+    source_root: PathBuf,
+    destination_root: PathBuf,
+    symlinks: SymlinkPolicy,
+    fail_on_dangling_symlinks: bool,
+    ownership: Arc<BuildScriptOwnership>,
+    fat: bool,
+    hardlinks: HashMap<(u64, u64), PathBuf>,
+    dereference_stack: Vec<PathBuf>,
+}
+
+impl ExportCopier {
+    pub fn new(
+        source_root: PathBuf,
+        destination_root: PathBuf,
+        symlinks: SymlinkPolicy,
+        fail_on_dangling_symlinks: bool,
+        ownership: Arc<BuildScriptOwnership>,
+    ) -> Self {
+        let fat = is_fat_filesystem(&destination_root);
+        Self {
+            source_root,
+            destination_root,
+            symlinks,
+            fail_on_dangling_symlinks,
+            ownership,
+            fat,
+            hardlinks: HashMap::new(),
+            dereference_stack: Vec::new(),
+        }
+    }
+
+    pub fn copy(&mut self, path: &Path) -> Result<(), BuildfsError> {
+        let host_destination_path = resolve_entry_destination(&self.destination_root, path)?;
+        self.dereference_stack.push(path.to_path_buf());
+        let result = self.copy_entry(path, path, &host_destination_path);
+        self.dereference_stack.pop();
+        result
+    }
+
+    fn copy_entry(
+        &mut self,
+        source_path: &Path,
+        destination_path: &Path,
+        host_destination_path: &Path,
+    ) -> Result<(), BuildfsError> {
+        let host_source_path = self.source_root.adjoin_absolute(source_path);
+        let metadata = std::fs::symlink_metadata(&host_source_path).map_err(|err| {
+            BuildfsError::Filesystem(format!(
+                "Could not read metadata of exported path {source_path:?}: {err}"
+            ))
+        })?;
+
+        if metadata.is_symlink() {
+            return self.copy_symlink(source_path, destination_path, host_destination_path);
+        }
+
+        // a symlink already in the image where the entry goes is replaced, so writes can't be redirected through it
+        remove_symlink(host_destination_path)?;
+        if metadata.is_dir() {
+            std::fs::create_dir_all(host_destination_path)
+                .map_err(BuildfsError::io("Could not create exported directory"))?;
+            let mut entry_names = std::fs::read_dir(&host_source_path)
+                .map_err(BuildfsError::io("Could not read exported directory"))?
+                .map(|entry| entry.map(|entry| entry.file_name()))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(BuildfsError::io("Could not read exported directory entry"))?;
+            entry_names.sort();
+            if self.fat {
+                check_fat_name_collisions(source_path, &entry_names)?;
+            }
+
+            for entry_name in entry_names {
+                self.copy_entry(
+                    &source_path.join(&entry_name),
+                    &destination_path.join(&entry_name),
+                    &host_destination_path.join(&entry_name),
+                )?;
+            }
+
+            return apply_metadata(
+                host_destination_path,
+                &metadata,
+                get_owner(&self.ownership, destination_path, (metadata.uid(), metadata.gid())),
+                self.fat,
+            );
+        }
+
+        if self.fat && !metadata.is_file() {
+            log::warn!("Skipped exported special file {source_path:?}, as FAT filesystems cannot store it");
+            return Ok(());
+        }
+
+        // hardlinked files are copied once and linked afterwards, matching "cp --preserve=links"
+        if metadata.nlink() > 1 && !self.fat {
+            let inode = (metadata.dev(), metadata.ino());
+            if let Some(linked_path) = self.hardlinks.get(&inode) {
+                return std::fs::hard_link(linked_path, host_destination_path)
+                    .map_err(BuildfsError::io("Could not hardlink exported file"));
+            }
+            self.hardlinks.insert(inode, host_destination_path.to_path_buf());
+        }
+
+        if metadata.is_file() {
+            std::fs::copy(&host_source_path, host_destination_path)
+                .map_err(BuildfsError::io("Could not copy exported file"))?;
+        } else {
+            let c_path = to_c_path(host_destination_path);
+            if unsafe { libc::mknod(c_path.as_ptr(), metadata.mode(), metadata.rdev()) } != 0 {
+                return Err(BuildfsError::Filesystem(format!(
+                    "Could not create exported special file {destination_path:?}: {}",
+                    std::io::Error::last_os_error()
+                )));
+            }
+        }
+
+        apply_metadata(
+            host_destination_path,
+            &metadata,
+            get_owner(&self.ownership, destination_path, (metadata.uid(), metadata.gid())),
+            self.fat,
+        )
+    }
+
+    fn copy_symlink(
+        &mut self,
+        source_path: &Path,
+        destination_path: &Path,
+        host_destination_path: &Path,
+    ) -> Result<(), BuildfsError> {
+        let host_source_path = self.source_root.adjoin_absolute(source_path);
+        let link_target =
+            std::fs::read_link(&host_source_path).map_err(BuildfsError::io("Could not read exported symlink"))?;
+
+        let Some(resolved_path) = resolve_in_root(&self.source_root, source_path) else {
+            if self.fail_on_dangling_symlinks {
+                return Err(BuildfsError::Filesystem(format!(
+                    "Exported symlink {source_path:?} points to {link_target:?}, which does not exist in the rootfs"
+                )));
+            }
+
+            log::warn!(
+                "Exported symlink {source_path:?} points to {link_target:?}, which does not exist in the rootfs"
+            );
+            return create_symlink(&link_target, host_destination_path);
+        };
+
+        match self.symlinks {
+            _ if self.fat && self.symlinks != SymlinkPolicy::Dereference => {
+                log::warn!(
+                    "Skipped exported symlink {source_path:?}, as FAT filesystems cannot store symlinks (dereference them instead)"
+                );
+                Ok(())
+            }
+            SymlinkPolicy::Preserve => create_symlink(&link_target, host_destination_path),
+            SymlinkPolicy::RewriteRelative if link_target.is_absolute() => create_symlink(
+                &get_relative_target(destination_path.parent().unwrap_or(Path::new("/")), &link_target),
+                host_destination_path,
+            ),
+            SymlinkPolicy::RewriteRelative => create_symlink(&link_target, host_destination_path),
+            SymlinkPolicy::Dereference => {
+                if self
+                    .dereference_stack
+                    .iter()
+                    .any(|copied_path| copied_path.starts_with(&resolved_path))
+                {
+                    return Err(BuildfsError::Filesystem(format!(
+                        "Could not dereference exported symlink {source_path:?}, as it points to an enclosing directory"
+                    )));
+                }
+
+                self.dereference_stack.push(resolved_path.clone());
+                let result = self.copy_entry(&resolved_path, destination_path, host_destination_path);
+                self.dereference_stack.pop();
+                result
+            }
+        }
+    }
+}
+
+fn create_symlink(link_target: &Path, host_destination_path: &Path) -> Result<(), BuildfsError> {
+    remove_symlink(host_destination_path)?;
+    std::os::unix::fs::symlink(link_target, host_destination_path)
+        .map_err(BuildfsError::io("Could not create exported symlink"))
+}
+
+fn remove_symlink(host_path: &Path) -> Result<(), BuildfsError> {
+    if std::fs::symlink_metadata(host_path).is_ok_and(|metadata| metadata.is_symlink()) {
+        std::fs::remove_file(host_path).map_err(BuildfsError::io(
+            "Could not replace a symlink in the way of an exported path",
+        ))?;
+    }
+    Ok(())
+}
+
+fn get_owner(ownership: &BuildScriptOwnership, path: &Path, (uid, gid): (u32, u32)) -> (u32, u32) {
+    // the most specific rule wins, and paths without one have the ID maps applied
+    let policy = ownership
+        .rules
+        .iter()
+        .filter(|rule| path.starts_with(&rule.path))
+        .max_by_key(|rule| rule.path.components().count())
+        .map(|rule| rule.owner)
+        .unwrap_or_default();
+
+    match policy {
+        OwnershipPolicy::Mapped => (map_id(ownership, uid), map_id(ownership, gid)),
+        OwnershipPolicy::Root => (0, 0),
+        OwnershipPolicy::Preserve => (uid, gid),
+    }
+}
+
+fn map_id(ownership: &BuildScriptOwnership, id: u32) -> u32 {
+    ownership
+        .map
+        .iter()
+        .find(|id_map| id >= id_map.from && id - id_map.from < id_map.size)
+        .map(|id_map| id_map.to + (id - id_map.from))
+        .unwrap_or(id)
+}
+
+fn apply_metadata(
+    host_path: &Path,
+    metadata: &std::fs::Metadata,
+    (uid, gid): (u32, u32),
+    fat: bool,
+) -> Result<(), BuildfsError> {
+    if !fat {
+        // ownership is only carried over when running as root, as with "cp -p"
+        let _ = std::os::unix::fs::lchown(host_path, Some(uid), Some(gid));
+        std::fs::set_permissions(host_path, std::fs::Permissions::from_mode(metadata.mode()))
+            .map_err(BuildfsError::io("Could not set permissions of exported path"))?;
+    }
+
+    let times = [
+        libc::timespec {
+            tv_sec: metadata.atime() as _,
+            tv_nsec: metadata.atime_nsec() as _,
+        },
+        libc::timespec {
+            tv_sec: metadata.mtime() as _,
+            tv_nsec: metadata.mtime_nsec() as _,
+        },
+    ];
+    let c_path = to_c_path(host_path);
+    unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    Ok(())
+}
+
+pub fn is_fat_filesystem(path: &Path) -> bool {
+    let c_path = to_c_path(path);
+    let mut statfs = unsafe { std::mem::zeroed::<libc::statfs>() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut statfs) } != 0 {
+        return false;
+    }
+
+    statfs.f_type == libc::MSDOS_SUPER_MAGIC
+}
+
+pub fn get_available_bytes(path: &Path) -> Option<u64> {
+    let c_path = to_c_path(path);
+    let mut statvfs = unsafe { std::mem::zeroed::<libc::statvfs>() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut statvfs) } != 0 {
+        return None;
+    }
+
+    Some(statvfs.f_bavail as u64 * statvfs.f_frsize as u64)
+}
+
+pub fn check_fat_file_sizes(host_paths: Vec<PathBuf>) -> Result<(), BuildfsError> {
+    let mut oversized_paths = Vec::new();
+    for host_path in host_paths {
+        collect_oversized_files(&host_path, &mut oversized_paths);
+    }
+
+    if !oversized_paths.is_empty() {
+        return Err(BuildfsError::Filesystem(format!(
+            "Could not export into a FAT filesystem, as {} file(s) exceed its 4 GiB file size limit: {oversized_paths:?}",
+            oversized_paths.len()
+        )));
+    }
+    Ok(())
+}
+
+fn collect_oversized_files(host_path: &Path, oversized_paths: &mut Vec<PathBuf>) {
+    let Ok(metadata) = std::fs::symlink_metadata(host_path) else {
+        return;
+    };
+
+    if metadata.is_dir() {
+        for entry in std::fs::read_dir(host_path).into_iter().flatten().flatten() {
+            collect_oversized_files(&entry.path(), oversized_paths);
+        }
+    } else if metadata.is_file() && metadata.len() > MAX_FAT_FILE_SIZE {
+        oversized_paths.push(host_path.to_path_buf());
+    }
+}
+
+fn check_fat_name_collisions(source_path: &Path, entry_names: &[OsString]) -> Result<(), BuildfsError> {
+    let mut lowercase_names: HashMap<String, &OsString> = HashMap::new();
+    for entry_name in entry_names {
+        if let Some(colliding_name) = lowercase_names.insert(entry_name.to_string_lossy().to_lowercase(), entry_name) {
+            return Err(BuildfsError::Filesystem(format!(
+                "Could not export {:?} and {:?} into a FAT filesystem, as their names only differ in case",
+                source_path.join(colliding_name),
+                source_path.join(entry_name)
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn to_c_path(path: &Path) -> CString {
+    CString::new(path.as_os_str().as_bytes()).expect("Path contained a null byte")
+}
+
+fn resolve_in_root(root_path: &PathBuf, path: &Path) -> Option<PathBuf> {
+    let mut resolved_path = PathBuf::from("/");
+    let mut pending_components = get_components(path);
+    pending_components.reverse();
+    let mut hops = 0;
+
+    while let Some(component) = pending_components.pop() {
+        match component.to_str() {
+            Some("/") => resolved_path = PathBuf::from("/"),
+            Some("..") => {
+                resolved_path.pop();
+            }
+            _ => {
+                let candidate_path = resolved_path.join(&component);
+                let host_candidate_path = root_path.adjoin_absolute(&candidate_path);
+                let metadata = std::fs::symlink_metadata(&host_candidate_path).ok()?;
+
+                if metadata.is_symlink() {
+                    hops += 1;
+                    if hops > MAX_SYMLINK_HOPS {
+                        return None;
+                    }
+
+                    let link_target = std::fs::read_link(&host_candidate_path).ok()?;
+                    pending_components.extend(get_components(&link_target).into_iter().rev());
+                } else {
+                    resolved_path = candidate_path;
+                }
+            }
+        }
+    }
+
+    Some(resolved_path)
+}
+
+pub fn resolve_destination(root_path: &Path, path: &Path) -> Result<PathBuf, BuildfsError> {
+    // unlike resolve_in_root, the path doesn't have to exist yet, and whatever is missing is taken as-is
+    let mut resolved_path = PathBuf::new();
+    let mut pending_components = get_components(path);
+    pending_components.reverse();
+    let mut hops = 0;
+
+    while let Some(component) = pending_components.pop() {
+        match component.to_str() {
+            Some("/") => resolved_path = PathBuf::new(),
+            Some("..") => {
+                resolved_path.pop();
+            }
+            _ => {
+                let candidate_path = resolved_path.join(&component);
+                match std::fs::read_link(root_path.join(&candidate_path)) {
+                    Ok(_) if hops == MAX_SYMLINK_HOPS => {
+                        return Err(BuildfsError::Filesystem(format!(
+                            "Could not resolve {path:?} inside {root_path:?}: too many levels of symbolic links"
+                        )))
+                    }
+                    Ok(link_target) => {
+                        hops += 1;
+                        pending_components.extend(get_components(&link_target).into_iter().rev());
+                    }
+                    Err(_) => resolved_path = candidate_path,
+                }
+            }
+        }
+    }
+
+    Ok(root_path.join(resolved_path))
+}
+
+pub fn resolve_entry_destination(root_path: &Path, path: &Path) -> Result<PathBuf, BuildfsError> {
+    // only the parent is resolved, the entry itself replaces whatever symlink may be in its place
+    match (path.parent(), path.file_name()) {
+        (Some(parent_path), Some(file_name)) => Ok(resolve_destination(root_path, parent_path)?.join(file_name)),
+        _ => resolve_destination(root_path, path),
+    }
+}
+
+fn get_components(path: &Path) -> Vec<OsString> {
+    path.components()
+        .filter_map(|component| match component {
+            Component::RootDir => Some(OsString::from("/")),
+            Component::ParentDir => Some(OsString::from("..")),
+            Component::Normal(name) => Some(name.to_os_string()),
+            Component::CurDir | Component::Prefix(_) => None,
+        })
+        .collect()
+}
+
+fn get_relative_target(link_parent_path: &Path, link_target: &Path) -> PathBuf {
+    let normalize = |path: &Path| {
+        let mut components: Vec<OsString> = Vec::new();
+        for component in get_components(path) {
+            match component.to_str() {
+                Some("/") => components.clear(),
+                Some("..") => {
+                    components.pop();
+                }
+                _ => components.push(component),
+            }
+        }
+        components
+    };
+    let (parent_components, target_components) = (normalize(link_parent_path), normalize(link_target));
+    let common_components = parent_components
+        .iter()
+        .zip(&target_components)
+        .take_while(|(parent_component, target_component)| parent_component == target_component)
+        .count();
+
+    let mut relative_target = PathBuf::new();
+    for _ in common_components..parent_components.len() {
+        relative_target.push("..");
+    }
+    for target_component in &target_components[common_components..] {
+        relative_target.push(target_component);
+    }
+
+    if relative_target.as_os_str().is_empty() {
+        relative_target.push(".");
+    }
+    relative_target
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use uuid::Uuid;
+
+    use crate::{
+        error::BuildfsError,
+        schema::{
+            BuildScriptOwnership, BuildScriptOwnershipMap, BuildScriptOwnershipRule, OwnershipPolicy, SymlinkPolicy,
+        },
+    };
+
+    use super::{
+        check_fat_name_collisions, get_owner, get_relative_target, resolve_destination, resolve_entry_destination,
+        ExportCopier,
+    };
+
+    #[test]
+    fn names_differing_in_case_collide_on_fat() {
+        let result = check_fat_name_collisions(Path::new("/boot/efi"), &["BOOT".into(), "EFI".into(), "boot".into()]);
+        assert!(
+            matches!(result, Err(BuildfsError::Filesystem(message)) if message.contains("their names only differ in case"))
+        );
+    }
+
+    #[test]
+    fn dangling_symlink_fails_when_requested() {
+        let source_root = PathBuf::from(format!("/tmp/{}", Uuid::new_v4()));
+        std::fs::create_dir_all(source_root.join("etc")).unwrap();
+        std::os::unix::fs::symlink("/run/missing.conf", source_root.join("etc/missing.conf")).unwrap();
+
+        let result = ExportCopier::new(
+            source_root,
+            PathBuf::from(format!("/tmp/{}", Uuid::new_v4())),
+            SymlinkPolicy::Preserve,
+            true,
+            Default::default(),
+        )
+        .copy(Path::new("/etc"));
+        assert!(
+            matches!(result, Err(BuildfsError::Filesystem(message)) if message.contains("which does not exist in the rootfs"))
+        );
+    }
+
+    #[test]
+    fn ownership_rules_override_id_maps_by_specificity() {
+        let ownership = BuildScriptOwnership {
+            map: vec![BuildScriptOwnershipMap {
+                from: 100000,
+                to: 0,
+                size: 65536,
+            }],
+            rules: vec![
+                BuildScriptOwnershipRule {
+                    path: PathBuf::from("/usr"),
+                    owner: OwnershipPolicy::Root,
+                },
+                BuildScriptOwnershipRule {
+                    path: PathBuf::from("/home"),
+                    owner: OwnershipPolicy::Preserve,
+                },
+                BuildScriptOwnershipRule {
+                    path: PathBuf::from("/usr/local/src"),
+                    owner: OwnershipPolicy::Mapped,
+                },
+            ],
+        };
+
+        assert_eq!(
+            get_owner(&ownership, Path::new("/etc/passwd"), (100000, 100042)),
+            (0, 42)
+        );
+        assert_eq!(
+            get_owner(&ownership, Path::new("/etc/passwd"), (1000, 1000)),
+            (1000, 1000)
+        );
+        assert_eq!(
+            get_owner(&ownership, Path::new("/usr/bin/env"), (101000, 101000)),
+            (0, 0)
+        );
+        assert_eq!(
+            get_owner(&ownership, Path::new("/home/user/.profile"), (101000, 101000)),
+            (101000, 101000)
+        );
+        assert_eq!(
+            get_owner(&ownership, Path::new("/usr/local/src/app"), (101000, 101000)),
+            (1000, 1000)
+        );
+    }
+
+    #[test]
+    fn absolute_targets_are_rewritten_relative_to_the_link() {
+        assert_eq!(
+            get_relative_target(Path::new("/usr/bin"), Path::new("/usr/lib/busybox/sh")),
+            PathBuf::from("../lib/busybox/sh")
+        );
+        assert_eq!(
+            get_relative_target(Path::new("/etc"), Path::new("/run/systemd/../resolv.conf")),
+            PathBuf::from("../run/resolv.conf")
+        );
+        assert_eq!(
+            get_relative_target(Path::new("/"), Path::new("/usr/bin")),
+            PathBuf::from("usr/bin")
+        );
+        assert_eq!(
+            get_relative_target(Path::new("/usr"), Path::new("/usr")),
+            PathBuf::from(".")
+        );
+    }
+
+    #[test]
+    fn destinations_are_resolved_inside_the_root() {
+        let root = PathBuf::from(format!("/tmp/{}", Uuid::new_v4()));
+        std::fs::create_dir_all(root.join("usr/bin")).unwrap();
+        std::os::unix::fs::symlink("/usr/bin", root.join("bin")).unwrap();
+        std::os::unix::fs::symlink("../../../..", root.join("usr/up")).unwrap();
+
+        assert_eq!(
+            resolve_destination(&root, Path::new("/bin/sh")).unwrap(),
+            root.join("usr/bin/sh")
+        );
+        assert_eq!(
+            resolve_destination(&root, Path::new("/usr/up/etc/passwd")).unwrap(),
+            root.join("etc/passwd")
+        );
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn image_symlinks_pointing_outside_the_root_are_not_followed() {
+        let (source_root, destination_root, host_path) = (
+            PathBuf::from(format!("/tmp/{}", Uuid::new_v4())),
+            PathBuf::from(format!("/tmp/{}", Uuid::new_v4())),
+            PathBuf::from(format!("/tmp/{}", Uuid::new_v4())),
+        );
+        std::fs::create_dir_all(source_root.join("etc/ssh")).unwrap();
+        std::fs::write(source_root.join("etc/ssh/sshd_config"), "PermitRootLogin no").unwrap();
+        std::fs::write(source_root.join("etc/hostname"), "vm").unwrap();
+        std::fs::create_dir_all(&destination_root).unwrap();
+        std::fs::create_dir_all(&host_path).unwrap();
+        std::os::unix::fs::symlink(&host_path, destination_root.join("etc")).unwrap();
+        std::os::unix::fs::symlink(host_path.join("hostname"), destination_root.join("hostname")).unwrap();
+
+        let mut copier = ExportCopier::new(
+            source_root.clone(),
+            destination_root.clone(),
+            SymlinkPolicy::Preserve,
+            false,
+            Default::default(),
+        );
+        copier.copy(Path::new("/etc/ssh")).unwrap();
+        assert!(destination_root
+            .join(host_path.strip_prefix("/").unwrap())
+            .join("ssh/sshd_config")
+            .is_file());
+        copier.copy(Path::new("/etc")).unwrap();
+        assert!(destination_root.join("etc/hostname").is_file());
+        assert_eq!(
+            resolve_entry_destination(&destination_root, Path::new("/hostname")).unwrap(),
+            destination_root.join("hostname")
+        );
+        assert_eq!(std::fs::read_dir(&host_path).unwrap().count(), 0);
+
+        for path in [source_root, destination_root, host_path] {
+            std::fs::remove_dir_all(path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    collections::HashSet,
+    io::{Error, ErrorKind, Read},
+    os::unix::fs::MetadataExt,
+    path::{Component, Path, PathBuf},
+};
+
+use tar::EntryType;
+
+use crate::dry_run::escapes_root;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ExtractOptions {
+    pub confine_symlinks: bool,
+    pub same_filesystem: bool,
+}
+
+pub fn extract_tar<R: Read>(
+    reader: R,
+    destination_path: &Path,
+    extract_options: ExtractOptions,
+) -> std::io::Result<()> {
+    std::fs::create_dir_all(destination_path)?;
+    let destination_dev = std::fs::metadata(destination_path)?.dev();
+    let mut verified_paths = HashSet::new();
+    let mut archive = tar::Archive::new(reader);
+    // directories are unpacked last, like tar's own unpack does, so their permissions can't block their contents
+    let mut directory_entries = Vec::new();
+
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let entry_path = entry.path()?.into_owned();
+        check_entry_path(&entry_path, "path")?;
+
+        match entry.header().entry_type() {
+            EntryType::Link => {
+                let link_name = entry
+                    .link_name()?
+                    .ok_or_else(|| unsafe_entry(&entry_path, "is a hard link without a target"))?;
+                check_entry_path(&link_name, "hard link target")?;
+            }
+            EntryType::Symlink if extract_options.confine_symlinks => {
+                let link_name = entry
+                    .link_name()?
+                    .ok_or_else(|| unsafe_entry(&entry_path, "is a symlink without a target"))?;
+                let link_parent = entry_path.parent().unwrap_or(Path::new(""));
+                if link_name.is_absolute() || escapes_root(&link_parent.join(&link_name)) {
+                    return Err(unsafe_entry(
+                        &entry_path,
+                        &format!("is a symlink to {link_name:?}, which points outside of the archive"),
+                    ));
+                }
+            }
+            _ => {}
+        }
+
+        check_parent_directories(
+            destination_path,
+            &entry_path,
+            destination_dev,
+            extract_options,
+            &mut verified_paths,
+        )?;
+
+        if entry.header().entry_type() == EntryType::Directory {
+            directory_entries.push(entry);
+        } else {
+            unpack_entry(entry, destination_path, &entry_path)?;
+        }
+    }
+
+    for directory_entry in directory_entries {
+        let entry_path = directory_entry.path()?.into_owned();
+        unpack_entry(directory_entry, destination_path, &entry_path)?;
+    }
+
+    Ok(())
+}
+
+fn unpack_entry<R: Read>(
+    mut entry: tar::Entry<'_, R>,
+    destination_path: &Path,
+    entry_path: &Path,
+) -> std::io::Result<()> {
+    match entry.unpack_in(destination_path)? {
+        true => Ok(()),
+        false => Err(unsafe_entry(entry_path, "was refused by the tar unpacker")),
+    }
+}
+
+fn check_entry_path(path: &Path, kind: &str) -> std::io::Result<()> {
+    if path.components().any(|component| {
+        matches!(
+            component,
+            Component::RootDir | Component::Prefix(_) | Component::ParentDir
+        )
+    }) {
+        return Err(unsafe_entry(
+            path,
+            &format!("has an absolute or parent-traversing {kind}"),
+        ));
+    }
+    Ok(())
+}
+
+fn check_parent_directories(
+    destination_path: &Path,
+    entry_path: &Path,
+    destination_dev: u64,
+    extract_options: ExtractOptions,
+    verified_paths: &mut HashSet<PathBuf>,
+) -> std::io::Result<()> {
+    // every existing ancestor of an entry must be a real directory, or writing the entry would follow a link out
+    let mut ancestor_path = PathBuf::new();
+    let components = entry_path.components().collect::<Vec<_>>();
+    for component in components.iter().take(components.len().saturating_sub(1)) {
+        ancestor_path.push(component);
+        if verified_paths.contains(&ancestor_path) {
+            continue;
+        }
+
+        match std::fs::symlink_metadata(destination_path.join(&ancestor_path)) {
+            Ok(metadata) if metadata.is_symlink() => {
+                return Err(unsafe_entry(
+                    entry_path,
+                    &format!("would be written through the symlink {ancestor_path:?}"),
+                ));
+            }
+            Ok(metadata) if extract_options.same_filesystem && metadata.dev() != destination_dev => {
+                return Err(unsafe_entry(
+                    entry_path,
+                    &format!("would be written onto another filesystem mounted at {ancestor_path:?}"),
+                ));
+            }
+            Ok(metadata) if metadata.is_dir() => {
+                verified_paths.insert(ancestor_path.clone());
+            }
+            // a missing ancestor is created by the unpack as a real directory, so there is nothing to check yet
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn unsafe_entry(entry_path: &Path, reason: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Refusing to extract archive entry {entry_path:?}, since it {reason}"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use uuid::Uuid;
+
+    use super::{extract_tar, ExtractOptions};
+
+    fn build_archive(entries: &[(&str, tar::EntryType, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, entry_type, link_name) in entries {
+            let mut header = tar::Header::new_gnu();
+            // the names are set directly, since the builder itself refuses to write ".." components
+            header.as_gnu_mut().unwrap().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_entry_type(*entry_type);
+            header.set_mode(0o755);
+            header.set_size(0);
+            if !link_name.is_empty() {
+                header.set_link_name(link_name).unwrap();
+            }
+            header.set_cksum();
+            builder.append(&header, std::io::empty()).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn escaping_entries_are_refused() {
+        let destination_path = PathBuf::from(format!("/tmp/{}", Uuid::new_v4()));
+        let extract = |entries: &[(&str, tar::EntryType, &str)], confine_symlinks: bool| {
+            let extract_options = ExtractOptions {
+                confine_symlinks,
+                same_filesystem: true,
+            };
+            extract_tar(build_archive(entries).as_slice(), &destination_path, extract_options)
+        };
+
+        assert!(extract(&[("../evil", tar::EntryType::Regular, "")], false).is_err());
+        assert!(extract(&[("etc/passwd", tar::EntryType::Link, "../../etc/passwd")], false).is_err());
+        assert!(extract(&[("bin", tar::EntryType::Symlink, "/usr/bin")], true).is_err());
+        assert!(extract(
+            &[
+                ("lib", tar::EntryType::Symlink, "/tmp"),
+                ("lib/evil", tar::EntryType::Regular, "")
+            ],
+            false
+        )
+        .is_err());
+        assert!(extract(
+            &[
+                ("usr/bin", tar::EntryType::Directory, ""),
+                ("bin", tar::EntryType::Symlink, "usr/bin"),
+                ("usr/bin/sh", tar::EntryType::Regular, "")
+            ],
+            true
+        )
+        .is_ok());
+        assert!(Path::new(&destination_path).join("usr/bin/sh").exists());
+        assert!(!Path::new("/tmp/evil").exists());
+
+        std::fs::remove_dir_all(destination_path).unwrap();
+    }
+}
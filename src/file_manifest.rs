@@ -0,0 +1,160 @@
+use std::{
+    fs::File,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    audit::{AuditAction, AuditLog},
+    error::BuildfsError,
+};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FileManifestKind {
+    File,
+    Directory,
+    Symlink,
+    Other,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FileManifestEntry {
+    pub path: PathBuf,
+    pub kind: FileManifestKind,
+    pub size: u64,
+    pub mode: String,
+    pub uid: u32,
+    pub gid: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link_target: Option<PathBuf>,
+}
+
+pub async fn write_file_manifest(
+    rootfs_path: &Path,
+    manifest_path: &Path,
+    audit_log: &AuditLog,
+) -> Result<(), BuildfsError> {
+    let rootfs_path = rootfs_path.to_path_buf();
+    let entries = tokio::task::spawn_blocking(move || gather_file_manifest(&rootfs_path))
+        .await
+        .expect("Join on blocking task failed")?;
+
+    let manifest_json = serde_json::to_string_pretty(&entries).expect("Could not encode file manifest into JSON");
+    audit_log.record(AuditAction::WriteFile, manifest_path);
+    tokio::fs::write(manifest_path, manifest_json)
+        .await
+        .map_err(BuildfsError::io("Could not write file manifest to its path"))?;
+    log::info!(
+        "Wrote a manifest of {} path(s) in the image to {manifest_path:?}",
+        entries.len()
+    );
+    Ok(())
+}
+
+pub fn gather_file_manifest(rootfs_path: &Path) -> Result<Vec<FileManifestEntry>, BuildfsError> {
+    let mut entries = Vec::new();
+    collect_entries(rootfs_path, rootfs_path, &mut entries)?;
+    // the walk order depends on the filesystem, so entries are sorted to make manifests of releases diffable
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+fn collect_entries(
+    rootfs_path: &Path,
+    dir_path: &Path,
+    entries: &mut Vec<FileManifestEntry>,
+) -> Result<(), BuildfsError> {
+    let read_dir = std::fs::read_dir(dir_path).map_err(BuildfsError::io(
+        "Could not read directory while gathering file manifest",
+    ))?;
+    for entry in read_dir {
+        let entry = entry.map_err(BuildfsError::io(
+            "Could not read directory entry while gathering file manifest",
+        ))?;
+        let entry_path = entry.path();
+        // the symlink itself is recorded, so links are never followed out of the image
+        let metadata = std::fs::symlink_metadata(&entry_path)
+            .map_err(BuildfsError::io("Could not inspect path for file manifest"))?;
+
+        let (kind, sha256, link_target) = if metadata.is_symlink() {
+            let link_target = std::fs::read_link(&entry_path)
+                .map_err(BuildfsError::io("Could not read symlink for file manifest"))?;
+            (FileManifestKind::Symlink, None, Some(link_target))
+        } else if metadata.is_dir() {
+            (FileManifestKind::Directory, None, None)
+        } else if metadata.is_file() {
+            (FileManifestKind::File, Some(hash_file(&entry_path)?), None)
+        } else {
+            (FileManifestKind::Other, None, None)
+        };
+
+        entries.push(FileManifestEntry {
+            path: Path::new("/").join(
+                entry_path
+                    .strip_prefix(rootfs_path)
+                    .expect("Manifest entry is not inside the filesystem"),
+            ),
+            kind,
+            size: match kind {
+                FileManifestKind::File | FileManifestKind::Symlink => metadata.len(),
+                _ => 0,
+            },
+            mode: format!("{:04o}", metadata.mode() & 0o7777),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            sha256,
+            link_target,
+        });
+
+        if kind == FileManifestKind::Directory {
+            collect_entries(rootfs_path, &entry_path, entries)?;
+        }
+    }
+    Ok(())
+}
+
+fn hash_file(path: &Path) -> Result<String, BuildfsError> {
+    let mut file = File::open(path).map_err(BuildfsError::io("Could not open file to hash for file manifest"))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).map_err(BuildfsError::io("Could not read file to hash for file manifest"))?;
+    Ok(hasher.finalize().iter().map(|byte| format!("{byte:02x}")).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use uuid::Uuid;
+
+    use super::{gather_file_manifest, FileManifestKind};
+
+    #[test]
+    fn manifest_hashes_files_and_records_links() {
+        let rootfs_path = PathBuf::from(format!("/tmp/{}", Uuid::new_v4()));
+        std::fs::create_dir_all(rootfs_path.join("etc")).unwrap();
+        std::fs::write(rootfs_path.join("etc/hostname"), "abc").unwrap();
+        std::os::unix::fs::symlink("/etc/hostname", rootfs_path.join("hostname")).unwrap();
+
+        let entries = gather_file_manifest(&rootfs_path).unwrap();
+        std::fs::remove_dir_all(&rootfs_path).unwrap();
+
+        let paths = entries.iter().map(|entry| entry.path.as_path()).collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            [Path::new("/etc"), Path::new("/etc/hostname"), Path::new("/hostname")]
+        );
+        assert_eq!(entries[0].kind, FileManifestKind::Directory);
+        assert_eq!(
+            entries[1].sha256.as_deref(),
+            Some("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+        assert_eq!(entries[1].size, 3);
+        assert_eq!(entries[2].link_target.as_deref(), Some(Path::new("/etc/hostname")));
+    }
+}
@@ -0,0 +1,240 @@
+use std::{
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    audit::{AuditAction, AuditLog},
+    dry_run::AdjoinAbsolute,
+    error::BuildfsError,
+    schema::BuildScriptFirstBoot,
+};
+
+static FIRST_BOOT_SCRIPTS_PATH: &str = "/usr/lib/buildfs/first-boot";
+static FIRST_BOOT_RUNNER_PATH: &str = "/usr/lib/buildfs/run-first-boot";
+static FIRST_BOOT_MARKER_PATH: &str = "/var/lib/buildfs/first-boot.done";
+static SYSTEMD_UNIT_PATH: &str = "/etc/systemd/system/buildfs-first-boot.service";
+static SYSTEMD_WANTS_PATH: &str = "/etc/systemd/system/multi-user.target.wants/buildfs-first-boot.service";
+static OPENRC_LOCAL_PATH: &str = "/etc/local.d/buildfs-first-boot.start";
+static OPENRC_LOCAL_SERVICE_PATH: &str = "/etc/init.d/local";
+static OPENRC_LOCAL_RUNLEVEL_PATH: &str = "/etc/runlevels/default/local";
+static SYSTEMD_PROBES: [&str; 2] = ["/usr/lib/systemd/systemd", "/lib/systemd/systemd"];
+static OPENRC_PROBES: [&str; 2] = ["/sbin/openrc", "/usr/sbin/openrc"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FirstBootHook {
+    Systemd,
+    OpenRc,
+}
+
+pub async fn install_first_boot(
+    first_boot: &[BuildScriptFirstBoot],
+    unpack_path: &PathBuf,
+    destination_path: &PathBuf,
+    audit_log: &AuditLog,
+) -> Result<(), BuildfsError> {
+    if first_boot.is_empty() {
+        return Ok(());
+    }
+
+    let hook = detect_first_boot_hook(destination_path).await.ok_or_else(|| {
+        BuildfsError::Validation(
+            "[[first_boot]] scripts need systemd or OpenRC inside the filesystem to run them on first boot, but neither was found"
+                .to_string(),
+        )
+    })?;
+
+    let scripts_path = destination_path.adjoin_absolute(&PathBuf::from(FIRST_BOOT_SCRIPTS_PATH));
+    tokio::fs::create_dir_all(&scripts_path)
+        .await
+        .map_err(BuildfsError::io(
+            "Could not create first-boot script directory inside the filesystem",
+        ))?;
+    audit_log.record(AuditAction::CreateDirectory, &scripts_path);
+    for (index, entry) in first_boot.iter().enumerate() {
+        let script = match (&entry.script_inline, &entry.script_path) {
+            (Some(script_inline), _) => script_inline.clone(),
+            (None, Some(script_path)) => tokio::fs::read_to_string(unpack_path.adjoin_absolute(script_path))
+                .await
+                .map_err(BuildfsError::io(
+                    "Could not read first-boot script from the unpacked package",
+                ))?,
+            (None, None) => unreachable!(),
+        };
+        // scripts are prefixed with their position so that the runner's glob keeps the build script's order
+        write_executable(
+            &scripts_path.join(format!("{:02}-{}", index + 1, entry.name)),
+            script,
+            audit_log,
+        )
+        .await?;
+    }
+
+    write_executable(
+        &destination_path.adjoin_absolute(&PathBuf::from(FIRST_BOOT_RUNNER_PATH)),
+        get_runner_script(hook),
+        audit_log,
+    )
+    .await?;
+
+    match hook {
+        FirstBootHook::Systemd => {
+            let unit_path = destination_path.adjoin_absolute(&PathBuf::from(SYSTEMD_UNIT_PATH));
+            write_file(&unit_path, get_systemd_unit(), audit_log).await?;
+            symlink(SYSTEMD_UNIT_PATH, SYSTEMD_WANTS_PATH, destination_path, audit_log).await?;
+        }
+        FirstBootHook::OpenRc => {
+            write_executable(
+                &destination_path.adjoin_absolute(&PathBuf::from(OPENRC_LOCAL_PATH)),
+                format!("#!/bin/sh\nexec {FIRST_BOOT_RUNNER_PATH}\n"),
+                audit_log,
+            )
+            .await?;
+            // local.d scripts only run when the "local" service is in the default runlevel
+            if tokio::fs::try_exists(destination_path.adjoin_absolute(&PathBuf::from(OPENRC_LOCAL_SERVICE_PATH)))
+                .await
+                .unwrap_or(false)
+            {
+                symlink(
+                    OPENRC_LOCAL_SERVICE_PATH,
+                    OPENRC_LOCAL_RUNLEVEL_PATH,
+                    destination_path,
+                    audit_log,
+                )
+                .await?;
+            }
+        }
+    }
+
+    log::info!(
+        "Installed {} first-boot script(s) into the filesystem, run once via {hook:?}",
+        first_boot.len()
+    );
+    Ok(())
+}
+
+pub async fn detect_first_boot_hook(destination_path: &PathBuf) -> Option<FirstBootHook> {
+    for (hook, probes) in [
+        (FirstBootHook::Systemd, SYSTEMD_PROBES),
+        (FirstBootHook::OpenRc, OPENRC_PROBES),
+    ] {
+        for probe in probes {
+            if tokio::fs::symlink_metadata(destination_path.adjoin_absolute(&PathBuf::from(probe)))
+                .await
+                .is_ok()
+            {
+                return Some(hook);
+            }
+        }
+    }
+
+    None
+}
+
+fn get_runner_script(hook: FirstBootHook) -> String {
+    let hook_path = match hook {
+        FirstBootHook::Systemd => SYSTEMD_WANTS_PATH,
+        FirstBootHook::OpenRc => OPENRC_LOCAL_PATH,
+    };
+    // the marker keeps the scripts from running twice even if the hook outlives its removal, e.g. on a read-only /etc
+    format!(
+        "#!/bin/sh\nset -e\n[ -e {FIRST_BOOT_MARKER_PATH} ] && exit 0\nfor script in {FIRST_BOOT_SCRIPTS_PATH}/*; do\n    \"$script\"\ndone\nmkdir -p {}\ntouch {FIRST_BOOT_MARKER_PATH}\nrm -f {hook_path}\n",
+        Path::new(FIRST_BOOT_MARKER_PATH).parent().unwrap().display()
+    )
+}
+
+fn get_systemd_unit() -> String {
+    format!(
+        "[Unit]\nDescription=Run buildfs first-boot scripts\nWants=network-online.target\nAfter=network-online.target\nConditionPathExists=!{FIRST_BOOT_MARKER_PATH}\n\n[Service]\nType=oneshot\nExecStart={FIRST_BOOT_RUNNER_PATH}\nRemainAfterExit=yes\n\n[Install]\nWantedBy=multi-user.target\n"
+    )
+}
+
+async fn write_file(path: &PathBuf, contents: String, audit_log: &AuditLog) -> Result<(), BuildfsError> {
+    tokio::fs::create_dir_all(path.parent().unwrap())
+        .await
+        .map_err(BuildfsError::io(
+            "Could not create parent directory of a first-boot file inside the filesystem",
+        ))?;
+    audit_log.record(AuditAction::WriteFile, path);
+    tokio::fs::write(path, contents).await.map_err(BuildfsError::io(
+        "Could not write first-boot file inside the filesystem",
+    ))
+}
+
+async fn write_executable(path: &PathBuf, contents: String, audit_log: &AuditLog) -> Result<(), BuildfsError> {
+    write_file(path, contents, audit_log).await?;
+    tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))
+        .await
+        .map_err(BuildfsError::io(
+            "Could not make first-boot file executable inside the filesystem",
+        ))
+}
+
+async fn symlink(
+    target: &str,
+    link: &str,
+    destination_path: &PathBuf,
+    audit_log: &AuditLog,
+) -> Result<(), BuildfsError> {
+    let link_path = destination_path.adjoin_absolute(&PathBuf::from(link));
+    tokio::fs::create_dir_all(link_path.parent().unwrap())
+        .await
+        .map_err(BuildfsError::io(
+            "Could not create parent directory of a first-boot symlink inside the filesystem",
+        ))?;
+    let _ = tokio::fs::remove_file(&link_path).await;
+    audit_log.record(AuditAction::Symlink, &link_path);
+    tokio::fs::symlink(target, &link_path).await.map_err(BuildfsError::io(
+        "Could not create first-boot symlink inside the filesystem",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::fs::PermissionsExt, path::PathBuf};
+
+    use uuid::Uuid;
+
+    use crate::{audit::AuditLog, schema::BuildScriptFirstBoot};
+
+    use super::install_first_boot;
+
+    #[tokio::test]
+    async fn systemd_images_get_a_self_disabling_unit() {
+        let destination_path = PathBuf::from(format!("/tmp/{}", Uuid::new_v4()));
+        tokio::fs::create_dir_all(destination_path.join("usr/lib/systemd"))
+            .await
+            .unwrap();
+        tokio::fs::write(destination_path.join("usr/lib/systemd/systemd"), "")
+            .await
+            .unwrap();
+        let first_boot = vec![BuildScriptFirstBoot {
+            name: "regenerate-host-keys".to_string(),
+            script_inline: Some("#!/bin/sh\nssh-keygen -A\n".to_string()),
+            script_path: None,
+        }];
+
+        install_first_boot(&first_boot, &destination_path, &destination_path, &AuditLog::default())
+            .await
+            .unwrap();
+
+        let script_path = destination_path.join("usr/lib/buildfs/first-boot/01-regenerate-host-keys");
+        assert_eq!(
+            tokio::fs::metadata(&script_path).await.unwrap().permissions().mode() & 0o777,
+            0o755
+        );
+        assert_eq!(
+            tokio::fs::read_link(
+                destination_path.join("etc/systemd/system/multi-user.target.wants/buildfs-first-boot.service")
+            )
+            .await
+            .unwrap(),
+            PathBuf::from("/etc/systemd/system/buildfs-first-boot.service")
+        );
+        let runner = tokio::fs::read_to_string(destination_path.join("usr/lib/buildfs/run-first-boot"))
+            .await
+            .unwrap();
+        assert!(runner.ends_with("rm -f /etc/systemd/system/multi-user.target.wants/buildfs-first-boot.service\n"));
+        tokio::fs::remove_dir_all(destination_path).await.unwrap();
+    }
+}
@@ -0,0 +1,127 @@
+use std::{
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+
+use tokio::process::{Child, Command};
+
+use crate::{
+    cleanup::{register_mount, release_mount, MountKind},
+    error::BuildfsError,
+    run::get_mount_fstype,
+    schema::FilesystemType,
+    tools::{get_tool_path, ToolsConfig},
+};
+
+static FUSE_MOUNT_ATTEMPTS: u32 = 100;
+
+pub struct FuseMount {
+    child: Child,
+    mount_path: PathBuf,
+}
+
+pub fn is_fuse_available() -> bool {
+    Path::new("/dev/fuse").exists()
+}
+
+pub async fn mount_fuse(
+    tools: &ToolsConfig,
+    filesystem_type: &FilesystemType,
+    image_path: &PathBuf,
+    mount_path: &PathBuf,
+) -> Result<FuseMount, BuildfsError> {
+    // lklfuse runs the kernel's own filesystem drivers in userspace, so it can write btrfs, xfs and vfat without root
+    let lklfuse_path = get_tool_path(tools, "lklfuse").ok_or_else(|| {
+        BuildfsError::InvalidArguments("Could not locate \"lklfuse\" binary in PATH for the FUSE backend".to_string())
+    })?;
+    let mut child = Command::new(lklfuse_path)
+        .arg("-f")
+        .arg("-o")
+        .arg(format!("type={}", get_mount_fstype(filesystem_type)))
+        .arg(image_path)
+        .arg(mount_path)
+        .stdin(Stdio::null())
+        .spawn()
+        .map_err(BuildfsError::io("Could not fork \"lklfuse\" to mount the rootfs"))?;
+
+    let parent_dev = get_dev(mount_path.parent().unwrap_or(Path::new("/")));
+    for _ in 0..FUSE_MOUNT_ATTEMPTS {
+        if get_dev(mount_path) != parent_dev {
+            log::info!("Mounted {image_path:?} at {mount_path:?} via FUSE");
+            register_mount(mount_path, MountKind::Fuse);
+            return Ok(FuseMount {
+                child,
+                mount_path: mount_path.clone(),
+            });
+        }
+
+        if let Ok(Some(exit_status)) = child.try_wait() {
+            return Err(BuildfsError::Filesystem(format!(
+                "Could not mount rootfs via FUSE: \"lklfuse\" exited with {exit_status}"
+            )));
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let _ = child.kill().await;
+    Err(BuildfsError::Filesystem(format!(
+        "Could not mount rootfs via FUSE: the mount never appeared at {mount_path:?}"
+    )))
+}
+
+pub async fn unmount_fuse(mut fuse_mount: FuseMount) -> Result<(), BuildfsError> {
+    let fusermount_path = get_fusermount_path().map_err(|_| {
+        BuildfsError::InvalidArguments("Could not locate \"fusermount3\" or \"fusermount\" binary in PATH".to_string())
+    })?;
+    let exit_status = Command::new(fusermount_path)
+        .arg("-u")
+        .arg(&fuse_mount.mount_path)
+        .status()
+        .await
+        .map_err(BuildfsError::io("Could not fork \"fusermount\" to unmount the rootfs"))?;
+    if !exit_status.success() {
+        return Err(BuildfsError::Filesystem(format!(
+            "Could not unmount FUSE rootfs at {:?}: \"fusermount\" exited with {exit_status}",
+            fuse_mount.mount_path
+        )));
+    }
+    release_mount(&fuse_mount.mount_path);
+
+    // the image is only fully written once lklfuse has flushed and exited
+    let exit_status = fuse_mount
+        .child
+        .wait()
+        .await
+        .map_err(BuildfsError::io("Could not wait on \"lklfuse\" to exit"))?;
+    if !exit_status.success() {
+        return Err(BuildfsError::Filesystem(format!(
+            "\"lklfuse\" exited with {exit_status} after unmounting, the image may be incomplete"
+        )));
+    }
+    Ok(())
+}
+
+pub fn unmount_fuse_lazily(mount_path: &Path) -> std::io::Result<()> {
+    let fusermount_path = get_fusermount_path().map_err(std::io::Error::other)?;
+    let exit_status = std::process::Command::new(fusermount_path)
+        .arg("-u")
+        .arg("-z")
+        .arg(mount_path)
+        .status()?;
+    if !exit_status.success() {
+        return Err(std::io::Error::other(format!(
+            "\"fusermount\" exited with {exit_status}"
+        )));
+    }
+    Ok(())
+}
+
+fn get_fusermount_path() -> Result<PathBuf, which::Error> {
+    which::which("fusermount3").or_else(|_| which::which("fusermount"))
+}
+
+fn get_dev(path: &Path) -> Option<u64> {
+    std::fs::metadata(path).ok().map(|metadata| metadata.dev())
+}
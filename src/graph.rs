@@ -0,0 +1,268 @@
+use crate::{
+    config::Config,
+    dry_run::load_package,
+    error::BuildfsError,
+    explain::{build_plan, PlanPhase},
+    schema::BuildScript,
+    GraphArgs, GraphFormat, PackageType,
+};
+
+static MAX_LABEL_LENGTH: usize = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NodeKind {
+    Step,
+    Artifact,
+}
+
+#[derive(Debug)]
+struct GraphNode {
+    id: String,
+    label: String,
+    kind: NodeKind,
+}
+
+#[derive(Debug, Default)]
+pub struct BuildGraph {
+    nodes: Vec<GraphNode>,
+    edges: Vec<(String, String)>,
+}
+
+impl BuildGraph {
+    fn add_node(&mut self, prefix: &str, label: String, kind: NodeKind) -> String {
+        let id = format!("{prefix}{}", self.nodes.len() + 1);
+        self.nodes.push(GraphNode {
+            id: id.clone(),
+            label: shorten_label(&label),
+            kind,
+        });
+        id
+    }
+
+    fn add_edge(&mut self, from: &str, to: &str) {
+        self.edges.push((from.to_string(), to.to_string()));
+    }
+}
+
+pub async fn graph_command(graph_args: GraphArgs, config: &Config) -> Result<(), BuildfsError> {
+    let (build_script, package_type, unpack_path, _) = load_package(&graph_args.package, config).await?;
+    let graph = build_graph(&build_script);
+    print!("{}", render_graph(&graph, graph_args.format));
+
+    if matches!(package_type, PackageType::Tar | PackageType::TarGz) {
+        tokio::fs::remove_dir_all(&unpack_path)
+            .await
+            .map_err(BuildfsError::io("Could not remove temporary unpacked package"))?;
+    }
+
+    Ok(())
+}
+
+pub fn build_graph(build_script: &BuildScript) -> BuildGraph {
+    let mut graph = BuildGraph::default();
+    let mut commands = build_script.commands.iter();
+    let mut previous_step: Option<String> = None;
+    let mut export_step = None;
+
+    for phase in build_plan(build_script) {
+        // empty overlay and export phases are left out, since they'd only add noise to the graph
+        match phase {
+            PlanPhase::ApplyOverlays { ref destinations, .. } if destinations.is_empty() => continue,
+            PlanPhase::CopyExports {
+                ref include_directories,
+                ref create_directories,
+                ref include_files,
+                ref create_files,
+            } if include_directories.is_empty()
+                && create_directories.is_empty()
+                && include_files.is_empty()
+                && create_files.is_empty() =>
+            {
+                continue
+            }
+            _ => {}
+        }
+
+        let step = graph.add_node("step", get_phase_label(&phase), NodeKind::Step);
+        if let Some(ref previous_step) = previous_step {
+            graph.add_edge(previous_step, &step);
+        }
+
+        match phase {
+            PlanPhase::StartContainer { .. } | PlanPhase::AttachContainer { .. } => {
+                if let Some(ref context) = build_script.context {
+                    let context_node = graph.add_node(
+                        "artifact",
+                        format!("context {}", context.get_host_path().to_string_lossy()),
+                        NodeKind::Artifact,
+                    );
+                    graph.add_edge(&step, &context_node);
+                }
+            }
+            PlanPhase::Exec { .. } => {
+                if let Some(save_output_to) = commands.next().and_then(|command| command.save_output_to.as_ref()) {
+                    let output_node = graph.add_node(
+                        "artifact",
+                        format!("output {}", save_output_to.to_string_lossy()),
+                        NodeKind::Artifact,
+                    );
+                    graph.add_edge(&step, &output_node);
+                }
+            }
+            PlanPhase::ExportContainer { .. } => export_step = Some(step.clone()),
+            PlanPhase::ApplyOverlays { mounted, .. } => {
+                for overlay in build_script
+                    .overlays
+                    .iter()
+                    .filter(|overlay| overlay.mounted == mounted)
+                {
+                    let source = match overlay.source {
+                        Some(ref source) => source.to_string_lossy().to_string(),
+                        None => "<inline overlay>".to_string(),
+                    };
+                    let overlay_node = graph.add_node(
+                        "artifact",
+                        format!("{source} -> {}", overlay.destination.to_string_lossy()),
+                        NodeKind::Artifact,
+                    );
+                    graph.add_edge(&overlay_node, &step);
+                }
+            }
+            PlanPhase::CopyExports {
+                ref include_directories,
+                ref include_files,
+                ..
+            } => {
+                for path in include_directories.iter().chain(include_files) {
+                    let export_node = graph.add_node(
+                        "artifact",
+                        format!("export {}", path.to_string_lossy()),
+                        NodeKind::Artifact,
+                    );
+                    if let Some(ref export_step) = export_step {
+                        graph.add_edge(export_step, &export_node);
+                    }
+                    graph.add_edge(&export_node, &step);
+                }
+            }
+            _ => {}
+        }
+
+        previous_step = Some(step);
+    }
+
+    let image_node = graph.add_node(
+        "artifact",
+        format!("{} image", build_script.filesystem.filesystem_type),
+        NodeKind::Artifact,
+    );
+    if let Some(ref previous_step) = previous_step {
+        graph.add_edge(previous_step, &image_node);
+    }
+
+    graph
+}
+
+pub fn render_graph(graph: &BuildGraph, format: GraphFormat) -> String {
+    let mut lines = Vec::with_capacity(graph.nodes.len() + graph.edges.len() + 3);
+    match format {
+        GraphFormat::Dot => {
+            lines.push("digraph buildfs {".to_string());
+            lines.push("    node [shape=box];".to_string());
+            for node in &graph.nodes {
+                let label = node.label.replace('\\', "\\\\").replace('"', "\\\"");
+                lines.push(match node.kind {
+                    NodeKind::Step => format!("    {} [label=\"{label}\"];", node.id),
+                    NodeKind::Artifact => format!("    {} [label=\"{label}\", shape=note];", node.id),
+                });
+            }
+            for (from, to) in &graph.edges {
+                lines.push(format!("    {from} -> {to};"));
+            }
+            lines.push("}".to_string());
+        }
+        GraphFormat::Mermaid => {
+            lines.push("flowchart TD".to_string());
+            for node in &graph.nodes {
+                let label = node.label.replace('"', "#quot;");
+                lines.push(match node.kind {
+                    NodeKind::Step => format!("    {}[\"{label}\"]", node.id),
+                    NodeKind::Artifact => format!("    {}[/\"{label}\"/]", node.id),
+                });
+            }
+            for (from, to) in &graph.edges {
+                lines.push(format!("    {from} --> {to}"));
+            }
+        }
+    }
+
+    lines.iter().map(|line| format!("{line}\n")).collect()
+}
+
+fn get_phase_label(phase: &PlanPhase) -> String {
+    match phase {
+        PlanPhase::PullImage { image } => format!("pull {image}"),
+        PlanPhase::StartContainer { engine, .. } => format!("start {engine} container"),
+        PlanPhase::AttachContainer { target, .. } => format!("attach to {target}"),
+        PlanPhase::WaitUntilReady { command, .. } => format!("wait until {command}"),
+        PlanPhase::InstallPackages { packages, .. } => format!("install {}", packages.join(", ")),
+        PlanPhase::Exec { cmd, .. } => format!("exec {cmd}"),
+        PlanPhase::CommitImage { image, push: true } => format!("commit and push {image}"),
+        PlanPhase::CommitImage { image, push: false } => format!("commit {image}"),
+        PlanPhase::ExportContainer { .. } => "export container".to_string(),
+        PlanPhase::Minimize { .. } => "minimize rootfs".to_string(),
+        PlanPhase::CreateFilesystem {
+            filesystem_type,
+            size_mib,
+            ..
+        } => format!("create {size_mib} MiB {filesystem_type} filesystem"),
+        PlanPhase::PackSquashfs { .. } => "pack squashfs".to_string(),
+        PlanPhase::ApplyOverlays { mounted, destinations } => match mounted {
+            true => format!("apply {} mounted overlay(s)", destinations.len()),
+            false => format!("apply {} overlay(s)", destinations.len()),
+        },
+        PlanPhase::InstallFirstBoot { names } => format!("install first-boot {}", names.join(", ")),
+        PlanPhase::CopyExports { .. } => "copy exports".to_string(),
+        PlanPhase::ConfigureGuestNetwork { .. } => "configure guest network".to_string(),
+        PlanPhase::InstallGuestAgent { vsock_port, .. } => format!("install guest agent on vsock port {vsock_port}"),
+    }
+}
+
+fn shorten_label(label: &str) -> String {
+    // multi-line inline commands are cut down to their first line, so the graph stays readable
+    let first_line = label.lines().next().unwrap_or_default();
+    match first_line.chars().count() > MAX_LABEL_LENGTH || first_line.len() < label.trim_end().len() {
+        true => format!(
+            "{}...",
+            first_line.chars().take(MAX_LABEL_LENGTH).collect::<String>().trim_end()
+        ),
+        false => first_line.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{schema::parse_build_script, GraphFormat};
+
+    use super::{build_graph, render_graph};
+
+    #[test]
+    fn graph_connects_steps_and_artifacts() {
+        let build_script = parse_build_script(
+            "schema_version = 1\n[filesystem]\nsize_mib = 64\n[container]\nimage = { name = \"debian\", tag = \"bookworm\" }\n[[commands]]\ncommand = \"echo \\\"hi\\\"\"\nsave_output_to = \"hi.txt\"\n[[overlays]]\nsource = \"motd\"\ndestination = \"/etc/motd\"\n[export.files]\ninclude = [\"/etc/hostname\"]\n",
+        );
+        let graph = build_graph(&build_script);
+
+        let dot = render_graph(&graph, GraphFormat::Dot);
+        assert!(dot.starts_with("digraph buildfs {\n"));
+        assert!(dot.contains("[label=\"exec echo \\\"hi\\\"\"];"));
+        assert!(dot.contains("[label=\"output hi.txt\", shape=note];"));
+        assert!(dot.contains("[label=\"motd -> /etc/motd\", shape=note];"));
+
+        let mermaid = render_graph(&graph, GraphFormat::Mermaid);
+        assert!(mermaid.starts_with("flowchart TD\n"));
+        assert!(mermaid.contains("[/\"export /etc/hostname\"/]"));
+        assert!(mermaid.contains("[/\"Ext4 image\"/]"));
+        assert_eq!(mermaid.matches(" --> ").count(), dot.matches(" -> ").count() - 1);
+    }
+}
@@ -0,0 +1,393 @@
+use std::{
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    audit::{AuditAction, AuditLog},
+    dry_run::AdjoinAbsolute,
+    error::BuildfsError,
+    export::resolve_destination,
+    first_boot::{detect_first_boot_hook, FirstBootHook},
+    schema::{BuildScriptGuestAgent, BuildScriptGuestNetwork, ResolvConfPolicy},
+};
+
+static SYSTEMD_RESOLVED_STUB_PATH: &str = "../run/systemd/resolve/stub-resolv.conf";
+static AGENT_BINARY_PATH: &str = "/usr/lib/buildfs/buildfs-agent";
+static AGENT_VERSION_PATH: &str = "/usr/lib/buildfs/agent-version";
+static AGENT_SYSTEMD_UNIT_PATH: &str = "/etc/systemd/system/buildfs-agent.service";
+static AGENT_SYSTEMD_WANTS_PATH: &str = "/etc/systemd/system/multi-user.target.wants/buildfs-agent.service";
+static AGENT_OPENRC_SERVICE_PATH: &str = "/etc/init.d/buildfs-agent";
+static AGENT_OPENRC_RUNLEVEL_PATH: &str = "/etc/runlevels/default/buildfs-agent";
+pub static DEFAULT_AGENT_VSOCK_PORT: u32 = 52;
+
+pub async fn apply_guest_network(
+    network: BuildScriptGuestNetwork,
+    destination_path: &Path,
+    audit_log: &AuditLog,
+) -> Result<(), BuildfsError> {
+    if network.hostname.is_some()
+        || network.nsswitch_hosts.is_some()
+        || matches!(
+            network.resolv_conf,
+            ResolvConfPolicy::Inline | ResolvConfPolicy::SystemdResolved
+        )
+    {
+        tokio::fs::create_dir_all(resolve_destination(destination_path, Path::new("/etc"))?)
+            .await
+            .map_err(BuildfsError::io(
+                "Could not create /etc directory inside the filesystem",
+            ))?;
+    }
+
+    if let Some(hostname) = network.hostname {
+        let hostname_path = resolve_guest_file(destination_path, "/etc/hostname")?;
+        remove_if_exists(&hostname_path).await?;
+        audit_log.record(AuditAction::WriteFile, &hostname_path);
+        tokio::fs::write(&hostname_path, format!("{hostname}\n"))
+            .await
+            .map_err(BuildfsError::io("Could not write /etc/hostname inside the filesystem"))?;
+        log::debug!("Set guest hostname to {hostname}");
+    }
+
+    let resolv_conf_path = resolve_guest_file(destination_path, "/etc/resolv.conf")?;
+    match network.resolv_conf {
+        ResolvConfPolicy::Keep => {}
+        // the container engine's resolv.conf is a regular file, while a symlink in the image only points inside it
+        ResolvConfPolicy::Empty => {
+            if tokio::fs::symlink_metadata(&resolv_conf_path)
+                .await
+                .is_ok_and(|metadata| metadata.is_file())
+            {
+                remove_if_exists(&resolv_conf_path).await?;
+                audit_log.record(AuditAction::WriteFile, &resolv_conf_path);
+                tokio::fs::write(&resolv_conf_path, "").await.map_err(BuildfsError::io(
+                    "Could not write /etc/resolv.conf inside the filesystem",
+                ))?;
+                log::debug!("Emptied the /etc/resolv.conf exported from the build container");
+            }
+        }
+        ResolvConfPolicy::Inline => {
+            remove_if_exists(&resolv_conf_path).await?;
+            audit_log.record(AuditAction::WriteFile, &resolv_conf_path);
+            tokio::fs::write(
+                &resolv_conf_path,
+                network.resolv_conf_inline.ok_or_else(|| {
+                    BuildfsError::Validation(
+                        "resolv_conf_inline has to be set for the Inline resolv.conf policy".to_string(),
+                    )
+                })?,
+            )
+            .await
+            .map_err(BuildfsError::io(
+                "Could not write /etc/resolv.conf inside the filesystem",
+            ))?;
+            log::debug!("Replaced /etc/resolv.conf inside the filesystem with inline contents");
+        }
+        ResolvConfPolicy::SystemdResolved => {
+            remove_if_exists(&resolv_conf_path).await?;
+            audit_log.record(AuditAction::Symlink, &resolv_conf_path);
+            tokio::fs::symlink(SYSTEMD_RESOLVED_STUB_PATH, &resolv_conf_path)
+                .await
+                .map_err(BuildfsError::io(
+                    "Could not symlink /etc/resolv.conf to the systemd-resolved stub",
+                ))?;
+            log::debug!("Symlinked /etc/resolv.conf to the systemd-resolved stub");
+        }
+    }
+
+    if let Some(nsswitch_hosts) = network.nsswitch_hosts {
+        let nsswitch_path = resolve_guest_file(destination_path, "/etc/nsswitch.conf")?;
+        let nsswitch =
+            tokio::fs::read_to_string(resolve_destination(destination_path, Path::new("/etc/nsswitch.conf"))?)
+                .await
+                .unwrap_or_default();
+
+        let mut replaced = false;
+        let mut lines = nsswitch
+            .lines()
+            .map(|line| {
+                if line.trim_start().starts_with("hosts:") {
+                    replaced = true;
+                    format!("hosts: {nsswitch_hosts}")
+                } else {
+                    line.to_string()
+                }
+            })
+            .collect::<Vec<_>>();
+        if !replaced {
+            lines.push(format!("hosts: {nsswitch_hosts}"));
+        }
+
+        remove_if_exists(&nsswitch_path).await?;
+        audit_log.record(AuditAction::WriteFile, &nsswitch_path);
+        tokio::fs::write(&nsswitch_path, lines.join("\n") + "\n")
+            .await
+            .map_err(BuildfsError::io(
+                "Could not write /etc/nsswitch.conf inside the filesystem",
+            ))?;
+        log::debug!("Set the \"hosts\" database of /etc/nsswitch.conf to: {nsswitch_hosts}");
+    }
+
+    Ok(())
+}
+
+pub async fn install_guest_agent(
+    agent: BuildScriptGuestAgent,
+    unpack_path: &Path,
+    destination_path: &PathBuf,
+    audit_log: &AuditLog,
+) -> Result<(), BuildfsError> {
+    let hook = detect_first_boot_hook(destination_path).await.ok_or_else(|| {
+        BuildfsError::Validation(
+            "the guest agent needs systemd or OpenRC inside the filesystem to run it, but neither was found"
+                .to_string(),
+        )
+    })?;
+    let source_path = unpack_path.to_path_buf().adjoin_absolute(&agent.source);
+    if !tokio::fs::try_exists(&source_path).await.unwrap_or(false) {
+        return Err(BuildfsError::Validation(format!(
+            "guest.agent.source must point at the guest agent binary, but {source_path:?} does not exist"
+        )));
+    }
+
+    let binary_path = resolve_guest_file(destination_path, AGENT_BINARY_PATH)?;
+    tokio::fs::create_dir_all(binary_path.parent().unwrap())
+        .await
+        .map_err(BuildfsError::io(
+            "Could not create guest agent directory inside the filesystem",
+        ))?;
+    remove_if_exists(&binary_path).await?;
+    audit_log.record(AuditAction::CopyIntoFilesystem, &binary_path);
+    tokio::fs::copy(&source_path, &binary_path)
+        .await
+        .map_err(BuildfsError::io(
+            "Could not copy guest agent binary into the filesystem",
+        ))?;
+    tokio::fs::set_permissions(&binary_path, std::fs::Permissions::from_mode(0o755))
+        .await
+        .map_err(BuildfsError::io(
+            "Could not make guest agent binary executable inside the filesystem",
+        ))?;
+    let version = get_agent_version(&binary_path).await?;
+    write_agent_file(AGENT_VERSION_PATH, format!("{version}\n"), destination_path, audit_log).await?;
+
+    let vsock_port = agent.vsock_port.unwrap_or(DEFAULT_AGENT_VSOCK_PORT);
+    let (service_path, service, link_target, link_path) = match hook {
+        FirstBootHook::Systemd => (
+            AGENT_SYSTEMD_UNIT_PATH,
+            format!(
+                "[Unit]\nDescription=buildfs guest agent\n\n[Service]\nExecStart={AGENT_BINARY_PATH} --vsock-port {vsock_port}\nRestart=always\n\n[Install]\nWantedBy=multi-user.target\n"
+            ),
+            AGENT_SYSTEMD_UNIT_PATH,
+            AGENT_SYSTEMD_WANTS_PATH,
+        ),
+        FirstBootHook::OpenRc => (
+            AGENT_OPENRC_SERVICE_PATH,
+            format!(
+                "#!/sbin/openrc-run\ndescription=\"buildfs guest agent\"\ncommand={AGENT_BINARY_PATH}\ncommand_args=\"--vsock-port {vsock_port}\"\ncommand_background=true\npidfile=/run/buildfs-agent.pid\n"
+            ),
+            AGENT_OPENRC_SERVICE_PATH,
+            AGENT_OPENRC_RUNLEVEL_PATH,
+        ),
+    };
+    let service_path = write_agent_file(service_path, service, destination_path, audit_log).await?;
+    if let FirstBootHook::OpenRc = hook {
+        tokio::fs::set_permissions(&service_path, std::fs::Permissions::from_mode(0o755))
+            .await
+            .map_err(BuildfsError::io(
+                "Could not make guest agent service executable inside the filesystem",
+            ))?;
+    }
+
+    let link_path = resolve_guest_file(destination_path, link_path)?;
+    tokio::fs::create_dir_all(link_path.parent().unwrap())
+        .await
+        .map_err(BuildfsError::io(
+            "Could not create parent directory of the guest agent service link inside the filesystem",
+        ))?;
+    remove_if_exists(&link_path).await?;
+    audit_log.record(AuditAction::Symlink, &link_path);
+    tokio::fs::symlink(link_target, &link_path)
+        .await
+        .map_err(BuildfsError::io(
+            "Could not enable the guest agent service inside the filesystem",
+        ))?;
+
+    log::info!(
+        "Installed the guest agent ({version}) into the filesystem, listening on vsock port {vsock_port} via {hook:?}"
+    );
+    Ok(())
+}
+
+async fn write_agent_file(
+    path: &str,
+    contents: String,
+    destination_path: &Path,
+    audit_log: &AuditLog,
+) -> Result<PathBuf, BuildfsError> {
+    let file_path = resolve_guest_file(destination_path, path)?;
+    tokio::fs::create_dir_all(file_path.parent().unwrap())
+        .await
+        .map_err(BuildfsError::io(
+            "Could not create parent directory of a guest agent file inside the filesystem",
+        ))?;
+    remove_if_exists(&file_path).await?;
+    audit_log.record(AuditAction::WriteFile, &file_path);
+    tokio::fs::write(&file_path, contents).await.map_err(BuildfsError::io(
+        "Could not write guest agent file inside the filesystem",
+    ))?;
+    Ok(file_path)
+}
+
+async fn get_agent_version(binary_path: &Path) -> Result<String, BuildfsError> {
+    // a packaged agent carries no version buildfs knows of, so its digest identifies it instead
+    let binary = tokio::fs::read(binary_path).await.map_err(BuildfsError::io(
+        "Could not read guest agent binary inside the filesystem",
+    ))?;
+    Ok(format!("sha256:{:x}", Sha256::digest(&binary)))
+}
+
+fn resolve_guest_file(destination_path: &Path, path: &str) -> Result<PathBuf, BuildfsError> {
+    // the file itself is replaced instead of written through, so a symlink in its place can't redirect the write
+    let path = Path::new(path);
+    Ok(resolve_destination(destination_path, path.parent().unwrap())?.join(path.file_name().unwrap()))
+}
+
+async fn remove_if_exists(path: &Path) -> Result<(), BuildfsError> {
+    if tokio::fs::symlink_metadata(path).await.is_ok() {
+        tokio::fs::remove_file(path)
+            .await
+            .map_err(BuildfsError::io("Could not remove existing file inside the filesystem"))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use uuid::Uuid;
+
+    use crate::{
+        audit::AuditLog,
+        schema::{BuildScriptGuestAgent, BuildScriptGuestNetwork, ResolvConfPolicy},
+    };
+
+    use super::{apply_guest_network, install_guest_agent};
+
+    #[tokio::test]
+    async fn guest_network_files_replace_symlinks_instead_of_following_them() {
+        let destination_path = PathBuf::from(format!("/tmp/{}", Uuid::new_v4()));
+        let host_path = PathBuf::from(format!("/tmp/{}", Uuid::new_v4()));
+        tokio::fs::create_dir_all(destination_path.join("etc")).await.unwrap();
+        tokio::fs::write(&host_path, "host").await.unwrap();
+        tokio::fs::symlink(&host_path, destination_path.join("etc/hostname"))
+            .await
+            .unwrap();
+        let network = BuildScriptGuestNetwork {
+            hostname: Some("guest".to_string()),
+            resolv_conf: ResolvConfPolicy::Keep,
+            resolv_conf_inline: None,
+            nsswitch_hosts: None,
+        };
+
+        apply_guest_network(network, &destination_path, &AuditLog::default())
+            .await
+            .unwrap();
+
+        assert_eq!(tokio::fs::read_to_string(&host_path).await.unwrap(), "host");
+        assert_eq!(
+            tokio::fs::read_to_string(destination_path.join("etc/hostname"))
+                .await
+                .unwrap(),
+            "guest\n"
+        );
+        assert!(!destination_path.join("etc/resolv.conf").exists());
+        tokio::fs::remove_dir_all(destination_path).await.unwrap();
+        tokio::fs::remove_file(host_path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn packaged_agent_is_installed_as_an_enabled_service() {
+        let destination_path = PathBuf::from(format!("/tmp/{}", Uuid::new_v4()));
+        tokio::fs::create_dir_all(destination_path.join("usr/lib/systemd"))
+            .await
+            .unwrap();
+        tokio::fs::write(destination_path.join("usr/lib/systemd/systemd"), "")
+            .await
+            .unwrap();
+        tokio::fs::write(destination_path.join("agent"), "#!/bin/sh\n")
+            .await
+            .unwrap();
+        let agent = BuildScriptGuestAgent {
+            source: PathBuf::from("/agent"),
+            vsock_port: Some(1024),
+        };
+
+        install_guest_agent(agent, &destination_path, &destination_path, &AuditLog::default())
+            .await
+            .unwrap();
+
+        assert!(destination_path.join("usr/lib/buildfs/buildfs-agent").exists());
+        assert!(
+            tokio::fs::read_to_string(destination_path.join("usr/lib/buildfs/agent-version"))
+                .await
+                .unwrap()
+                .starts_with("sha256:")
+        );
+        let unit = tokio::fs::read_to_string(destination_path.join("etc/systemd/system/buildfs-agent.service"))
+            .await
+            .unwrap();
+        assert!(unit.contains("ExecStart=/usr/lib/buildfs/buildfs-agent --vsock-port 1024\n"));
+        assert_eq!(
+            tokio::fs::read_link(
+                destination_path.join("etc/systemd/system/multi-user.target.wants/buildfs-agent.service")
+            )
+            .await
+            .unwrap(),
+            PathBuf::from("/etc/systemd/system/buildfs-agent.service")
+        );
+        tokio::fs::remove_dir_all(destination_path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn container_resolv_conf_is_emptied_by_default() {
+        let destination_path = PathBuf::from(format!("/tmp/{}", Uuid::new_v4()));
+        tokio::fs::create_dir_all(destination_path.join("etc")).await.unwrap();
+        tokio::fs::write(destination_path.join("etc/resolv.conf"), "nameserver 10.0.2.3\n")
+            .await
+            .unwrap();
+
+        apply_guest_network(Default::default(), &destination_path, &AuditLog::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            tokio::fs::read_to_string(destination_path.join("etc/resolv.conf"))
+                .await
+                .unwrap(),
+            ""
+        );
+
+        tokio::fs::remove_file(destination_path.join("etc/resolv.conf"))
+            .await
+            .unwrap();
+        tokio::fs::symlink(
+            "../run/systemd/resolve/stub-resolv.conf",
+            destination_path.join("etc/resolv.conf"),
+        )
+        .await
+        .unwrap();
+        apply_guest_network(Default::default(), &destination_path, &AuditLog::default())
+            .await
+            .unwrap();
+        assert!(tokio::fs::symlink_metadata(destination_path.join("etc/resolv.conf"))
+            .await
+            .unwrap()
+            .is_symlink());
+        tokio::fs::remove_dir_all(destination_path).await.unwrap();
+    }
+}
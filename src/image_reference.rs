@@ -0,0 +1,172 @@
+use std::fmt::Display;
+
+use regex::Regex;
+
+static DEFAULT_TAG: &str = "latest";
+static PATH_COMPONENT_REGEX: &str = "^[a-z0-9]+(?:(?:[._]|__|-+)[a-z0-9]+)*$";
+static DOMAIN_REGEX: &str = r"^(?:[a-zA-Z0-9](?:[a-zA-Z0-9-]*[a-zA-Z0-9])?(?:\.[a-zA-Z0-9](?:[a-zA-Z0-9-]*[a-zA-Z0-9])?)*|\[[0-9a-fA-F:]+\])(?::[0-9]+)?$";
+static TAG_REGEX: &str = r"^[\w][\w.-]{0,127}$";
+static DIGEST_REGEX: &str = "^[a-z0-9]+(?:[.+_-][a-z0-9]+)*:[0-9a-fA-F]{32,}$";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageReference {
+    pub registry: Option<String>,
+    pub repository: String,
+    pub tag: Option<String>,
+    pub digest: Option<String>,
+}
+
+impl ImageReference {
+    pub fn name(&self) -> String {
+        match self.registry {
+            Some(ref registry) => format!("{registry}/{}", self.repository),
+            None => self.repository.clone(),
+        }
+    }
+}
+
+impl Display for ImageReference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())?;
+        if let Some(ref tag) = self.tag {
+            write!(f, ":{tag}")?;
+        }
+        if let Some(ref digest) = self.digest {
+            write!(f, "@{digest}")?;
+        }
+        Ok(())
+    }
+}
+
+pub fn parse_image_reference(reference: &str) -> Result<ImageReference, String> {
+    if reference.is_empty() {
+        return Err("the image reference is empty".to_string());
+    }
+
+    let (remainder, digest) = match reference.split_once('@') {
+        Some((remainder, digest)) => (remainder, Some(validate_digest(digest)?)),
+        None => (reference, None),
+    };
+
+    // a colon only separates the tag when it comes after the last slash, otherwise it belongs to a registry port
+    let (name, tag) = match remainder.rsplit_once(':') {
+        Some((name, tag)) if !tag.contains('/') => (name, Some(validate_tag(tag)?)),
+        _ => (remainder, None),
+    };
+
+    let (registry, repository) = match name.split_once('/') {
+        Some((first_component, repository))
+            if first_component.contains(['.', ':']) || first_component == "localhost" =>
+        {
+            if !Regex::new(DOMAIN_REGEX).unwrap().is_match(first_component) {
+                return Err(format!(
+                    "registry \"{first_component}\" must be a host name or IP address with an optional port"
+                ));
+            }
+            (Some(first_component.to_string()), repository)
+        }
+        _ => (None, name),
+    };
+
+    let path_component_regex = Regex::new(PATH_COMPONENT_REGEX).unwrap();
+    for path_component in repository.split('/') {
+        if !path_component_regex.is_match(path_component) {
+            return Err(format!(
+                "repository path component \"{path_component}\" must be lowercase letters and digits, optionally separated by '.', '_', '__' or '-'"
+            ));
+        }
+    }
+
+    Ok(ImageReference {
+        registry,
+        repository: repository.to_string(),
+        tag,
+        digest,
+    })
+}
+
+pub fn normalize_image_reference(name: &str, tag: &str, digest: Option<&str>) -> Result<ImageReference, String> {
+    let mut image_reference = parse_image_reference(name)?;
+
+    // explicitly set fields may repeat what is embedded in the name, but never contradict it
+    let (tag, tag_digest) = match tag.split_once('@') {
+        Some((tag, tag_digest)) => (tag, Some(tag_digest)),
+        None => (tag, None),
+    };
+    if !tag.is_empty() {
+        merge_field(&mut image_reference.tag, validate_tag(tag)?, "tag")?;
+    }
+    for digest in [tag_digest, digest].into_iter().flatten() {
+        merge_field(&mut image_reference.digest, validate_digest(digest)?, "digest")?;
+    }
+
+    if image_reference.tag.is_none() && image_reference.digest.is_none() {
+        image_reference.tag = Some(DEFAULT_TAG.to_string());
+    }
+
+    Ok(image_reference)
+}
+
+fn merge_field(field: &mut Option<String>, value: String, field_name: &str) -> Result<(), String> {
+    match field {
+        Some(existing) if *existing != value => Err(format!(
+            "the name embeds {field_name} \"{existing}\", which conflicts with the separately set {field_name} \"{value}\""
+        )),
+        _ => {
+            *field = Some(value);
+            Ok(())
+        }
+    }
+}
+
+fn validate_tag(tag: &str) -> Result<String, String> {
+    match Regex::new(TAG_REGEX).unwrap().is_match(tag) {
+        true => Ok(tag.to_string()),
+        false => Err(format!(
+            "tag \"{tag}\" must be at most 128 letters, digits, '_', '.' and '-', and not start with '.' or '-'"
+        )),
+    }
+}
+
+fn validate_digest(digest: &str) -> Result<String, String> {
+    match Regex::new(DIGEST_REGEX).unwrap().is_match(digest) {
+        true => Ok(digest.to_string()),
+        false => Err(format!(
+            "digest \"{digest}\" must be of the form \"<algorithm>:<hex>\", e.g. \"sha256:\" followed by 64 hex digits"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize_image_reference, parse_image_reference};
+
+    #[test]
+    fn embedded_tags_and_digests_are_normalized() {
+        let digest = format!("sha256:{}", "a".repeat(64));
+        let image_reference = parse_image_reference(&format!("localhost:5000/org/app:1.2@{digest}")).unwrap();
+        assert_eq!(image_reference.registry.as_deref(), Some("localhost:5000"));
+        assert_eq!(image_reference.repository, "org/app");
+        assert_eq!(image_reference.tag.as_deref(), Some("1.2"));
+
+        assert_eq!(
+            normalize_image_reference("ghcr.io/org/app:1.2", "", None)
+                .unwrap()
+                .to_string(),
+            "ghcr.io/org/app:1.2"
+        );
+        assert_eq!(
+            normalize_image_reference("debian", &format!("bookworm@{digest}"), None)
+                .unwrap()
+                .to_string(),
+            format!("debian:bookworm@{digest}")
+        );
+        assert_eq!(
+            normalize_image_reference("debian", "", None).unwrap().to_string(),
+            "debian:latest"
+        );
+        assert!(normalize_image_reference("ghcr.io/org/app:1.2", "1.3", None).is_err());
+        assert!(parse_image_reference("ghcr.io/Org/app").is_err());
+        assert!(parse_image_reference("debian@sha256:abc").is_err());
+    }
+}
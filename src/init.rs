@@ -0,0 +1,127 @@
+use std::{os::unix::fs::PermissionsExt, path::Path};
+
+use crate::{error::BuildfsError, package::BUILD_SCRIPT_FILENAME, InitArgs, InitTemplate};
+
+static SETUP_SCRIPT_PATH: &str = "scripts/setup.sh";
+
+pub async fn init_command(init_args: InitArgs) -> Result<(), BuildfsError> {
+    let build_script_path = init_args.directory.join(BUILD_SCRIPT_FILENAME);
+    if !init_args.force && tokio::fs::try_exists(&build_script_path).await.unwrap_or(false) {
+        return Err(BuildfsError::InvalidArguments(format!(
+            "{build_script_path:?} already exists, pass --force to overwrite it"
+        )));
+    }
+
+    let name = get_package_name(&init_args.directory);
+    let setup_script_path = init_args.directory.join(SETUP_SCRIPT_PATH);
+    tokio::fs::create_dir_all(setup_script_path.parent().unwrap())
+        .await
+        .map_err(BuildfsError::io(
+            "Could not create the scripts directory of the new package",
+        ))?;
+    tokio::fs::write(&build_script_path, get_build_script(init_args.template, &name))
+        .await
+        .map_err(BuildfsError::io("Could not write the build script of the new package"))?;
+    tokio::fs::write(&setup_script_path, get_setup_script(init_args.template))
+        .await
+        .map_err(BuildfsError::io(
+            "Could not write the example script of the new package",
+        ))?;
+    tokio::fs::set_permissions(&setup_script_path, std::fs::Permissions::from_mode(0o755))
+        .await
+        .map_err(BuildfsError::io(
+            "Could not make the example script of the new package executable",
+        ))?;
+
+    log::info!(
+        "Created a {:?} package in {:?}, check it with \"buildfs dry-run {}\"",
+        init_args.template,
+        init_args.directory,
+        init_args.directory.to_string_lossy()
+    );
+    Ok(())
+}
+
+fn get_package_name(directory: &Path) -> String {
+    std::path::absolute(directory)
+        .ok()
+        .and_then(|directory| directory.file_name().map(|name| name.to_string_lossy().to_string()))
+        .unwrap_or_else(|| "rootfs".to_string())
+}
+
+fn get_build_script(template: InitTemplate, name: &str) -> String {
+    let (image, packages, size) = match template {
+        InitTemplate::Debian => (
+            "{ name = \"debian\", tag = \"bookworm\" }",
+            "\"ca-certificates\"",
+            "1 GiB",
+        ),
+        InitTemplate::Alpine => (
+            "{ name = \"alpine\", tag = \"3.20\" }",
+            "\"openrc\", \"ca-certificates\"",
+            "256 MiB",
+        ),
+        InitTemplate::Firecracker => (
+            "{ name = \"debian\", tag = \"bookworm\" }",
+            "\"systemd-sysv\", \"udev\", \"iproute2\", \"openssh-server\"",
+            "2 GiB",
+        ),
+    };
+    // the top-level directories of usr-merged Debian images are symlinks into /usr, which are exported as such
+    let include_directories = match template {
+        InitTemplate::Alpine => "\"/bin\", \"/etc\", \"/lib\", \"/root\", \"/sbin\", \"/usr\", \"/var\"",
+        _ => "\"/bin\", \"/etc\", \"/home\", \"/lib\", \"/lib64\", \"/opt\", \"/root\", \"/sbin\", \"/usr\", \"/var\"",
+    };
+
+    let mut build_script = format!(
+        "schema_version = 1\n\n[metadata]\nname = \"{name}\"\nversion = \"0.1.0\"\n\n[filesystem]\ntype = \"Ext4\"\nsize = \"{size}\"\n\n[container]\nimage = {image}\n\n[[packages]]\ninstall = [{packages}]\n\n[[commands]]\nscript_path = \"/{SETUP_SCRIPT_PATH}\"\n\n[export.directories]\ninclude = [{include_directories}]\ncreate = [\"/dev\", \"/proc\", \"/run\", \"/sys\", \"/tmp\"]\n"
+    );
+    if let InitTemplate::Firecracker = template {
+        build_script.push_str(&format!(
+            "\n[guest.network]\nhostname = \"{name}\"\nresolv_conf = \"Inline\"\nresolv_conf_inline = \"nameserver 1.1.1.1\\n\"\n\n[[first_boot]]\nname = \"regenerate-host-keys\"\nscript_inline = \"#!/bin/sh\\nrm -f /etc/ssh/ssh_host_*\\nssh-keygen -A\\n\"\n"
+        ));
+    }
+    build_script
+}
+
+fn get_setup_script(template: InitTemplate) -> String {
+    let body = match template {
+        InitTemplate::Debian => "# runs inside the build container after the packages are installed\necho \"Built by buildfs\" > /etc/motd\n",
+        InitTemplate::Alpine => "# runs inside the build container after the packages are installed\nrc-update add devfs sysinit\nrc-update add procfs boot\necho \"Built by buildfs\" > /etc/motd\n",
+        InitTemplate::Firecracker => "# runs inside the build container after the packages are installed\n# Firecracker guests talk over the serial console, so a login prompt is kept on it\nsystemctl enable serial-getty@ttyS0.service\necho \"Built by buildfs\" > /etc/motd\n",
+    };
+    format!("#!/bin/sh\nset -e\n\n{body}")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use uuid::Uuid;
+
+    use crate::{schema::parse_build_script, InitArgs, InitTemplate};
+
+    use super::init_command;
+
+    #[tokio::test]
+    async fn templates_produce_valid_packages() {
+        for template in [InitTemplate::Debian, InitTemplate::Alpine, InitTemplate::Firecracker] {
+            let directory = PathBuf::from(format!("/tmp/{}", Uuid::new_v4()));
+            let init_args = InitArgs {
+                directory: directory.clone(),
+                template,
+                force: false,
+            };
+            init_command(init_args.clone()).await.unwrap();
+            assert!(init_command(init_args).await.is_err());
+
+            let build_script = parse_build_script(&std::fs::read_to_string(directory.join("build.toml")).unwrap());
+            assert_eq!(
+                build_script.commands[0].script_path,
+                Some(PathBuf::from("/scripts/setup.sh"))
+            );
+            assert!(directory.join("scripts/setup.sh").exists());
+            std::fs::remove_dir_all(directory).unwrap();
+        }
+    }
+}
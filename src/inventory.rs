@@ -0,0 +1,151 @@
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+use crate::{
+    audit::{AuditAction, AuditLog},
+    container_engine::ContainerEngine,
+    dry_run::AdjoinAbsolute,
+    error::BuildfsError,
+    run::exec_and_collect,
+};
+
+static MANIFEST_FILE_PATH: &str = "/etc/buildfs-manifest.json";
+// exec-ed commands are split on whitespace and never go through a shell, so tabs and newlines are left for the
+// package managers themselves to expand
+static PACKAGE_QUERIES: [(&str, &str); 3] = [
+    ("dpkg", "dpkg-query -W -f=${Package}\\t${Version}\\n"),
+    ("rpm", "rpm -qa --queryformat=%{NAME}\\t%{VERSION}-%{RELEASE}\\n"),
+    ("apk", "apk info -v"),
+];
+
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Inventory {
+    pub package_manager: Option<String>,
+    pub packages: Vec<InventoryPackage>,
+    pub kernel_versions: Vec<String>,
+    pub libc_version: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct InventoryPackage {
+    pub name: String,
+    pub version: String,
+}
+
+pub async fn gather_inventory(
+    container_engine: &dyn ContainerEngine,
+    container_id: &str,
+    container_name: &str,
+) -> Result<Inventory, BuildfsError> {
+    let mut inventory = Inventory::default();
+
+    for (package_manager, query_cmd) in PACKAGE_QUERIES {
+        let (exit_code, output) = exec_and_collect(container_engine, container_id, container_name, query_cmd).await?;
+        if exit_code == Some(0) {
+            inventory.package_manager = Some(package_manager.to_string());
+            inventory.packages = parse_packages(package_manager, &output);
+            break;
+        }
+    }
+    if inventory.package_manager.is_none() {
+        log::warn!("Could not query dpkg, rpm or apk inside the container, so the inventory lists no packages");
+    }
+
+    // the container shares the host's kernel, so the image's kernels are the ones it ships modules for
+    let (exit_code, output) =
+        exec_and_collect(container_engine, container_id, container_name, "ls /lib/modules").await?;
+    if exit_code == Some(0) {
+        inventory.kernel_versions = output.split_whitespace().map(|version| version.to_string()).collect();
+    }
+
+    let (exit_code, output) = exec_and_collect(
+        container_engine,
+        container_id,
+        container_name,
+        "getconf GNU_LIBC_VERSION",
+    )
+    .await?;
+    inventory.libc_version = match exit_code {
+        Some(0) => Some(output.trim().to_string()),
+        _ => inventory
+            .packages
+            .iter()
+            .find(|package| package.name == "musl")
+            .map(|package| format!("musl {}", package.version)),
+    };
+
+    log::info!(
+        "Gathered an inventory of {} package(s) from the container",
+        inventory.packages.len()
+    );
+    Ok(inventory)
+}
+
+pub async fn write_manifest_file(
+    inventory: &Inventory,
+    destination_path: &PathBuf,
+    audit_log: &AuditLog,
+) -> Result<(), BuildfsError> {
+    let manifest_json = serde_json::to_string_pretty(inventory).expect("Could not encode inventory into JSON");
+    let manifest_path = destination_path.adjoin_absolute(&PathBuf::from(MANIFEST_FILE_PATH));
+    tokio::fs::create_dir_all(manifest_path.parent().unwrap())
+        .await
+        .map_err(BuildfsError::io(
+            "Could not create /etc directory inside the filesystem",
+        ))?;
+    audit_log.record(AuditAction::WriteFile, &manifest_path);
+    tokio::fs::write(&manifest_path, manifest_json)
+        .await
+        .map_err(BuildfsError::io("Could not write manifest file inside the filesystem"))
+}
+
+fn parse_packages(package_manager: &str, output: &str) -> Vec<InventoryPackage> {
+    let mut packages = output
+        .lines()
+        .filter_map(|line| match package_manager {
+            // apk prints "name-version-rN", and package names can contain dashes themselves
+            "apk" => {
+                let mut parts = line.trim().rsplitn(3, '-');
+                let release = parts.next()?;
+                let version = parts.next()?;
+                let name = parts.next()?;
+                Some(InventoryPackage {
+                    name: name.to_string(),
+                    version: format!("{version}-{release}"),
+                })
+            }
+            _ => {
+                let (name, version) = line.trim().split_once('\t')?;
+                Some(InventoryPackage {
+                    name: name.to_string(),
+                    version: version.to_string(),
+                })
+            }
+        })
+        .collect::<Vec<_>>();
+    packages.sort_by(|a, b| a.name.cmp(&b.name));
+    packages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_packages, InventoryPackage};
+
+    #[test]
+    fn apk_package_names_keep_their_dashes() {
+        assert_eq!(
+            parse_packages("apk", "musl-1.2.4-r2\nca-certificates-bundle-20240226-r0\n"),
+            vec![
+                InventoryPackage {
+                    name: "ca-certificates-bundle".to_string(),
+                    version: "20240226-r0".to_string(),
+                },
+                InventoryPackage {
+                    name: "musl".to_string(),
+                    version: "1.2.4-r2".to_string(),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,280 @@
+use std::{
+    collections::HashMap,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+
+use crate::{
+    dry_run::{escapes_root, AdjoinAbsolute},
+    error::BuildfsError,
+    registry::pull_artifact_directly,
+    schema::{BuildScript, BuildScriptCommand, BuildScriptOverlay, BuildScriptUse},
+    variables::interpolate_text,
+};
+
+pub static LIBRARY_FILENAME: &str = "buildfs-lib.toml";
+static OCI_SCHEME: &str = "oci://";
+static OCI_TITLE_ANNOTATION: &str = "org.opencontainers.image.title";
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Library {
+    #[serde(default)]
+    pub variables: HashMap<String, String>,
+    #[serde(default)]
+    pub commands: Vec<BuildScriptCommand>,
+    #[serde(default)]
+    pub overlays: Vec<BuildScriptOverlay>,
+}
+
+pub fn get_library_package_path(library_use: &BuildScriptUse) -> Option<PathBuf> {
+    let source = library_use.get_source();
+    (!source.starts_with(OCI_SCHEME)).then(|| PathBuf::from(source))
+}
+
+pub async fn splice_libraries(build_script: &mut BuildScript, unpack_path: &Path) -> Result<(), BuildfsError> {
+    if build_script.uses.is_empty() {
+        return Ok(());
+    }
+
+    let mut commands = Vec::new();
+    let mut overlays = Vec::new();
+    for library_use in &build_script.uses {
+        let source = library_use.get_source();
+        let library_toml = fetch_library(source, unpack_path).await?;
+        let library = toml::from_str::<Library>(&library_toml).map_err(|err| {
+            BuildfsError::Validation(format!("library {source} could not be decoded from TOML: {err}"))
+        })?;
+        let overrides = match library_use {
+            BuildScriptUse::Reference(_) => HashMap::new(),
+            BuildScriptUse::Parameterized { variables, .. } => variables.clone(),
+        };
+
+        let (library_commands, library_overlays) = instantiate_library(library, &overrides, &build_script.variables)
+            .map_err(|err| BuildfsError::Validation(format!("library {source} {err}")))?;
+        log::info!(
+            "Spliced {} command(s) and {} overlay(s) from library {source} into the build script",
+            library_commands.len(),
+            library_overlays.len()
+        );
+        commands.extend(library_commands);
+        overlays.extend(library_overlays);
+    }
+
+    // library steps come first, so that the package's own commands and overlays can build on and override them
+    commands.append(&mut build_script.commands);
+    build_script.commands = commands;
+    overlays.append(&mut build_script.overlays);
+    build_script.overlays = overlays;
+    Ok(())
+}
+
+async fn fetch_library(source: &str, unpack_path: &Path) -> Result<String, BuildfsError> {
+    if let Some(reference) = source.strip_prefix(OCI_SCHEME) {
+        let layout_path = pull_artifact_directly(reference).await;
+        let blocking_layout_path = layout_path.clone();
+        let result = tokio::task::spawn_blocking(move || read_library_from_layout(&blocking_layout_path))
+            .await
+            .expect("Join on blocking task failed");
+        tokio::fs::remove_dir_all(&layout_path)
+            .await
+            .map_err(BuildfsError::io("Could not remove temporary pulled library"))?;
+        return result.map_err(|err| BuildfsError::Validation(format!("library {source} {err}")));
+    }
+
+    let reference_path = PathBuf::from(source);
+    if !reference_path.is_absolute() || escapes_root(&reference_path) {
+        return Err(BuildfsError::Validation(format!(
+            "library {source} must either be an {OCI_SCHEME} reference or an absolute path (relative to package root)"
+        )));
+    }
+    if unpack_path.is_file() {
+        return Err(BuildfsError::Validation(format!(
+            "A non-packaged script can only use {OCI_SCHEME} libraries, but uses {source}"
+        )));
+    }
+    let mut library_path = unpack_path.to_path_buf().adjoin_absolute(&reference_path);
+    if library_path.is_dir() {
+        library_path.push(LIBRARY_FILENAME);
+    }
+
+    tokio::fs::read_to_string(&library_path)
+        .await
+        .map_err(BuildfsError::io("Could not read library from the package"))
+}
+
+fn read_library_from_layout(layout_path: &Path) -> Result<String, String> {
+    let manifest = std::fs::read_to_string(layout_path.join("manifest.json"))
+        .ok()
+        .and_then(|manifest| serde_json::from_str::<serde_json::Value>(&manifest).ok())
+        .ok_or_else(|| "was pulled without a readable manifest".to_string())?;
+    let layers = manifest
+        .get("layers")
+        .and_then(|layers| layers.as_array())
+        .cloned()
+        .unwrap_or_default();
+
+    for layer in &layers {
+        let media_type = layer
+            .get("mediaType")
+            .and_then(|media_type| media_type.as_str())
+            .unwrap_or_default();
+        let digest = layer
+            .get("digest")
+            .and_then(|digest| digest.as_str())
+            .unwrap_or_default();
+        // skopeo's dir layout names blobs by their digest without the algorithm prefix
+        let blob_path = layout_path.join(digest.split_once(':').map_or(digest, |(_, hex)| hex));
+
+        if media_type.contains("tar") {
+            let blob = std::fs::File::open(&blob_path).map_err(|err| format!("has an unreadable layer: {err}"))?;
+            let reader: Box<dyn Read> = match media_type.ends_with("gzip") {
+                true => Box::new(GzDecoder::new(blob)),
+                false => Box::new(blob),
+            };
+            if let Some(library_toml) = find_library_in_tar(reader)? {
+                return Ok(library_toml);
+            }
+            continue;
+        }
+
+        // artifacts pushed with tools like oras carry the file as a plain blob, named by its title annotation
+        let title = layer
+            .get("annotations")
+            .and_then(|annotations| annotations.get(OCI_TITLE_ANNOTATION))
+            .and_then(|title| title.as_str());
+        if title == Some(LIBRARY_FILENAME) || (title.is_none() && layers.len() == 1) {
+            return std::fs::read_to_string(&blob_path).map_err(|err| format!("has an unreadable layer: {err}"));
+        }
+    }
+
+    Err(format!("contains no {LIBRARY_FILENAME}"))
+}
+
+fn find_library_in_tar<R: Read>(reader: R) -> Result<Option<String>, String> {
+    let mut archive = tar::Archive::new(reader);
+    let entries = archive
+        .entries()
+        .map_err(|err| format!("has an unreadable layer: {err}"))?;
+    for entry in entries {
+        let mut entry = entry.map_err(|err| format!("has an unreadable layer: {err}"))?;
+        let is_library = entry
+            .path()
+            .map(|path| path.file_name() == Some(LIBRARY_FILENAME.as_ref()))
+            .unwrap_or(false);
+        if is_library {
+            let mut library_toml = String::new();
+            entry
+                .read_to_string(&mut library_toml)
+                .map_err(|err| format!("has an unreadable {LIBRARY_FILENAME}: {err}"))?;
+            return Ok(Some(library_toml));
+        }
+    }
+    Ok(None)
+}
+
+pub fn instantiate_library(
+    library: Library,
+    overrides: &HashMap<String, String>,
+    build_script_variables: &HashMap<String, String>,
+) -> Result<(Vec<BuildScriptCommand>, Vec<BuildScriptOverlay>), String> {
+    let mut library_variables = library.variables;
+    for (name, value) in overrides {
+        if !library_variables.contains_key(name) {
+            return Err(format!("has no variable \"{name}\""));
+        }
+        library_variables.insert(name.clone(), value.clone());
+    }
+    // libraries are spliced after the build script is interpolated, so its variables are resolved here as well
+    let variables = build_script_variables
+        .clone()
+        .into_iter()
+        .chain(library_variables)
+        .collect::<HashMap<_, _>>();
+
+    let mut commands = library.commands;
+    for command in commands.iter_mut() {
+        if command.script_path.is_some() {
+            return Err(
+                "can only contain inline commands and scripts, since its files aren't part of the package".to_string(),
+            );
+        }
+        for text in command
+            .command
+            .iter_mut()
+            .chain(command.script_inline.iter_mut())
+            .chain(command.env.values_mut())
+        {
+            *text = interpolate_text(text, &variables);
+        }
+    }
+
+    let mut overlays = library.overlays;
+    for overlay in overlays.iter_mut() {
+        if overlay.source.is_some() {
+            return Err("can only contain inline overlays, since its files aren't part of the package".to_string());
+        }
+        if let Some(ref mut source_inline) = overlay.source_inline {
+            *source_inline = interpolate_text(source_inline, &variables);
+        }
+        overlay.destination = PathBuf::from(interpolate_text(&overlay.destination.to_string_lossy(), &variables));
+    }
+
+    Ok((commands, overlays))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use uuid::Uuid;
+
+    use crate::schema::parse_build_script;
+
+    use super::splice_libraries;
+
+    #[tokio::test]
+    async fn libraries_are_parameterized_and_spliced_first() {
+        let package_path = PathBuf::from(format!("/tmp/{}", Uuid::new_v4()));
+        std::fs::create_dir_all(package_path.join("libs/ssh")).unwrap();
+        std::fs::write(
+            package_path.join("libs/ssh/buildfs-lib.toml"),
+            "[variables]\nport = \"22\"\nuser = \"root\"\n[[commands]]\ncommand = \"echo Port ${port} >> /etc/ssh/sshd_config\"\n[[overlays]]\nsource_inline = \"AllowUsers ${user} ${group}\\n\"\ndestination = \"/etc/ssh/sshd_config.d/${user}.conf\"\n",
+        )
+        .unwrap();
+
+        let mut build_script = parse_build_script(
+            "schema_version = 1\nuse = [{ source = \"/libs/ssh\", variables = { port = \"2222\" } }]\n[variables]\ngroup = \"wheel\"\n[filesystem]\nsize_mib = 64\n[container]\nimage = { name = \"debian\", tag = \"bookworm\" }\n[[commands]]\ncommand = \"true\"\n",
+        );
+        splice_libraries(&mut build_script, &package_path).await.unwrap();
+
+        assert_eq!(
+            build_script.commands[0].command.as_deref(),
+            Some("echo Port 2222 >> /etc/ssh/sshd_config")
+        );
+        assert_eq!(build_script.commands[1].command.as_deref(), Some("true"));
+        assert_eq!(
+            build_script.overlays[0].source_inline.as_deref(),
+            Some("AllowUsers root wheel\n")
+        );
+        assert_eq!(
+            build_script.overlays[0].destination,
+            PathBuf::from("/etc/ssh/sshd_config.d/root.conf")
+        );
+
+        let mut build_script = parse_build_script(
+            "schema_version = 1\nuse = [{ source = \"/libs/ssh\", variables = { address = \"::\" } }]\n[filesystem]\nsize_mib = 64\n[container]\nimage = { name = \"debian\", tag = \"bookworm\" }\n",
+        );
+        let err = splice_libraries(&mut build_script, &package_path).await.unwrap_err();
+        assert!(err.to_string().contains("has no variable \"address\""));
+
+        let mut build_script = parse_build_script(
+            "schema_version = 1\nuse = [\"../libs/ssh\"]\n[filesystem]\nsize_mib = 64\n[container]\nimage = { name = \"debian\", tag = \"bookworm\" }\n",
+        );
+        assert!(splice_libraries(&mut build_script, &package_path).await.is_err());
+
+        std::fs::remove_dir_all(package_path).unwrap();
+    }
+}
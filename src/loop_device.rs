@@ -0,0 +1,157 @@
+use std::{
+    fs::File,
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    cleanup::{register_loop_device, release_loop_device},
+    schema::BuildScriptLoopDevice,
+};
+
+static LOOP_CONTROL_PATH: &str = "/dev/loop-control";
+
+const LOOP_SET_FD: libc::c_ulong = 0x4C00;
+const LOOP_CLR_FD: libc::c_ulong = 0x4C01;
+const LOOP_SET_STATUS64: libc::c_ulong = 0x4C04;
+const LOOP_SET_DIRECT_IO: libc::c_ulong = 0x4C08;
+const LOOP_CTL_GET_FREE: libc::c_ulong = 0x4C82;
+const BLKRASET: libc::c_ulong = 0x1262;
+
+const LO_FLAGS_PARTSCAN: u32 = 8;
+
+#[repr(C)]
+struct LoopInfo64 {
+    lo_device: u64,
+    lo_inode: u64,
+    lo_rdevice: u64,
+    lo_offset: u64,
+    lo_sizelimit: u64,
+    lo_number: u32,
+    lo_encrypt_type: u32,
+    lo_encrypt_key_size: u32,
+    lo_flags: u32,
+    lo_file_name: [u8; 64],
+    lo_crypt_name: [u8; 64],
+    lo_encrypt_key: [u8; 32],
+    lo_init: [u64; 2],
+}
+
+#[derive(Debug)]
+pub struct LoopDevice {
+    device_path: PathBuf,
+    device_file: File,
+}
+
+impl LoopDevice {
+    pub fn attach(backing_path: &Path, options: &BuildScriptLoopDevice) -> std::io::Result<Self> {
+        let loop_control = File::options().read(true).write(true).open(LOOP_CONTROL_PATH)?;
+        let device_number = unsafe { libc::ioctl(loop_control.as_raw_fd(), LOOP_CTL_GET_FREE) };
+        check_ioctl(device_number, "find a free loop device")?;
+
+        let device_path = PathBuf::from(format!("/dev/loop{device_number}"));
+        let device_file = File::options().read(true).write(true).open(&device_path)?;
+
+        let mut backing_options = File::options();
+        backing_options.read(true).write(true);
+        if options.direct_io {
+            backing_options.custom_flags(libc::O_DIRECT);
+        }
+        let backing_file = backing_options.open(backing_path)?;
+
+        check_ioctl(
+            unsafe { libc::ioctl(device_file.as_raw_fd(), LOOP_SET_FD, backing_file.as_raw_fd()) },
+            "attach the filesystem image to the loop device",
+        )?;
+        // from here on, dropping the loop device detaches it, including on failure paths below
+        let loop_device = Self {
+            device_path,
+            device_file,
+        };
+        register_loop_device(&loop_device.device_path);
+
+        if options.partition_scan {
+            let mut loop_info = unsafe { std::mem::zeroed::<LoopInfo64>() };
+            loop_info.lo_flags = LO_FLAGS_PARTSCAN;
+            check_ioctl(
+                unsafe {
+                    libc::ioctl(
+                        loop_device.device_file.as_raw_fd(),
+                        LOOP_SET_STATUS64,
+                        &loop_info as *const LoopInfo64,
+                    )
+                },
+                "enable partition scanning on the loop device",
+            )?;
+        }
+
+        if options.direct_io {
+            check_ioctl(
+                unsafe {
+                    libc::ioctl(
+                        loop_device.device_file.as_raw_fd(),
+                        LOOP_SET_DIRECT_IO,
+                        1 as libc::c_ulong,
+                    )
+                },
+                "enable direct I/O on the loop device",
+            )?;
+        }
+
+        if let Some(read_ahead_kib) = options.read_ahead_kib {
+            // the read-ahead is specified in 512-byte sectors
+            check_ioctl(
+                unsafe {
+                    libc::ioctl(
+                        loop_device.device_file.as_raw_fd(),
+                        BLKRASET,
+                        (read_ahead_kib * 2) as libc::c_ulong,
+                    )
+                },
+                "set read-ahead on the loop device",
+            )?;
+        }
+
+        log::debug!(
+            "Attached {backing_path:?} to loop device {:?} with options: {options:?}",
+            loop_device.device_path
+        );
+        Ok(loop_device)
+    }
+
+    pub fn device_path(&self) -> &Path {
+        &self.device_path
+    }
+}
+
+impl Drop for LoopDevice {
+    fn drop(&mut self) {
+        release_loop_device(&self.device_path);
+        match clear_fd(&self.device_file) {
+            Ok(()) => log::debug!("Detached loop device {:?}", self.device_path),
+            Err(err) => log::error!("Could not detach loop device {:?}: {err}", self.device_path),
+        }
+    }
+}
+
+pub fn detach_loop_device(device_path: &Path) -> std::io::Result<()> {
+    clear_fd(&File::options().read(true).open(device_path)?)
+}
+
+fn clear_fd(device_file: &File) -> std::io::Result<()> {
+    if unsafe { libc::ioctl(device_file.as_raw_fd(), LOOP_CLR_FD) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn check_ioctl(result: libc::c_int, action: &str) -> std::io::Result<()> {
+    if result < 0 {
+        let error = std::io::Error::last_os_error();
+        return Err(std::io::Error::new(
+            error.kind(),
+            format!("Could not {action}: {error}"),
+        ));
+    }
+    Ok(())
+}
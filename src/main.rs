@@ -1,16 +1,71 @@
-use std::{fmt::Display, path::PathBuf};
+use std::{fmt::Display, path::PathBuf, process::ExitCode};
 
+use bench::bench_command;
 use clap::{Args, Parser, Subcommand, ValueEnum};
-use dry_run::dry_run_command;
+use config::load_config;
+use dry_run::{dry_run_command, validate_command};
+use error::BuildfsError;
+use explain::explain_command;
+use graph::graph_command;
+use init::init_command;
 use package::{pack_command, unpack_command};
-use run::run_command;
+use run::{resume_command, run_command};
+use runtime_stats::RuntimeStatsSampler;
+use schema::{schema_command, ContainerEngineType};
 use serde::{Deserialize, Serialize};
+use variables::parse_variable;
 
+pub mod audit;
+pub mod bench;
+pub mod boot;
+pub mod checkpoint;
+pub mod cleanup;
+pub mod config;
 pub mod container_engine;
 pub mod dry_run;
+pub mod epilogue;
+pub mod error;
+pub mod explain;
+pub mod export;
+pub mod extract;
+pub mod file_manifest;
+pub mod first_boot;
+pub mod fuse;
+pub mod graph;
+pub mod guest;
+pub mod image_reference;
+pub mod init;
+pub mod inventory;
+pub mod library;
+pub mod loop_device;
+pub mod metadata;
+pub mod minimize;
 pub mod package;
+pub mod package_ignore;
+pub mod packages;
+pub mod payload;
+pub mod plugin;
+pub mod policy;
+pub mod privilege;
+pub mod registry;
+pub mod report;
 pub mod run;
+pub mod runtime_stats;
+pub mod scheduler;
 pub mod schema;
+pub mod size;
+pub mod squashfs;
+pub mod ssh;
+pub mod step_mount;
+pub mod template;
+pub mod tools;
+pub mod unmount;
+pub mod variables;
+pub mod verify;
+pub mod warnings;
+pub mod wasm;
+
+static BLOCKING_THREAD_HEADROOM: usize = 16;
 
 #[derive(Parser, Debug, Clone)]
 #[command(
@@ -24,14 +79,13 @@ pub struct Cli {
     #[arg(
         short = 'A',
         long = "async-threads",
-        help = "The amount of asynchronous threads to give to Tokio",
-        default_value_t = 1
+        help = "The amount of asynchronous threads to give to Tokio, defaults to 2 (or 1 on a single CPU) since they mostly stream exec output"
     )]
-    pub async_threads: usize,
+    pub async_threads: Option<usize>,
     #[arg(
         short = 'B',
         long = "max-blocking-threads",
-        help = "The limit to the amount of blocking threads for Tokio. Setting this limit may degrade file I/O performance!"
+        help = "The limit to the amount of blocking threads for Tokio, defaults to the job count plus headroom. Setting this below the job count degrades file I/O performance!"
     )]
     pub max_blocking_threads: Option<usize>,
     #[arg(
@@ -47,10 +101,53 @@ pub struct Cli {
         help = "Disable logging of the output of scripts run inside the container, and pipe \"dd\" and \"mkfs\" output to /dev/null"
     )]
     pub no_exec_logs: bool,
+    #[arg(
+        short = 'c',
+        long = "config",
+        help = "The path to the buildfs config file, defaults to /etc/buildfs/config.toml if it exists"
+    )]
+    pub config_path: Option<PathBuf>,
+    #[arg(
+        short = 'j',
+        long = "jobs",
+        help = "The amount of pipeline jobs (overlays, exports, copies) to run concurrently, defaults to twice the CPU count"
+    )]
+    pub jobs: Option<usize>,
+    #[arg(
+        long = "runtime-stats",
+        help = "Print per-phase thread pool and job utilization of the Tokio runtime after the command finishes"
+    )]
+    pub runtime_stats: bool,
+    #[arg(
+        long = "set",
+        value_name = "KEY=VALUE",
+        value_parser = parse_variable,
+        global = true,
+        help = "Set a variable declared in the build script's variables, which can be passed multiple times and takes precedence over BUILDFS_VAR_<KEY> environment variables"
+    )]
+    pub variables: Vec<(String, String)>,
+    #[arg(
+        long = "privilege-helper",
+        env = "BUILDFS_PRIVILEGE_HELPER",
+        help = "Run \"run\" and \"resume\" without root inside a user namespace of the subordinate IDs, leaving only mounts and loop devices to a helper launched via sudo, pkexec or a setuid binary"
+    )]
+    pub privilege_helper: Option<PrivilegeHelperLauncher>,
+    #[arg(
+        long = "helper-path",
+        env = "BUILDFS_HELPER_PATH",
+        help = "The path to the setuid copy of buildfs that \"--privilege-helper setuid\" launches",
+        default_value = "/usr/libexec/buildfs/buildfs-helper"
+    )]
+    pub helper_path: PathBuf,
 }
 
 #[derive(Subcommand, Debug, Clone)]
 pub enum CliCommand {
+    #[command(about = "Create a starter package with a build script and an example script")]
+    Init {
+        #[command(flatten)]
+        args: InitArgs,
+    },
     #[command(about = "Pack a build script with its dependencies into an executable package")]
     Pack {
         #[command(flatten)]
@@ -65,12 +162,67 @@ pub enum CliCommand {
     DryRun {
         #[command(flatten)]
         args: DryRunArgs,
+        #[arg(
+            long = "deep",
+            help = "Also pull and start the container to check that the interpreters used by commands exist in the image"
+        )]
+        deep: bool,
+    },
+    #[command(about = "Statically validate an executable package without connecting to a container engine")]
+    Validate {
+        #[command(flatten)]
+        args: ValidateArgs,
     },
     #[command(about = "Run an executable package to produce a root filesystem")]
     Run {
         #[command(flatten)]
-        args: RunArgs,
+        args: Box<RunArgs>,
+    },
+    #[command(about = "Resume a killed run from its state file, reconnecting to its container or exported rootfs")]
+    Resume {
+        #[command(flatten)]
+        args: ResumeArgs,
     },
+    #[command(about = "Print the fully-resolved build plan of an executable package without executing it")]
+    Explain {
+        #[command(flatten)]
+        args: ExplainArgs,
+    },
+    #[command(about = "Render the steps, overlays, exports and outputs of an executable package as a graph")]
+    Graph {
+        #[command(flatten)]
+        args: GraphArgs,
+    },
+    #[command(about = "Print a JSON Schema of the build script format for editors and linters")]
+    Schema {
+        #[command(flatten)]
+        args: SchemaArgs,
+    },
+    #[command(about = "Measure the I/O pipeline of buildfs on a synthetic tree on this host")]
+    Bench {
+        #[command(flatten)]
+        args: BenchArgs,
+    },
+    #[command(
+        hide = true,
+        about = "Serve mount, unmount and loop device requests of an unprivileged buildfs over stdin and stdout"
+    )]
+    PrivilegedHelper,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct InitArgs {
+    #[arg(help = "The directory to create the package in", default_value = ".")]
+    directory: PathBuf,
+    #[arg(
+        long = "template",
+        short = 't',
+        help = "The kind of image the starter build script builds",
+        default_value = "debian"
+    )]
+    template: InitTemplate,
+    #[arg(long = "force", help = "Overwrite an existing build script in the directory")]
+    force: bool,
 }
 
 #[derive(Args, Clone, Debug)]
@@ -79,6 +231,11 @@ pub struct UnpackArgs {
     source_path: PathBuf,
     #[arg(help = "The path to the location of the unpacked content(s)")]
     destination_path: PathBuf,
+    #[arg(
+        long = "same-filesystem",
+        help = "Refuse to unpack anything onto a filesystem mounted inside the destination directory"
+    )]
+    same_filesystem: bool,
 }
 
 #[derive(Args, Clone, Debug)]
@@ -87,19 +244,239 @@ pub struct PackArgs {
     destination_path: PathBuf,
     #[arg(long = "type", short = 't', help = "The type of package to produce")]
     package_type: PackageType,
+    #[arg(
+        long = "age-recipient",
+        help = "An age recipient (or age plugin recipient) to encrypt encrypted overlay payloads to, which can be passed multiple times"
+    )]
+    age_recipients: Vec<String>,
+    #[arg(
+        long = "update",
+        help = "Update the existing tar or tar.gz package at the destination path in place, only rewriting the entries that changed"
+    )]
+    update: bool,
+    #[arg(long = "force", help = "Overwrite the destination path if it already exists")]
+    force: bool,
 }
 
-#[derive(Args, Clone, Debug)]
+#[derive(Args, Serialize, Deserialize, Clone, Debug)]
 pub struct DryRunArgs {
     package: PathBuf,
+    #[arg(
+        long = "json-warnings",
+        help = "Print the collected warnings as JSON instead of logging them"
+    )]
+    json_warnings: bool,
+    #[arg(
+        long = "policy",
+        help = "The path to a policy TOML to enforce on the build script, overriding the policy from the config file"
+    )]
+    policy_path: Option<PathBuf>,
+    #[arg(
+        long = "hermetic",
+        help = "Require a network-less container, no host volumes and a digest-pinned image for a reproducible build"
+    )]
+    hermetic: bool,
+    #[arg(
+        long = "offline",
+        help = "Forbid image pulls and registry access, failing up front if the image isn't already available locally"
+    )]
+    offline: bool,
+    #[arg(
+        long = "engine",
+        env = "BUILDFS_ENGINE",
+        help = "The container engine to use instead of the one set in the build script"
+    )]
+    engine: Option<ContainerEngineType>,
+    #[arg(
+        long = "connection-uri",
+        env = "BUILDFS_CONNECTION_URI",
+        help = "The container engine connection URI to use instead of the one set in the build script"
+    )]
+    connection_uri: Option<String>,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct ValidateArgs {
+    package: PathBuf,
+    #[arg(
+        long = "json-warnings",
+        help = "Print the collected warnings as JSON instead of logging them"
+    )]
+    json_warnings: bool,
+    #[arg(
+        long = "policy",
+        help = "The path to a policy TOML to enforce on the build script, overriding the policy from the config file"
+    )]
+    policy_path: Option<PathBuf>,
+    #[arg(
+        long = "hermetic",
+        help = "Require a network-less container, no host volumes and a digest-pinned image for a reproducible build"
+    )]
+    hermetic: bool,
+    #[arg(
+        long = "engine",
+        env = "BUILDFS_ENGINE",
+        help = "The container engine to check the build script against instead of the one set in it"
+    )]
+    engine: Option<ContainerEngineType>,
+    #[arg(long = "strict", help = "Fail the validation when any warnings were collected")]
+    strict: bool,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct ExplainArgs {
+    package: PathBuf,
+    #[arg(long = "json", help = "Print the build plan as JSON")]
+    json: bool,
 }
 
 #[derive(Args, Clone, Debug)]
+pub struct GraphArgs {
+    package: PathBuf,
+    #[arg(
+        long = "format",
+        help = "The graph description language to print the graph in",
+        default_value = "dot"
+    )]
+    format: GraphFormat,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct SchemaArgs {
+    #[arg(
+        long = "output",
+        short = 'o',
+        help = "The path to write the JSON Schema to instead of printing it"
+    )]
+    output_path: Option<PathBuf>,
+}
+
+#[derive(Args, Serialize, Deserialize, Clone, Debug)]
 pub struct RunArgs {
     #[command(flatten)]
     dry_run_args: DryRunArgs,
-    #[arg(long = "output", short = 'o', help = "The path to the produced root filesystem")]
+    #[arg(
+        long = "output",
+        short = 'o',
+        help = "The path to the produced root filesystem, which may contain {name}, {version}, {image.name}, {image.tag}, {engine}, {filesystem.type}, {date}, {time}, {timestamp} and {env.VAR} placeholders",
+        default_value = "{name}-{version}.{filesystem.type}"
+    )]
     output_path: PathBuf,
+    #[arg(
+        long = "copy-to",
+        help = "An additional path to copy the finished image to (reflinked when possible), which may contain the same placeholders as --output and be passed multiple times"
+    )]
+    copy_paths: Vec<PathBuf>,
+    #[arg(
+        long = "age-identity",
+        env = "BUILDFS_AGE_IDENTITY",
+        help = "The path to an age identity file to decrypt encrypted overlay payloads with"
+    )]
+    age_identity: Option<PathBuf>,
+    #[arg(long = "report", short = 'r', help = "The path to write a JSON build report to")]
+    report_path: Option<PathBuf>,
+    #[arg(
+        long = "manifest",
+        help = "The path to write a JSON manifest of every path in the image, with its size, mode, owner and SHA-256, to"
+    )]
+    manifest_path: Option<PathBuf>,
+    #[arg(
+        long = "verify",
+        help = "Re-mount the finished image read-only, run fsck on it and spot-check the expected paths"
+    )]
+    verify: bool,
+    #[command(flatten)]
+    provenance_args: ProvenanceArgs,
+    #[arg(
+        long = "state-file",
+        help = "The path to persist the run's progress to, so that a killed run can be continued via \"buildfs resume\""
+    )]
+    state_path: Option<PathBuf>,
+    #[arg(
+        long = "keep-staging",
+        help = "Retain the exported container tarball, the unpacked staging tree and the inline script files after the run"
+    )]
+    keep_staging: bool,
+    #[arg(
+        long = "staging-dir",
+        help = "Move the retained staging artifacts under this directory in a subdirectory named after the run ID, implies --keep-staging"
+    )]
+    staging_dir: Option<PathBuf>,
+    #[arg(
+        long = "fs-backend",
+        help = "How the filesystem is populated: \"kernel\" mounts it, \"userspace\" stages the rootfs in a directory and formats the image from it (Ext4 and Squashfs only), \"fuse\" mounts it via lklfuse (Ext4, Btrfs and Xfs only), and \"auto\" picks kernel as root and the best rootless backend otherwise",
+        default_value = "auto"
+    )]
+    fs_backend: FsBackend,
+    #[arg(
+        long = "print-effective-config",
+        help = "Print the runtime configuration merged from the command line, environment, config file and build script as JSON before running"
+    )]
+    print_effective_config: bool,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct ResumeArgs {
+    #[arg(help = "The path to the state file of the run to resume")]
+    state_path: PathBuf,
+}
+
+#[derive(Args, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ProvenanceArgs {
+    #[arg(
+        long = "build-id",
+        env = "BUILDFS_BUILD_ID",
+        help = "A build ID to record as BUILD_ID in the image's /etc/os-release"
+    )]
+    build_id: Option<String>,
+    #[arg(
+        long = "image-version",
+        env = "BUILDFS_IMAGE_VERSION",
+        help = "An image version to record as IMAGE_VERSION in the image's /etc/os-release"
+    )]
+    image_version: Option<String>,
+    #[arg(
+        long = "vcs-commit",
+        env = "BUILDFS_VCS_COMMIT",
+        help = "A VCS commit to record as BUILDFS_VCS_COMMIT in the image's /etc/os-release"
+    )]
+    vcs_commit: Option<String>,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct BenchArgs {
+    #[arg(
+        long = "work-dir",
+        help = "The directory to create the synthetic tree and images in",
+        default_value = "/tmp"
+    )]
+    work_dir: PathBuf,
+    #[arg(
+        long = "files",
+        help = "The amount of files in the synthetic tree",
+        default_value_t = 1000
+    )]
+    files: u64,
+    #[arg(
+        long = "file-size-kib",
+        help = "The size of each file in the synthetic tree",
+        default_value_t = 64
+    )]
+    file_size_kib: u64,
+    #[arg(
+        long = "image-size-mib",
+        help = "The size of the image created via dd and fallocate",
+        default_value_t = 256
+    )]
+    image_size_mib: u64,
+    #[arg(
+        long = "iterations",
+        help = "The amount of times to run each phase",
+        default_value_t = 3
+    )]
+    iterations: u32,
+    #[arg(long = "json", help = "Print the benchmark results as JSON")]
+    json: bool,
 }
 
 #[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Default, Debug)]
@@ -112,6 +489,45 @@ pub enum PackageType {
     BuildScript,
 }
 
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum FsBackend {
+    #[default]
+    Auto,
+    Kernel,
+    Userspace,
+    Fuse,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum PrivilegeHelperLauncher {
+    Sudo,
+    Pkexec,
+    Setuid,
+}
+
+#[derive(ValueEnum, Clone, Copy, Default, Debug)]
+pub enum InitTemplate {
+    #[default]
+    Debian,
+    Alpine,
+    Firecracker,
+}
+
+#[derive(ValueEnum, Clone, Copy, Default, Debug)]
+pub enum GraphFormat {
+    #[default]
+    Dot,
+    Mermaid,
+}
+
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct RuntimeSettings {
+    pub async_threads: usize,
+    pub max_blocking_threads: usize,
+    pub jobs: usize,
+}
+
 #[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Default, Debug)]
 pub enum LogLevel {
     Trace,
@@ -145,44 +561,110 @@ impl Display for PackageType {
     }
 }
 
-fn main() {
+fn main() -> ExitCode {
     let cli = Cli::parse();
 
     simple_logger::init_with_level(cli.log_level.into()).expect("Could not initialize simple_logger");
+    if let CliCommand::PrivilegedHelper = cli.command {
+        return privilege::helper_command();
+    }
+
+    // a user namespace can only be entered by a single-threaded process, so it's done before the runtime starts
+    if !privilege::connect_helper() && unsafe { libc::geteuid() } != 0 {
+        if let (Some(launcher), CliCommand::Run { .. } | CliCommand::Resume { .. }) =
+            (cli.privilege_helper, &cli.command)
+        {
+            return privilege::run_in_user_namespace(launcher, &cli.helper_path);
+        }
+    }
+    epilogue::install_panic_hook();
+    scheduler::init_scheduler(cli.jobs);
 
-    if std::env::consts::OS == "windows" {
-        panic!("buildfs cannot run on Windows due to a lack of mkfs tools!");
+    // packages can still be prepared and checked against a remote daemon from Windows, but never finalized there
+    if std::env::consts::OS == "windows"
+        && matches!(
+            cli.command,
+            CliCommand::Run { .. } | CliCommand::Resume { .. } | CliCommand::Bench { .. }
+        )
+    {
+        let err = BuildfsError::InvalidArguments(
+            "buildfs cannot create filesystems on Windows due to a lack of mount and mkfs tools".to_string(),
+        );
+        log::error!("{err}");
+        return err.exit_code();
     }
 
     if std::env::consts::OS == "macos" {
-        log::warn!("Running buildfs on macOS is neither recommended nor supported. Proceed with heavy caution!!!");
+        log::info!("Running on macOS, so only Ext4 and Squashfs images can be built, via the userspace backend");
     }
 
+    // blocking threads are spawned on demand and idle out, so the limit only needs to cover the scheduler's jobs
+    // plus the few blocking calls made outside of it, while async threads mostly sit waiting on exec output
+    let cpus = std::thread::available_parallelism().map(|cpus| cpus.get()).unwrap_or(1);
+    let async_threads = cli.async_threads.unwrap_or(cpus.min(2));
+    let max_blocking_threads = cli
+        .max_blocking_threads
+        .unwrap_or(scheduler::get_jobs() + BLOCKING_THREAD_HEADROOM);
+    if max_blocking_threads < scheduler::get_jobs() {
+        log::warn!(
+            "The blocking thread limit of {max_blocking_threads} is below the job count of {}, which will throttle file I/O",
+            scheduler::get_jobs()
+        );
+    }
+    log::debug!(
+        "Tokio runtime uses {async_threads} async thread(s) and up to {max_blocking_threads} blocking thread(s)"
+    );
+
     let mut runtime_builder = tokio::runtime::Builder::new_multi_thread();
     runtime_builder.enable_all();
-    runtime_builder.worker_threads(cli.async_threads);
-
-    if let Some(max_blocking_threads) = cli.max_blocking_threads {
-        runtime_builder.max_blocking_threads(max_blocking_threads);
-    }
+    runtime_builder.worker_threads(async_threads);
+    runtime_builder.max_blocking_threads(max_blocking_threads);
+    let runtime_settings = RuntimeSettings {
+        async_threads,
+        max_blocking_threads,
+        jobs: scheduler::get_jobs(),
+    };
 
     runtime_builder
         .build()
         .expect("Could not start Tokio runtime")
         .block_on(async {
-            match cli.command {
-                CliCommand::Pack { args } => {
-                    pack_command(args).await;
-                }
-                CliCommand::Unpack { args } => {
-                    unpack_command(args).await;
+            tokio::spawn(cleanup::handle_termination_signals());
+            let runtime_stats_sampler = cli.runtime_stats.then(RuntimeStatsSampler::start);
+            let mut config = load_config(cli.config_path).await;
+            config.variable_overrides = cli.variables.into_iter().collect();
+
+            let result = match cli.command {
+                CliCommand::Init { args } => init_command(args).await,
+                CliCommand::Pack { args } => pack_command(args, &config).await,
+                CliCommand::Unpack { args } => unpack_command(args).await,
+                CliCommand::DryRun { args, deep } => dry_run_command(args, deep, &config).await,
+                CliCommand::Validate { args } => validate_command(args, &config).await,
+                CliCommand::Run { args } => run_command(*args, cli.no_exec_logs, &config, runtime_settings).await,
+                CliCommand::Resume { args } => resume_command(args, cli.no_exec_logs, &config, runtime_settings).await,
+                CliCommand::Explain { args } => explain_command(args, &config).await,
+                CliCommand::Graph { args } => graph_command(args, &config).await,
+                CliCommand::Schema { args } => schema_command(args).await,
+                CliCommand::Bench { args } => {
+                    bench_command(args).await;
+                    Ok(())
                 }
-                CliCommand::DryRun { args } => {
-                    dry_run_command(args).await;
+                CliCommand::PrivilegedHelper => {
+                    unreachable!("The privileged helper is served before the runtime starts")
                 }
-                CliCommand::Run { args } => {
-                    run_command(args, cli.no_exec_logs).await;
+            };
+
+            if let Some(runtime_stats_sampler) = runtime_stats_sampler {
+                runtime_stats_sampler.finish().await;
+            }
+
+            match result {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(err) => {
+                    epilogue::report_failure(&err.to_string());
+                    cleanup::clean_up();
+                    err.exit_code()
                 }
             }
-        });
+        })
 }
@@ -0,0 +1,112 @@
+use std::path::Path;
+
+use crate::{
+    audit::{AuditAction, AuditLog},
+    error::BuildfsError,
+    export::resolve_destination,
+    schema::BuildScriptMetadata,
+    ProvenanceArgs,
+};
+
+static RELEASE_FILE_PATH: &str = "/etc/buildfs-release";
+static OS_RELEASE_PATH: &str = "/etc/os-release";
+
+pub async fn write_release_file(
+    metadata: &BuildScriptMetadata,
+    destination_path: &Path,
+    audit_log: &AuditLog,
+) -> Result<(), BuildfsError> {
+    let mut fields = vec![("BUILDFS_VERSION".to_string(), env!("CARGO_PKG_VERSION").to_string())];
+    if let Some(ref name) = metadata.name {
+        fields.push(("NAME".to_string(), name.clone()));
+    }
+    if let Some(ref version) = metadata.version {
+        fields.push(("VERSION".to_string(), version.clone()));
+    }
+    if let Some(ref description) = metadata.description {
+        fields.push(("DESCRIPTION".to_string(), description.clone()));
+    }
+
+    let mut labels = metadata.labels.iter().collect::<Vec<_>>();
+    labels.sort();
+    for (key, value) in labels {
+        let key = key
+            .chars()
+            .map(|character| match character.is_ascii_alphanumeric() {
+                true => character.to_ascii_uppercase(),
+                false => '_',
+            })
+            .collect::<String>();
+        fields.push((format!("LABEL_{key}"), value.clone()));
+    }
+
+    let release = fields
+        .into_iter()
+        .map(|(key, value)| format!("{key}=\"{}\"\n", escape_value(&value)))
+        .collect::<String>();
+
+    let release_path = resolve_destination(destination_path, Path::new(RELEASE_FILE_PATH))?;
+    tokio::fs::create_dir_all(release_path.parent().unwrap())
+        .await
+        .map_err(BuildfsError::io(
+            "Could not create /etc directory inside the filesystem",
+        ))?;
+    audit_log.record(AuditAction::WriteFile, &release_path);
+    tokio::fs::write(&release_path, release)
+        .await
+        .map_err(BuildfsError::io("Could not write release file inside the filesystem"))
+}
+
+pub async fn augment_os_release(
+    provenance_args: &ProvenanceArgs,
+    destination_path: &Path,
+    audit_log: &AuditLog,
+) -> Result<(), BuildfsError> {
+    let fields = [
+        ("BUILD_ID", &provenance_args.build_id),
+        ("IMAGE_VERSION", &provenance_args.image_version),
+        ("BUILDFS_VCS_COMMIT", &provenance_args.vcs_commit),
+    ]
+    .into_iter()
+    .filter_map(|(key, value)| value.as_ref().map(|value| (key, value)))
+    .collect::<Vec<_>>();
+    if fields.is_empty() {
+        return Ok(());
+    }
+
+    // /etc/os-release is commonly a symlink to /usr/lib/os-release, which must be resolved inside the image
+    let os_release_path = resolve_destination(destination_path, Path::new(OS_RELEASE_PATH))?;
+
+    let os_release = tokio::fs::read_to_string(&os_release_path).await.unwrap_or_default();
+    let mut lines = os_release
+        .lines()
+        .filter(|line| !fields.iter().any(|(key, _)| line.starts_with(&format!("{key}="))))
+        .map(|line| line.to_string())
+        .collect::<Vec<_>>();
+    lines.extend(
+        fields
+            .iter()
+            .map(|(key, value)| format!("{key}=\"{}\"", escape_value(value))),
+    );
+
+    audit_log.record(AuditAction::WriteFile, &os_release_path);
+    tokio::fs::write(&os_release_path, lines.join("\n") + "\n")
+        .await
+        .map_err(BuildfsError::io(
+            "Could not write /etc/os-release inside the filesystem",
+        ))?;
+    log::info!(
+        "Recorded {} provenance field(s) in /etc/os-release inside the filesystem",
+        fields.len()
+    );
+    Ok(())
+}
+
+fn escape_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('$', "\\$")
+        .replace('`', "\\`")
+        .replace('\n', " ")
+}
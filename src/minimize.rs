@@ -0,0 +1,305 @@
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hasher},
+    io::Read,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use crate::{
+    dry_run::AdjoinAbsolute,
+    error::BuildfsError,
+    report::{DedupReport, MinimizeReport},
+    schema::{BuildScriptDedup, BuildScriptMinimize},
+};
+
+static DOC_PATHS: &[&str] = &[
+    "/usr/share/doc",
+    "/usr/share/man",
+    "/usr/share/info",
+    "/usr/share/gtk-doc",
+];
+static LOCALE_PATHS: &[&str] = &["/usr/share/locale", "/usr/share/i18n/locales"];
+static PACKAGE_CACHE_PATHS: &[&str] = &[
+    "/var/cache/apt/archives",
+    "/var/lib/apt/lists",
+    "/var/cache/dnf",
+    "/var/cache/yum",
+    "/var/cache/apk",
+    "/var/cache/zypp",
+];
+static ELF_MAGIC: &[u8] = b"\x7fELF";
+
+pub async fn minimize_rootfs(
+    minimize: BuildScriptMinimize,
+    rootfs_path: &Path,
+) -> Result<MinimizeReport, BuildfsError> {
+    let rootfs_path = rootfs_path.to_path_buf();
+
+    tokio::task::spawn_blocking(move || {
+        let mut report = MinimizeReport::default();
+
+        if minimize.strip_docs {
+            for doc_path in DOC_PATHS {
+                report.docs_bytes += remove_dir_contents(&rootfs_path.adjoin_absolute(Path::new(doc_path)), &[])?;
+            }
+            log::debug!("Stripped documentation, saving {} byte(s)", report.docs_bytes);
+        }
+
+        if let Some(strip_locales) = minimize.strip_locales {
+            let keep_prefixes = strip_locales
+                .iter()
+                .filter_map(|entry| entry.strip_prefix("keep:"))
+                .collect::<Vec<_>>();
+            for locale_path in LOCALE_PATHS {
+                report.locales_bytes +=
+                    remove_dir_contents(&rootfs_path.adjoin_absolute(Path::new(locale_path)), &keep_prefixes)?;
+            }
+            log::debug!(
+                "Stripped locales except for {keep_prefixes:?}, saving {} byte(s)",
+                report.locales_bytes
+            );
+        }
+
+        if minimize.clean_package_cache {
+            for package_cache_path in PACKAGE_CACHE_PATHS {
+                report.package_cache_bytes +=
+                    remove_dir_contents(&rootfs_path.adjoin_absolute(Path::new(package_cache_path)), &[])?;
+            }
+            log::debug!("Cleaned package caches, saving {} byte(s)", report.package_cache_bytes);
+        }
+
+        if minimize.strip_binaries {
+            let strip_path = which::which("strip")
+                .map_err(|_| BuildfsError::InvalidArguments("Could not locate \"strip\" binary in PATH".to_string()))?;
+            let mut elf_paths = Vec::new();
+            collect_elf_paths(&rootfs_path, &mut elf_paths);
+
+            for elf_path in elf_paths {
+                let size_before = get_tree_size(&elf_path);
+                let exit_status = Command::new(&strip_path)
+                    .arg("--strip-unneeded")
+                    .arg(&elf_path)
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .status()
+                    .map_err(BuildfsError::io("Could not fork \"strip\" process"))?;
+
+                if exit_status.success() {
+                    report.binaries_bytes += size_before.saturating_sub(get_tree_size(&elf_path));
+                } else {
+                    log::debug!("\"strip\" could not process {elf_path:?}, skipping it");
+                }
+            }
+            log::debug!("Stripped ELF binaries, saving {} byte(s)", report.binaries_bytes);
+        }
+
+        Ok(report)
+    })
+    .await
+    .expect("Join on blocking task failed")
+}
+
+pub async fn dedup_rootfs(dedup: BuildScriptDedup, rootfs_path: &Path) -> Result<DedupReport, BuildfsError> {
+    let rootfs_path = rootfs_path.to_path_buf();
+    let excluded_paths = dedup
+        .get_exclude()
+        .iter()
+        .map(|path| rootfs_path.adjoin_absolute(path))
+        .collect::<Vec<_>>();
+
+    tokio::task::spawn_blocking(move || {
+        let mut report = DedupReport::default();
+        let mut candidates = HashMap::<(u64, u32, u32, u32), Vec<PathBuf>>::new();
+        collect_dedup_candidates(
+            &rootfs_path,
+            dedup.get_min_size_kib() * 1024,
+            &excluded_paths,
+            &mut candidates,
+        );
+
+        for ((size, _, _, _), paths) in candidates.into_iter().filter(|(_, paths)| paths.len() > 1) {
+            let mut hash_groups = HashMap::<u64, Vec<PathBuf>>::new();
+            for path in paths {
+                hash_groups.entry(hash_file(&path)?).or_default().push(path);
+            }
+
+            for (_, paths) in hash_groups.into_iter().filter(|(_, paths)| paths.len() > 1) {
+                let canonical_path = &paths[0];
+                let canonical_metadata = std::fs::metadata(canonical_path)
+                    .map_err(BuildfsError::io("Could not inspect metadata of deduplicated file"))?;
+
+                for duplicate_path in &paths[1..] {
+                    let duplicate_metadata = std::fs::metadata(duplicate_path)
+                        .map_err(BuildfsError::io("Could not inspect metadata of duplicate file"))?;
+                    if duplicate_metadata.dev() == canonical_metadata.dev()
+                        && duplicate_metadata.ino() == canonical_metadata.ino()
+                    {
+                        continue;
+                    }
+
+                    if !contents_equal(canonical_path, duplicate_path)? {
+                        continue;
+                    }
+
+                    let mut tmp_link_path = duplicate_path.clone();
+                    tmp_link_path.as_mut_os_string().push(".buildfs-dedup");
+                    std::fs::hard_link(canonical_path, &tmp_link_path)
+                        .map_err(BuildfsError::io("Could not create hardlink for deduplicated file"))?;
+                    std::fs::rename(&tmp_link_path, duplicate_path)
+                        .map_err(BuildfsError::io("Could not replace duplicate file with a hardlink"))?;
+
+                    report.linked_files += 1;
+                    report.saved_bytes += size;
+                }
+            }
+        }
+
+        Ok(report)
+    })
+    .await
+    .expect("Join on blocking task failed")
+}
+
+fn collect_dedup_candidates(
+    dir_path: &Path,
+    min_size: u64,
+    excluded_paths: &[PathBuf],
+    candidates: &mut HashMap<(u64, u32, u32, u32), Vec<PathBuf>>,
+) {
+    let Ok(read_dir) = std::fs::read_dir(dir_path) else {
+        return;
+    };
+
+    for entry in read_dir.flatten() {
+        if excluded_paths
+            .iter()
+            .any(|excluded_path| entry.path().starts_with(excluded_path))
+        {
+            continue;
+        }
+        let Ok(metadata) = std::fs::symlink_metadata(entry.path()) else {
+            continue;
+        };
+
+        if metadata.is_dir() {
+            collect_dedup_candidates(&entry.path(), min_size, excluded_paths, candidates);
+        } else if metadata.is_file() && metadata.len() > 0 && metadata.len() >= min_size {
+            candidates
+                .entry((metadata.len(), metadata.mode(), metadata.uid(), metadata.gid()))
+                .or_default()
+                .push(entry.path());
+        }
+    }
+}
+
+fn hash_file(path: &Path) -> Result<u64, BuildfsError> {
+    let mut file = std::fs::File::open(path).map_err(BuildfsError::io("Could not open file for hashing"))?;
+    let mut hasher = DefaultHasher::new();
+    let mut buffer = [0u8; 64 * 1024];
+
+    loop {
+        let read = file
+            .read(&mut buffer)
+            .map_err(BuildfsError::io("Could not read file for hashing"))?;
+        if read == 0 {
+            break;
+        }
+        hasher.write(&buffer[..read]);
+    }
+
+    Ok(hasher.finish())
+}
+
+fn contents_equal(first_path: &Path, second_path: &Path) -> Result<bool, BuildfsError> {
+    let open = |path| std::fs::File::open(path).map_err(BuildfsError::io("Could not open file for comparison"));
+    let (mut first_file, mut second_file) = (open(first_path)?, open(second_path)?);
+    let mut first_buffer = [0u8; 64 * 1024];
+    let mut second_buffer = [0u8; 64 * 1024];
+
+    loop {
+        let first_read = first_file
+            .read(&mut first_buffer)
+            .map_err(BuildfsError::io("Could not read file for comparison"))?;
+        if first_read == 0 {
+            return Ok(true);
+        }
+
+        if second_file.read_exact(&mut second_buffer[..first_read]).is_err()
+            || first_buffer[..first_read] != second_buffer[..first_read]
+        {
+            return Ok(false);
+        }
+    }
+}
+
+fn remove_dir_contents(dir_path: &Path, keep_prefixes: &[&str]) -> Result<u64, BuildfsError> {
+    let Ok(read_dir) = std::fs::read_dir(dir_path) else {
+        return Ok(0);
+    };
+    let mut removed_bytes = 0;
+
+    for entry in read_dir {
+        let entry = entry.map_err(BuildfsError::io("Could not read directory entry while minimizing"))?;
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        if keep_prefixes.iter().any(|prefix| file_name.starts_with(prefix)) {
+            continue;
+        }
+
+        let entry_path = entry.path();
+        removed_bytes += get_tree_size(&entry_path);
+
+        let file_type = entry
+            .file_type()
+            .map_err(BuildfsError::io("Could not inspect directory entry type"))?;
+        if file_type.is_dir() {
+            std::fs::remove_dir_all(&entry_path)
+                .map_err(BuildfsError::io("Could not remove directory while minimizing"))?;
+        } else {
+            std::fs::remove_file(&entry_path).map_err(BuildfsError::io("Could not remove file while minimizing"))?;
+        }
+    }
+
+    Ok(removed_bytes)
+}
+
+fn collect_elf_paths(dir_path: &Path, elf_paths: &mut Vec<PathBuf>) {
+    let Ok(read_dir) = std::fs::read_dir(dir_path) else {
+        return;
+    };
+
+    for entry in read_dir.flatten() {
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+
+        if file_type.is_dir() {
+            collect_elf_paths(&entry.path(), elf_paths);
+        } else if file_type.is_file() {
+            let mut magic = [0u8; 4];
+            let is_elf = std::fs::File::open(entry.path())
+                .and_then(|mut file| file.read_exact(&mut magic))
+                .is_ok()
+                && magic == ELF_MAGIC;
+
+            if is_elf {
+                elf_paths.push(entry.path());
+            }
+        }
+    }
+}
+
+pub fn get_tree_size(path: &Path) -> u64 {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return 0;
+    };
+
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+
+    std::fs::read_dir(path)
+        .map(|read_dir| read_dir.flatten().map(|entry| get_tree_size(&entry.path())).sum())
+        .unwrap_or(0)
+}
@@ -1,52 +1,86 @@
-use std::{collections::HashMap, fs::File, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::File,
+    io::{Read, Write},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use flate2::Compression;
-use tokio::task::JoinSet;
+use ignore::gitignore::Gitignore;
+use tar::EntryType;
+use uuid::Uuid;
 
-use crate::{schema::BuildScript, PackArgs, PackageType, UnpackArgs};
+use crate::{
+    cleanup::{register_path, release_path},
+    config::Config,
+    dry_run::AdjoinAbsolute,
+    error::BuildfsError,
+    extract::{extract_tar, ExtractOptions},
+    library::{get_library_package_path, LIBRARY_FILENAME},
+    package_ignore::{is_ignored, load_package_ignore, prune_ignored, IGNORE_FILENAME},
+    payload::encode_payload,
+    scheduler::JobSet,
+    schema::{try_parse_build_script, BuildScriptFormat},
+    PackArgs, PackageType, UnpackArgs,
+};
 
 pub static BUILD_SCRIPT_FILENAME: &'static str = "build.toml";
+static BUILD_SCRIPT_FILENAMES: [&str; 4] = [BUILD_SCRIPT_FILENAME, "build.yaml", "build.yml", "build.json"];
+static COMPARE_BUFFER_SIZE: usize = 64 * 1024;
 
-pub async fn get_package_type(path: &PathBuf) -> PackageType {
+pub async fn get_package_type(path: &PathBuf) -> Result<PackageType, BuildfsError> {
     let package_type = {
         let metadata = tokio::fs::metadata(path)
             .await
-            .expect("Could not inspect file/directory metadata");
+            .map_err(BuildfsError::io("Could not inspect file/directory metadata"))?;
         if metadata.is_dir() {
-            return PackageType::Directory;
+            return Ok(PackageType::Directory);
         }
 
-        let extension = path.extension().expect("File has no extension").to_string_lossy();
+        let extension = path
+            .extension()
+            .ok_or_else(|| BuildfsError::InvalidArguments(format!("Package {path:?} has no file extension")))?
+            .to_string_lossy();
         match extension.to_string().as_str() {
-            "toml" => PackageType::BuildScript,
+            "toml" | "yaml" | "yml" | "json" => PackageType::BuildScript,
             "tar" => PackageType::Tar,
             "tar.gz" => PackageType::TarGz,
             _ => {
-                panic!("File extension {extension} is not recognizable as a type of package");
+                return Err(BuildfsError::InvalidArguments(format!(
+                    "File extension {extension} is not recognizable as a type of package"
+                )));
             }
         }
     };
     log::info!("Detected package type of {path:?} to be {package_type}");
 
-    package_type
+    Ok(package_type)
 }
 
-pub async fn unpack_command(unpack_args: UnpackArgs) {
-    let package_type = get_package_type(&unpack_args.source_path).await;
+pub async fn unpack_command(unpack_args: UnpackArgs) -> Result<(), BuildfsError> {
+    let package_type = get_package_type(&unpack_args.source_path).await?;
     tokio::fs::create_dir_all(&unpack_args.destination_path)
         .await
-        .expect("Could not ensure that the destination directory exists");
+        .map_err(BuildfsError::io(
+            "Could not ensure that the destination directory exists",
+        ))?;
 
+    // packages are untrusted input, so nothing they contain may point outside of the unpacked directory
+    let extract_options = ExtractOptions {
+        confine_symlinks: true,
+        same_filesystem: unpack_args.same_filesystem,
+    };
     tokio::task::spawn_blocking(move || {
-        let file = File::open(&unpack_args.source_path).expect("Could not open source file representing the package");
+        let file = File::open(&unpack_args.source_path)
+            .map_err(BuildfsError::io("Could not open source file representing the package"))?;
 
         match package_type {
             PackageType::TarGz => {
                 let gz_decoder = flate2::read::GzDecoder::new(file);
-                let mut archive = tar::Archive::new(gz_decoder);
-                archive
-                    .unpack(&unpack_args.destination_path)
-                    .expect("Extracting package tarball failed");
+                extract_tar(gz_decoder, &unpack_args.destination_path, extract_options)
+                    .map_err(BuildfsError::io("Extracting package tarball failed"))?;
                 log::info!(
                     "Extraction of {:?} into {:?} finished",
                     unpack_args.source_path,
@@ -54,10 +88,8 @@ pub async fn unpack_command(unpack_args: UnpackArgs) {
                 );
             }
             PackageType::Tar => {
-                let mut archive = tar::Archive::new(file);
-                archive
-                    .unpack(&unpack_args.destination_path)
-                    .expect("Extracting package tar failed");
+                extract_tar(file, &unpack_args.destination_path, extract_options)
+                    .map_err(BuildfsError::io("Extracting package tar failed"))?;
                 log::info!(
                     "Extraction of {:?} into {:?} finished",
                     unpack_args.source_path,
@@ -68,95 +100,785 @@ pub async fn unpack_command(unpack_args: UnpackArgs) {
                 log::warn!("Tried to unpack a package of type {package_type}, which cannot be unpacked");
             }
         }
+        Ok(())
     })
     .await
-    .expect("Join on blocking task failed");
+    .expect("Join on blocking task failed")
 }
 
-pub async fn pack_command(pack_args: PackArgs) {
+pub async fn pack_command(pack_args: PackArgs, config: &Config) -> Result<(), BuildfsError> {
+    if pack_args.update {
+        return update_package(pack_args, config).await;
+    }
+    if !pack_args.force
+        && tokio::fs::try_exists(&pack_args.destination_path)
+            .await
+            .unwrap_or(false)
+    {
+        return Err(BuildfsError::InvalidArguments(format!(
+            "{:?} already exists, pass --force to overwrite it",
+            pack_args.destination_path
+        )));
+    }
+
     if let PackageType::BuildScript = pack_args.package_type {
         tokio::fs::copy(pack_args.source_path, pack_args.destination_path)
             .await
-            .expect("Could not copy build script to its destination path");
-        return;
+            .map_err(BuildfsError::io("Could not copy build script to its destination path"))?;
+        return Ok(());
     }
 
-    tokio::fs::create_dir_all(&pack_args.destination_path)
-        .await
-        .expect("Could not ensure destination path exists as a directory");
-
     let source_parent_path = pack_args
         .source_path
         .parent()
-        .expect("Source path has no parent for lookup");
+        .ok_or_else(|| BuildfsError::InvalidArguments("The source path has no parent directory".to_string()))?
+        .to_path_buf();
+    let package_ignore = Arc::new(load_package_ignore(&source_parent_path)?);
 
-    let build_script_json = tokio::fs::read_to_string(&pack_args.source_path)
+    let build_script_format = BuildScriptFormat::from_path(&pack_args.source_path).unwrap_or_default();
+    let build_script_text = tokio::fs::read_to_string(&pack_args.source_path)
         .await
-        .expect("Could not read source build script");
-    let build_script = toml::from_str::<BuildScript>(&build_script_json)
-        .expect("Could not decode the given build script file from TOML");
-    let mut paths = HashMap::with_capacity(1);
+        .map_err(BuildfsError::io("Could not read source build script"))?;
+    let build_script = try_parse_build_script(&build_script_text, build_script_format, &config.variable_overrides)
+        .map_err(BuildfsError::Validation)?;
+    // paths inside the package are mapped to the paths on the host they're taken from
+    let mut paths = BTreeMap::new();
     paths.insert(
+        PathBuf::from(build_script_format.get_build_script_filename()),
         pack_args.source_path.clone(),
-        pack_args.destination_path.join(BUILD_SCRIPT_FILENAME),
     );
 
-    for command in build_script.commands {
-        if let Some(script_path) = command.script_path {
-            paths.insert(
-                source_parent_path.join(&script_path),
-                pack_args.destination_path.join(&script_path),
-            );
+    for script_path in build_script
+        .commands
+        .iter()
+        .filter_map(|command| command.script_path.as_ref())
+    {
+        insert_package_path(&mut paths, &source_parent_path, script_path, &package_ignore)?;
+    }
+
+    let encrypted_payloads = build_script
+        .overlays
+        .iter()
+        .filter(|overlay| overlay.payload.encrypted)
+        .count();
+    if encrypted_payloads > 0 && pack_args.age_recipients.is_empty() {
+        return Err(BuildfsError::InvalidArguments(format!(
+            "{encrypted_payloads} overlay payload(s) are marked as encrypted, but no --age-recipient was given"
+        )));
+    }
+
+    // encoded payloads are compressed and encrypted on their way into the package and never sit there in plaintext
+    let encoded_path = PathBuf::from(format!("/tmp/{}", Uuid::new_v4()));
+    register_path(&encoded_path);
+    for overlay in build_script
+        .overlays
+        .iter()
+        .filter(|overlay| overlay.payload.is_encoded())
+    {
+        let source_path = overlay.source.as_ref().ok_or_else(|| {
+            BuildfsError::Validation("Could not encode an overlay payload without a source path".to_string())
+        })?;
+        if is_ignored(&package_ignore, source_path, false) {
+            return Err(BuildfsError::Validation(format!(
+                "{source_path:?} is referenced by the build script, but excluded by {IGNORE_FILENAME}"
+            )));
         }
+        let package_path = get_package_path(source_path);
+        let destination_path = encoded_path.join(&package_path);
+        if let Some(parent_path) = destination_path.parent() {
+            tokio::fs::create_dir_all(parent_path).await.map_err(BuildfsError::io(
+                "Could not create parent directory of an encoded payload",
+            ))?;
+        }
+        encode_payload(
+            &overlay.payload,
+            &source_parent_path.adjoin_absolute(source_path),
+            &destination_path,
+            &pack_args.age_recipients,
+            &config.tools,
+        )
+        .await?;
+        paths.insert(package_path, destination_path);
+        log::info!("Encoded overlay payload {source_path:?} into the package");
     }
 
     for source_path in build_script
         .overlays
         .iter()
+        .filter(|overlay| !overlay.payload.is_encoded())
         .filter_map(|overlay| overlay.source.as_ref())
+        .chain(
+            build_script
+                .first_boot
+                .iter()
+                .filter_map(|first_boot| first_boot.script_path.as_ref()),
+        )
+        .chain(build_script.guest.agent.as_ref().map(|agent| &agent.source))
     {
-        paths.insert(
-            source_parent_path.join(source_path),
-            pack_args.destination_path.join(source_path),
-        );
+        insert_package_path(&mut paths, &source_parent_path, source_path, &package_ignore)?;
+    }
+
+    // local libraries are already spliced into the build script at run time, but have to travel with the package
+    for library_path in build_script.uses.iter().filter_map(get_library_package_path) {
+        let library_path = match source_parent_path.adjoin_absolute(&library_path).is_dir() {
+            true => library_path.join(LIBRARY_FILENAME),
+            false => library_path,
+        };
+        insert_package_path(&mut paths, &source_parent_path, &library_path, &package_ignore)?;
+    }
+
+    let result = match pack_args.package_type {
+        PackageType::Directory => copy_package(&pack_args.destination_path, paths, package_ignore).await,
+        package_type => {
+            let destination_path = pack_args.destination_path.clone();
+            tokio::task::spawn_blocking(move || {
+                write_package_archive(
+                    &destination_path,
+                    package_type,
+                    &source_parent_path,
+                    &paths,
+                    &package_ignore,
+                )
+                .map_err(|err| {
+                    // a half-written archive is never a usable package, so it isn't left behind
+                    let _ = std::fs::remove_file(&destination_path);
+                    BuildfsError::io("Could not write package archive")(err)
+                })
+            })
+            .await
+            .expect("Join on blocking task failed")
+        }
+    };
+
+    if tokio::fs::try_exists(&encoded_path).await.unwrap_or(false) {
+        tokio::fs::remove_dir_all(&encoded_path)
+            .await
+            .map_err(BuildfsError::io("Could not remove temporary encoded payloads"))?;
     }
+    release_path(&encoded_path);
+    result
+}
 
-    let mut copy_join_set = JoinSet::new();
-    for (src_path, dst_path) in paths {
-        copy_join_set.spawn_blocking(move || std::fs::copy(src_path, dst_path));
+fn get_package_path(reference_path: &Path) -> PathBuf {
+    reference_path.strip_prefix("/").unwrap_or(reference_path).to_path_buf()
+}
+
+fn insert_package_path(
+    paths: &mut BTreeMap<PathBuf, PathBuf>,
+    source_parent_path: &PathBuf,
+    reference_path: &Path,
+    package_ignore: &Gitignore,
+) -> Result<(), BuildfsError> {
+    let source_path = source_parent_path.adjoin_absolute(reference_path);
+    if is_ignored(package_ignore, reference_path, source_path.is_dir()) {
+        return Err(BuildfsError::Validation(format!(
+            "{reference_path:?} is referenced by the build script, but excluded by {IGNORE_FILENAME}"
+        )));
     }
+    paths.insert(get_package_path(reference_path), source_path);
+    Ok(())
+}
 
-    while let Some(result) = copy_join_set.join_next().await {
-        result
-            .expect("Joining on copy blocking task failed")
-            .expect("Copy blocking task failed");
+async fn copy_package(
+    destination_path: &Path,
+    paths: BTreeMap<PathBuf, PathBuf>,
+    package_ignore: Arc<Gitignore>,
+) -> Result<(), BuildfsError> {
+    // the package is copied next to its destination and swapped in, so that an overwritten package keeps no stale files
+    let mut tmp_package_path = destination_path.to_path_buf();
+    tmp_package_path.as_mut_os_string().push("_tmp");
+    if tokio::fs::try_exists(&tmp_package_path).await.unwrap_or(false) {
+        tokio::fs::remove_dir_all(&tmp_package_path)
+            .await
+            .map_err(BuildfsError::io(
+                "Could not remove leftover temporary package directory",
+            ))?;
     }
+    tokio::fs::create_dir_all(&tmp_package_path)
+        .await
+        .map_err(BuildfsError::io("Could not create temporary package directory"))?;
 
-    if let PackageType::Directory = pack_args.package_type {
-        return;
+    let mut copy_job_set = JobSet::new();
+    for (package_path, src_path) in paths {
+        let package_ignore = package_ignore.clone();
+        let dst_path = tmp_package_path.join(&package_path);
+        copy_job_set.spawn_blocking(move || copy_package_path(&src_path, &dst_path, &package_path, &package_ignore));
     }
 
-    tokio::task::spawn_blocking(move || {
-        let mut tmp_destination_path = pack_args.destination_path.clone();
-        tmp_destination_path.as_mut_os_string().push("_tmp");
-        std::fs::rename(&pack_args.destination_path, &tmp_destination_path)
-            .expect("Could not rename destination directory to a temporary one");
-
-        let file = File::create(pack_args.destination_path).expect("Could not create destination file");
-
-        if let PackageType::TarGz = pack_args.package_type {
-            let gz_encoder = flate2::write::GzEncoder::new(file, Compression::best());
-            let mut tar = tar::Builder::new(gz_encoder);
-            tar.append_dir_all(".", &tmp_destination_path)
-                .expect("Could not insert into tarball");
-        } else {
-            let mut tar = tar::Builder::new(file);
-            tar.append_dir_all(".", &tmp_destination_path)
-                .expect("Could not insert into tar");
+    let mut result = Ok(());
+    while let Some(copy_result) = copy_job_set.join_next().await {
+        if let (Err(err), Ok(())) = (copy_result.expect("Joining on copy blocking task failed"), &result) {
+            result = Err(BuildfsError::io("Copy blocking task failed")(err));
+        }
+    }
+    if result.is_ok() {
+        result = replace_package_directory(destination_path, &tmp_package_path).await;
+    }
+    if result.is_err() {
+        let _ = tokio::fs::remove_dir_all(&tmp_package_path).await;
+    }
+    result
+}
+
+async fn replace_package_directory(destination_path: &Path, tmp_package_path: &Path) -> Result<(), BuildfsError> {
+    match tokio::fs::symlink_metadata(destination_path).await {
+        Ok(metadata) if metadata.is_dir() => tokio::fs::remove_dir_all(destination_path)
+            .await
+            .map_err(BuildfsError::io("Could not remove the package being overwritten"))?,
+        Ok(_) => tokio::fs::remove_file(destination_path)
+            .await
+            .map_err(BuildfsError::io("Could not remove the package being overwritten"))?,
+        Err(_) => {}
+    }
+    tokio::fs::rename(tmp_package_path, destination_path)
+        .await
+        .map_err(BuildfsError::io("Could not move the package into its destination path"))
+}
+
+fn write_package_archive(
+    destination_path: &Path,
+    package_type: PackageType,
+    source_parent_path: &Path,
+    paths: &BTreeMap<PathBuf, PathBuf>,
+    package_ignore: &Gitignore,
+) -> std::io::Result<()> {
+    let file = File::create(destination_path)?;
+    if let PackageType::TarGz = package_type {
+        let mut tar = tar::Builder::new(flate2::write::GzEncoder::new(file, Compression::best()));
+        append_package_paths(&mut tar, source_parent_path, paths, package_ignore)?;
+        tar.into_inner()?.finish()?;
+    } else {
+        let mut tar = tar::Builder::new(file);
+        append_package_paths(&mut tar, source_parent_path, paths, package_ignore)?;
+        tar.into_inner()?;
+    }
+    Ok(())
+}
+
+fn append_package_paths<W: Write>(
+    tar: &mut tar::Builder<W>,
+    source_parent_path: &Path,
+    paths: &BTreeMap<PathBuf, PathBuf>,
+    package_ignore: &Gitignore,
+) -> std::io::Result<()> {
+    let mut appended_directories = HashSet::new();
+    for (package_path, source_path) in paths {
+        // parent directories get entries of their own, so they keep the modes they have in the source tree
+        for ancestor_path in package_path.ancestors().skip(1).collect::<Vec<_>>().into_iter().rev() {
+            if !ancestor_path.as_os_str().is_empty() && appended_directories.insert(ancestor_path.to_path_buf()) {
+                tar.append_path_with_name(source_parent_path.join(ancestor_path), ancestor_path)?;
+            }
+        }
+        append_package_path(
+            tar,
+            source_path,
+            package_path,
+            package_ignore,
+            &mut appended_directories,
+        )?;
+    }
+    Ok(())
+}
+
+fn append_package_path<W: Write>(
+    tar: &mut tar::Builder<W>,
+    source_path: &Path,
+    package_path: &Path,
+    package_ignore: &Gitignore,
+    appended_directories: &mut HashSet<PathBuf>,
+) -> std::io::Result<()> {
+    if !source_path.is_dir() {
+        return tar.append_path_with_name(source_path, package_path);
+    }
+    if appended_directories.insert(package_path.to_path_buf()) {
+        tar.append_path_with_name(source_path, package_path)?;
+    }
+
+    let mut entries = std::fs::read_dir(source_path)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let entry_package_path = package_path.join(entry.file_name());
+        if !is_ignored(package_ignore, &entry_package_path, entry.path().is_dir()) {
+            append_package_path(
+                tar,
+                &entry.path(),
+                &entry_package_path,
+                package_ignore,
+                appended_directories,
+            )?;
         }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+struct PackageUpdate {
+    kept: usize,
+    replaced: usize,
+    added: usize,
+    removed: usize,
+}
+
+async fn update_package(pack_args: PackArgs, config: &Config) -> Result<(), BuildfsError> {
+    if !matches!(pack_args.package_type, PackageType::Tar | PackageType::TarGz) {
+        return Err(BuildfsError::InvalidArguments(format!(
+            "Only tar and tar.gz packages can be updated in place, not {} packages",
+            pack_args.package_type
+        )));
+    }
+    if !tokio::fs::try_exists(&pack_args.destination_path)
+        .await
+        .unwrap_or(false)
+    {
+        return Err(BuildfsError::InvalidArguments(format!(
+            "{:?} doesn't exist, so there is no package to update",
+            pack_args.destination_path
+        )));
+    }
+
+    // the package is staged like a directory package first, so that both share the same collection of paths
+    let staging_path = PathBuf::from(format!("/tmp/{}", Uuid::new_v4()));
+    Box::pin(pack_command(
+        PackArgs {
+            destination_path: staging_path.clone(),
+            package_type: PackageType::Directory,
+            update: false,
+            ..pack_args.clone()
+        },
+        config,
+    ))
+    .await?;
 
-        std::fs::remove_dir_all(tmp_destination_path).expect("Could not remove temporary destination");
+    let package_path = pack_args.destination_path.clone();
+    let blocking_staging_path = staging_path.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        let mut tmp_package_path = package_path.clone();
+        tmp_package_path.as_mut_os_string().push("_tmp");
+        let package_update = rewrite_package(
+            &package_path,
+            &tmp_package_path,
+            &blocking_staging_path,
+            pack_args.package_type,
+        )
+        .map_err(BuildfsError::io("Could not rewrite package with its changed entries"))?;
+        std::fs::rename(&tmp_package_path, &package_path)
+            .map_err(BuildfsError::io("Could not replace package with its updated version"))?;
+        Ok::<_, BuildfsError>(package_update)
     })
     .await
-    .expect("Could not join on blocking task");
+    .expect("Join on blocking task failed");
+
+    tokio::fs::remove_dir_all(&staging_path)
+        .await
+        .map_err(BuildfsError::io("Could not remove temporary staging directory"))?;
+    let package_update = result?;
+    log::info!(
+        "Updated package {:?}: {} entries unchanged, {} replaced, {} added and {} removed",
+        pack_args.destination_path,
+        package_update.kept,
+        package_update.replaced,
+        package_update.added,
+        package_update.removed
+    );
+    Ok(())
+}
+
+fn rewrite_package(
+    package_path: &Path,
+    tmp_package_path: &Path,
+    staging_path: &Path,
+    package_type: PackageType,
+) -> std::io::Result<PackageUpdate> {
+    let package_file = File::open(package_path)?;
+    let tmp_package_file = File::create(tmp_package_path)?;
+
+    if let PackageType::TarGz = package_type {
+        let mut tar = tar::Builder::new(flate2::write::GzEncoder::new(tmp_package_file, Compression::best()));
+        let package_update = rewrite_entries(flate2::read::GzDecoder::new(package_file), &mut tar, staging_path)?;
+        tar.into_inner()?.finish()?;
+        Ok(package_update)
+    } else {
+        let mut tar = tar::Builder::new(tmp_package_file);
+        let package_update = rewrite_entries(package_file, &mut tar, staging_path)?;
+        tar.into_inner()?;
+        Ok(package_update)
+    }
+}
+
+fn rewrite_entries<R: Read, W: Write>(
+    reader: R,
+    tar: &mut tar::Builder<W>,
+    staging_path: &Path,
+) -> std::io::Result<PackageUpdate> {
+    let mut staged_paths = BTreeMap::new();
+    collect_staged_paths(staging_path, staging_path, &mut staged_paths)?;
+    let mut package_update = PackageUpdate::default();
+    let mut archive = tar::Archive::new(reader);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_path = entry.path()?.into_owned();
+        let relative_path = entry_path.strip_prefix(".").unwrap_or(&entry_path).to_path_buf();
+        let mut header = entry.header().clone();
+        if relative_path.as_os_str().is_empty() {
+            tar.append_data(&mut header, &entry_path, std::io::empty())?;
+            continue;
+        }
+
+        let Some(staged_path) = staged_paths.remove(&relative_path) else {
+            package_update.removed += 1;
+            continue;
+        };
+        let metadata = std::fs::symlink_metadata(&staged_path)?;
+        let unchanged = match header.entry_type() {
+            EntryType::Directory => metadata.is_dir(),
+            EntryType::Regular => {
+                metadata.is_file()
+                    && header.mode()? & 0o7777 == metadata.mode() & 0o7777
+                    && entry.size() == metadata.len()
+                    && has_same_contents(&mut entry, &staged_path, metadata.len())?
+            }
+            _ => false,
+        };
+
+        if !unchanged {
+            tar.append_path_with_name(&staged_path, &entry_path)?;
+            package_update.replaced += 1;
+        } else if metadata.is_dir() {
+            tar.append_data(&mut header, &entry_path, std::io::empty())?;
+            package_update.kept += 1;
+        } else {
+            // the staged copy is byte-for-byte identical, so it provides the data under the old entry's header
+            tar.append_data(&mut header, &entry_path, File::open(&staged_path)?)?;
+            package_update.kept += 1;
+        }
+    }
+
+    for (relative_path, staged_path) in staged_paths {
+        tar.append_path_with_name(&staged_path, relative_path)?;
+        package_update.added += 1;
+    }
+    Ok(package_update)
+}
+
+fn collect_staged_paths(
+    staging_path: &Path,
+    dir_path: &Path,
+    staged_paths: &mut BTreeMap<PathBuf, PathBuf>,
+) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir_path)? {
+        let entry_path = entry?.path();
+        let relative_path = entry_path
+            .strip_prefix(staging_path)
+            .expect("Staged path is not inside the staging directory")
+            .to_path_buf();
+        if entry_path.is_dir() {
+            collect_staged_paths(staging_path, &entry_path, staged_paths)?;
+        }
+        staged_paths.insert(relative_path, entry_path);
+    }
+    Ok(())
+}
+
+fn has_same_contents<R: Read>(mut reader: R, path: &Path, size: u64) -> std::io::Result<bool> {
+    let mut file = File::open(path)?;
+    let mut entry_buffer = vec![0u8; COMPARE_BUFFER_SIZE];
+    let mut file_buffer = vec![0u8; COMPARE_BUFFER_SIZE];
+    let mut remaining = size;
+
+    while remaining > 0 {
+        let chunk_size = remaining.min(COMPARE_BUFFER_SIZE as u64) as usize;
+        reader.read_exact(&mut entry_buffer[..chunk_size])?;
+        file.read_exact(&mut file_buffer[..chunk_size])?;
+        if entry_buffer[..chunk_size] != file_buffer[..chunk_size] {
+            return Ok(false);
+        }
+        remaining -= chunk_size as u64;
+    }
+    Ok(true)
+}
+
+pub fn find_build_script(package_path: &Path) -> PathBuf {
+    // the first build script found wins, and a missing one is reported as build.toml when it's read
+    BUILD_SCRIPT_FILENAMES
+        .iter()
+        .map(|filename| package_path.join(filename))
+        .find(|build_script_path| build_script_path.is_file())
+        .unwrap_or_else(|| package_path.join(BUILD_SCRIPT_FILENAME))
+}
+
+fn copy_package_path(
+    src_path: &Path,
+    dst_path: &Path,
+    reference_path: &Path,
+    package_ignore: &Gitignore,
+) -> std::io::Result<()> {
+    let parent_path = dst_path.parent().expect("Packaged path has no parent");
+    std::fs::create_dir_all(parent_path)?;
+    if !src_path.is_dir() {
+        return std::fs::copy(src_path, dst_path).map(|_| ());
+    }
+
+    // directories land inside the parent under their own name, after which anything ignored is pruned again
+    fs_extra::dir::copy(src_path, parent_path, &fs_extra::dir::CopyOptions::default())
+        .map_err(|err| std::io::Error::other(err.to_string()))?;
+    let pruned = prune_ignored(package_ignore, reference_path, dst_path)?;
+    if pruned > 0 {
+        log::info!("Left {pruned} path(s) excluded by {IGNORE_FILENAME} out of {reference_path:?}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, io::Read, os::unix::fs::PermissionsExt, path::PathBuf};
+
+    use uuid::Uuid;
+
+    use crate::{config::Config, PackArgs, PackageType, UnpackArgs};
+
+    use super::{get_package_type, pack_command, rewrite_entries, unpack_command, BUILD_SCRIPT_FILENAME};
+
+    static BUILD_SCRIPT: &str =
+        "schema_version = 1\n[filesystem]\nsize_mib = 64\n[container]\nimage = { name = \"debian\", tag = \"bookworm\" }\n";
+
+    fn get_tmp_path() -> PathBuf {
+        PathBuf::from(format!("/tmp/{}", Uuid::new_v4()))
+    }
+
+    #[tokio::test]
+    async fn tar_package_round_trips() {
+        let work_path = get_tmp_path();
+        tokio::fs::create_dir_all(&work_path).await.unwrap();
+        let source_path = work_path.join("script.toml");
+        tokio::fs::write(&source_path, BUILD_SCRIPT).await.unwrap();
+
+        let package_path = work_path.join("package.tar");
+        let pack_args = PackArgs {
+            source_path,
+            destination_path: package_path.clone(),
+            package_type: PackageType::Tar,
+            age_recipients: Vec::new(),
+            update: false,
+            force: false,
+        };
+        pack_command(pack_args.clone(), &Config::default()).await.unwrap();
+        assert!(matches!(
+            get_package_type(&package_path).await.unwrap(),
+            PackageType::Tar
+        ));
+        assert!(pack_command(pack_args.clone(), &Config::default()).await.is_err());
+        pack_command(
+            PackArgs {
+                force: true,
+                ..pack_args
+            },
+            &Config::default(),
+        )
+        .await
+        .unwrap();
+
+        let unpack_path = work_path.join("unpacked");
+        unpack_command(UnpackArgs {
+            source_path: package_path,
+            destination_path: unpack_path.clone(),
+            same_filesystem: true,
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            tokio::fs::read_to_string(unpack_path.join(BUILD_SCRIPT_FILENAME))
+                .await
+                .unwrap(),
+            BUILD_SCRIPT
+        );
+
+        tokio::fs::remove_dir_all(work_path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn variable_overrides_apply_when_packing() {
+        let work_path = get_tmp_path();
+        tokio::fs::create_dir_all(&work_path).await.unwrap();
+        let source_path = work_path.join("script.toml");
+        tokio::fs::write(
+            &source_path,
+            format!(
+                "{BUILD_SCRIPT}[variables]\nSCRIPT = \"missing\"\n[[commands]]\nscript_path = \"/${{SCRIPT}}.sh\"\n"
+            ),
+        )
+        .await
+        .unwrap();
+        tokio::fs::write(work_path.join("setup.sh"), "true").await.unwrap();
+
+        let package_path = work_path.join("package");
+        let config = Config {
+            variable_overrides: HashMap::from([("SCRIPT".to_string(), "setup".to_string())]),
+            ..Default::default()
+        };
+        pack_command(
+            PackArgs {
+                source_path,
+                destination_path: package_path.clone(),
+                package_type: PackageType::Directory,
+                age_recipients: Vec::new(),
+                update: false,
+                force: false,
+            },
+            &config,
+        )
+        .await
+        .unwrap();
+
+        assert!(package_path.join("setup.sh").is_file());
+        tokio::fs::remove_dir_all(work_path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn directory_package_contains_build_script() {
+        let work_path = get_tmp_path();
+        tokio::fs::create_dir_all(&work_path).await.unwrap();
+        let source_path = work_path.join("script.toml");
+        tokio::fs::write(&source_path, BUILD_SCRIPT).await.unwrap();
+
+        let package_path = work_path.join("package");
+        let pack_args = PackArgs {
+            source_path,
+            destination_path: package_path.clone(),
+            package_type: PackageType::Directory,
+            age_recipients: Vec::new(),
+            update: false,
+            force: false,
+        };
+        pack_command(pack_args.clone(), &Config::default()).await.unwrap();
+
+        assert!(matches!(
+            get_package_type(&package_path).await.unwrap(),
+            PackageType::Directory
+        ));
+        assert_eq!(
+            tokio::fs::read_to_string(package_path.join(BUILD_SCRIPT_FILENAME))
+                .await
+                .unwrap(),
+            BUILD_SCRIPT
+        );
+
+        tokio::fs::write(package_path.join("stale.sh"), "true").await.unwrap();
+        pack_command(
+            PackArgs {
+                force: true,
+                ..pack_args
+            },
+            &Config::default(),
+        )
+        .await
+        .unwrap();
+        assert!(package_path.join(BUILD_SCRIPT_FILENAME).exists());
+        assert!(!package_path.join("stale.sh").exists());
+
+        tokio::fs::remove_dir_all(work_path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn ignored_paths_are_left_out_of_packages() {
+        let work_path = get_tmp_path();
+        tokio::fs::create_dir_all(work_path.join("rootfs/etc")).await.unwrap();
+        tokio::fs::write(work_path.join("rootfs/etc/motd"), "hi").await.unwrap();
+        tokio::fs::write(work_path.join("rootfs/etc/motd.swp"), "")
+            .await
+            .unwrap();
+        tokio::fs::write(work_path.join(".buildfsignore"), "*.swp\n")
+            .await
+            .unwrap();
+        let source_path = work_path.join("script.toml");
+        let pack = |source_path: PathBuf, destination_path: PathBuf| async move {
+            pack_command(
+                PackArgs {
+                    source_path,
+                    destination_path,
+                    package_type: PackageType::Directory,
+                    age_recipients: Vec::new(),
+                    update: false,
+                    force: false,
+                },
+                &Config::default(),
+            )
+            .await
+        };
+
+        tokio::fs::write(
+            &source_path,
+            format!("{BUILD_SCRIPT}[[overlays]]\nsource = \"/rootfs\"\ndestination = \"/\"\nis_directory = true\n"),
+        )
+        .await
+        .unwrap();
+        let package_path = work_path.join("package");
+        pack(source_path.clone(), package_path.clone()).await.unwrap();
+        assert!(package_path.join("rootfs/etc/motd").exists());
+        assert!(!package_path.join("rootfs/etc/motd.swp").exists());
+
+        tokio::fs::write(
+            &source_path,
+            format!("{BUILD_SCRIPT}[[overlays]]\nsource = \"/rootfs/etc/motd.swp\"\ndestination = \"/etc/motd\"\n"),
+        )
+        .await
+        .unwrap();
+        assert!(pack(source_path, work_path.join("other")).await.is_err());
+
+        tokio::fs::remove_dir_all(work_path).await.unwrap();
+    }
+
+    #[test]
+    fn package_update_keeps_unchanged_entries() {
+        let staging_path = get_tmp_path();
+        std::fs::create_dir_all(&staging_path).unwrap();
+        for (name, contents) in [("kept", "same"), ("replaced", "new"), ("added", "new")] {
+            std::fs::write(staging_path.join(name), contents).unwrap();
+            std::fs::set_permissions(staging_path.join(name), std::fs::Permissions::from_mode(0o644)).unwrap();
+        }
+
+        let mut old_tar = tar::Builder::new(Vec::new());
+        for (name, contents) in [("./kept", "same"), ("./replaced", "old"), ("./removed", "old")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(1);
+            old_tar.append_data(&mut header, name, contents.as_bytes()).unwrap();
+        }
+        let old_archive = old_tar.into_inner().unwrap();
+
+        let mut new_tar = tar::Builder::new(Vec::new());
+        let package_update = rewrite_entries(old_archive.as_slice(), &mut new_tar, &staging_path).unwrap();
+        let new_archive = new_tar.into_inner().unwrap();
+        std::fs::remove_dir_all(staging_path).unwrap();
+
+        assert_eq!(
+            (
+                package_update.kept,
+                package_update.replaced,
+                package_update.added,
+                package_update.removed
+            ),
+            (1, 1, 1, 1)
+        );
+        let mut entries = Vec::new();
+        for entry in tar::Archive::new(new_archive.as_slice()).entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents).unwrap();
+            entries.push((
+                entry.path().unwrap().to_string_lossy().to_string(),
+                entry.header().mtime().unwrap() == 1,
+                contents,
+            ));
+        }
+        assert_eq!(
+            entries,
+            [
+                ("kept".to_string(), true, "same".to_string()),
+                ("replaced".to_string(), false, "new".to_string()),
+                ("added".to_string(), false, "new".to_string()),
+            ]
+        );
+    }
 }
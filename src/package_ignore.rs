@@ -0,0 +1,88 @@
+use std::path::Path;
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+use crate::error::BuildfsError;
+
+pub static IGNORE_FILENAME: &str = ".buildfsignore";
+
+pub fn load_package_ignore(package_path: &Path) -> Result<Gitignore, BuildfsError> {
+    let ignore_path = package_path.join(IGNORE_FILENAME);
+    if !ignore_path.is_file() {
+        return Ok(Gitignore::empty());
+    }
+
+    let mut builder = GitignoreBuilder::new(package_path);
+    if let Some(err) = builder.add(&ignore_path) {
+        return Err(BuildfsError::Validation(format!(
+            "{IGNORE_FILENAME} could not be read: {err}"
+        )));
+    }
+    let gitignore = builder
+        .build()
+        .map_err(|err| BuildfsError::Validation(format!("{IGNORE_FILENAME} could not be parsed: {err}")))?;
+    log::debug!(
+        "Loaded {} ignore pattern(s) from {ignore_path:?}",
+        gitignore.num_ignores()
+    );
+    Ok(gitignore)
+}
+
+pub fn is_ignored(gitignore: &Gitignore, reference_path: &Path, is_dir: bool) -> bool {
+    // references are absolute to the package root, while the matcher expects paths relative to it
+    let relative_path = reference_path.strip_prefix("/").unwrap_or(reference_path);
+    !relative_path.as_os_str().is_empty() && gitignore.matched_path_or_any_parents(relative_path, is_dir).is_ignore()
+}
+
+pub fn prune_ignored(gitignore: &Gitignore, reference_path: &Path, copied_path: &Path) -> std::io::Result<usize> {
+    let mut pruned = 0;
+    for entry in std::fs::read_dir(copied_path)? {
+        let entry = entry?;
+        let entry_reference_path = reference_path.join(entry.file_name());
+        let file_type = entry.file_type()?;
+
+        if is_ignored(gitignore, &entry_reference_path, file_type.is_dir()) {
+            match file_type.is_dir() {
+                true => std::fs::remove_dir_all(entry.path())?,
+                false => std::fs::remove_file(entry.path())?,
+            }
+            pruned += 1;
+        } else if file_type.is_dir() {
+            pruned += prune_ignored(gitignore, &entry_reference_path, &entry.path())?;
+        }
+    }
+    Ok(pruned)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use uuid::Uuid;
+
+    use super::{is_ignored, load_package_ignore, prune_ignored, IGNORE_FILENAME};
+
+    #[test]
+    fn ignored_paths_are_matched_and_pruned() {
+        let package_path = PathBuf::from(format!("/tmp/{}", Uuid::new_v4()));
+        std::fs::create_dir_all(package_path.join("rootfs/.git/objects")).unwrap();
+        std::fs::create_dir_all(package_path.join("rootfs/etc")).unwrap();
+        std::fs::write(package_path.join("rootfs/etc/motd"), "hi").unwrap();
+        std::fs::write(package_path.join("rootfs/etc/motd.swp"), "").unwrap();
+        std::fs::write(package_path.join("rootfs/etc/keep.swp"), "").unwrap();
+        std::fs::write(package_path.join(IGNORE_FILENAME), ".git/\n*.swp\n!keep.swp\n").unwrap();
+
+        let gitignore = load_package_ignore(&package_path).unwrap();
+        assert!(is_ignored(&gitignore, Path::new("/rootfs/.git/objects"), true));
+        assert!(is_ignored(&gitignore, Path::new("/rootfs/etc/motd.swp"), false));
+        assert!(!is_ignored(&gitignore, Path::new("/rootfs/etc/motd"), false));
+
+        let pruned = prune_ignored(&gitignore, Path::new("/rootfs"), &package_path.join("rootfs")).unwrap();
+        assert_eq!(pruned, 2);
+        assert!(package_path.join("rootfs/etc/motd").exists());
+        assert!(package_path.join("rootfs/etc/keep.swp").exists());
+        assert!(!package_path.join("rootfs/.git").exists());
+
+        std::fs::remove_dir_all(package_path).unwrap();
+    }
+}
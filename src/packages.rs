@@ -0,0 +1,105 @@
+use crate::{
+    container_engine::ContainerEngine,
+    error::BuildfsError,
+    run::exec_and_collect,
+    schema::{BuildScriptCommand, BuildScriptPackages, PackageManager},
+};
+
+static PACKAGE_MANAGER_PROBES: [(PackageManager, &str); 4] = [
+    (PackageManager::Apt, "apt-get --version"),
+    (PackageManager::Dnf, "dnf --version"),
+    (PackageManager::Apk, "apk --version"),
+    (PackageManager::Zypper, "zypper --version"),
+];
+
+pub async fn get_package_commands(
+    packages: &[BuildScriptPackages],
+    container_engine: &dyn ContainerEngine,
+    container_id: &str,
+    container_name: &str,
+) -> Result<Vec<BuildScriptCommand>, BuildfsError> {
+    if packages.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut detected_package_manager = None;
+    let mut package_commands = Vec::new();
+    for package_set in packages {
+        let package_manager = match package_set.manager {
+            Some(package_manager) => package_manager,
+            None => match detected_package_manager {
+                Some(package_manager) => package_manager,
+                None => {
+                    let package_manager =
+                        detect_package_manager(container_engine, container_id, container_name).await?;
+                    detected_package_manager = Some(package_manager);
+                    package_manager
+                }
+            },
+        };
+
+        package_commands.push(BuildScriptCommand {
+            command: Some(get_install_command(package_manager, &package_set.install)),
+            ..Default::default()
+        });
+    }
+
+    Ok(package_commands)
+}
+
+async fn detect_package_manager(
+    container_engine: &dyn ContainerEngine,
+    container_id: &str,
+    container_name: &str,
+) -> Result<PackageManager, BuildfsError> {
+    for (package_manager, probe_cmd) in PACKAGE_MANAGER_PROBES {
+        let (exit_code, _) = exec_and_collect(container_engine, container_id, container_name, probe_cmd).await?;
+        if exit_code == Some(0) {
+            log::info!("Detected {package_manager:?} as the package manager of the image");
+            return Ok(package_manager);
+        }
+    }
+
+    Err(BuildfsError::Validation(
+        "Could not detect apt, dnf, apk or zypper inside the container, set \"manager\" on [[packages]] explicitly"
+            .to_string(),
+    ))
+}
+
+pub fn get_install_command(package_manager: PackageManager, install: &[String]) -> String {
+    let install = install.join(" ");
+    // every invocation cleans up after itself so that package indices and caches never end up in the image
+    match package_manager {
+        PackageManager::Apt => format!(
+            "apt-get update && DEBIAN_FRONTEND=noninteractive apt-get install -y --no-install-recommends {install} && apt-get clean && rm -rf /var/lib/apt/lists/*"
+        ),
+        PackageManager::Dnf => {
+            format!("dnf install -y --setopt=install_weak_deps=False {install} && dnf clean all")
+        }
+        PackageManager::Apk => format!("apk add --no-cache {install}"),
+        PackageManager::Zypper => {
+            format!("zypper --non-interactive install --no-recommends {install} && zypper clean --all")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schema::PackageManager;
+
+    use super::get_install_command;
+
+    #[test]
+    fn install_commands_are_noninteractive_and_clean_up() {
+        let install = vec!["openssh-server".to_string(), "ca-certificates".to_string()];
+
+        assert_eq!(
+            get_install_command(PackageManager::Apk, &install),
+            "apk add --no-cache openssh-server ca-certificates"
+        );
+        assert_eq!(
+            get_install_command(PackageManager::Dnf, &install),
+            "dnf install -y --setopt=install_weak_deps=False openssh-server ca-certificates && dnf clean all"
+        );
+    }
+}
@@ -0,0 +1,253 @@
+use std::path::{Path, PathBuf};
+
+use uuid::Uuid;
+
+use crate::{
+    audit::{AuditAction, AuditLog},
+    cleanup::{register_path, release_path},
+    dry_run::AdjoinAbsolute,
+    error::BuildfsError,
+    export::resolve_destination,
+    minimize::get_tree_size,
+    schema::{BuildScriptOverlay, BuildScriptPayload, PayloadCompression},
+    tools::{get_tool_command, ToolsConfig},
+};
+
+static ZSTD_LEVEL: &str = "-19";
+
+pub async fn encode_payload(
+    payload: &BuildScriptPayload,
+    source_path: &Path,
+    destination_path: &Path,
+    age_recipients: &[String],
+    tools: &ToolsConfig,
+) -> Result<(), BuildfsError> {
+    // compression has to come first, since encrypted data no longer compresses
+    let compressed_path = get_tmp_path();
+    let mut current_path = source_path.to_path_buf();
+    if let Some(PayloadCompression::Zstd) = payload.compression {
+        run_zstd(&["-q", ZSTD_LEVEL, "-f"], &current_path, &compressed_path, tools).await?;
+        current_path = compressed_path.clone();
+    }
+
+    if payload.encrypted {
+        let mut command = get_tool_command(tools, "age", &[get_parent(&current_path), get_parent(destination_path)])?;
+        command.arg("-e");
+        for age_recipient in age_recipients {
+            command.arg("-r").arg(age_recipient);
+        }
+        let exit_status = command
+            .arg("-o")
+            .arg(destination_path)
+            .arg(&current_path)
+            .status()
+            .await
+            .map_err(BuildfsError::io("Could not fork \"age\" to encrypt a payload"))?;
+        if !exit_status.success() {
+            return Err(BuildfsError::Filesystem(format!(
+                "Could not encrypt payload {source_path:?}: \"age\" exited with {exit_status}"
+            )));
+        }
+    } else {
+        tokio::fs::copy(&current_path, destination_path)
+            .await
+            .map_err(BuildfsError::io("Could not copy encoded payload into the package"))?;
+    }
+
+    let _ = tokio::fs::remove_file(compressed_path).await;
+    Ok(())
+}
+
+pub async fn decode_payload(
+    payload: &BuildScriptPayload,
+    source_path: &Path,
+    destination_path: &Path,
+    age_identity: Option<&Path>,
+    tools: &ToolsConfig,
+) -> Result<(), BuildfsError> {
+    let decrypted_path = get_tmp_path();
+    let mut current_path = source_path.to_path_buf();
+    if payload.encrypted {
+        let age_identity = age_identity.ok_or_else(|| {
+            BuildfsError::InvalidArguments("Could not decrypt an encrypted payload without --age-identity".to_string())
+        })?;
+        let exit_status = get_tool_command(
+            tools,
+            "age",
+            &[get_parent(&current_path), get_parent(age_identity), Path::new("/tmp")],
+        )?
+        .arg("-d")
+        .arg("-i")
+        .arg(age_identity)
+        .arg("-o")
+        .arg(&decrypted_path)
+        .arg(&current_path)
+        .status()
+        .await
+        .map_err(BuildfsError::io("Could not fork \"age\" to decrypt a payload"))?;
+        if !exit_status.success() {
+            return Err(BuildfsError::Filesystem(format!(
+                "Could not decrypt payload {source_path:?}: \"age\" exited with {exit_status}"
+            )));
+        }
+        current_path = decrypted_path.clone();
+    }
+
+    let result = match payload.compression {
+        Some(PayloadCompression::Zstd) => run_zstd(&["-q", "-d", "-f"], &current_path, destination_path, tools).await,
+        None => tokio::fs::copy(&current_path, destination_path)
+            .await
+            .map(|_| ())
+            .map_err(BuildfsError::io("Could not copy decoded payload")),
+    };
+
+    let _ = tokio::fs::remove_file(decrypted_path).await;
+    result
+}
+
+pub struct DecodedPayload {
+    destination: PathBuf,
+    decoded_path: PathBuf,
+}
+
+// payloads are decoded on the host first, since only their decoded size tells whether they fit into the image
+pub async fn decode_payload_overlays(
+    overlays: Vec<BuildScriptOverlay>,
+    unpack_path: &PathBuf,
+    age_identity: Option<&Path>,
+    tools: &ToolsConfig,
+) -> Result<(Vec<DecodedPayload>, u64), BuildfsError> {
+    let mut decoded_payloads = Vec::with_capacity(overlays.len());
+    let mut decoded_bytes = 0;
+    for overlay in overlays {
+        let Some(ref source_path) = overlay.source else {
+            return Err(BuildfsError::Validation(format!(
+                "the overlay payload for {:?} has no source path to decode",
+                overlay.destination
+            )));
+        };
+        let decoded_path = get_tmp_path();
+        register_path(&decoded_path);
+        decode_payload(
+            &overlay.payload,
+            &unpack_path.adjoin_absolute(source_path),
+            &decoded_path,
+            age_identity,
+            tools,
+        )
+        .await?;
+        decoded_bytes += get_tree_size(&decoded_path);
+        decoded_payloads.push(DecodedPayload {
+            destination: overlay.destination,
+            decoded_path,
+        });
+    }
+
+    Ok((decoded_payloads, decoded_bytes))
+}
+
+pub async fn apply_payload_overlays(
+    decoded_payloads: Vec<DecodedPayload>,
+    destination_path: &Path,
+    audit_log: &AuditLog,
+) -> Result<(), BuildfsError> {
+    if decoded_payloads.is_empty() {
+        return Ok(());
+    }
+
+    let payload_count = decoded_payloads.len();
+    for decoded_payload in decoded_payloads {
+        let overlay_path = resolve_destination(destination_path, &decoded_payload.destination)?;
+        tokio::fs::create_dir_all(overlay_path.parent().unwrap_or(destination_path))
+            .await
+            .map_err(BuildfsError::io(
+                "Could not create parent directory tree for overlayed payload",
+            ))?;
+        audit_log.record(AuditAction::CopyIntoFilesystem, &overlay_path);
+        tokio::fs::copy(&decoded_payload.decoded_path, &overlay_path)
+            .await
+            .map_err(BuildfsError::io("Could not copy decoded payload into the filesystem"))?;
+        tokio::fs::remove_file(&decoded_payload.decoded_path)
+            .await
+            .map_err(BuildfsError::io("Could not remove decoded payload"))?;
+        release_path(&decoded_payload.decoded_path);
+    }
+
+    log::info!("Decoded {payload_count} compressed or encrypted overlay payload(s) into the mounted filesystem");
+    Ok(())
+}
+
+async fn run_zstd(
+    args: &[&str],
+    source_path: &Path,
+    destination_path: &Path,
+    tools: &ToolsConfig,
+) -> Result<(), BuildfsError> {
+    let exit_status = get_tool_command(tools, "zstd", &[get_parent(source_path), get_parent(destination_path)])?
+        .args(args)
+        .arg("-o")
+        .arg(destination_path)
+        .arg(source_path)
+        .status()
+        .await
+        .map_err(BuildfsError::io("Could not fork \"zstd\" to process a payload"))?;
+    if !exit_status.success() {
+        return Err(BuildfsError::Filesystem(format!(
+            "Could not process payload {source_path:?}: \"zstd\" exited with {exit_status}"
+        )));
+    }
+
+    Ok(())
+}
+
+fn get_parent(path: &Path) -> &Path {
+    path.parent().unwrap_or(Path::new("/"))
+}
+
+fn get_tmp_path() -> PathBuf {
+    PathBuf::from(format!("/tmp/{}", Uuid::new_v4()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use uuid::Uuid;
+
+    use crate::{
+        schema::{BuildScriptPayload, PayloadCompression},
+        tools::ToolsConfig,
+    };
+
+    use super::{decode_payload, encode_payload};
+
+    #[tokio::test]
+    async fn zstd_payloads_round_trip() {
+        if which::which("zstd").is_err() {
+            return;
+        }
+
+        let paths = (0..3)
+            .map(|_| PathBuf::from(format!("/tmp/{}", Uuid::new_v4())))
+            .collect::<Vec<_>>();
+        let contents = "proprietary binary ".repeat(1024);
+        tokio::fs::write(&paths[0], &contents).await.unwrap();
+        let payload = BuildScriptPayload {
+            compression: Some(PayloadCompression::Zstd),
+            encrypted: false,
+        };
+
+        encode_payload(&payload, &paths[0], &paths[1], &[], &ToolsConfig::default())
+            .await
+            .unwrap();
+        assert!(tokio::fs::metadata(&paths[1]).await.unwrap().len() < contents.len() as u64);
+        decode_payload(&payload, &paths[1], &paths[2], None, &ToolsConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(tokio::fs::read_to_string(&paths[2]).await.unwrap(), contents);
+
+        for path in paths {
+            tokio::fs::remove_file(path).await.unwrap();
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use std::{path::PathBuf, process::Stdio};
+
+use serde::Serialize;
+use tokio::{io::AsyncWriteExt, process::Command};
+
+use crate::{
+    error::BuildfsError,
+    schema::{BuildScriptPlugin, PluginHook},
+};
+
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct PluginState {
+    pub package_path: PathBuf,
+    pub output_path: PathBuf,
+    pub container_id: Option<String>,
+    pub container_name: Option<String>,
+    pub staging_path: Option<PathBuf>,
+    pub mount_path: Option<PathBuf>,
+    pub context_path: Option<PathBuf>,
+}
+
+#[derive(Serialize)]
+struct PluginInvocation<'a> {
+    hook: PluginHook,
+    config: &'a toml::Table,
+    #[serde(flatten)]
+    state: &'a PluginState,
+}
+
+pub fn get_plugin_binary_name(plugin: &BuildScriptPlugin) -> String {
+    format!("buildfs-{}", plugin.name)
+}
+
+pub async fn run_plugins(
+    plugins: &[BuildScriptPlugin],
+    hook: PluginHook,
+    state: &PluginState,
+) -> Result<(), BuildfsError> {
+    for plugin in plugins.iter().filter(|plugin| plugin.hook == hook) {
+        let binary_name = get_plugin_binary_name(plugin);
+        let binary_path = which::which(&binary_name).map_err(|_| {
+            BuildfsError::Validation(format!("plugin binary \"{binary_name}\" could not be located in PATH"))
+        })?;
+        let invocation_json = serde_json::to_vec(&PluginInvocation {
+            hook,
+            config: &plugin.config,
+            state,
+        })
+        .expect("Could not encode plugin invocation into JSON");
+
+        let mut child = Command::new(binary_path)
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(BuildfsError::io("Could not fork plugin process"))?;
+        let mut stdin = child.stdin.take().expect("Plugin process has no stdin");
+        stdin
+            .write_all(&invocation_json)
+            .await
+            .map_err(BuildfsError::io("Could not write invocation to plugin stdin"))?;
+        drop(stdin);
+
+        let exit_status = child
+            .wait()
+            .await
+            .map_err(BuildfsError::io("Could not wait on plugin process"))?;
+        if !exit_status.success() {
+            return Err(BuildfsError::CommandFailed(format!(
+                "Plugin \"{binary_name}\" failed at hook {hook:?} with exit status: {exit_status}"
+            )));
+        }
+
+        log::info!("Plugin \"{binary_name}\" finished at hook {hook:?}");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,430 @@
+use std::path::{Component, Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::{
+    container_engine::{CapabilitySupport, ContainerEngine, EngineCapability},
+    error::BuildfsError,
+    schema::{BuildScript, BuildScriptContainerImage, BuildScriptExport, DirectPullPolicy},
+};
+
+static DEFAULT_REGISTRY: &str = "docker.io";
+static HERMETIC_NETWORK_MODE: &str = "none";
+
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct Policy {
+    #[serde(default)]
+    pub allowed_registries: Option<Vec<String>>,
+    #[serde(default)]
+    pub denied_registries: Vec<String>,
+    #[serde(default)]
+    pub allow_rootful: Option<bool>,
+    #[serde(default)]
+    pub allow_privileged_commands: Option<bool>,
+    #[serde(default)]
+    pub allowed_cap_add: Option<Vec<String>>,
+    #[serde(default)]
+    pub denied_cap_add: Vec<String>,
+    #[serde(default)]
+    pub allowed_volume_paths: Option<Vec<PathBuf>>,
+    #[serde(default)]
+    pub denied_volume_paths: Vec<PathBuf>,
+    #[serde(default)]
+    pub allowed_network_modes: Option<Vec<String>>,
+    #[serde(default)]
+    pub denied_network_modes: Vec<String>,
+    #[serde(default)]
+    pub allowed_content_paths: Option<Vec<String>>,
+    #[serde(default)]
+    pub denied_content_paths: Vec<String>,
+}
+
+pub async fn load_policy(policy_path: &PathBuf) -> Result<Policy, BuildfsError> {
+    let policy_toml = tokio::fs::read_to_string(policy_path)
+        .await
+        .map_err(BuildfsError::io("Could not read policy file"))?;
+    toml::from_str::<Policy>(&policy_toml)
+        .map_err(|err| BuildfsError::InvalidArguments(format!("Could not decode policy file from TOML: {err}")))
+}
+
+pub fn get_image_registry(image: &BuildScriptContainerImage) -> String {
+    image.get_registry().unwrap_or_else(|| DEFAULT_REGISTRY.to_string())
+}
+
+pub fn enforce_policy(policy: &Policy, build_script: &BuildScript) -> Result<(), BuildfsError> {
+    let mut violations = Vec::new();
+    let container = &build_script.container;
+
+    for image in container.image.get_pull_candidates() {
+        let registry = get_image_registry(&image);
+        if policy.denied_registries.contains(&registry) {
+            violations.push(format!("image registry \"{registry}\" is denied"));
+        } else if let Some(ref allowed_registries) = policy.allowed_registries {
+            if !allowed_registries.contains(&registry) {
+                violations.push(format!(
+                    "image registry \"{registry}\" is not in the list of allowed registries"
+                ));
+            }
+        }
+    }
+
+    if container.rootful && policy.allow_rootful == Some(false) {
+        violations.push("rootful containers are not allowed".to_string());
+    }
+
+    if policy.allow_privileged_commands == Some(false) {
+        let privileged_commands = build_script
+            .commands
+            .iter()
+            .filter(|command| command.privileged == Some(true))
+            .count();
+        if privileged_commands > 0 {
+            violations.push(format!(
+                "{privileged_commands} command(s) are privileged, but privileged commands are not allowed"
+            ));
+        }
+    }
+
+    for capability in container.cap_add.iter().flatten() {
+        if policy.denied_cap_add.iter().any(|denied| denied == capability) {
+            violations.push(format!("added capability \"{capability}\" is denied"));
+        } else if let Some(ref allowed_cap_add) = policy.allowed_cap_add {
+            if !allowed_cap_add.iter().any(|allowed| allowed == capability) {
+                violations.push(format!(
+                    "added capability \"{capability}\" is not in the list of allowed capabilities"
+                ));
+            }
+        }
+    }
+
+    let step_mount_paths = container
+        .caches
+        .values()
+        .chain(container.secrets.values())
+        .map(|step_mount| &step_mount.source);
+    for source_path in container.volumes.keys().chain(step_mount_paths) {
+        // "/allowed/../etc" and symlinks out of an allowed directory must not pass for paths inside of it
+        let resolved_source_path = resolve_volume_path(source_path);
+        if policy
+            .denied_volume_paths
+            .iter()
+            .any(|denied| resolved_source_path.starts_with(resolve_volume_path(denied)))
+        {
+            violations.push(format!("host volume path {source_path:?} is denied"));
+        } else if let Some(ref allowed_volume_paths) = policy.allowed_volume_paths {
+            if !allowed_volume_paths
+                .iter()
+                .any(|allowed| resolved_source_path.starts_with(resolve_volume_path(allowed)))
+            {
+                violations.push(format!(
+                    "host volume path {source_path:?} is not within any of the allowed volume paths"
+                ));
+            }
+        }
+    }
+
+    if let Some(ref network_mode) = container.network_mode {
+        if policy.denied_network_modes.iter().any(|denied| denied == network_mode) {
+            violations.push(format!("network mode \"{network_mode}\" is denied"));
+        } else if let Some(ref allowed_network_modes) = policy.allowed_network_modes {
+            if !allowed_network_modes.iter().any(|allowed| allowed == network_mode) {
+                violations.push(format!(
+                    "network mode \"{network_mode}\" is not in the list of allowed network modes"
+                ));
+            }
+        }
+    }
+
+    if !violations.is_empty() {
+        return Err(BuildfsError::Validation(format!(
+            "{} policy violation(s):\n{}",
+            violations.len(),
+            format_violations(&violations)
+        )));
+    }
+
+    log::debug!("Build script complies with the policy");
+    Ok(())
+}
+
+pub fn enforce_hermetic(build_script: &BuildScript) -> Result<(), BuildfsError> {
+    let mut violations = Vec::new();
+    let container = &build_script.container;
+
+    if container.network_mode.as_deref() != Some(HERMETIC_NETWORK_MODE) {
+        violations.push(format!(
+            "the container's network_mode must be \"{HERMETIC_NETWORK_MODE}\", but is {:?}",
+            container.network_mode
+        ));
+    }
+
+    if !container.volumes.is_empty() {
+        violations.push(format!(
+            "{} host volume(s) are mounted into the container",
+            container.volumes.len()
+        ));
+    }
+
+    if !container.caches.is_empty() {
+        violations.push(format!(
+            "{} cache(s) carry state from earlier builds into the container",
+            container.caches.len()
+        ));
+    }
+
+    if container.image.digest.is_none() {
+        violations.push(format!(
+            "image {} is not pinned to a digest, set \"digest\" or use a name of the form \"<name>@sha256:<digest>\"",
+            container.image.full_name()
+        ));
+    }
+
+    for library_use in &build_script.uses {
+        let source = library_use.get_source();
+        if source.starts_with("oci://") && !source.contains("@sha256:") {
+            violations.push(format!(
+                "library {source} is not pinned to a digest, use a reference of the form \"oci://<name>@sha256:<digest>\""
+            ));
+        }
+    }
+
+    if !violations.is_empty() {
+        return Err(BuildfsError::Validation(format!(
+            "{} hermetic mode violation(s):\n{}",
+            violations.len(),
+            format_violations(&violations)
+        )));
+    }
+
+    log::debug!("Build script complies with hermetic mode");
+    Ok(())
+}
+
+pub async fn enforce_offline(
+    build_script: &BuildScript,
+    container_engine: &dyn ContainerEngine,
+) -> Result<(), BuildfsError> {
+    let mut missing_assets = Vec::new();
+    let container = &build_script.container;
+
+    if container.direct_pull != DirectPullPolicy::Never {
+        missing_assets.push(format!(
+            "direct_pull is {:?}, which pulls the image from its registry",
+            container.direct_pull
+        ));
+    }
+
+    if container.attach_to.is_none()
+        && EngineCapability::ImageCache.support(&container.engine) == CapabilitySupport::Unsupported
+    {
+        missing_assets.push(format!(
+            "image {} can't be looked up in the {} engine's image cache, so it can't be known to be available locally",
+            container.image.full_name(),
+            container.engine
+        ));
+    } else if container.attach_to.is_none() && !container_engine.image_exists(&container.image).await {
+        missing_assets.push(format!(
+            "image {} is not available locally in the {} engine",
+            container.image.full_name(),
+            container.engine
+        ));
+    }
+
+    if !missing_assets.is_empty() {
+        return Err(BuildfsError::Validation(format!(
+            "{} asset(s) are unavailable in offline mode:\n{}",
+            missing_assets.len(),
+            format_violations(&missing_assets)
+        )));
+    }
+
+    log::info!("Everything the build needs is available locally, running offline");
+    Ok(())
+}
+
+pub async fn enforce_content_policy(
+    policy: &Policy,
+    rootfs_path: &Path,
+    export: &BuildScriptExport,
+) -> Result<(), BuildfsError> {
+    if policy.allowed_content_paths.is_none() && policy.denied_content_paths.is_empty() {
+        return Ok(());
+    }
+
+    let included_paths = export
+        .directories
+        .include
+        .iter()
+        .chain(&export.files.include)
+        .cloned()
+        .collect::<Vec<_>>();
+    let (policy, rootfs_path) = (policy.clone(), rootfs_path.to_path_buf());
+    let violations = tokio::task::spawn_blocking(move || {
+        let mut violations = Vec::new();
+        for included_path in included_paths {
+            collect_content_violations(&policy, &rootfs_path, included_path, &mut violations)?;
+        }
+        Ok::<_, BuildfsError>(violations)
+    })
+    .await
+    .expect("Join on blocking task failed")?;
+
+    if !violations.is_empty() {
+        return Err(BuildfsError::Validation(format!(
+            "content policy enforcement failed, {} path(s) exported from the container are not allowed into the image:\n{}",
+            violations.len(),
+            format_violations(&violations)
+        )));
+    }
+
+    log::info!("Exported content complies with the content policy");
+    Ok(())
+}
+
+fn collect_content_violations(
+    policy: &Policy,
+    rootfs_path: &Path,
+    path: PathBuf,
+    violations: &mut Vec<String>,
+) -> Result<(), BuildfsError> {
+    let host_path = rootfs_path.join(path.strip_prefix("/").unwrap_or(&path));
+    let Ok(metadata) = std::fs::symlink_metadata(&host_path) else {
+        return Ok(());
+    };
+
+    if let Some(pattern) = policy
+        .denied_content_paths
+        .iter()
+        .find(|pattern| matches_glob(pattern, &path))
+    {
+        violations.push(format!("{path:?} matches the denied content pattern \"{pattern}\""));
+    } else if let Some(ref allowed_content_paths) = policy.allowed_content_paths {
+        // directories only hold content, so only what's inside them has to be allowed
+        if !metadata.is_dir() && !allowed_content_paths.iter().any(|pattern| matches_glob(pattern, &path)) {
+            violations.push(format!("{path:?} does not match any of the allowed content patterns"));
+        }
+    }
+
+    if metadata.is_dir() {
+        for entry in std::fs::read_dir(&host_path).map_err(BuildfsError::io(
+            "Could not read exported directory to enforce the content policy",
+        ))? {
+            let entry = entry.map_err(BuildfsError::io(
+                "Could not read exported directory entry to enforce the content policy",
+            ))?;
+            collect_content_violations(policy, rootfs_path, path.join(entry.file_name()), violations)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn resolve_volume_path(path: &Path) -> PathBuf {
+    if let Ok(canonical_path) = std::fs::canonicalize(path) {
+        return canonical_path;
+    }
+
+    // paths that don't exist yet are normalized lexically, with ".." never climbing above "/"
+    let absolute_path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut resolved_path = PathBuf::from("/");
+    for component in absolute_path.components() {
+        match component {
+            Component::Normal(name) => resolved_path.push(name),
+            Component::ParentDir => {
+                resolved_path.pop();
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    resolved_path
+}
+
+// "*" and "?" match within a single path component, while "**" matches any number of whole components
+fn matches_glob(pattern: &str, path: &Path) -> bool {
+    let pattern_components = pattern
+        .split('/')
+        .filter(|component| !component.is_empty())
+        .collect::<Vec<_>>();
+    let path_components = path
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    matches_components(&pattern_components, &path_components)
+}
+
+fn matches_components(pattern: &[&str], path: &[String]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|skip| matches_components(rest, &path[skip..])),
+        Some((component, rest)) => {
+            !path.is_empty()
+                && matches_component(component.as_bytes(), path[0].as_bytes())
+                && matches_components(rest, &path[1..])
+        }
+    }
+}
+
+fn matches_component(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| matches_component(rest, &name[skip..])),
+        Some((b'?', rest)) => !name.is_empty() && matches_component(rest, &name[1..]),
+        Some((character, rest)) => name.first() == Some(character) && matches_component(rest, &name[1..]),
+    }
+}
+
+fn format_violations(violations: &[String]) -> String {
+    violations
+        .iter()
+        .map(|violation| format!("- {violation}"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{container_engine::mock::MockContainerEngine, schema::parse_build_script};
+
+    use super::{enforce_offline, matches_glob, resolve_volume_path};
+
+    #[test]
+    fn content_globs_match_across_and_within_components() {
+        assert!(matches_glob("/root/.ssh/**", Path::new("/root/.ssh")));
+        assert!(matches_glob("/root/.ssh/**", Path::new("/root/.ssh/id_ed25519")));
+        assert!(matches_glob("**/*.pem", Path::new("/etc/ssl/private/server.pem")));
+        assert!(matches_glob("**/*.pem", Path::new("/server.pem")));
+        assert!(!matches_glob("**/*.pem", Path::new("/etc/ssl/server.pem.bak")));
+        assert!(!matches_glob("/root/*", Path::new("/root/.ssh/id_ed25519")));
+    }
+
+    #[test]
+    fn volume_paths_cannot_climb_out_of_allowed_directories() {
+        let resolved_path = resolve_volume_path(Path::new("/buildfs-allowed/../etc/./shadow"));
+
+        assert_eq!(resolved_path, Path::new("/etc/shadow"));
+        assert!(!resolved_path.starts_with(resolve_volume_path(Path::new("/buildfs-allowed"))));
+        assert_eq!(
+            resolve_volume_path(Path::new("/buildfs-missing/../../..")),
+            Path::new("/")
+        );
+    }
+
+    #[tokio::test]
+    async fn offline_runs_refuse_engines_without_an_image_cache_lookup() {
+        let build_script = parse_build_script(
+            "schema_version = 1\n[filesystem]\nsize_mib = 64\n[container]\nengine = \"Kubernetes\"\nimage = { name = \"debian\", tag = \"bookworm\" }\ndirect_pull = \"Never\"\n",
+        );
+
+        let err = enforce_offline(&build_script, &MockContainerEngine::default())
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("can't be looked up in the Kubernetes engine's image cache"));
+    }
+}
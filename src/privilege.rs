@@ -0,0 +1,833 @@
+use std::{
+    ffi::{CString, OsStr},
+    fs::File,
+    io::{BufRead, BufReader, Read, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd, RawFd},
+        unix::{
+            ffi::OsStrExt,
+            fs::{MetadataExt, OpenOptionsExt},
+            process::CommandExt,
+        },
+    },
+    path::{Path, PathBuf},
+    process::{Command, ExitCode, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, OnceLock,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+use sys_mount::{Unmount, UnmountFlags};
+
+use crate::{
+    cleanup::{register_mount, release_mount, MountKind},
+    error::BuildfsError,
+    loop_device::LoopDevice,
+    schema::BuildScriptLoopDevice,
+    PrivilegeHelperLauncher,
+};
+
+static HELPER_SESSION_ENV: &str = "BUILDFS_HELPER_SESSION";
+static HELPER_RESPONSE_FD: RawFd = 3;
+static HELPER_REQUEST_FD: RawFd = 4;
+static ID_MAPS_READY_FD: RawFd = 5;
+static SUBUID_PATH: &str = "/etc/subuid";
+static SUBGID_PATH: &str = "/etc/subgid";
+static HELPER_FSTYPES: &[&str] = &["btrfs", "ext4", "squashfs", "vfat", "xfs"];
+static HELPER_STAGING_TEMPLATE: &str = "/tmp/buildfs-helper-XXXXXX";
+
+static HELPER_CONNECTION: OnceLock<Mutex<HelperConnection>> = OnceLock::new();
+static HOST_UID: OnceLock<u32> = OnceLock::new();
+
+const NS_GET_OWNER_UID: libc::c_ulong = 0xB704;
+const OPEN_TREE_CLONE: libc::c_uint = 0x1;
+const MOVE_MOUNT_F_EMPTY_PATH: libc::c_uint = 0x4;
+const MOVE_MOUNT_T_EMPTY_PATH: libc::c_uint = 0x40;
+const MOUNT_ATTR_IDMAP: u64 = 0x0010_0000;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum HelperRequest {
+    Mount {
+        image_path: PathBuf,
+        mount_path: PathBuf,
+        fstype: String,
+        data: String,
+        read_only: bool,
+        loop_device: BuildScriptLoopDevice,
+        userns_pid: u32,
+    },
+    Unmount {
+        mount_path: PathBuf,
+        lazy: bool,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HelperError {
+    pub message: String,
+    pub errno: Option<i32>,
+}
+
+impl HelperError {
+    fn rejected(message: String) -> Self {
+        Self { message, errno: None }
+    }
+
+    fn io(action: &str) -> impl FnOnce(std::io::Error) -> HelperError + '_ {
+        move |error| HelperError {
+            message: format!("{action}: {error}"),
+            errno: error.raw_os_error(),
+        }
+    }
+
+    fn last_os_error(action: &str) -> Self {
+        Self::io(action)(std::io::Error::last_os_error())
+    }
+}
+
+struct HelperConnection {
+    responses: BufReader<File>,
+    requests: File,
+}
+
+#[derive(Debug)]
+pub struct HelperMount {
+    mount_path: PathBuf,
+    mounted: AtomicBool,
+}
+
+impl Unmount for HelperMount {
+    fn unmount(&self, flags: UnmountFlags) -> std::io::Result<()> {
+        unmount_via_helper(&self.mount_path, flags.contains(UnmountFlags::DETACH))?;
+        self.mounted.store(false, Ordering::Relaxed);
+        release_mount(&self.mount_path);
+        Ok(())
+    }
+}
+
+impl Drop for HelperMount {
+    fn drop(&mut self) {
+        if self.mounted.load(Ordering::Relaxed) {
+            if let Err(err) = self.unmount(UnmountFlags::DETACH) {
+                log::error!(
+                    "Could not unmount {:?} via the privileged helper: {err}",
+                    self.mount_path
+                );
+            }
+        }
+    }
+}
+
+pub fn is_helper_connected() -> bool {
+    HELPER_CONNECTION.get().is_some()
+}
+
+pub fn get_host_uid() -> u32 {
+    HOST_UID.get().copied().unwrap_or_else(|| unsafe { libc::geteuid() })
+}
+
+pub fn mount_via_helper(
+    image_path: &Path,
+    mount_path: &Path,
+    fstype: &str,
+    data: &str,
+    read_only: bool,
+    loop_device: Option<&BuildScriptLoopDevice>,
+) -> Result<HelperMount, BuildfsError> {
+    send_request(&HelperRequest::Mount {
+        image_path: image_path.to_path_buf(),
+        mount_path: mount_path.to_path_buf(),
+        fstype: fstype.to_string(),
+        data: data.to_string(),
+        read_only,
+        loop_device: loop_device.cloned().unwrap_or_default(),
+        userns_pid: std::process::id(),
+    })
+    .map_err(BuildfsError::io("Could not reach the privileged helper"))?
+    .map_err(|error| {
+        BuildfsError::Filesystem(format!(
+            "The privileged helper could not mount {image_path:?}: {}",
+            error.message
+        ))
+    })?;
+    log::debug!("Mounted {image_path:?} at {mount_path:?} via the privileged helper");
+    register_mount(mount_path, MountKind::Helper);
+
+    Ok(HelperMount {
+        mount_path: mount_path.to_path_buf(),
+        mounted: AtomicBool::new(true),
+    })
+}
+
+pub fn unmount_via_helper(mount_path: &Path, lazy: bool) -> std::io::Result<()> {
+    send_request(&HelperRequest::Unmount {
+        mount_path: mount_path.to_path_buf(),
+        lazy,
+    })?
+    .map_err(|error| match error.errno {
+        Some(errno) => std::io::Error::from_raw_os_error(errno),
+        None => std::io::Error::other(error.message),
+    })
+}
+
+fn send_request(request: &HelperRequest) -> std::io::Result<Result<(), HelperError>> {
+    let connection = HELPER_CONNECTION
+        .get()
+        .ok_or_else(|| std::io::Error::other("no privileged helper is connected"))?;
+    let mut connection = connection.lock().unwrap_or_else(|error| error.into_inner());
+    let mut request_line = serde_json::to_string(request)?;
+    request_line.push('\n');
+    connection.requests.write_all(request_line.as_bytes())?;
+
+    let mut response_line = String::new();
+    if connection.responses.read_line(&mut response_line)? == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "the privileged helper exited",
+        ));
+    }
+    Ok(serde_json::from_str(&response_line)?)
+}
+
+pub fn connect_helper() -> bool {
+    let Some(host_uid) = std::env::var(HELPER_SESSION_ENV)
+        .ok()
+        .and_then(|host_uid| host_uid.parse().ok())
+    else {
+        return false;
+    };
+    std::env::remove_var(HELPER_SESSION_ENV);
+
+    // the parent writes the ID maps of this process' user namespace only after it was started
+    let mut id_maps_ready = unsafe { File::from_raw_fd(ID_MAPS_READY_FD) };
+    id_maps_ready
+        .read_exact(&mut [0])
+        .expect("Could not wait for the ID maps of the user namespace to be written");
+    for fd in [HELPER_RESPONSE_FD, HELPER_REQUEST_FD] {
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+
+    let _ = HOST_UID.set(host_uid);
+    let _ = HELPER_CONNECTION.set(Mutex::new(HelperConnection {
+        responses: BufReader::new(unsafe { File::from_raw_fd(HELPER_RESPONSE_FD) }),
+        requests: unsafe { File::from_raw_fd(HELPER_REQUEST_FD) },
+    }));
+    log::debug!("Connected to the privileged helper from a user namespace of host UID {host_uid}");
+    true
+}
+
+pub fn run_in_user_namespace(launcher: PrivilegeHelperLauncher, helper_path: &Path) -> ExitCode {
+    match run_helper_session(launcher, helper_path) {
+        Ok(exit_code) => exit_code,
+        Err(err) => {
+            log::error!("{err}");
+            err.exit_code()
+        }
+    }
+}
+
+fn run_helper_session(launcher: PrivilegeHelperLauncher, helper_path: &Path) -> Result<ExitCode, BuildfsError> {
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    let user_name = get_user_name(uid);
+    let get_range = |ids_path: &str| {
+        get_subordinate_range(
+            &std::fs::read_to_string(ids_path).unwrap_or_default(),
+            uid,
+            user_name.as_deref(),
+        )
+        .ok_or_else(|| {
+            BuildfsError::InvalidArguments(format!(
+                "--privilege-helper needs a range of subordinate IDs for UID {uid} in {ids_path}"
+            ))
+        })
+    };
+    let (subuid_range, subgid_range) = (get_range(SUBUID_PATH)?, get_range(SUBGID_PATH)?);
+
+    let current_exe = std::env::current_exe().map_err(BuildfsError::io("Could not locate the buildfs binary"))?;
+    let mut helper_command = match launcher {
+        PrivilegeHelperLauncher::Sudo => {
+            let mut helper_command = Command::new("sudo");
+            helper_command.arg("--").arg(&current_exe);
+            helper_command
+        }
+        PrivilegeHelperLauncher::Pkexec => {
+            let mut helper_command = Command::new("pkexec");
+            helper_command.arg(&current_exe);
+            helper_command
+        }
+        PrivilegeHelperLauncher::Setuid => Command::new(helper_path),
+    };
+    let mut helper = helper_command
+        .arg("privileged-helper")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(BuildfsError::io("Could not launch the privileged helper"))?;
+    let helper_requests = helper.stdin.take().expect("Privileged helper has no stdin");
+    let helper_responses = helper.stdout.take().expect("Privileged helper has no stdout");
+    let (id_maps_ready_read, mut id_maps_ready_write) =
+        create_pipe().map_err(BuildfsError::io("Could not create a pipe for the user namespace"))?;
+
+    let fd_targets = [
+        (helper_responses.as_raw_fd(), HELPER_RESPONSE_FD),
+        (helper_requests.as_raw_fd(), HELPER_REQUEST_FD),
+        (id_maps_ready_read.as_raw_fd(), ID_MAPS_READY_FD),
+    ];
+    let mut command = Command::new(&current_exe);
+    command
+        .args(std::env::args_os().skip(1))
+        .env(HELPER_SESSION_ENV, uid.to_string());
+    unsafe {
+        command.pre_exec(move || {
+            // the fds are moved out of the way first, since one of them may already sit on another's target
+            let moved_fds = fd_targets.map(|(fd, _)| libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 10));
+            for (moved_fd, (_, target_fd)) in moved_fds.into_iter().zip(fd_targets) {
+                if moved_fd < 0 || libc::dup2(moved_fd, target_fd) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            if libc::unshare(libc::CLONE_NEWUSER) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        })
+    };
+    let mut child = command
+        .spawn()
+        .map_err(BuildfsError::io("Could not start buildfs in a user namespace"))?;
+    drop((helper_requests, helper_responses, id_maps_ready_read));
+
+    if let Err(err) = write_id_maps(child.id(), (uid, subuid_range), (gid, subgid_range)) {
+        let _ = child.kill();
+        let _ = child.wait();
+        let _ = helper.wait();
+        return Err(err);
+    }
+    id_maps_ready_write
+        .write_all(&[0])
+        .map_err(BuildfsError::io("Could not signal the user namespace to continue"))?;
+    drop(id_maps_ready_write);
+
+    // the child cleans up after an interrupt on its own, and the helper unmounts whatever is left once it exits
+    unsafe { libc::signal(libc::SIGINT, libc::SIG_IGN) };
+    let exit_status = child
+        .wait()
+        .map_err(BuildfsError::io("Could not wait on buildfs in the user namespace"))?;
+    let _ = helper.wait();
+
+    Ok(match exit_status.code() {
+        Some(code) => ExitCode::from(code as u8),
+        None => ExitCode::FAILURE,
+    })
+}
+
+fn write_id_maps(pid: u32, uid_map: (u32, (u32, u32)), gid_map: (u32, (u32, u32))) -> Result<(), BuildfsError> {
+    // root inside is the invoking user, so that its files stay accessible, followed by the subordinate IDs
+    for (tool_name, (id, (first_subordinate_id, count))) in [("newuidmap", uid_map), ("newgidmap", gid_map)] {
+        let exit_status = Command::new(tool_name)
+            .arg(pid.to_string())
+            .args(["0", &id.to_string(), "1"])
+            .args(["1", &first_subordinate_id.to_string(), &count.to_string()])
+            .status()
+            .map_err(BuildfsError::io("Could not fork \"newuidmap\" or \"newgidmap\""))?;
+        if !exit_status.success() {
+            return Err(BuildfsError::CommandFailed(format!(
+                "\"{tool_name}\" could not map the subordinate IDs into the user namespace: {exit_status}"
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+fn create_pipe() -> std::io::Result<(File, File)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+fn get_user_name(uid: u32) -> Option<String> {
+    let passwd = unsafe { libc::getpwuid(uid) };
+    if passwd.is_null() {
+        return None;
+    }
+    let name = unsafe { std::ffi::CStr::from_ptr((*passwd).pw_name) };
+    Some(name.to_string_lossy().to_string())
+}
+
+fn get_subordinate_range(ids_text: &str, id: u32, user_name: Option<&str>) -> Option<(u32, u32)> {
+    ids_text.lines().find_map(|line| {
+        let mut fields = line.trim().split(':');
+        let owner = fields.next()?;
+        if owner != id.to_string() && Some(owner) != user_name {
+            return None;
+        }
+        let first_subordinate_id = fields.next()?.parse().ok()?;
+        let count = fields.next()?.parse().ok()?;
+        Some((first_subordinate_id, count))
+    })
+}
+
+pub fn helper_command() -> ExitCode {
+    if unsafe { libc::geteuid() } != 0 {
+        log::error!("The privileged helper has to be launched as root, via sudo, pkexec or a setuid binary");
+        return ExitCode::FAILURE;
+    }
+    // an interrupt reaches the whole process group, but the helper has to outlive buildfs to clean up after it
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_IGN);
+        libc::signal(libc::SIGHUP, libc::SIG_IGN);
+    }
+
+    let mut session = HelperSession {
+        caller_uid: get_caller_uid(
+            unsafe { libc::getuid() },
+            std::env::var("SUDO_UID").ok().as_deref(),
+            std::env::var("PKEXEC_UID").ok().as_deref(),
+        ),
+        mounts: Vec::new(),
+    };
+    // logs go to stdout as well, so the responses get the original stdout to themselves and the logs go to stderr
+    let responses_fd = unsafe { libc::fcntl(libc::STDOUT_FILENO, libc::F_DUPFD_CLOEXEC, 10) };
+    if responses_fd < 0 || unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+        log::error!(
+            "Could not set up the responses of the privileged helper: {}",
+            std::io::Error::last_os_error()
+        );
+        return ExitCode::FAILURE;
+    }
+    let mut responses = unsafe { File::from_raw_fd(responses_fd) };
+    for request_line in std::io::stdin().lock().lines() {
+        let Ok(request_line) = request_line else {
+            break;
+        };
+        let response = match serde_json::from_str::<HelperRequest>(&request_line) {
+            Ok(request) => session.handle(request),
+            Err(err) => Err(HelperError::rejected(format!("Could not decode request: {err}"))),
+        };
+        let response_line = serde_json::to_string(&response).expect("Could not encode helper response into JSON");
+        if writeln!(responses, "{response_line}").is_err() {
+            break;
+        }
+    }
+
+    session.clean_up();
+    ExitCode::SUCCESS
+}
+
+fn get_caller_uid(real_uid: u32, sudo_uid: Option<&str>, pkexec_uid: Option<&str>) -> u32 {
+    // a setuid helper keeps the caller as its real user, and the environment can't be trusted in that case
+    if real_uid != 0 {
+        return real_uid;
+    }
+    sudo_uid.or(pkexec_uid).and_then(|uid| uid.parse().ok()).unwrap_or(0)
+}
+
+fn is_allowed_mount_data(data: &str) -> bool {
+    data.is_empty()
+        || data.split(',').all(|option| {
+            option == "quiet"
+                || option
+                    .strip_prefix("codepage=")
+                    .is_some_and(|codepage| !codepage.is_empty() && codepage.chars().all(|c| c.is_ascii_digit()))
+        })
+}
+
+struct HelperSession {
+    caller_uid: u32,
+    mounts: Vec<SessionMount>,
+}
+
+struct SessionMount {
+    mount_path: PathBuf,
+    device: u64,
+    root_inode: u64,
+    _loop_device: LoopDevice,
+}
+
+impl HelperSession {
+    fn handle(&mut self, request: HelperRequest) -> Result<(), HelperError> {
+        match request {
+            HelperRequest::Mount {
+                image_path,
+                mount_path,
+                fstype,
+                data,
+                read_only,
+                loop_device,
+                userns_pid,
+            } => self.mount(
+                &image_path,
+                mount_path,
+                &fstype,
+                &data,
+                read_only,
+                &loop_device,
+                userns_pid,
+            ),
+            HelperRequest::Unmount { mount_path, lazy } => self.unmount(&mount_path, lazy),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn mount(
+        &mut self,
+        image_path: &Path,
+        mount_path: PathBuf,
+        fstype: &str,
+        data: &str,
+        read_only: bool,
+        loop_device_options: &BuildScriptLoopDevice,
+        userns_pid: u32,
+    ) -> Result<(), HelperError> {
+        if !HELPER_FSTYPES.contains(&fstype) {
+            return Err(HelperError::rejected(format!(
+                "{fstype} is not a filesystem type that the helper mounts"
+            )));
+        }
+        if !is_allowed_mount_data(data) {
+            return Err(HelperError::rejected(format!(
+                "\"{data}\" contains mount options that the helper doesn't pass on"
+            )));
+        }
+
+        let image_file = self.open_caller_path(image_path, 0)?;
+        if !image_file.metadata().is_ok_and(|metadata| metadata.is_file()) {
+            return Err(HelperError::rejected(format!("{image_path:?} is not a regular file")));
+        }
+        let mount_file = self.open_caller_path(&mount_path, libc::O_PATH | libc::O_DIRECTORY)?;
+        if std::fs::read_dir(get_fd_path(&mount_file))
+            .map_err(HelperError::io("Could not read the mount point"))?
+            .next()
+            .is_some()
+        {
+            return Err(HelperError::rejected(format!(
+                "{mount_path:?} is not an empty directory"
+            )));
+        }
+        let userns_file = self.open_caller_user_namespace(userns_pid)?;
+
+        let loop_device = LoopDevice::attach(&get_fd_path(&image_file), loop_device_options)
+            .map_err(HelperError::io("Could not attach the image to a loop device"))?;
+        let staging_path = create_staging_dir()?;
+        let result = mount_idmapped(
+            loop_device.device_path(),
+            &staging_path,
+            fstype,
+            data,
+            read_only,
+            &userns_file,
+            &mount_file,
+        );
+        let _ = std::fs::remove_dir(&staging_path);
+        let (device, root_inode) = result?;
+
+        self.mounts.push(SessionMount {
+            mount_path,
+            device,
+            root_inode,
+            _loop_device: loop_device,
+        });
+        Ok(())
+    }
+
+    fn unmount(&mut self, mount_path: &Path, lazy: bool) -> Result<(), HelperError> {
+        let index = self
+            .mounts
+            .iter()
+            .position(|session_mount| session_mount.mount_path == mount_path)
+            .ok_or_else(|| HelperError::rejected(format!("{mount_path:?} was not mounted by this helper")))?;
+        unmount_session_mount(&self.mounts[index], lazy)?;
+        // dropping the mount detaches its loop device
+        self.mounts.remove(index);
+        Ok(())
+    }
+
+    fn clean_up(&mut self) {
+        for session_mount in self.mounts.drain(..) {
+            if let Err(err) = unmount_session_mount(&session_mount, true) {
+                log::error!(
+                    "Could not unmount {:?} left behind by buildfs: {}",
+                    session_mount.mount_path,
+                    err.message
+                );
+            }
+        }
+    }
+
+    fn open_caller_path(&self, path: &Path, flags: libc::c_int) -> Result<File, HelperError> {
+        let file = File::options()
+            .read(true)
+            .custom_flags(flags | libc::O_NOFOLLOW | libc::O_CLOEXEC)
+            .open(path)
+            .map_err(HelperError::io("Could not open a requested path"))?;
+        let metadata = file
+            .metadata()
+            .map_err(HelperError::io("Could not inspect a requested path"))?;
+        if metadata.uid() != self.caller_uid {
+            return Err(HelperError::rejected(format!(
+                "{path:?} is not owned by the calling user"
+            )));
+        }
+        Ok(file)
+    }
+
+    fn open_caller_user_namespace(&self, pid: u32) -> Result<File, HelperError> {
+        let userns_file = File::open(format!("/proc/{pid}/ns/user"))
+            .map_err(HelperError::io("Could not open the user namespace of the caller"))?;
+        let mut owner_uid: libc::uid_t = 0;
+        if unsafe { libc::ioctl(userns_file.as_raw_fd(), NS_GET_OWNER_UID, &mut owner_uid) } < 0 {
+            return Err(HelperError::last_os_error(
+                "Could not look up the owner of the user namespace",
+            ));
+        }
+        let own_userns_inode = std::fs::metadata("/proc/self/ns/user")
+            .map_err(HelperError::io("Could not inspect the user namespace of the helper"))?
+            .ino();
+        let userns_inode = userns_file
+            .metadata()
+            .map_err(HelperError::io("Could not inspect the user namespace of the caller"))?
+            .ino();
+
+        // the idmapped mount may only hand out the IDs that the caller's own user namespace was given
+        if owner_uid != self.caller_uid || userns_inode == own_userns_inode {
+            return Err(HelperError::rejected(format!(
+                "process {pid} is not in a user namespace created by the calling user"
+            )));
+        }
+        Ok(userns_file)
+    }
+}
+
+fn unmount_session_mount(session_mount: &SessionMount, lazy: bool) -> Result<(), HelperError> {
+    let mount_path = &session_mount.mount_path;
+    let (Some(parent_path), Some(mount_name)) = (mount_path.parent(), mount_path.file_name()) else {
+        return Err(HelperError::rejected(format!("{mount_path:?} has no parent directory")));
+    };
+    let parent_file = File::options()
+        .read(true)
+        .custom_flags(libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC)
+        .open(parent_path)
+        .map_err(HelperError::io("Could not open the parent of the mount point"))?;
+    let mount_name = get_c_string(mount_name)?;
+
+    // the mount is unmounted relative to the checked parent, so that swapping in a symlink can't redirect it
+    let mut mount_stat = unsafe { std::mem::zeroed::<libc::stat>() };
+    if unsafe {
+        libc::fstatat(
+            parent_file.as_raw_fd(),
+            mount_name.as_ptr(),
+            &mut mount_stat,
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    } < 0
+    {
+        return Err(HelperError::last_os_error("Could not inspect the mount point"));
+    }
+    if (mount_stat.st_dev as u64, mount_stat.st_ino as u64) != (session_mount.device, session_mount.root_inode) {
+        return Err(HelperError::rejected(format!(
+            "{mount_path:?} no longer refers to the filesystem mounted by this helper"
+        )));
+    }
+
+    if unsafe { libc::fchdir(parent_file.as_raw_fd()) } < 0 {
+        return Err(HelperError::last_os_error(
+            "Could not enter the parent of the mount point",
+        ));
+    }
+    let flags = match lazy {
+        true => libc::MNT_DETACH | libc::UMOUNT_NOFOLLOW,
+        false => libc::UMOUNT_NOFOLLOW,
+    };
+    let result = match unsafe { libc::umount2(mount_name.as_ptr(), flags) } {
+        0 => Ok(()),
+        _ => Err(HelperError::last_os_error("Could not unmount the filesystem")),
+    };
+    unsafe { libc::chdir(c"/".as_ptr()) };
+    result
+}
+
+fn mount_idmapped(
+    device_path: &Path,
+    staging_path: &Path,
+    fstype: &str,
+    data: &str,
+    read_only: bool,
+    userns_file: &File,
+    mount_file: &File,
+) -> Result<(u64, u64), HelperError> {
+    let (device_path, staging_path) = (
+        get_c_string(device_path.as_os_str())?,
+        get_c_string(staging_path.as_os_str())?,
+    );
+    let (fstype, data) = (get_c_string(OsStr::new(fstype))?, get_c_string(OsStr::new(data))?);
+    let mut flags = libc::MS_NOSUID | libc::MS_NODEV;
+    if read_only {
+        flags |= libc::MS_RDONLY;
+    }
+    if unsafe {
+        libc::mount(
+            device_path.as_ptr(),
+            staging_path.as_ptr(),
+            fstype.as_ptr(),
+            flags,
+            data.as_ptr().cast(),
+        )
+    } < 0
+    {
+        return Err(HelperError::last_os_error("Could not mount the filesystem"));
+    }
+
+    // only a detached copy of a mount can be idmapped, so the original is dropped right after cloning it
+    let tree_fd = unsafe {
+        libc::syscall(
+            libc::SYS_open_tree,
+            libc::AT_FDCWD,
+            staging_path.as_ptr(),
+            OPEN_TREE_CLONE | libc::O_CLOEXEC as libc::c_uint,
+        )
+    };
+    let clone_error = std::io::Error::last_os_error();
+    unsafe { libc::umount2(staging_path.as_ptr(), libc::MNT_DETACH) };
+    if tree_fd < 0 {
+        return Err(HelperError::io("Could not clone the mount")(clone_error));
+    }
+    let tree_file = unsafe { File::from_raw_fd(tree_fd as RawFd) };
+
+    let mount_attr = libc::mount_attr {
+        attr_set: MOUNT_ATTR_IDMAP,
+        attr_clr: 0,
+        propagation: 0,
+        userns_fd: userns_file.as_raw_fd() as u64,
+    };
+    if unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            tree_file.as_raw_fd(),
+            c"".as_ptr(),
+            libc::AT_EMPTY_PATH,
+            &mount_attr as *const libc::mount_attr,
+            std::mem::size_of::<libc::mount_attr>(),
+        )
+    } < 0
+    {
+        return Err(HelperError::last_os_error(
+            "Could not map the mount to the caller's user namespace",
+        ));
+    }
+    if unsafe {
+        libc::syscall(
+            libc::SYS_move_mount,
+            tree_file.as_raw_fd(),
+            c"".as_ptr(),
+            mount_file.as_raw_fd(),
+            c"".as_ptr(),
+            MOVE_MOUNT_F_EMPTY_PATH | MOVE_MOUNT_T_EMPTY_PATH,
+        )
+    } < 0
+    {
+        return Err(HelperError::last_os_error(
+            "Could not attach the mount to the mount point",
+        ));
+    }
+
+    let metadata = tree_file
+        .metadata()
+        .map_err(HelperError::io("Could not inspect the mounted filesystem"))?;
+    Ok((metadata.dev(), metadata.ino()))
+}
+
+fn create_staging_dir() -> Result<PathBuf, HelperError> {
+    let template = CString::new(HELPER_STAGING_TEMPLATE).expect("Staging directory template contains a NUL byte");
+    let template_ptr = template.into_raw();
+    let result = unsafe { libc::mkdtemp(template_ptr) };
+    let staging_path = unsafe { CString::from_raw(template_ptr) };
+    if result.is_null() {
+        return Err(HelperError::last_os_error("Could not create a staging mount point"));
+    }
+    Ok(PathBuf::from(OsStr::from_bytes(staging_path.as_bytes())))
+}
+
+fn get_fd_path(file: &File) -> PathBuf {
+    PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd()))
+}
+
+fn get_c_string(value: &OsStr) -> Result<CString, HelperError> {
+    CString::new(value.as_bytes()).map_err(|_| HelperError::rejected(format!("{value:?} contains a NUL byte")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::fs::MetadataExt, path::PathBuf};
+
+    use uuid::Uuid;
+
+    use crate::schema::BuildScriptLoopDevice;
+
+    use super::{get_caller_uid, get_subordinate_range, is_allowed_mount_data, HelperRequest, HelperSession};
+
+    #[test]
+    fn subordinate_ranges_are_found_by_name_or_uid() {
+        let ids_text = "# comment\nalice:100000:65536\n1001:165536:65536\n";
+        assert_eq!(
+            get_subordinate_range(ids_text, 1000, Some("alice")),
+            Some((100000, 65536))
+        );
+        assert_eq!(get_subordinate_range(ids_text, 1001, None), Some((165536, 65536)));
+        assert_eq!(get_subordinate_range(ids_text, 1002, Some("bob")), None);
+    }
+
+    #[test]
+    fn caller_is_taken_from_the_environment_only_when_launched_as_root() {
+        assert_eq!(get_caller_uid(1000, Some("0"), None), 1000);
+        assert_eq!(get_caller_uid(0, Some("1000"), None), 1000);
+        assert_eq!(get_caller_uid(0, None, Some("1001")), 1001);
+        assert_eq!(get_caller_uid(0, None, None), 0);
+    }
+
+    #[test]
+    fn only_known_mount_options_are_passed_on() {
+        assert!(is_allowed_mount_data(""));
+        assert!(is_allowed_mount_data("quiet,codepage=437"));
+        assert!(!is_allowed_mount_data("quiet,uid=0"));
+        assert!(!is_allowed_mount_data("codepage="));
+    }
+
+    #[test]
+    fn helper_rejects_paths_the_caller_does_not_own() {
+        let image_path = PathBuf::from(format!("/tmp/{}", Uuid::new_v4()));
+        std::fs::write(&image_path, "").unwrap();
+        let owner_uid = std::fs::metadata(&image_path).unwrap().uid();
+        let mut session = HelperSession {
+            caller_uid: owner_uid + 1,
+            mounts: Vec::new(),
+        };
+        let mount_request = |fstype: &str| HelperRequest::Mount {
+            image_path: image_path.clone(),
+            mount_path: PathBuf::from("/tmp"),
+            fstype: fstype.to_string(),
+            data: String::new(),
+            read_only: false,
+            loop_device: BuildScriptLoopDevice::default(),
+            userns_pid: std::process::id(),
+        };
+
+        let error = session.handle(mount_request("ext4")).unwrap_err();
+        assert!(error.message.contains("is not owned by the calling user"));
+        let error = session.handle(mount_request("proc")).unwrap_err();
+        assert!(error.message.contains("not a filesystem type"));
+        let error = session
+            .handle(HelperRequest::Unmount {
+                mount_path: PathBuf::from("/"),
+                lazy: true,
+            })
+            .unwrap_err();
+        assert!(error.message.contains("was not mounted by this helper"));
+
+        std::fs::remove_file(image_path).unwrap();
+    }
+}
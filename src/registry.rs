@@ -0,0 +1,140 @@
+use std::path::PathBuf;
+
+use bollard::auth::DockerCredentials;
+use uuid::Uuid;
+
+use crate::schema::BuildScriptContainerImage;
+
+pub async fn pull_image_directly(image: &BuildScriptContainerImage) -> PathBuf {
+    let skopeo_path = which::which("skopeo")
+        .expect("Could not locate the \"skopeo\" binary in PATH, which is needed to pull images directly");
+    let archive_path = PathBuf::from(format!("/tmp/{}.tar", Uuid::new_v4()));
+    // docker-archive references cannot carry a digest, so the archive is named after the tag alone
+    let archive_reference = match image.tag.is_empty() {
+        true => image.name.clone(),
+        false => format!("{}:{}", image.name, image.tag),
+    };
+
+    let mut command = tokio::process::Command::new(skopeo_path);
+    command.arg("copy");
+    if let Some(auth_file) = get_docker_auth_file() {
+        log::debug!("Using registry credentials from {auth_file:?}");
+        command.arg("--authfile").arg(auth_file);
+    }
+    let output = command
+        .arg(format!("docker://{}", image.full_name()))
+        .arg(format!(
+            "docker-archive:{}:{archive_reference}",
+            archive_path.to_string_lossy()
+        ))
+        .output()
+        .await
+        .expect("Could not invoke skopeo to pull image");
+
+    if !output.status.success() {
+        panic!(
+            "Could not pull image directly from its registry: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    archive_path
+}
+
+pub async fn pull_artifact_directly(reference: &str) -> PathBuf {
+    let skopeo_path = which::which("skopeo")
+        .expect("Could not locate the \"skopeo\" binary in PATH, which is needed to pull artifacts directly");
+    let layout_path = PathBuf::from(format!("/tmp/{}", Uuid::new_v4()));
+
+    let mut command = tokio::process::Command::new(skopeo_path);
+    command.arg("copy");
+    if let Some(auth_file) = get_docker_auth_file() {
+        log::debug!("Using registry credentials from {auth_file:?}");
+        command.arg("--authfile").arg(auth_file);
+    }
+    let output = command
+        .arg(format!("docker://{reference}"))
+        .arg(format!("dir:{}", layout_path.to_string_lossy()))
+        .output()
+        .await
+        .expect("Could not invoke skopeo to pull artifact");
+
+    if !output.status.success() {
+        panic!(
+            "Could not pull artifact {reference} from its registry: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    layout_path
+}
+
+pub fn get_registry_credentials(image: &BuildScriptContainerImage) -> Option<DockerCredentials> {
+    let auth_file = get_docker_auth_file()?;
+    let config = serde_json::from_str::<serde_json::Value>(&std::fs::read_to_string(&auth_file).ok()?).ok()?;
+    let registry = image.get_registry();
+    let auths = config.get("auths")?.as_object()?;
+    // the CLI stores Docker Hub under its legacy index URL, while other registries are keyed by their bare host
+    let auth = match registry.as_deref() {
+        Some(registry) => auths
+            .iter()
+            .find(|(key, _)| key.trim_start_matches("https://").trim_end_matches('/') == registry),
+        None => auths.get_key_value("https://index.docker.io/v1/"),
+    }?
+    .1
+    .get("auth")?
+    .as_str()?;
+
+    let decoded_auth = String::from_utf8(decode_base64(auth)?).ok()?;
+    let (username, password) = decoded_auth.split_once(':')?;
+    log::debug!("Using registry credentials from {auth_file:?}");
+    Some(DockerCredentials {
+        username: Some(username.to_string()),
+        password: Some(password.to_string()),
+        serveraddress: registry,
+        ..Default::default()
+    })
+}
+
+fn decode_base64(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 3 / 4);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for byte in encoded.trim_end_matches('=').bytes() {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        buffer = (buffer << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+fn get_docker_auth_file() -> Option<PathBuf> {
+    let config_dir = match std::env::var_os("DOCKER_CONFIG") {
+        Some(config_dir) => PathBuf::from(config_dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".docker"),
+    };
+    let auth_file = config_dir.join("config.json");
+    auth_file.exists().then_some(auth_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode_base64;
+
+    #[test]
+    fn registry_auth_entries_are_decoded() {
+        assert_eq!(decode_base64("bWU6c2VjcmV0").unwrap(), b"me:secret");
+        assert_eq!(decode_base64("bWU6c2VjcmV0!"), None);
+    }
+}
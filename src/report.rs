@@ -0,0 +1,116 @@
+use std::{fmt::Display, path::PathBuf};
+
+use serde::Serialize;
+
+use crate::{
+    container_engine::{ContainerChange, ContainerInspection, ExecOutcome},
+    inventory::Inventory,
+    schema::BuildScriptMetadata,
+    warnings::Warning,
+};
+
+#[derive(Serialize, Debug, Default)]
+pub struct BuildReport {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<BuildScriptMetadata>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub steps: Vec<StepReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub minimize: Option<MinimizeReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dedup: Option<DedupReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inventory: Option<Inventory>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<ImageReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failure: Option<FailureReport>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<Warning>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct StepReport {
+    pub cmd: String,
+    pub outcome: ExecOutcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub changes: Option<Vec<ContainerChange>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resources: Option<StepResources>,
+}
+
+#[derive(Serialize, Debug, Default, PartialEq, Eq)]
+pub struct StepResources {
+    pub cpu_time_ms: u64,
+    pub peak_memory_bytes: u64,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct MinimizeReport {
+    pub docs_bytes: u64,
+    pub locales_bytes: u64,
+    pub package_cache_bytes: u64,
+    pub binaries_bytes: u64,
+}
+
+impl MinimizeReport {
+    pub fn total_bytes(&self) -> u64 {
+        self.docs_bytes + self.locales_bytes + self.package_cache_bytes + self.binaries_bytes
+    }
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct ImageReport {
+    pub logical_bytes: u64,
+    pub allocated_bytes: u64,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct DedupReport {
+    pub linked_files: u64,
+    pub saved_bytes: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FailureReport {
+    pub cmd: String,
+    pub reason: String,
+    pub inspection: Option<ContainerInspection>,
+    pub logs_excerpt: String,
+}
+
+impl Display for FailureReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Command \"{}\" failed: {}", self.cmd, self.reason)?;
+
+        match self.inspection {
+            Some(ref inspection) => {
+                write!(
+                    f,
+                    "Container state: {} (exit code: {}, OOM-killed: {})",
+                    inspection.status,
+                    inspection
+                        .exit_code
+                        .map(|exit_code| exit_code.to_string())
+                        .unwrap_or_else(|| "none".to_string()),
+                    inspection.oom_killed
+                )?;
+                if let Some(ref error) = inspection.error {
+                    write!(f, ", error: {error}")?;
+                }
+                writeln!(f)?;
+            }
+            None => writeln!(f, "Container state: the container no longer exists")?,
+        }
+
+        write!(f, "Last container logs:\n{}", self.logs_excerpt)
+    }
+}
+
+pub async fn write_report(report: &BuildReport, report_path: &PathBuf) {
+    let report_json = serde_json::to_string_pretty(report).expect("Could not encode build report into JSON");
+    tokio::fs::write(report_path, report_json)
+        .await
+        .expect("Could not write build report to its path");
+    log::info!("Wrote build report to {report_path:?}");
+}
@@ -1,68 +1,1061 @@
 use std::{
-    collections::HashMap, fs::Permissions, os::unix::fs::PermissionsExt, path::PathBuf, process::Stdio, sync::Arc,
+    collections::{HashMap, HashSet},
+    fs::Permissions,
+    os::{
+        fd::AsRawFd,
+        unix::fs::{FileExt, MetadataExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Arc,
+    time::Duration,
 };
 
 use colored::Colorize;
-use sys_mount::{Mount, UnmountDrop, UnmountFlags};
-use tokio::{io::AsyncWriteExt, process::Command, task::JoinSet};
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use sys_mount::{Mount, UnmountFlags};
+use tokio::{io::AsyncWriteExt, process::Command, sync::Notify, task::JoinHandle};
 use uuid::Uuid;
 
 use crate::{
-    container_engine::{ContainerEngine, ExecParams, StreamType},
-    dry_run::{prepare_for_run, AdjoinAbsolute},
+    audit::{AuditAction, AuditLog},
+    boot::{detect_init_path, get_boot_metadata, write_boot_metadata},
+    checkpoint::{
+        begin_checkpoint, complete_command, end_checkpoint, load_run_state, save_checkpoint, RunCheckpoint, RunState,
+    },
+    cleanup::{
+        register_container, register_mount, register_path, release_container, release_mount, release_path, MountKind,
+    },
+    config::{get_effective_config, Config},
+    container_engine::{
+        quote_shell_word, CapabilitySupport, ContainerChange, ContainerEngine, ContainerInspection, ContainerStats,
+        EngineCapability, ExecParams, ExecSession, StreamType,
+    },
+    dry_run::{check_interpreters, prepare_for_run, AdjoinAbsolute, PreparedRun},
+    epilogue::{enter_phase, enter_step, record_output, BuildPhase},
+    error::BuildfsError,
+    export::{
+        check_fat_file_sizes, get_available_bytes, is_fat_filesystem, resolve_destination, resolve_entry_destination,
+        ExportCopier,
+    },
+    extract::{extract_tar, ExtractOptions},
+    file_manifest::write_file_manifest,
+    first_boot::install_first_boot,
+    fuse::{is_fuse_available, mount_fuse, unmount_fuse, FuseMount},
+    guest::{apply_guest_network, install_guest_agent},
+    inventory::{gather_inventory, write_manifest_file, Inventory},
+    loop_device::LoopDevice,
+    metadata::{augment_os_release, write_release_file},
+    minimize::{dedup_rootfs, get_tree_size, minimize_rootfs},
+    package_ignore::{load_package_ignore, prune_ignored},
+    packages::get_package_commands,
+    payload::{apply_payload_overlays, decode_payload_overlays},
+    plugin::{run_plugins, PluginState},
+    policy::{enforce_content_policy, Policy},
+    privilege::{self, mount_via_helper},
+    registry::pull_image_directly,
+    report::{write_report, BuildReport, FailureReport, ImageReport, StepReport, StepResources},
+    scheduler::JobSet,
     schema::{
-        BuildScript, BuildScriptCommand, BuildScriptExport, BuildScriptFilesystem, BuildScriptOverlay, FilesystemType,
+        BuildScript, BuildScriptCommand, BuildScriptContainer, BuildScriptContainerImage, BuildScriptExport,
+        BuildScriptFilesystem, BuildScriptGuest, BuildScriptOverlay, BuildScriptPlugin, BuildScriptReadyCheck,
+        BuildScriptStepMount, DirectPullPolicy, FilesystemType, PluginHook, ResolvConfPolicy,
     },
-    RunArgs,
+    squashfs::pack_squashfs,
+    step_mount::{attach_step_mounts, detach_step_mounts, CACHE_MOUNTS_PATH},
+    template::resolve_output_path,
+    tools::{check_tools_available, get_tool_command, ToolsConfig},
+    unmount::{unmount_rootfs, ImageMount},
+    verify::{plan_verification, verify_rootfs},
+    warnings::{WarningCollector, WarningKind},
+    FsBackend, ResumeArgs, RunArgs, RuntimeSettings,
 };
 
-pub async fn run_command(run_args: RunArgs, no_exec_logs: bool) {
-    let (build_script, container_engine, unpack_path, can_delete_unpack_path) =
-        prepare_for_run(&run_args.dry_run_args).await;
+static FAILURE_LOG_LINES: usize = 50;
+static FAILURE_LOG_CHARS: usize = 4096;
+static EARLY_EXPORT_MARKER_PATH: &str = "/.buildfs-early-export";
+static DEFAULT_COMMAND_SHELL: &str = "/bin/sh";
+static STATS_SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+static SPARSE_COPY_BUFFER_SIZE: usize = 1024 * 1024;
+static RESOLV_CONF_PATH: &str = "/etc/resolv.conf";
+
+pub async fn run_command(
+    run_args: RunArgs,
+    no_exec_logs: bool,
+    config: &Config,
+    runtime_settings: RuntimeSettings,
+) -> Result<(), BuildfsError> {
+    run_build(run_args, no_exec_logs, config, runtime_settings, None).await
+}
 
-    let (container_id, container_name, inline_mount_paths) =
-        pull_and_start_container(&container_engine, &build_script, &unpack_path).await;
+pub async fn resume_command(
+    resume_args: ResumeArgs,
+    no_exec_logs: bool,
+    config: &Config,
+    runtime_settings: RuntimeSettings,
+) -> Result<(), BuildfsError> {
+    let mut run_state = load_run_state(&resume_args.state_path).await;
+    run_state.run_args.state_path = Some(resume_args.state_path);
+    log::info!(
+        "Resuming run with ID {} from its {:?} checkpoint",
+        run_state.run_id,
+        run_state.checkpoint
+    );
 
-    run_commands_in_container(
-        &inline_mount_paths,
-        build_script.commands,
-        &container_id,
-        &container_name,
-        &container_engine,
+    run_build(
+        run_state.run_args.clone(),
         no_exec_logs,
+        config,
+        runtime_settings,
+        Some(run_state),
     )
-    .await;
+    .await
+}
 
-    let container_rootfs_path = export_and_remove_container(
-        &container_engine,
-        &container_name,
+async fn run_build(
+    mut run_args: RunArgs,
+    no_exec_logs: bool,
+    config: &Config,
+    runtime_settings: RuntimeSettings,
+    resumed_state: Option<RunState>,
+) -> Result<(), BuildfsError> {
+    let PreparedRun {
+        mut build_script,
+        container_engine,
+        unpack_path,
         can_delete_unpack_path,
-        &unpack_path,
-        inline_mount_paths,
-        build_script.container.wait_timeout_s,
-    )
-    .await;
+        mut warnings,
+        ssh_tunnel: _ssh_tunnel,
+        connection_uri,
+        policy,
+    } = prepare_for_run(&run_args.dry_run_args, config).await?;
+    if can_delete_unpack_path {
+        register_path(&unpack_path);
+    }
+
+    run_args.fs_backend = resolve_fs_backend(run_args.fs_backend, build_script.filesystem.filesystem_type);
+    if std::env::consts::OS == "macos" {
+        if !matches!(run_args.fs_backend, FsBackend::Userspace) {
+            return Err(BuildfsError::InvalidArguments(format!(
+                "The {:?} filesystem backend is not available on macOS, only the userspace backend is",
+                run_args.fs_backend
+            )));
+        }
+        let tool_names: &[&str] = match build_script.filesystem.filesystem_type {
+            FilesystemType::Squashfs => &["mksquashfs"],
+            _ => &["dd", "mkfs.ext4"],
+        };
+        check_tools_available(&config.tools, tool_names)?;
+    }
+    let engine = &build_script.container.engine;
+    let uses_early_export = build_script.commands.iter().any(|command| command.early_export);
+    if EngineCapability::ContainerDiff.support(engine) == CapabilitySupport::Unsupported
+        && (run_args.report_path.is_some() || uses_early_export)
+    {
+        warnings.warn(
+            WarningKind::EngineSpecific,
+            format!("{engine} can't diff the container's filesystem, so report steps record no changes and early export is skipped"),
+        );
+    }
+    if EngineCapability::ResourceStats.support(engine) == CapabilitySupport::Unsupported
+        && run_args.report_path.is_some()
+    {
+        warnings.warn(
+            WarningKind::EngineSpecific,
+            format!("{engine} can't report container resource statistics, so report steps record no resource usage"),
+        );
+    }
+    if run_args.age_identity.is_none() && build_script.overlays.iter().any(|overlay| overlay.payload.encrypted) {
+        return Err(BuildfsError::InvalidArguments(
+            "The build script contains encrypted overlay payloads, but no --age-identity was given to decrypt them"
+                .to_string(),
+        ));
+    }
+    match (run_args.fs_backend, build_script.filesystem.filesystem_type) {
+        (FsBackend::Userspace, FilesystemType::Ext4 | FilesystemType::Squashfs) => {}
+        (FsBackend::Fuse, FilesystemType::Ext4 | FilesystemType::Btrfs | FilesystemType::Xfs) => {}
+        (FsBackend::Userspace | FsBackend::Fuse, filesystem_type) => {
+            return Err(BuildfsError::InvalidArguments(format!(
+                "The {:?} filesystem backend does not support {filesystem_type} images",
+                run_args.fs_backend
+            )))
+        }
+        _ => {}
+    }
+
+    run_args.output_path = resolve_output_path(&run_args.output_path, &build_script);
+    run_args.copy_paths = run_args
+        .copy_paths
+        .iter()
+        .map(|copy_path| resolve_output_path(copy_path, &build_script))
+        .collect();
+    for output_path in std::iter::once(&run_args.output_path).chain(&run_args.copy_paths) {
+        if let Some(output_parent_path) = output_path.parent() {
+            tokio::fs::create_dir_all(output_parent_path)
+                .await
+                .map_err(BuildfsError::io(
+                    "Could not create parent directory tree of the output path",
+                ))?;
+        }
+    }
+    // the context directory is kept alongside the produced image, like saved command output
+    if let Some(ref mut context) = build_script.context {
+        let output_parent_path = run_args.output_path.parent().unwrap_or(Path::new(""));
+        context.host_path = Some(output_parent_path.join(context.get_host_path()));
+    }
+    if run_args.print_effective_config {
+        let effective_config = get_effective_config(
+            &run_args,
+            no_exec_logs,
+            config,
+            runtime_settings,
+            &build_script,
+            &unpack_path,
+        );
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&effective_config).expect("Could not encode effective config into JSON")
+        );
+    }
+    log::info!("Producing root filesystem at {:?}", run_args.output_path);
+
+    let mut report = BuildReport {
+        metadata: build_script.metadata.clone(),
+        ..Default::default()
+    };
+    let filesystem_type = build_script.filesystem.filesystem_type;
+    let verify_paths = run_args.verify.then(|| plan_verification(&build_script));
+    let (run_id, resumed_checkpoint) = match resumed_state {
+        Some(run_state) => (run_state.run_id, run_state.checkpoint),
+        None => (Uuid::new_v4().to_string(), RunCheckpoint::Started),
+    };
+    let audit_log = AuditLog::new(config.audit_log.clone(), run_id.clone());
+    log::info!("Starting run with ID {run_id}");
+
+    if let Some(ref state_path) = run_args.state_path {
+        begin_checkpoint(
+            state_path.clone(),
+            RunState {
+                run_id: run_id.clone(),
+                run_args: run_args.clone(),
+                checkpoint: resumed_checkpoint.clone(),
+            },
+        );
+        log::info!("Persisting run state to {state_path:?}");
+    }
 
-    let (rootfs_mount_path, unmount_drop) = init_rootfs(build_script.filesystem, &run_args, no_exec_logs).await;
+    let plugins = std::mem::take(&mut build_script.plugins);
+    let mut plugin_state = PluginState {
+        package_path: unpack_path.clone(),
+        output_path: run_args.output_path.clone(),
+        context_path: build_script.context.as_ref().map(|context| context.get_host_path()),
+        ..Default::default()
+    };
 
+    let keep_staging = run_args.keep_staging || run_args.staging_dir.is_some();
+    let mut rootfs_preparation = RootfsPreparation::spawn(
+        &build_script.filesystem,
+        &run_args,
+        no_exec_logs,
+        &config.tools,
+        &audit_log,
+    );
+    let container_phase = run_container_phase(ContainerPhase {
+        build_script: &mut build_script,
+        container_engine,
+        run_args: &run_args,
+        unpack_path: &unpack_path,
+        can_delete_unpack_path,
+        connection_uri,
+        run_id: &run_id,
+        resumed_checkpoint,
+        plugins: &plugins,
+        plugin_state: &mut plugin_state,
+        report: &mut report,
+        warnings: &warnings,
+        keep_staging,
+        no_exec_logs,
+    })
+    .await;
+    let (container_rootfs_path, inline_script_paths, inventory) =
+        rootfs_preparation.discard_on_error(container_phase).await?;
+
+    enter_phase(BuildPhase::Minimizing);
+    let content_bytes = prepare_exported_content(
+        &build_script,
+        &container_rootfs_path,
+        &plugins,
+        &mut plugin_state,
+        policy.as_ref(),
+        &mut report,
+        &mut warnings,
+    )
+    .await;
+    let content_bytes = rootfs_preparation.discard_on_error(content_bytes).await?;
+
+    enter_phase(BuildPhase::CreatingFilesystem);
+    let (rootfs_mount_path, rootfs_handle, loop_device) = rootfs_preparation.finish().await?;
+
+    enter_phase(BuildPhase::Finalizing);
+    let (payload_overlays, overlays) = build_script
+        .overlays
+        .into_iter()
+        .partition::<Vec<_>, _>(|overlay| overlay.payload.is_encoded());
+    let copy_in_space = CopyInSpace {
+        mount_path: &rootfs_mount_path,
+        filesystem: &build_script.filesystem,
+        staged: matches!(rootfs_handle, RootfsHandle::Staged),
+    };
+    let overlay_bytes = estimate_overlay_size(&overlays, &unpack_path).await;
+    check_copy_in_space(
+        &copy_in_space,
+        content_bytes + overlay_bytes,
+        "exported content and overlays",
+    )
+    .await?;
     apply_overlays_and_finalize(
-        Arc::new(container_rootfs_path),
-        Arc::new(rootfs_mount_path),
-        build_script.overlays,
+        Arc::new(container_rootfs_path.clone()),
+        Arc::new(rootfs_mount_path.clone()),
+        overlays,
         build_script.export,
-        Arc::new(unpack_path),
-        unmount_drop,
+        build_script.guest,
+        Arc::new(unpack_path.clone()),
+        &audit_log,
     )
-    .await;
+    .await?;
+    let (decoded_payloads, payload_bytes) = decode_payload_overlays(
+        payload_overlays,
+        &unpack_path,
+        run_args.age_identity.as_deref(),
+        &config.tools,
+    )
+    .await?;
+    check_copy_in_space(&copy_in_space, payload_bytes, "decoded overlay payloads").await?;
+    apply_payload_overlays(decoded_payloads, &rootfs_mount_path, &audit_log).await?;
+    install_first_boot(&build_script.first_boot, &unpack_path, &rootfs_mount_path, &audit_log).await?;
+    let init_path = detect_init_path(&rootfs_mount_path).await;
+
+    if let Some(ref metadata) = build_script.metadata {
+        write_release_file(metadata, &rootfs_mount_path, &audit_log).await?;
+        log::info!("Wrote build metadata to /etc/buildfs-release inside the filesystem");
+    }
+    augment_os_release(&run_args.provenance_args, &rootfs_mount_path, &audit_log).await?;
+    if let Some(inventory) = inventory {
+        if build_script.inventory.manifest {
+            write_manifest_file(&inventory, &rootfs_mount_path, &audit_log).await?;
+            log::info!("Wrote the package inventory to /etc/buildfs-manifest.json inside the filesystem");
+        }
+        if build_script.inventory.report {
+            report.inventory = Some(inventory);
+        }
+    }
+
+    plugin_state.mount_path = Some(rootfs_mount_path.clone());
+    run_plugins(&plugins, PluginHook::PreUnmount, &plugin_state).await?;
+    plugin_state.mount_path = None;
+    if let Some(ref manifest_path) = run_args.manifest_path {
+        write_file_manifest(&rootfs_mount_path, manifest_path, &audit_log).await?;
+    }
+    plugin_state.staging_path = None;
+
+    match rootfs_handle {
+        RootfsHandle::Mounted(unmount_drop) => {
+            enter_phase(BuildPhase::Unmounting);
+            unmount_rootfs(unmount_drop, &rootfs_mount_path, &mut warnings).await?;
+            release_mount(&rootfs_mount_path);
+            audit_log.record(AuditAction::Unmount, &rootfs_mount_path);
+            log::info!("Filesystem unmounted");
+        }
+        RootfsHandle::FuseMounted(fuse_mount) => {
+            enter_phase(BuildPhase::Unmounting);
+            unmount_fuse(fuse_mount).await?;
+            audit_log.record(AuditAction::Unmount, &rootfs_mount_path);
+            log::info!("FUSE filesystem unmounted");
+        }
+        RootfsHandle::Staged => {
+            enter_phase(BuildPhase::Packing);
+            let (filesystem, output_path) = (&build_script.filesystem, &run_args.output_path);
+            match filesystem_type {
+                FilesystemType::Squashfs => {
+                    pack_squashfs(
+                        filesystem,
+                        &rootfs_mount_path,
+                        output_path,
+                        no_exec_logs,
+                        &config.tools,
+                        &audit_log,
+                    )
+                    .await?
+                }
+                _ => {
+                    populate_ext4(
+                        filesystem,
+                        &rootfs_mount_path,
+                        output_path,
+                        no_exec_logs,
+                        &config.tools,
+                        &audit_log,
+                    )
+                    .await?
+                }
+            }
+        }
+    }
+
+    if let Some(loop_device) = loop_device {
+        audit_log.record(AuditAction::DetachLoopDevice, loop_device.device_path());
+        drop(loop_device);
+    }
+    match copy_in_space.staged {
+        true => tokio::fs::remove_dir_all(&rootfs_mount_path).await,
+        false => tokio::fs::remove_dir(&rootfs_mount_path).await,
+    }
+    .map_err(BuildfsError::io(
+        "Could not remove the filesystem mount point or staging directory",
+    ))?;
+    release_path(&rootfs_mount_path);
+
+    if let Some(verify_paths) = verify_paths {
+        enter_phase(BuildPhase::Verifying);
+        verify_rootfs(
+            &filesystem_type,
+            &run_args.output_path,
+            &container_rootfs_path,
+            verify_paths,
+            &config.tools,
+            &audit_log,
+        )
+        .await?;
+    }
+
+    let boot_metadata = get_boot_metadata(&filesystem_type, &run_args.output_path, init_path, &config.tools).await;
+    let boot_metadata_path = write_boot_metadata(&boot_metadata, &run_args.output_path, &audit_log).await?;
+    log::info!("Wrote boot metadata to {boot_metadata_path:?}");
+    report.image = Some(get_image_report(&run_args.output_path)?);
+
+    if !run_args.copy_paths.is_empty() {
+        enter_phase(BuildPhase::Copying);
+        copy_image(&run_args.output_path, &run_args.copy_paths, &audit_log).await?;
+    }
+
+    release_path(&run_args.output_path);
+    release_path(&container_rootfs_path);
+    if keep_staging {
+        retain_staging(
+            &run_id,
+            run_args.staging_dir.as_ref(),
+            container_rootfs_path,
+            inline_script_paths,
+        )
+        .await?;
+    } else {
+        tokio::fs::remove_dir_all(&container_rootfs_path)
+            .await
+            .map_err(BuildfsError::io(
+                "Could not clean up unneeded container rootfs directory",
+            ))?;
+    }
+    log::info!("Root filesystem creation finished normally");
+
+    run_plugins(&plugins, PluginHook::PostBuild, &plugin_state).await?;
+
+    warnings.surface(run_args.dry_run_args.json_warnings);
+    report.warnings = warnings.warnings().to_vec();
+
+    if let Some(ref report_path) = run_args.report_path {
+        write_report(&report, report_path).await;
+    }
+
+    end_checkpoint();
+    Ok(())
+}
+
+struct ContainerPhase<'a> {
+    build_script: &'a mut BuildScript,
+    container_engine: Box<dyn ContainerEngine>,
+    run_args: &'a RunArgs,
+    unpack_path: &'a PathBuf,
+    can_delete_unpack_path: bool,
+    connection_uri: Option<String>,
+    run_id: &'a str,
+    resumed_checkpoint: RunCheckpoint,
+    plugins: &'a [BuildScriptPlugin],
+    plugin_state: &'a mut PluginState,
+    report: &'a mut BuildReport,
+    warnings: &'a WarningCollector,
+    keep_staging: bool,
+    no_exec_logs: bool,
+}
+
+async fn run_container_phase(
+    container_phase: ContainerPhase<'_>,
+) -> Result<(PathBuf, Vec<PathBuf>, Option<Inventory>), BuildfsError> {
+    let ContainerPhase {
+        build_script,
+        container_engine,
+        run_args,
+        unpack_path,
+        can_delete_unpack_path,
+        connection_uri,
+        run_id,
+        resumed_checkpoint,
+        plugins,
+        plugin_state,
+        report,
+        warnings,
+        keep_staging,
+        no_exec_logs,
+    } = container_phase;
+    let mut inventory = None;
+    let (container_rootfs_path, inline_script_paths) =
+        if let RunCheckpoint::Exported { staging_path } = resumed_checkpoint {
+            log::info!("Resuming from the exported container rootfs at {staging_path:?}");
+            if build_script.inventory.is_enabled() {
+                log::warn!("The container is gone after resuming from its export, so no inventory will be recorded");
+            }
+            (staging_path, Vec::new())
+        } else {
+            let (container_id, container_name, inline_mount_paths, completed_commands) = match resumed_checkpoint {
+                RunCheckpoint::RunningCommands {
+                    container_id,
+                    container_name,
+                    inline_mount_paths,
+                    completed_commands,
+                } => {
+                    let inspection = container_engine.inspect_container(&container_name).await;
+                    if !inspection.is_some_and(|inspection| inspection.running) {
+                        return Err(BuildfsError::Resume(format!(
+                            "Could not resume run {run_id}: container {container_name} is no longer running"
+                        )));
+                    }
+                    log::info!(
+                        "Reconnected to container {container_name}, skipping {completed_commands} completed command(s)"
+                    );
+                    (container_id, container_name, inline_mount_paths, completed_commands)
+                }
+                _ => {
+                    enter_phase(BuildPhase::StartingContainer);
+                    let (container_id, container_name, inline_mount_paths) = pull_and_start_container(
+                        &container_engine,
+                        build_script,
+                        unpack_path,
+                        run_args.dry_run_args.offline,
+                    )
+                    .await?;
+                    (container_id, container_name, inline_mount_paths, 0)
+                }
+            };
+            register_container(&build_script.container, connection_uri, &container_name);
+            for (host_path, _) in inline_mount_paths.values() {
+                register_path(host_path);
+            }
+            save_checkpoint(RunCheckpoint::RunningCommands {
+                container_id: container_id.clone(),
+                container_name: container_name.clone(),
+                inline_mount_paths: inline_mount_paths.clone(),
+                completed_commands,
+            });
+
+            plugin_state.container_id = Some(container_id.clone());
+            plugin_state.container_name = Some(container_name.clone());
+            if completed_commands == 0 {
+                check_interpreters(build_script, container_engine.as_ref(), &container_id, &container_name).await?;
+                run_plugins(plugins, PluginHook::PostStart, plugin_state).await?;
+            }
+
+            // package installs run as the first commands, so they are checkpointed and resumed like any other
+            let package_commands = get_package_commands(
+                &build_script.packages,
+                container_engine.as_ref(),
+                &container_id,
+                &container_name,
+            )
+            .await?;
+
+            enter_phase(BuildPhase::RunningCommands);
+            // output files are kept alongside the produced image
+            let output_parent_path = run_args.output_path.parent().unwrap_or(Path::new("")).to_path_buf();
+            let mut commands = package_commands
+                .into_iter()
+                .chain(std::mem::take(&mut build_script.commands))
+                .skip(completed_commands)
+                .map(|mut command| {
+                    command.env = get_effective_env(&build_script.container.env, command.env);
+                    command.save_output_to = command
+                        .save_output_to
+                        .map(|save_output_to| output_parent_path.join(save_output_to));
+                    command
+                })
+                .collect::<Vec<_>>();
+            let command_mounts = CommandMounts::new(&inline_mount_paths, &build_script.container);
+            let late_commands = match commands.iter().position(|command| command.early_export) {
+                Some(index) => commands.split_off(index + 1),
+                None => Vec::new(),
+            };
+            let mut failure = run_commands_in_container(
+                &command_mounts,
+                commands,
+                &container_id,
+                &container_name,
+                &container_engine,
+                no_exec_logs,
+                run_args.report_path.as_ref().map(|_| &mut *report),
+            )
+            .await?;
+
+            let mut early_export_path = None;
+            if failure.is_none() && !late_commands.is_empty() {
+                match start_early_export(container_engine.as_ref(), &container_id, &container_name).await? {
+                    Some(changes_before) => {
+                        let (container_rootfs_path, late_failure) = tokio::join!(
+                            export_container_rootfs(container_engine.as_ref(), &container_name, keep_staging),
+                            run_commands_in_container(
+                                &command_mounts,
+                                late_commands,
+                                &container_id,
+                                &container_name,
+                                &container_engine,
+                                no_exec_logs,
+                                run_args.report_path.as_ref().map(|_| &mut *report),
+                            )
+                        );
+                        let container_rootfs_path = container_rootfs_path?;
+                        failure = late_failure?;
+                        let _ = tokio::fs::remove_file(
+                            container_rootfs_path.join(EARLY_EXPORT_MARKER_PATH.trim_start_matches('/')),
+                        )
+                        .await;
+
+                        if failure.is_none()
+                            && is_early_export_current(
+                                container_engine.as_ref(),
+                                &container_id,
+                                &container_name,
+                                &build_script.export,
+                                changes_before,
+                            )
+                            .await?
+                        {
+                            early_export_path = Some(container_rootfs_path);
+                        } else {
+                            tokio::fs::remove_dir_all(&container_rootfs_path)
+                                .await
+                                .map_err(BuildfsError::io(
+                                    "Could not remove stale early export of the container rootfs",
+                                ))?;
+                            release_path(&container_rootfs_path);
+                        }
+                    }
+                    None => {
+                        failure = run_commands_in_container(
+                            &command_mounts,
+                            late_commands,
+                            &container_id,
+                            &container_name,
+                            &container_engine,
+                            no_exec_logs,
+                            run_args.report_path.as_ref().map(|_| &mut *report),
+                        )
+                        .await?;
+                    }
+                }
+            }
+
+            if let Some(failure) = failure {
+                let message = failure.to_string();
+                remove_container_and_clean_up(
+                    container_engine.as_ref(),
+                    &container_name,
+                    can_delete_unpack_path,
+                    unpack_path,
+                    inline_mount_paths,
+                    &build_script.container,
+                    keep_staging,
+                )
+                .await?;
+                end_checkpoint();
+                report.failure = Some(failure);
+                report.warnings = warnings.warnings().to_vec();
+                if let Some(ref report_path) = run_args.report_path {
+                    write_report(report, report_path).await;
+                }
+                return Err(BuildfsError::CommandFailed(message));
+            }
+
+            run_plugins(plugins, PluginHook::PostCommands, plugin_state).await?;
+
+            if build_script.inventory.is_enabled() {
+                inventory = Some(gather_inventory(container_engine.as_ref(), &container_id, &container_name).await?);
+            }
+
+            if build_script.container.attach_to.is_some() {
+                remove_uploaded_scripts(container_engine.as_ref(), &container_id, &container_name).await?;
+            }
+            if let Some(commit_image) = build_script.post_build.get_commit_image() {
+                enter_phase(BuildPhase::CommittingImage);
+                commit_and_push_image(
+                    container_engine.as_ref(),
+                    &container_name,
+                    &commit_image,
+                    build_script.post_build.push,
+                )
+                .await?;
+            }
+
+            enter_phase(BuildPhase::ExportingContainer);
+            let container_rootfs_path = match early_export_path {
+                Some(container_rootfs_path) => {
+                    log::info!("Reusing the early export of the container rootfs");
+                    remove_container_and_clean_up(
+                        container_engine.as_ref(),
+                        &container_name,
+                        can_delete_unpack_path,
+                        unpack_path,
+                        inline_mount_paths.clone(),
+                        &build_script.container,
+                        keep_staging,
+                    )
+                    .await?;
+                    container_rootfs_path
+                }
+                None => {
+                    export_and_remove_container(
+                        &container_engine,
+                        &container_name,
+                        can_delete_unpack_path,
+                        unpack_path,
+                        inline_mount_paths.clone(),
+                        &build_script.container,
+                        keep_staging,
+                    )
+                    .await?
+                }
+            };
+            save_checkpoint(RunCheckpoint::Exported {
+                staging_path: container_rootfs_path.clone(),
+            });
+            (
+                container_rootfs_path,
+                inline_mount_paths
+                    .into_values()
+                    .map(|(host_path, _)| host_path)
+                    .collect::<Vec<_>>(),
+            )
+        };
+
+    Ok((container_rootfs_path, inline_script_paths, inventory))
+}
+
+async fn prepare_exported_content(
+    build_script: &BuildScript,
+    container_rootfs_path: &Path,
+    plugins: &[BuildScriptPlugin],
+    plugin_state: &mut PluginState,
+    policy: Option<&Policy>,
+    report: &mut BuildReport,
+    warnings: &mut WarningCollector,
+) -> Result<u64, BuildfsError> {
+    if build_script.minimize.is_enabled() {
+        let minimize_report = minimize_rootfs(build_script.minimize.clone(), container_rootfs_path).await?;
+        log::info!(
+            "Minimized container rootfs, saving {} MiB in total",
+            minimize_report.total_bytes() / 1024 / 1024
+        );
+        report.minimize = Some(minimize_report);
+    }
+
+    if let Some(dedup) = build_script.minimize.dedup.clone() {
+        let dedup_report = dedup_rootfs(dedup, container_rootfs_path).await?;
+        log::info!(
+            "Deduplicated {} file(s) into hardlinks, saving {} MiB",
+            dedup_report.linked_files,
+            dedup_report.saved_bytes / 1024 / 1024
+        );
+        report.dedup = Some(dedup_report);
+    }
+
+    plugin_state.container_id = None;
+    plugin_state.container_name = None;
+    plugin_state.staging_path = Some(container_rootfs_path.to_path_buf());
+    run_plugins(plugins, PluginHook::PostExport, plugin_state).await?;
+
+    if let Some(policy) = policy {
+        enforce_content_policy(policy, container_rootfs_path, &build_script.export).await?;
+    }
+
+    let content_bytes = estimate_export_size(container_rootfs_path, &build_script.export).await;
+    if content_bytes > build_script.filesystem.size_mib as u64 * 1024 * 1024 {
+        warnings.warn(
+            WarningKind::SuspectValue,
+            format!(
+                "Exported content takes up {} MiB, which exceeds the filesystem size of {} MiB",
+                content_bytes / 1024 / 1024,
+                build_script.filesystem.size_mib
+            ),
+        );
+    }
+
+    Ok(content_bytes)
+}
+
+async fn copy_image(output_path: &Path, copy_paths: &[PathBuf], audit_log: &AuditLog) -> Result<(), BuildfsError> {
+    let mut job_set = JobSet::new();
+    for copy_path in copy_paths {
+        audit_log.record(AuditAction::CopyImage, copy_path);
+        let (output_path, copy_path) = (output_path.to_path_buf(), copy_path.clone());
+        job_set.spawn_blocking(move || {
+            let reflinked = reflink_file(&output_path, &copy_path);
+            if !reflinked {
+                copy_sparse(&output_path, &copy_path).map_err(BuildfsError::io("Could not copy the image"))?;
+            }
+            log::info!(
+                "{} the image to {copy_path:?}",
+                if reflinked { "Reflinked" } else { "Copied" }
+            );
+            Ok(())
+        });
+    }
+
+    while let Some(result) = job_set.join_next().await {
+        result.expect("Could not join on blocking I/O task")?;
+    }
+
+    Ok(())
+}
+
+fn reflink_file(source_path: &Path, destination_path: &Path) -> bool {
+    let (Ok(source_file), Ok(destination_file)) = (
+        std::fs::File::open(source_path),
+        std::fs::File::create(destination_path),
+    ) else {
+        return false;
+    };
+
+    // cloning only works within one reflink-capable filesystem (Btrfs, XFS), so failing here just means a full copy
+    unsafe { libc::ioctl(destination_file.as_raw_fd(), libc::FICLONE, source_file.as_raw_fd()) == 0 }
+}
+
+fn copy_sparse(source_path: &Path, destination_path: &Path) -> std::io::Result<()> {
+    let source_file = std::fs::File::open(source_path)?;
+    let destination_file = std::fs::File::create(destination_path)?;
+    let length = source_file.metadata()?.len();
+    destination_file.set_len(length)?;
+
+    // only the data extents are copied, so the holes of a mostly-empty image stay holes in the copy
+    let mut buffer = vec![0; SPARSE_COPY_BUFFER_SIZE];
+    let mut offset = 0;
+    while offset < length {
+        let data_offset = unsafe { libc::lseek(source_file.as_raw_fd(), offset as i64, libc::SEEK_DATA) };
+        if data_offset < 0 {
+            match std::io::Error::last_os_error().raw_os_error() {
+                Some(libc::ENXIO) => break,
+                // filesystems without hole reporting get a full copy of the remainder
+                Some(libc::EINVAL) => return copy_range(&source_file, &destination_file, offset, length, &mut buffer),
+                _ => return Err(std::io::Error::last_os_error()),
+            }
+        }
+
+        let hole_offset = unsafe { libc::lseek(source_file.as_raw_fd(), data_offset, libc::SEEK_HOLE) };
+        if hole_offset < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        copy_range(
+            &source_file,
+            &destination_file,
+            data_offset as u64,
+            hole_offset as u64,
+            &mut buffer,
+        )?;
+        offset = hole_offset as u64;
+    }
+
+    Ok(())
+}
+
+fn copy_range(
+    source_file: &std::fs::File,
+    destination_file: &std::fs::File,
+    start: u64,
+    end: u64,
+    buffer: &mut [u8],
+) -> std::io::Result<()> {
+    let mut offset = start;
+    while offset < end {
+        let chunk_length = buffer.len().min((end - offset) as usize);
+        let read_length = source_file.read_at(&mut buffer[..chunk_length], offset)?;
+        if read_length == 0 {
+            break;
+        }
+        destination_file.write_all_at(&buffer[..read_length], offset)?;
+        offset += read_length as u64;
+    }
+
+    Ok(())
+}
+
+fn get_image_report(output_path: &Path) -> Result<ImageReport, BuildfsError> {
+    let metadata =
+        std::fs::metadata(output_path).map_err(BuildfsError::io("Could not read metadata of the produced image"))?;
+    let image_report = ImageReport {
+        logical_bytes: metadata.len(),
+        allocated_bytes: metadata.blocks() * 512,
+    };
+    log::info!(
+        "The image is {} MiB in size, of which {} MiB are allocated",
+        image_report.logical_bytes / 1024 / 1024,
+        image_report.allocated_bytes / 1024 / 1024
+    );
+
+    Ok(image_report)
+}
+
+async fn retain_staging(
+    run_id: &str,
+    staging_dir: Option<&PathBuf>,
+    container_rootfs_path: PathBuf,
+    inline_script_paths: Vec<PathBuf>,
+) -> Result<(), BuildfsError> {
+    let mut retained_paths = vec![
+        (
+            "container rootfs tarball",
+            container_rootfs_path.with_extension("tar"),
+            PathBuf::from("rootfs.tar"),
+        ),
+        ("staging tree", container_rootfs_path, PathBuf::from("rootfs")),
+    ];
+    retained_paths.extend(inline_script_paths.into_iter().map(|path| {
+        let retained_name =
+            PathBuf::from("scripts").join(path.file_name().expect("Inline script path has no file name"));
+        ("inline script", path, retained_name)
+    }));
+
+    if let Some(staging_dir) = staging_dir {
+        let run_staging_path = staging_dir.join(run_id);
+        tokio::fs::create_dir_all(run_staging_path.join("scripts"))
+            .await
+            .map_err(BuildfsError::io("Could not create staging retention directory"))?;
+
+        let mv_path = which::which("mv")
+            .map_err(|err| BuildfsError::Filesystem(format!("Could not locate \"mv\" binary in PATH: {err}")))?;
+        for (_, path, retained_name) in &mut retained_paths {
+            let destination_path = run_staging_path.join(&retained_name);
+            let exit_status = Command::new(&mv_path)
+                .arg(&path)
+                .arg(&destination_path)
+                .status()
+                .await
+                .map_err(BuildfsError::io(
+                    "Could not fork \"mv\" to move a retained staging artifact",
+                ))?;
+            if !exit_status.success() {
+                return Err(BuildfsError::Filesystem(format!(
+                    "\"mv\" exited with non-zero exit status: {exit_status}"
+                )));
+            }
+            *path = destination_path;
+        }
+    }
+
+    for (label, path, _) in retained_paths {
+        log::info!("Retained {label} at {path:?}");
+    }
+
+    Ok(())
+}
+
+async fn estimate_export_size(container_rootfs_path: &Path, export: &BuildScriptExport) -> u64 {
+    let export_paths = export
+        .directories
+        .include
+        .iter()
+        .chain(export.files.include.iter())
+        .map(|path| container_rootfs_path.to_path_buf().adjoin_absolute(path))
+        .collect::<Vec<_>>();
+
+    tokio::task::spawn_blocking(move || export_paths.iter().map(|path| get_tree_size(path)).sum())
+        .await
+        .expect("Join on blocking task failed")
+}
+
+async fn estimate_overlay_size(overlays: &[BuildScriptOverlay], unpack_path: &Path) -> u64 {
+    let inline_bytes = overlays
+        .iter()
+        .filter_map(|overlay| overlay.source_inline.as_ref())
+        .map(|source_inline| source_inline.len() as u64)
+        .sum::<u64>();
+    let source_paths = overlays
+        .iter()
+        .filter_map(|overlay| overlay.source.as_ref())
+        .map(|source_path| unpack_path.to_path_buf().adjoin_absolute(source_path))
+        .collect::<Vec<_>>();
+
+    let source_bytes =
+        tokio::task::spawn_blocking(move || source_paths.iter().map(|path| get_tree_size(path)).sum::<u64>())
+            .await
+            .expect("Join on blocking task failed");
+    inline_bytes + source_bytes
+}
+
+struct CopyInSpace<'a> {
+    mount_path: &'a PathBuf,
+    filesystem: &'a BuildScriptFilesystem,
+    staged: bool,
+}
+
+async fn check_copy_in_space(
+    copy_in_space: &CopyInSpace<'_>,
+    needed_bytes: u64,
+    batch: &str,
+) -> Result<(), BuildfsError> {
+    // squashfs compresses its contents, so the uncompressed size says nothing about whether they fit
+    if needed_bytes == 0 || matches!(copy_in_space.filesystem.filesystem_type, FilesystemType::Squashfs) {
+        return Ok(());
+    }
+
+    let mount_path = copy_in_space.mount_path.clone();
+    let size_bytes = copy_in_space.filesystem.size_mib as u64 * 1024 * 1024;
+    let staged = copy_in_space.staged;
+    // staged contents are only formatted into the image later, so their budget is the image size minus what's staged
+    let available_bytes = tokio::task::spawn_blocking(move || match staged {
+        true => Some(size_bytes.saturating_sub(get_tree_size(&mount_path))),
+        false => get_available_bytes(&mount_path),
+    })
+    .await
+    .expect("Join on blocking task failed");
+    let Some(available_bytes) = available_bytes else {
+        log::warn!("Could not determine the free space in the filesystem, so it isn't checked before copying {batch}");
+        return Ok(());
+    };
+
+    if needed_bytes > available_bytes {
+        return Err(BuildfsError::Filesystem(format!(
+            "The image is too small for the {batch}: needed ~{} MiB more than the {} MiB still free in the {} MiB filesystem",
+            (needed_bytes - available_bytes).div_ceil(1024 * 1024),
+            available_bytes / 1024 / 1024,
+            copy_in_space.filesystem.size_mib
+        )));
+    }
+    log::debug!(
+        "The {batch} take up ~{} MiB of the {} MiB still free in the filesystem",
+        needed_bytes / 1024 / 1024,
+        available_bytes / 1024 / 1024
+    );
+
+    Ok(())
 }
 
 async fn pull_and_start_container(
     container_engine: &Box<dyn ContainerEngine>,
     build_script: &BuildScript,
     unpack_path: &PathBuf,
-) -> (String, String, HashMap<String, (PathBuf, PathBuf)>) {
-    container_engine.pull_image(&build_script.container.image).await;
-    log::info!("Pulled image: {}", build_script.container.image.full_name());
+    offline: bool,
+) -> Result<(String, String, HashMap<String, (PathBuf, PathBuf)>), BuildfsError> {
+    // offline runs have already checked that the image is available locally
+    let mut container = build_script.container.clone();
+    if container.attach_to.is_none() && !offline {
+        container.image = pull_image(container_engine.as_ref(), &build_script.container).await?;
+    }
 
     let base_script_path = PathBuf::from("/__scripts");
     let mut volumes = build_script
@@ -80,17 +1073,26 @@ async fn pull_and_start_container(
 
     for command in &build_script.commands {
         if let Some(ref script) = command.script_inline {
+            // identical scripts share one file, since everything else that differs between their commands is exec-time
+            let script_contents = get_inline_script_contents(script, command.interpreter.as_deref());
+            if inline_mount_paths.contains_key(&script_contents) {
+                continue;
+            }
+
             let host_path = get_tmp_path();
-            let mount_path = base_script_path.join(Uuid::new_v4().to_string());
-            tokio::fs::write(&host_path, script)
+            register_path(&host_path);
+            let mount_path = base_script_path.join(format!("inline-{}", get_inline_script_key(&script_contents)));
+            tokio::fs::write(&host_path, &script_contents)
                 .await
-                .expect("Could not write inline script to a bind-mounted host path");
-            tokio::fs::set_permissions(&host_path, Permissions::from_mode(0o111))
+                .map_err(BuildfsError::io(
+                    "Could not write inline script to a bind-mounted host path",
+                ))?;
+            tokio::fs::set_permissions(&host_path, Permissions::from_mode(0o555))
                 .await
-                .expect("Could not make inline script file executable");
+                .map_err(BuildfsError::io("Could not make inline script file executable"))?;
 
             volumes.insert(host_path.clone(), mount_path.clone());
-            inline_mount_paths.insert(script.clone(), (host_path, mount_path));
+            inline_mount_paths.insert(script_contents, (host_path, mount_path));
         }
     }
 
@@ -98,9 +1100,12 @@ async fn pull_and_start_container(
         if overlay.mounted {
             if let Some(ref source_inline) = overlay.source_inline {
                 let source_path = get_tmp_path();
+                register_path(&source_path);
                 tokio::fs::write(&source_path, source_inline)
                     .await
-                    .expect("Could not write inline pre overlay to a bind-mounted host path");
+                    .map_err(BuildfsError::io(
+                        "Could not write inline pre overlay to a bind-mounted host path",
+                    ))?;
                 volumes.insert(source_path, overlay.destination.clone());
             } else if let Some(ref source_path) = overlay.source {
                 volumes.insert(unpack_path.adjoin_absolute(source_path), overlay.destination.clone());
@@ -108,31 +1113,261 @@ async fn pull_and_start_container(
         }
     }
 
+    // caches stay mounted out of the way for the container's lifetime and are only linked into place for their steps
+    for (name, cache) in &build_script.container.caches {
+        tokio::fs::create_dir_all(&cache.source)
+            .await
+            .map_err(BuildfsError::io("Could not create host directory of a cache"))?;
+        volumes.insert(cache.source.clone(), PathBuf::from(CACHE_MOUNTS_PATH).join(name));
+    }
+
+    // the context directory is shared by every command and stays on the host after the run for hooks to pick up
+    if let Some(ref context) = build_script.context {
+        let host_path = context.get_host_path();
+        tokio::fs::create_dir_all(&host_path)
+            .await
+            .map_err(BuildfsError::io("Could not create host directory of the build context"))?;
+        log::info!(
+            "Sharing build context directory {host_path:?} at {:?}",
+            context.get_destination()
+        );
+        volumes.insert(host_path, context.get_destination());
+    }
+
     log::debug!("Resolved container volumes to: {volumes:?}");
 
-    let (container_id, container_name) = container_engine
-        .start_container(build_script.container.clone(), volumes)
-        .await;
-    log::info!("Created and started container with name {container_name} and ID {container_id}");
+    // attached and remote containers cannot see host paths, so the files are uploaded instead of bind-mounted
+    let upload_volumes = build_script.container.attach_to.is_some() || build_script.container.is_remote();
+    let (container_id, container_name) = match build_script.container.attach_to {
+        Some(ref target) => {
+            let (container_id, container_name) = container_engine.resolve_attach_target(target).await?;
+            log::info!("Attached to existing container with name {container_name} and ID {container_id}");
+            (container_id, container_name)
+        }
+        None => {
+            let (container_id, container_name) = container_engine
+                .start_container(
+                    container,
+                    if upload_volumes {
+                        HashMap::new()
+                    } else {
+                        volumes.clone()
+                    },
+                )
+                .await?;
+            log::info!("Created and started container with name {container_name} and ID {container_id}");
+            (container_id, container_name)
+        }
+    };
+
+    if upload_volumes {
+        for (host_path, container_path) in &volumes {
+            container_engine
+                .upload_file(&container_name, host_path, container_path)
+                .await?;
+        }
+        log::info!("Uploaded {} file(s) into the container", volumes.len());
+    }
+
+    if let Some(ref ready_check) = build_script.container.ready_check {
+        wait_until_ready(
+            container_engine.as_ref(),
+            &container_id,
+            &container_name,
+            ready_check,
+            &build_script.container.env,
+        )
+        .await?;
+        log::info!("Container passed its readiness check");
+    }
+
+    Ok((container_id, container_name, inline_mount_paths))
+}
+
+pub async fn pull_image(
+    container_engine: &dyn ContainerEngine,
+    container: &BuildScriptContainer,
+) -> Result<BuildScriptContainerImage, BuildfsError> {
+    let image = &container.image;
+    if container.direct_pull != DirectPullPolicy::Always {
+        let mut errors = Vec::new();
+        for candidate in image.get_pull_candidates() {
+            let result = match image.pull_timeout_s {
+                Some(pull_timeout_s) => tokio::time::timeout(
+                    Duration::from_secs(pull_timeout_s),
+                    container_engine.pull_image(&candidate),
+                )
+                .await
+                .unwrap_or_else(|_| {
+                    Err(BuildfsError::Engine {
+                        context: "Could not pull image",
+                        message: format!("timed out after {pull_timeout_s}s"),
+                    })
+                }),
+                None => container_engine.pull_image(&candidate).await,
+            };
+
+            match result {
+                Ok(()) => {
+                    log::info!("Pulled image: {}", candidate.full_name());
+                    return Ok(candidate);
+                }
+                Err(err) => {
+                    log::warn!(
+                        "Could not pull {} via the container engine: {err}",
+                        candidate.full_name()
+                    );
+                    errors.push(format!("{}: {err}", candidate.full_name()));
+                }
+            }
+        }
+
+        if container.direct_pull == DirectPullPolicy::Never {
+            return Err(BuildfsError::Engine {
+                context: "Could not pull image via the container engine",
+                message: errors.join("; "),
+            });
+        }
+        log::warn!("Could not pull image via the container engine, falling back to a direct pull");
+    }
+
+    let archive_path = pull_image_directly(image).await;
+    container_engine.load_image(&archive_path).await?;
+    tokio::fs::remove_file(&archive_path)
+        .await
+        .map_err(BuildfsError::io("Could not remove temporary image archive"))?;
+    log::info!(
+        "Pulled image directly and loaded it into the container engine: {}",
+        image.full_name()
+    );
+    Ok(image.clone())
+}
+
+fn get_effective_env(
+    container_env: &HashMap<String, String>,
+    command_env: HashMap<String, String>,
+) -> HashMap<String, String> {
+    // exec-ed processes aren't guaranteed to see the container's env on every engine, so it's passed explicitly
+    let mut effective_env = container_env.clone();
+    effective_env.extend(command_env);
+    effective_env
+}
 
-    (container_id, container_name, inline_mount_paths)
+fn get_inline_script_contents(script: &str, interpreter: Option<&str>) -> String {
+    match interpreter {
+        Some(interpreter) => format!("{}\n{script}", get_shebang(interpreter)),
+        None => script.to_string(),
+    }
+}
+
+fn get_inline_script_key(script_contents: &str) -> String {
+    Sha256::digest(script_contents.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+fn get_shebang(interpreter: &str) -> String {
+    match interpreter.starts_with('/') {
+        true => format!("#!{interpreter}"),
+        false => format!("#!/usr/bin/env {interpreter}"),
+    }
+}
+
+async fn wait_until_ready(
+    container_engine: &dyn ContainerEngine,
+    container_id: &str,
+    container_name: &str,
+    ready_check: &BuildScriptReadyCheck,
+    container_env: &HashMap<String, String>,
+) -> Result<(), BuildfsError> {
+    let retries = ready_check.retries.unwrap_or(10);
+    let interval = Duration::from_secs(ready_check.interval_s.unwrap_or(1));
+
+    for attempt in 0..=retries {
+        let mut exec_session = ExecSession::new(
+            container_engine
+                .exec_in_container(ExecParams {
+                    container_name,
+                    container_id,
+                    cmd: ready_check.command.clone(),
+                    shell: None,
+                    uid: None,
+                    gid: None,
+                    working_dir: None,
+                    privileged: None,
+                    env: get_effective_env(container_env, HashMap::new()),
+                })
+                .await?,
+        );
+        while let Some((output, _)) = exec_session.read().await {
+            log::trace!("Readiness check output: {}", output.trim_end());
+        }
+
+        let exit_code = exec_session.finish(container_engine).await?.exit_code;
+        if exit_code == Some(0) {
+            return Ok(());
+        }
+
+        log::debug!("Readiness check attempt {attempt} failed with exit code {exit_code:?}");
+        if attempt < retries {
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    Err(BuildfsError::Engine {
+        context: "Container readiness check failed",
+        message: format!(
+            "\"{}\" did not succeed after {} attempt(s)",
+            ready_check.command,
+            retries + 1
+        ),
+    })
+}
+
+struct CommandMounts<'a> {
+    inline_scripts: &'a HashMap<String, (PathBuf, PathBuf)>,
+    caches: &'a HashMap<String, BuildScriptStepMount>,
+    secrets: &'a HashMap<String, BuildScriptStepMount>,
+}
+
+impl<'a> CommandMounts<'a> {
+    fn new(inline_scripts: &'a HashMap<String, (PathBuf, PathBuf)>, container: &'a BuildScriptContainer) -> Self {
+        Self {
+            inline_scripts,
+            caches: &container.caches,
+            secrets: &container.secrets,
+        }
+    }
 }
 
 async fn run_commands_in_container(
-    inline_mount_paths: &HashMap<String, (PathBuf, PathBuf)>,
+    command_mounts: &CommandMounts<'_>,
     commands: Vec<BuildScriptCommand>,
     container_id: &str,
     container_name: &str,
     container_engine: &Box<dyn ContainerEngine>,
     no_exec_logs: bool,
-) {
+    mut report: Option<&mut BuildReport>,
+) -> Result<Option<FailureReport>, BuildfsError> {
     let base_script_path = PathBuf::from("/__scripts");
+    let track_changes =
+        EngineCapability::ContainerDiff.support(&container_engine.engine_type()) == CapabilitySupport::Supported;
 
     for command in commands {
+        let expect_output_regex = command
+            .expect_output_regex
+            .map(|pattern| Regex::new(&pattern))
+            .transpose()
+            .map_err(|err| BuildfsError::Validation(format!("Could not compile expected output regex: {err}")))?;
+        let save_output_to = command.save_output_to;
+        let capture_output = expect_output_regex.is_some() || save_output_to.is_some();
+        let allow_failure = command.allow_failure;
         let mut exec_params = ExecParams {
             container_name,
             container_id,
             cmd: "".to_string(),
+            shell: None,
             uid: command.uid,
             gid: command.gid,
             working_dir: command.working_dir,
@@ -143,40 +1378,393 @@ async fn run_commands_in_container(
         if let Some(command_text) = command.command {
             log::info!("Exec-ing simple command inside container: \"{command_text}\"");
             exec_params.cmd = command_text;
+            exec_params.shell = match command.shell {
+                Some(shell) if shell.is_empty() => None,
+                Some(shell) => Some(shell),
+                None => Some(DEFAULT_COMMAND_SHELL.to_string()),
+            };
         }
 
         if let Some(script_path) = command.script_path {
             let actual_script_path = base_script_path.adjoin_absolute(&script_path);
             log::info!("Exec-ing script inside container that is bind-mounted into: {actual_script_path:?}");
-            exec_params.cmd = actual_script_path.to_string_lossy().to_string();
+            exec_params.cmd = match command.interpreter {
+                Some(ref interpreter) => format!("{interpreter} {}", actual_script_path.to_string_lossy()),
+                None => actual_script_path.to_string_lossy().to_string(),
+            };
         }
 
         if let Some(script) = command.script_inline {
-            let (_, inline_script_path) = inline_mount_paths
-                .get(&script)
+            let (_, inline_script_path) = command_mounts
+                .inline_scripts
+                .get(&get_inline_script_contents(&script, command.interpreter.as_deref()))
                 .expect("Could not resolve expectedly inserted mount path of an inlined script");
             log::info!("Exec-ing inline script inside container that is bind-mounted into: {inline_script_path:?}");
             exec_params.cmd = inline_script_path.to_string_lossy().to_string();
         }
 
-        let mut exec_reader = container_engine.exec_in_container(exec_params).await;
-        while let Some((mut output, stream_type)) = exec_reader.read().await {
-            if !no_exec_logs && !output.trim().is_empty() {
-                let prefix = match stream_type {
-                    StreamType::Stdout => "stdout".green(),
-                    StreamType::Stdin => "stdin".blue(),
-                    StreamType::Stderr => "stderr".red(),
-                    StreamType::Unknown => "unknown".bright_black(),
-                };
-
-                if !output.ends_with('\n') {
-                    output.push('\n');
+        let cmd = exec_params.cmd.clone();
+        enter_step(&cmd);
+        let attached_step_mounts = attach_step_mounts(
+            container_engine.as_ref(),
+            container_id,
+            container_name,
+            command_mounts.caches,
+            command_mounts.secrets,
+            &command.mounts,
+        )
+        .await?;
+        let stats_before = match report {
+            Some(_) => container_engine.container_stats(container_name).await,
+            None => None,
+        };
+        // the diff API reports every change since the container started, so it's snapshotted before each step
+        let changes_before = match report {
+            Some(_) if track_changes => Some(
+                container_engine
+                    .diff_container(container_name)
+                    .await?
+                    .into_iter()
+                    .collect::<HashSet<_>>(),
+            ),
+            _ => None,
+        };
+        let exec_done = Notify::new();
+        let mut captured_output = String::new();
+        let mut exec_session = ExecSession::new(container_engine.exec_in_container(exec_params).await?);
+        let (_, peak_memory_bytes) = tokio::join!(
+            async {
+                while let Some((mut output, stream_type)) = exec_session.read().await {
+                    record_output(&output);
+                    captured_output.push_str(&output);
+                    // without a regex or output file, only the tail is kept for a failure report
+                    if !capture_output {
+                        captured_output = get_logs_excerpt(std::mem::take(&mut captured_output));
+                    }
+                    if !no_exec_logs && !output.trim().is_empty() {
+                        let prefix = match stream_type {
+                            StreamType::Stdout => "stdout".green(),
+                            StreamType::Stdin => "stdin".blue(),
+                            StreamType::Stderr => "stderr".red(),
+                            StreamType::Unknown => "unknown".bright_black(),
+                        };
+
+                        if !output.ends_with('\n') {
+                            output.push('\n');
+                        }
+
+                        print!("{prefix}: {output}");
+                    }
+                }
+                exec_done.notify_one();
+            },
+            sample_peak_memory(container_engine.as_ref(), container_name, stats_before, &exec_done)
+        );
+        let outcome = exec_session.finish(container_engine.as_ref()).await?;
+        match outcome.exit_code {
+            Some(0) => log::debug!(
+                "Command exited after {} ms, writing {} byte(s) to stdout and {} byte(s) to stderr",
+                outcome.duration_ms,
+                outcome.stdout_bytes,
+                outcome.stderr_bytes
+            ),
+            exit_code if allow_failure => log::warn!(
+                "Command \"{cmd}\" exited with code {exit_code:?} after {} ms, which its allow_failure permits",
+                outcome.duration_ms
+            ),
+            exit_code => log::error!(
+                "Command \"{cmd}\" exited with code {exit_code:?} after {} ms",
+                outcome.duration_ms
+            ),
+        }
+        let exit_code = outcome.exit_code;
+
+        let inspection = container_engine.inspect_container(container_name).await;
+        if !inspection.as_ref().is_some_and(|inspection| inspection.running) {
+            return Ok(Some(
+                gather_failure(
+                    container_engine.as_ref(),
+                    container_name,
+                    cmd,
+                    "The container stopped running unexpectedly",
+                    inspection,
+                )
+                .await,
+            ));
+        }
+        detach_step_mounts(
+            container_engine.as_ref(),
+            container_id,
+            container_name,
+            attached_step_mounts,
+        )
+        .await?;
+
+        if let Some(ref mut report) = report {
+            let changes = match changes_before {
+                Some(changes_before) => {
+                    let changes = container_engine
+                        .diff_container(container_name)
+                        .await?
+                        .into_iter()
+                        .filter(|change| !changes_before.contains(change))
+                        .collect::<Vec<_>>();
+                    log::debug!("Command modified {} path(s) inside the container", changes.len());
+                    Some(changes)
                 }
+                None => None,
+            };
+            let resources = get_step_resources(
+                stats_before,
+                container_engine.container_stats(container_name).await,
+                peak_memory_bytes,
+            );
+            report.steps.push(StepReport {
+                cmd: cmd.clone(),
+                outcome,
+                changes,
+                resources,
+            });
+        }
 
-                print!("{prefix}: {output}");
+        if let Some(save_output_to) = save_output_to {
+            if let Some(save_output_parent) = save_output_to.parent() {
+                tokio::fs::create_dir_all(save_output_parent)
+                    .await
+                    .map_err(BuildfsError::io(
+                        "Could not create parent directory tree of the command output file",
+                    ))?;
+            }
+            tokio::fs::write(&save_output_to, &captured_output)
+                .await
+                .map_err(BuildfsError::io("Could not write command output file"))?;
+            log::info!("Saved command output to {save_output_to:?}");
+        }
+
+        if exit_code != Some(0) && !allow_failure {
+            let reason = match exit_code {
+                Some(exit_code) => format!("The command exited with code {exit_code}"),
+                None => "The command's exit code could not be determined".to_string(),
+            };
+            return Ok(Some(FailureReport {
+                cmd,
+                reason,
+                inspection,
+                logs_excerpt: get_logs_excerpt(captured_output),
+            }));
+        }
+
+        if let Some(expect_output_regex) = expect_output_regex {
+            if !expect_output_regex.is_match(&captured_output) {
+                return Ok(Some(FailureReport {
+                    cmd,
+                    reason: format!("The command output did not match the expected regex \"{expect_output_regex}\""),
+                    inspection,
+                    logs_excerpt: get_logs_excerpt(captured_output),
+                }));
             }
         }
+
+        complete_command();
     }
+
+    Ok(None)
+}
+
+async fn sample_peak_memory(
+    container_engine: &dyn ContainerEngine,
+    container_name: &str,
+    stats_before: Option<ContainerStats>,
+    exec_done: &Notify,
+) -> Option<u64> {
+    // engines that can't report stats aren't polled during the step at all
+    let mut peak_memory_bytes = stats_before?.memory_bytes;
+    loop {
+        tokio::select! {
+            _ = exec_done.notified() => return Some(peak_memory_bytes),
+            _ = tokio::time::sleep(STATS_SAMPLE_INTERVAL) => {}
+        }
+
+        if let Some(stats) = container_engine.container_stats(container_name).await {
+            peak_memory_bytes = peak_memory_bytes.max(stats.memory_bytes);
+        }
+    }
+}
+
+fn get_step_resources(
+    stats_before: Option<ContainerStats>,
+    stats_after: Option<ContainerStats>,
+    peak_memory_bytes: Option<u64>,
+) -> Option<StepResources> {
+    let (stats_before, stats_after) = (stats_before?, stats_after?);
+    let resources = StepResources {
+        cpu_time_ms: stats_after.cpu_usage_ns.saturating_sub(stats_before.cpu_usage_ns) / 1_000_000,
+        peak_memory_bytes: peak_memory_bytes.unwrap_or_default().max(stats_after.memory_bytes),
+    };
+    log::debug!(
+        "Command used {} ms of CPU time and peaked at {} MiB of memory",
+        resources.cpu_time_ms,
+        resources.peak_memory_bytes / 1024 / 1024
+    );
+
+    Some(resources)
+}
+
+async fn gather_failure(
+    container_engine: &dyn ContainerEngine,
+    container_name: &str,
+    cmd: String,
+    reason: &str,
+    inspection: Option<ContainerInspection>,
+) -> FailureReport {
+    let logs = container_engine.container_logs(container_name, FAILURE_LOG_LINES).await;
+
+    FailureReport {
+        cmd,
+        reason: reason.to_string(),
+        inspection,
+        logs_excerpt: get_logs_excerpt(logs),
+    }
+}
+
+fn get_logs_excerpt(logs: String) -> String {
+    match logs.char_indices().rev().nth(FAILURE_LOG_CHARS) {
+        Some((index, _)) => logs[index..].to_string(),
+        None => logs,
+    }
+}
+
+pub async fn commit_and_push_image(
+    container_engine: &dyn ContainerEngine,
+    container_name: &str,
+    image: &BuildScriptContainerImage,
+    push: bool,
+) -> Result<(), BuildfsError> {
+    container_engine.commit_container(container_name, image).await?;
+    log::info!("Committed the container into image {}", image.full_name());
+
+    if push {
+        container_engine.push_image(image).await?;
+        log::info!("Pushed image {}", image.full_name());
+    }
+
+    Ok(())
+}
+
+async fn remove_uploaded_scripts(
+    container_engine: &dyn ContainerEngine,
+    container_id: &str,
+    container_name: &str,
+) -> Result<(), BuildfsError> {
+    let (exit_code, _) = exec_and_collect(container_engine, container_id, container_name, "rm -rf /__scripts").await?;
+    if exit_code != Some(0) {
+        log::warn!("Could not remove uploaded scripts from the attached container");
+    }
+
+    Ok(())
+}
+
+pub async fn exec_and_collect(
+    container_engine: &dyn ContainerEngine,
+    container_id: &str,
+    container_name: &str,
+    cmd: &str,
+) -> Result<(Option<i64>, String), BuildfsError> {
+    let mut exec_session = ExecSession::new(
+        container_engine
+            .exec_in_container(ExecParams {
+                container_name,
+                container_id,
+                cmd: cmd.to_string(),
+                shell: None,
+                uid: None,
+                gid: None,
+                working_dir: None,
+                privileged: None,
+                env: HashMap::new(),
+            })
+            .await?,
+    );
+    let mut stdout = String::new();
+    while let Some((output, stream_type)) = exec_session.read().await {
+        if let StreamType::Stdout = stream_type {
+            stdout.push_str(&output);
+        }
+    }
+
+    Ok((exec_session.finish(container_engine).await?.exit_code, stdout))
+}
+
+async fn start_early_export(
+    container_engine: &dyn ContainerEngine,
+    container_id: &str,
+    container_name: &str,
+) -> Result<Option<HashSet<ContainerChange>>, BuildfsError> {
+    // without a diff, paths changed by the remaining commands can't be told apart from an up-to-date early export
+    if EngineCapability::ContainerDiff.support(&container_engine.engine_type()) != CapabilitySupport::Supported {
+        log::warn!("Not exporting early, since the container engine can't diff the container's filesystem");
+        return Ok(None);
+    }
+
+    let touch_cmd = format!("touch {EARLY_EXPORT_MARKER_PATH}");
+    if exec_and_collect(container_engine, container_id, container_name, &touch_cmd)
+        .await?
+        .0
+        != Some(0)
+    {
+        log::warn!("Not exporting early, since the early export marker could not be created in the container");
+        return Ok(None);
+    }
+
+    log::info!("Exporting the container early, alongside the remaining commands");
+    Ok(Some(
+        container_engine
+            .diff_container(container_name)
+            .await?
+            .into_iter()
+            .collect(),
+    ))
+}
+
+async fn is_early_export_current(
+    container_engine: &dyn ContainerEngine,
+    container_id: &str,
+    container_name: &str,
+    export: &BuildScriptExport,
+    changes_before: HashSet<ContainerChange>,
+) -> Result<bool, BuildfsError> {
+    let late_changes = container_engine
+        .diff_container(container_name)
+        .await?
+        .into_iter()
+        .filter(|change| !changes_before.contains(change))
+        .filter(|change| get_export_include_paths(export).any(|path| change.path.starts_with(path)))
+        .count();
+
+    // a path changed both before and after the early export looks the same to the diff API, so mtimes are checked too
+    let find_cmd = format!(
+        "find {} -newer {EARLY_EXPORT_MARKER_PATH}",
+        get_export_include_paths(export)
+            .map(|path| quote_shell_word(&path.to_string_lossy()))
+            .collect::<Vec<_>>()
+            .join(" ")
+    );
+    let (exit_code, newer_paths) = exec_and_collect(container_engine, container_id, container_name, &find_cmd).await?;
+    let rm_cmd = format!("rm -f {EARLY_EXPORT_MARKER_PATH}");
+    exec_and_collect(container_engine, container_id, container_name, &rm_cmd).await?;
+
+    if late_changes > 0 || exit_code != Some(0) || !newer_paths.trim().is_empty() {
+        log::warn!(
+            "Exported paths changed after the early export started ({late_changes} reported by the diff API), exporting the container again"
+        );
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+fn get_export_include_paths(export: &BuildScriptExport) -> impl Iterator<Item = &PathBuf> {
+    export.directories.include.iter().chain(export.files.include.iter())
 }
 
 async fn export_and_remove_container(
@@ -185,59 +1773,208 @@ async fn export_and_remove_container(
     can_delete_unpack_path: bool,
     unpack_path: &PathBuf,
     inline_mount_paths: HashMap<String, (PathBuf, PathBuf)>,
-    wait_timeout: Option<u64>,
-) -> PathBuf {
-    let container_rootfs_tar_path = get_tmp_path();
+    container: &BuildScriptContainer,
+    keep_staging: bool,
+) -> Result<PathBuf, BuildfsError> {
+    let container_rootfs_path =
+        export_container_rootfs(container_engine.as_ref(), container_name, keep_staging).await?;
+    remove_container_and_clean_up(
+        container_engine.as_ref(),
+        container_name,
+        can_delete_unpack_path,
+        unpack_path,
+        inline_mount_paths,
+        container,
+        keep_staging,
+    )
+    .await?;
+
+    Ok(container_rootfs_path)
+}
+
+async fn export_container_rootfs(
+    container_engine: &dyn ContainerEngine,
+    container_name: &str,
+    keep_staging: bool,
+) -> Result<PathBuf, BuildfsError> {
     let container_rootfs_path = get_tmp_path();
+    let container_rootfs_tar_path = container_rootfs_path.with_extension("tar");
+    register_path(&container_rootfs_path);
+    register_path(&container_rootfs_tar_path);
     container_engine
         .export_container(&container_name, &container_rootfs_tar_path)
-        .await;
+        .await?;
     log::info!("Export of container rootfs finished into tarball located at {container_rootfs_tar_path:?}");
 
     let container_rootfs_path_clone = container_rootfs_path.clone();
     tokio::task::spawn_blocking(move || {
-        let rootfs_tar_file =
-            std::fs::File::open(&container_rootfs_tar_path).expect("Could not open rootfs tarball file");
-        let mut archive = tar::Archive::new(rootfs_tar_file);
-        archive
-            .unpack(&container_rootfs_path_clone)
-            .expect("Could not unpack rootfs tarball");
-        drop(archive);
-
-        std::fs::remove_file(&container_rootfs_tar_path).expect("Could not remove rootfs tarball");
+        let rootfs_tar_file = std::fs::File::open(&container_rootfs_tar_path)
+            .map_err(BuildfsError::io("Could not open rootfs tarball file"))?;
+        // images legitimately contain absolute symlinks, but nothing may be unpacked through one
+        let extract_options = ExtractOptions {
+            confine_symlinks: false,
+            same_filesystem: true,
+        };
+        extract_tar(rootfs_tar_file, &container_rootfs_path_clone, extract_options)
+            .map_err(|err| BuildfsError::Filesystem(format!("Could not unpack rootfs tarball: {err}")))?;
+
+        if !keep_staging {
+            std::fs::remove_file(&container_rootfs_tar_path)
+                .map_err(BuildfsError::io("Could not remove rootfs tarball"))?;
+        }
+        release_path(&container_rootfs_tar_path);
         log::info!("Unpacked container rootfs from tarball into {container_rootfs_path_clone:?}");
+        Ok::<_, BuildfsError>(())
     })
     .await
-    .expect("Could not join on blocking task");
+    .expect("Could not join on blocking task")?;
+
+    Ok(container_rootfs_path)
+}
 
-    container_engine.remove_container(&container_name, wait_timeout).await;
-    log::info!("Stopped and removed container");
+async fn remove_container_and_clean_up(
+    container_engine: &dyn ContainerEngine,
+    container_name: &str,
+    can_delete_unpack_path: bool,
+    unpack_path: &PathBuf,
+    inline_mount_paths: HashMap<String, (PathBuf, PathBuf)>,
+    container: &BuildScriptContainer,
+    keep_staging: bool,
+) -> Result<(), BuildfsError> {
+    if container.attach_to.is_some() {
+        log::info!("Left the attached container running");
+    } else {
+        container_engine
+            .remove_container(&container_name, container.wait_timeout_s)
+            .await?;
+        log::info!("Stopped and removed container");
+    }
+    release_container();
 
-    let mut cleanup_join_set = JoinSet::new();
+    let mut cleanup_job_set = JobSet::new();
     for (_, (host_path, _)) in inline_mount_paths {
-        cleanup_join_set.spawn_blocking(move || std::fs::remove_file(host_path));
+        release_path(&host_path);
+        if !keep_staging {
+            cleanup_job_set.spawn_blocking(move || std::fs::remove_file(host_path));
+        }
     }
 
-    if can_delete_unpack_path {
-        let unpack_path = unpack_path.clone();
-        cleanup_join_set.spawn_blocking(move || std::fs::remove_dir_all(unpack_path));
+    if can_delete_unpack_path {
+        release_path(unpack_path);
+        let unpack_path = unpack_path.clone();
+        cleanup_job_set.spawn_blocking(move || std::fs::remove_dir_all(unpack_path));
+    }
+
+    while let Some(result) = cleanup_job_set.join_next().await {
+        result
+            .expect("Could not join on a set of blocking tasks intended for removing files/directories")
+            .map_err(BuildfsError::io("Could not cleanup a path"))?;
+    }
+
+    log::info!("Cleaned up all temporary resources");
+    Ok(())
+}
+
+enum RootfsHandle {
+    Mounted(ImageMount),
+    FuseMounted(FuseMount),
+    Staged,
+}
+
+type PreparedRootfs = (PathBuf, RootfsHandle, Option<LoopDevice>);
+
+// creating and formatting the image doesn't depend on the container, so it runs alongside the container phase
+struct RootfsPreparation {
+    task: JoinHandle<Result<PreparedRootfs, BuildfsError>>,
+    output_path: PathBuf,
+}
+
+impl RootfsPreparation {
+    fn spawn(
+        filesystem: &BuildScriptFilesystem,
+        run_args: &RunArgs,
+        no_exec_logs: bool,
+        tools: &ToolsConfig,
+        audit_log: &AuditLog,
+    ) -> Self {
+        // registered before the task starts, so an interrupted run never leaves a half-written image behind
+        register_path(&run_args.output_path);
+        let (filesystem, run_args, tools, audit_log) =
+            (filesystem.clone(), run_args.clone(), tools.clone(), audit_log.clone());
+
+        Self {
+            output_path: run_args.output_path.clone(),
+            task: tokio::spawn(
+                async move { init_rootfs(&filesystem, &run_args, no_exec_logs, &tools, &audit_log).await },
+            ),
+        }
+    }
+
+    async fn finish(&mut self) -> Result<PreparedRootfs, BuildfsError> {
+        match (&mut self.task).await {
+            Ok(output) => output,
+            Err(err) => std::panic::resume_unwind(err.into_panic()),
+        }
+    }
+
+    async fn discard(&mut self) {
+        self.task.abort();
+        if let Ok(Ok((rootfs_mount_path, rootfs_handle, loop_device))) = (&mut self.task).await {
+            let staged = matches!(rootfs_handle, RootfsHandle::Staged);
+            match rootfs_handle {
+                RootfsHandle::Mounted(image_mount) => drop(image_mount),
+                RootfsHandle::FuseMounted(fuse_mount) => {
+                    if let Err(err) = unmount_fuse(fuse_mount).await {
+                        log::warn!("Could not unmount the discarded FUSE rootfs: {err}");
+                    }
+                }
+                RootfsHandle::Staged => {}
+            }
+            drop(loop_device);
+            release_mount(&rootfs_mount_path);
+            let _ = match staged {
+                true => tokio::fs::remove_dir_all(&rootfs_mount_path).await,
+                false => tokio::fs::remove_dir(&rootfs_mount_path).await,
+            };
+            release_path(&rootfs_mount_path);
+        }
+
+        let _ = tokio::fs::remove_file(&self.output_path).await;
+        release_path(&self.output_path);
+        log::info!("Discarded the partially prepared filesystem at {:?}", self.output_path);
     }
 
-    while let Some(result) = cleanup_join_set.join_next().await {
+    async fn discard_on_error<T>(&mut self, result: Result<T, BuildfsError>) -> Result<T, BuildfsError> {
+        if result.is_err() {
+            self.discard().await;
+        }
         result
-            .expect("Could not join on a set of blocking tasks intended for removing files/directories")
-            .expect("Could not cleanup a path");
     }
+}
 
-    log::info!("Cleaned up all temporary resources");
-    container_rootfs_path
+impl Drop for RootfsPreparation {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
 }
 
 async fn init_rootfs(
-    filesystem: BuildScriptFilesystem,
+    filesystem: &BuildScriptFilesystem,
     run_args: &RunArgs,
     no_exec_logs: bool,
-) -> (PathBuf, UnmountDrop<Mount>) {
+    tools: &ToolsConfig,
+    audit_log: &AuditLog,
+) -> Result<PreparedRootfs, BuildfsError> {
+    // squashfs images are read-only, so they are staged in a directory and packed once finalized
+    if let FilesystemType::Squashfs = filesystem.filesystem_type {
+        get_tool_command(tools, "mksquashfs", &[])?;
+        return Ok((
+            create_staging_path(run_args, audit_log).await?,
+            RootfsHandle::Staged,
+            None,
+        ));
+    }
+
     let dd_block_size_mib = match filesystem.block_size_mib {
         Some(mib) => mib,
         None => 1,
@@ -250,13 +1987,10 @@ async fn init_rootfs(
         FilesystemType::Vfat => "mkfs.vfat",
         FilesystemType::Xfs => "mkfs.xfs",
     };
-    let mkfs_path = which::which(mkfs_name).expect("Could not locate appropriate mkfs binary in PATH");
-    log::debug!("Located appropriate \"mkfs\" binary at: {mkfs_path:?}");
+    let output_parent_path = run_args.output_path.parent().unwrap_or(Path::new("."));
+    let mut mkfs_command = get_tool_command(tools, mkfs_name, &[output_parent_path])?;
 
-    let dd_path = which::which("dd").expect("Could not locate \"dd\" binary in PATH");
-    log::debug!("Located \"dd\" binary at: {dd_path:?}");
-
-    let mut dd_command = Command::new(dd_path);
+    let mut dd_command = get_tool_command(tools, "dd", &[output_parent_path])?;
     let rootfs_mount_path = get_tmp_path();
     dd_command.arg("if=/dev/zero");
     dd_command.arg(format!("of={}", run_args.output_path.to_string_lossy()));
@@ -266,48 +2000,179 @@ async fn init_rootfs(
         dd_command.stdout(Stdio::null());
         dd_command.stderr(Stdio::null());
     }
-    dd_command.args(filesystem.dd_args);
+    dd_command.args(&filesystem.dd_args);
+    audit_log.record_with_args(
+        AuditAction::CreateImage,
+        &run_args.output_path,
+        &get_command_args(&dd_command),
+    );
 
-    let dd_exit_status = dd_command.status().await.expect("Failed to fork \"dd\" process");
+    let dd_exit_status = dd_command
+        .status()
+        .await
+        .map_err(BuildfsError::io("Failed to fork \"dd\" process"))?;
 
     if !dd_exit_status.success() {
-        panic!("\"dd\" invocation failed with exit status: {dd_exit_status}");
+        return Err(BuildfsError::Filesystem(format!(
+            "\"dd\" invocation failed with exit status: {dd_exit_status}"
+        )));
+    }
+
+    // the userspace backend formats and populates the image from a staging directory in one go once finalized
+    if let FsBackend::Userspace = run_args.fs_backend {
+        return Ok((
+            create_staging_path(run_args, audit_log).await?,
+            RootfsHandle::Staged,
+            None,
+        ));
     }
 
-    let mut mkfs_command = Command::new(mkfs_path);
     mkfs_command.arg(run_args.output_path.to_string_lossy().to_string());
     if no_exec_logs {
         mkfs_command.stdout(Stdio::null());
         mkfs_command.stderr(Stdio::null());
     }
-    mkfs_command.args(filesystem.mkfs_args);
+    if let Some(ref vfat) = filesystem.vfat {
+        if let Some(ref label) = vfat.label {
+            mkfs_command.arg("-n").arg(label);
+        }
+        if let Some(codepage) = vfat.codepage {
+            mkfs_command.arg(format!("--codepage={codepage}"));
+        }
+    }
+    mkfs_command.args(&filesystem.mkfs_args);
+    audit_log.record_with_args(
+        AuditAction::MakeFilesystem,
+        &run_args.output_path,
+        &get_command_args(&mkfs_command),
+    );
 
-    let mkfs_exit_status = mkfs_command.status().await.expect("Failed to fork \"mkfs\" process");
+    let mkfs_exit_status = mkfs_command
+        .status()
+        .await
+        .map_err(BuildfsError::io("Failed to fork \"mkfs\" process"))?;
 
     if !mkfs_exit_status.success() {
-        panic!("\"mkfs\" invocation failed with exit status: {mkfs_exit_status}");
+        return Err(BuildfsError::Filesystem(format!(
+            "\"mkfs\" invocation failed with exit status: {mkfs_exit_status}"
+        )));
     }
 
     tokio::fs::create_dir(&rootfs_mount_path)
         .await
-        .expect("Could not create filesystem mount point directory");
+        .map_err(BuildfsError::io("Could not create filesystem mount point directory"))?;
+    register_path(&rootfs_mount_path);
+    audit_log.record(AuditAction::CreateDirectory, &rootfs_mount_path);
+
+    if let FsBackend::Fuse = run_args.fs_backend {
+        let fuse_mount = mount_fuse(
+            tools,
+            &filesystem.filesystem_type,
+            &run_args.output_path,
+            &rootfs_mount_path,
+        )
+        .await?;
+        audit_log.record_with_args(
+            AuditAction::Mount,
+            &rootfs_mount_path,
+            &[run_args.output_path.to_string_lossy().to_string(), "fuse".to_string()],
+        );
+        return Ok((rootfs_mount_path, RootfsHandle::FuseMounted(fuse_mount), None));
+    }
+
+    let mount_data = get_mount_data(filesystem);
+    // inside the user namespace of --privilege-helper, the helper attaches the loop device and mounts on its own
+    if privilege::is_helper_connected() {
+        let helper_mount = mount_via_helper(
+            &run_args.output_path,
+            &rootfs_mount_path,
+            get_mount_fstype(&filesystem.filesystem_type),
+            &mount_data,
+            false,
+            filesystem.loop_device.as_ref(),
+        )?;
+        audit_log.record_with_args(
+            AuditAction::Mount,
+            &rootfs_mount_path,
+            &[run_args.output_path.to_string_lossy().to_string(), "helper".to_string()],
+        );
+        return Ok((
+            rootfs_mount_path,
+            RootfsHandle::Mounted(ImageMount::Helper(helper_mount)),
+            None,
+        ));
+    }
+
+    let loop_device = match filesystem.loop_device {
+        Some(ref loop_device_options) => {
+            let output_path = run_args.output_path.clone();
+            let loop_device_options = loop_device_options.clone();
+            let loop_device =
+                tokio::task::spawn_blocking(move || LoopDevice::attach(&output_path, &loop_device_options))
+                    .await
+                    .expect("Join on blocking task failed")
+                    .map_err(BuildfsError::io(
+                        "Could not attach the filesystem image to a loop device",
+                    ))?;
+            audit_log.record_with_args(
+                AuditAction::AttachLoopDevice,
+                loop_device.device_path(),
+                &[run_args.output_path.to_string_lossy().to_string()],
+            );
+            Some(loop_device)
+        }
+        None => None,
+    };
+    let mount_source = match loop_device {
+        Some(ref loop_device) => loop_device.device_path().to_path_buf(),
+        None => run_args.output_path.clone(),
+    };
+
     let unmount_drop = Mount::builder()
-        .fstype(match filesystem.filesystem_type {
-            FilesystemType::Ext4 => "ext4",
-            FilesystemType::Btrfs => "btrfs",
-            FilesystemType::Squashfs => "squashfs",
-            FilesystemType::Vfat => "vfat",
-            FilesystemType::Xfs => "xfs",
-        })
-        .mount_autodrop(&run_args.output_path, &rootfs_mount_path, UnmountFlags::empty())
-        .expect("Could not mount rootfs");
+        .fstype(get_mount_fstype(&filesystem.filesystem_type))
+        .data(&mount_data)
+        .mount_autodrop(&mount_source, &rootfs_mount_path, UnmountFlags::empty())
+        .map_err(BuildfsError::io("Could not mount rootfs"))?;
+    register_mount(&rootfs_mount_path, MountKind::Kernel);
+    audit_log.record_with_args(
+        AuditAction::Mount,
+        &rootfs_mount_path,
+        &[mount_source.to_string_lossy().to_string()],
+    );
 
     log::info!(
         "Created the filesystem at {:?} with mount at {rootfs_mount_path:?}",
         run_args.output_path
     );
 
-    (rootfs_mount_path, unmount_drop)
+    Ok((
+        rootfs_mount_path,
+        RootfsHandle::Mounted(ImageMount::Kernel(unmount_drop)),
+        loop_device,
+    ))
+}
+
+fn resolve_fs_backend(fs_backend: FsBackend, filesystem_type: FilesystemType) -> FsBackend {
+    let FsBackend::Auto = fs_backend else {
+        return fs_backend;
+    };
+
+    // macOS can't mount Linux filesystems at all, so images are formatted from a staging directory by the (possibly
+    // containerized) mkfs tools, which Docker Desktop or Colima run inside their Linux VM
+    let resolved_fs_backend = if std::env::consts::OS == "macos" {
+        FsBackend::Userspace
+    } else if unsafe { libc::geteuid() } == 0 {
+        FsBackend::Kernel
+    } else {
+        match filesystem_type {
+            FilesystemType::Ext4 | FilesystemType::Squashfs => FsBackend::Userspace,
+            FilesystemType::Btrfs | FilesystemType::Xfs if is_fuse_available() => FsBackend::Fuse,
+            _ => FsBackend::Kernel,
+        }
+    };
+    log::info!("Selected the {resolved_fs_backend:?} filesystem backend");
+
+    resolved_fs_backend
 }
 
 async fn apply_overlays_and_finalize(
@@ -315,151 +2180,1128 @@ async fn apply_overlays_and_finalize(
     destination_path: Arc<PathBuf>,
     overlays: Vec<BuildScriptOverlay>,
     export: BuildScriptExport,
+    guest: BuildScriptGuest,
     unpack_path: Arc<PathBuf>,
-    unmount_drop: UnmountDrop<Mount>,
-) {
+    audit_log: &AuditLog,
+) -> Result<(), BuildfsError> {
+    if is_fat_filesystem(&destination_path) {
+        let host_paths = export
+            .directories
+            .include
+            .iter()
+            .chain(&export.files.include)
+            .map(|path| source_path.adjoin_absolute(path))
+            .chain(
+                overlays
+                    .iter()
+                    .filter_map(|overlay| overlay.source.as_ref())
+                    .map(|path| unpack_path.adjoin_absolute(path)),
+            )
+            .collect::<Vec<_>>();
+        tokio::task::spawn_blocking(move || check_fat_file_sizes(host_paths))
+            .await
+            .expect("Join on blocking task failed")?;
+        log::info!("Checked exported file sizes against the FAT file size limit");
+    }
+
     apply_overlays(
         overlays.iter().filter(|overlay| !overlay.mounted).cloned().collect(),
         unpack_path.clone(),
         destination_path.clone(),
+        audit_log,
     )
-    .await;
+    .await?;
 
     log::info!("Applied non-mounted overlays to the mounted filesystem");
 
-    let mut join_set = JoinSet::new();
+    let mut job_set = JobSet::new();
+    let (symlinks, fail_on_dangling_symlinks) = (export.symlinks, export.fail_on_dangling_symlinks);
+    let ownership = Arc::new(export.ownership);
 
     for dir_path in export.directories.include {
-        let (source_path, destination_path) = (source_path.clone(), destination_path.clone());
-        join_set.spawn(async move {
-            let mut command = Command::new(which::which("cp").expect("Could not locate \"cp\" binary in PATH"));
-            command.arg("-r");
-            command.arg("-p");
-            command.arg(source_path.adjoin_absolute(&dir_path));
-            command.arg(destination_path.adjoin_absolute(&dir_path).parent().unwrap());
-            let exit_status = command
-                .status()
-                .await
-                .expect("Could not fork \"cp\" to perform recursive copy");
-
-            if !exit_status.success() {
-                panic!("\"cp\" exited with non-zero exit status: {exit_status}");
-            }
+        let (source_path, destination_path, ownership) =
+            (source_path.clone(), destination_path.clone(), ownership.clone());
+        audit_log.record(
+            AuditAction::CopyIntoFilesystem,
+            &destination_path.adjoin_absolute(&dir_path),
+        );
+        job_set.spawn_blocking(move || {
+            let destination_parent_path = resolve_destination(&destination_path, dir_path.parent().unwrap())?;
+            std::fs::create_dir_all(&destination_parent_path).map_err(BuildfsError::io(
+                "Could not create parent directory tree for export-included directory",
+            ))?;
+
+            ExportCopier::new(
+                source_path.to_path_buf(),
+                destination_path.to_path_buf(),
+                symlinks,
+                fail_on_dangling_symlinks,
+                ownership,
+            )
+            .copy(&dir_path)
         });
     }
 
     for dir_path in export.directories.create {
         let destination_path = destination_path.clone();
-        join_set.spawn_blocking(move || {
-            std::fs::create_dir_all(destination_path.adjoin_absolute(&dir_path))
-                .expect("Could not create directory tree for export-created directory")
+        audit_log.record(
+            AuditAction::CreateDirectory,
+            &destination_path.adjoin_absolute(&dir_path),
+        );
+        job_set.spawn_blocking(move || {
+            std::fs::create_dir_all(resolve_destination(&destination_path, &dir_path)?).map_err(BuildfsError::io(
+                "Could not create directory tree for export-created directory",
+            ))
         });
     }
 
     for file_path in export.files.include {
-        let (source_path, destination_path) = (source_path.clone(), destination_path.clone());
-        join_set.spawn_blocking(move || {
+        let (source_path, destination_path, ownership) =
+            (source_path.clone(), destination_path.clone(), ownership.clone());
+        audit_log.record(
+            AuditAction::CopyIntoFilesystem,
+            &destination_path.adjoin_absolute(&file_path),
+        );
+        job_set.spawn_blocking(move || {
             if let Some(parent_path) = file_path.parent() {
-                std::fs::create_dir_all(destination_path.adjoin_absolute(parent_path))
-                    .expect("Could not create parent directory tree for export-included file");
+                std::fs::create_dir_all(resolve_destination(&destination_path, parent_path)?).map_err(
+                    BuildfsError::io("Could not create parent directory tree for export-included file"),
+                )?;
             }
 
-            std::fs::copy(
-                source_path.adjoin_absolute(&file_path),
-                destination_path.adjoin_absolute(&file_path),
+            ExportCopier::new(
+                source_path.to_path_buf(),
+                destination_path.to_path_buf(),
+                symlinks,
+                fail_on_dangling_symlinks,
+                ownership,
             )
-            .expect("Could not move export-included file to destination");
+            .copy(&file_path)
         });
     }
 
     for file_path in export.files.create {
         let destination_path = destination_path.clone();
-        join_set.spawn_blocking(move || {
+        audit_log.record(AuditAction::WriteFile, &destination_path.adjoin_absolute(&file_path));
+        job_set.spawn_blocking(move || {
             if let Some(parent_path) = file_path.parent() {
-                std::fs::create_dir_all(destination_path.adjoin_absolute(parent_path))
-                    .expect("Could not create parent directory tree for export-created file");
+                std::fs::create_dir_all(resolve_destination(&destination_path, parent_path)?).map_err(
+                    BuildfsError::io("Could not create parent directory tree for export-created file"),
+                )?;
             }
 
-            std::fs::File::create_new(destination_path.adjoin_absolute(&file_path))
-                .expect("Could not create export-created file");
+            std::fs::File::create_new(resolve_entry_destination(&destination_path, &file_path)?)
+                .map_err(BuildfsError::io("Could not create export-created file"))?;
+            Ok(())
         });
     }
 
     log::info!(
-        "Spawned {} threads for exporting into the mounted filesystem",
-        join_set.len()
+        "Scheduled {} job(s) for exporting into the mounted filesystem",
+        job_set.len()
     );
 
-    while let Some(result) = join_set.join_next().await {
-        result.expect("Could not join on blocking I/O task");
+    while let Some(result) = job_set.join_next().await {
+        result.expect("Could not join on blocking I/O task")?;
     }
 
-    log::info!("All export threads finished execution");
+    log::info!("All export jobs finished execution");
+
+    // the resolv.conf policy applies even without [guest.network], so the build container's resolver doesn't leak
+    let mut network = guest.network.unwrap_or_default();
+    if let ResolvConfPolicy::Empty = network.resolv_conf {
+        if overlays
+            .iter()
+            .any(|overlay| overlay.destination == Path::new(RESOLV_CONF_PATH))
+        {
+            network.resolv_conf = ResolvConfPolicy::Keep;
+        }
+    }
+    apply_guest_network(network, &destination_path, audit_log).await?;
+    log::info!("Applied guest network configuration to the mounted filesystem");
+    if let Some(agent) = guest.agent {
+        install_guest_agent(agent, &unpack_path, &destination_path, audit_log).await?;
+    }
 
     apply_overlays(
         overlays.iter().filter(|overlay| overlay.mounted).cloned().collect(),
         unpack_path.clone(),
         destination_path.clone(),
+        audit_log,
     )
-    .await;
+    .await?;
 
-    drop(unmount_drop);
-    log::info!("Applied mounted overlays to the mounted filesystem, filesystem unmounted");
-
-    tokio::fs::remove_dir_all(source_path.as_path())
-        .await
-        .expect("Could not clean up unneeded container rootfs directory");
-    log::info!("Root filesystem creation finished normally");
+    log::info!("Applied mounted overlays to the mounted filesystem");
+    Ok(())
 }
 
-async fn apply_overlays(overlays: Vec<BuildScriptOverlay>, unpack_path: Arc<PathBuf>, destination_path: Arc<PathBuf>) {
+async fn apply_overlays(
+    overlays: Vec<BuildScriptOverlay>,
+    unpack_path: Arc<PathBuf>,
+    destination_path: Arc<PathBuf>,
+    audit_log: &AuditLog,
+) -> Result<(), BuildfsError> {
+    // overlays run concurrently in waves, and an overlay nested in or containing an earlier one waits for the next wave
+    let mut waves: Vec<Vec<BuildScriptOverlay>> = Vec::new();
     for overlay in overlays {
-        if overlay.is_directory {
-            let (unpack_path, destination_path) = (unpack_path.clone(), destination_path.clone());
-
-            tokio::task::spawn_blocking(move || {
-                fs_extra::dir::copy(
-                    unpack_path.adjoin_absolute(&overlay.source.unwrap()),
-                    destination_path.adjoin_absolute(&overlay.destination),
-                    &fs_extra::dir::CopyOptions::default(),
-                )
-            })
-            .await
-            .expect("Join on blocking task failed")
-            .expect("Recursively copying overlay failed");
+        let overlay_path = destination_path.adjoin_absolute(&overlay.destination);
+        if overlay.source_inline.is_some() {
+            audit_log.record(AuditAction::WriteFile, &overlay_path);
+        } else {
+            audit_log.record(AuditAction::CopyIntoFilesystem, &overlay_path);
+        }
+
+        match waves.last_mut() {
+            Some(wave)
+                if !wave.iter().any(|other| {
+                    other.destination.starts_with(&overlay.destination)
+                        || overlay.destination.starts_with(&other.destination)
+                }) =>
+            {
+                wave.push(overlay)
+            }
+            _ => waves.push(vec![overlay]),
+        }
+    }
 
-            continue;
+    for wave in waves {
+        let mut job_set = JobSet::new();
+        for overlay in wave {
+            job_set.spawn(apply_overlay(overlay, unpack_path.clone(), destination_path.clone()));
         }
 
-        if let Some(parent_path) = overlay.destination.parent() {
-            tokio::fs::create_dir_all(destination_path.adjoin_absolute(parent_path))
-                .await
-                .expect("Could not create parent directory tree for overlayed file");
+        while let Some(result) = job_set.join_next().await {
+            result.expect("Could not join on overlay task")?;
         }
+    }
 
-        if let Some(source_path) = overlay.source {
-            tokio::fs::copy(
+    Ok(())
+}
+
+async fn apply_overlay(
+    overlay: BuildScriptOverlay,
+    unpack_path: Arc<PathBuf>,
+    destination_path: Arc<PathBuf>,
+) -> Result<(), BuildfsError> {
+    let overlay_path = resolve_destination(&destination_path, &overlay.destination)?;
+
+    if overlay.is_directory {
+        let Some(source_path) = overlay.source else {
+            return Err(BuildfsError::Validation(format!(
+                "the directory overlay for {:?} has no source path to copy",
+                overlay.destination
+            )));
+        };
+        return tokio::task::spawn_blocking(move || {
+            fs_extra::dir::copy(
                 unpack_path.adjoin_absolute(&source_path),
-                destination_path.adjoin_absolute(&overlay.destination),
+                &overlay_path,
+                &fs_extra::dir::CopyOptions::default(),
             )
+            .map_err(|err| BuildfsError::Filesystem(format!("Recursively copying overlay failed: {err}")))?;
+
+            // directory packages are run in place, so what .buildfsignore leaves out of packages is pruned here
+            let package_ignore = load_package_ignore(&unpack_path)?;
+            if let Some(directory_name) = source_path.file_name() {
+                prune_ignored(&package_ignore, &source_path, &overlay_path.join(directory_name))
+                    .map_err(BuildfsError::io("Could not prune ignored paths from overlay"))?;
+            }
+            Ok(())
+        })
+        .await
+        .expect("Join on blocking task failed");
+    }
+
+    if let Some(parent_path) = overlay_path.parent() {
+        tokio::fs::create_dir_all(parent_path).await.map_err(BuildfsError::io(
+            "Could not create parent directory tree for overlayed file",
+        ))?;
+    }
+
+    if let Some(source_path) = overlay.source {
+        tokio::fs::copy(unpack_path.adjoin_absolute(&source_path), &overlay_path)
+            .await
+            .map_err(BuildfsError::io("Could not copy overlayed file"))?;
+    }
+
+    if let Some(source_inline) = overlay.source_inline {
+        let mut file = tokio::fs::File::options()
+            .create_new(true)
+            .write(true)
+            .open(&overlay_path)
+            .await
+            .map_err(BuildfsError::io("Could not create and open overlayed inline file"))?;
+        file.write_all(source_inline.as_bytes())
+            .await
+            .map_err(BuildfsError::io("Could not write overlayed inline file's contents"))?;
+    }
+
+    Ok(())
+}
+
+pub fn get_mount_fstype(filesystem_type: &FilesystemType) -> &'static str {
+    match filesystem_type {
+        FilesystemType::Ext4 => "ext4",
+        FilesystemType::Btrfs => "btrfs",
+        FilesystemType::Squashfs => "squashfs",
+        FilesystemType::Vfat => "vfat",
+        FilesystemType::Xfs => "xfs",
+    }
+}
+
+async fn create_staging_path(run_args: &RunArgs, audit_log: &AuditLog) -> Result<PathBuf, BuildfsError> {
+    let staging_path = get_tmp_path();
+    tokio::fs::create_dir(&staging_path)
+        .await
+        .map_err(BuildfsError::io("Could not create filesystem staging directory"))?;
+    register_path(&staging_path);
+    audit_log.record(AuditAction::CreateDirectory, &staging_path);
+    log::info!(
+        "Created staging directory at {staging_path:?} to be packed into {:?}",
+        run_args.output_path
+    );
+
+    Ok(staging_path)
+}
+
+async fn populate_ext4(
+    filesystem: &BuildScriptFilesystem,
+    staging_path: &Path,
+    output_path: &PathBuf,
+    no_exec_logs: bool,
+    tools: &ToolsConfig,
+    audit_log: &AuditLog,
+) -> Result<(), BuildfsError> {
+    let output_parent_path = output_path.parent().unwrap_or(Path::new("."));
+    let mut mkfs_command = get_tool_command(tools, "mkfs.ext4", &[staging_path, output_parent_path])?;
+    mkfs_command.arg("-F").arg("-d").arg(staging_path);
+    mkfs_command.args(&filesystem.mkfs_args);
+    mkfs_command.arg(output_path);
+    if no_exec_logs {
+        mkfs_command.stdout(Stdio::null());
+        mkfs_command.stderr(Stdio::null());
+    }
+    audit_log.record_with_args(
+        AuditAction::MakeFilesystem,
+        output_path,
+        &get_command_args(&mkfs_command),
+    );
+
+    let mkfs_exit_status = mkfs_command
+        .status()
+        .await
+        .map_err(BuildfsError::io("Failed to fork \"mkfs\" process"))?;
+    if !mkfs_exit_status.success() {
+        return Err(BuildfsError::Filesystem(format!(
+            "\"mkfs\" invocation failed with exit status: {mkfs_exit_status}"
+        )));
+    }
+
+    tokio::fs::remove_dir_all(staging_path)
+        .await
+        .map_err(BuildfsError::io("Could not remove filesystem staging directory"))?;
+    log::info!("Formatted {output_path:?} as ext4 and populated it from the staging directory");
+    Ok(())
+}
+
+fn get_mount_data(filesystem: &BuildScriptFilesystem) -> String {
+    let FilesystemType::Vfat = filesystem.filesystem_type else {
+        return String::new();
+    };
+
+    // "quiet" turns chmod/chown on FAT into no-ops, which overlays copied with their permissions rely on
+    let mut mount_data = String::from("quiet");
+    if let Some(codepage) = filesystem.vfat.as_ref().and_then(|vfat| vfat.codepage) {
+        mount_data.push_str(&format!(",codepage={codepage}"));
+    }
+    mount_data
+}
+
+fn get_command_args(command: &Command) -> Vec<String> {
+    command
+        .as_std()
+        .get_args()
+        .map(|arg| arg.to_string_lossy().to_string())
+        .collect()
+}
+
+fn get_tmp_path() -> PathBuf {
+    PathBuf::from(format!("/tmp/{}", Uuid::new_v4()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        audit::AuditLog,
+        container_engine::{
+            mock::{MockCall, MockContainerEngine},
+            ContainerChange, ContainerChangeKind, ContainerEngine, ContainerInspection,
+        },
+        report::StepResources,
+        schema::{parse_build_script, BuildScript},
+        tools::ToolsConfig,
+    };
+    use std::{
+        collections::{HashMap, HashSet},
+        os::unix::fs::{FileExt, MetadataExt},
+        path::PathBuf,
+        sync::Arc,
+    };
+
+    use super::{
+        apply_overlays_and_finalize, check_copy_in_space, commit_and_push_image, copy_image, copy_sparse,
+        export_and_remove_container, get_effective_env, get_tmp_path, is_early_export_current, populate_ext4,
+        pull_and_start_container, run_commands_in_container, BuildfsError, CommandMounts, CopyInSpace, RootfsHandle,
+        RootfsPreparation,
+    };
+
+    fn build_script(extra_toml: &str) -> BuildScript {
+        parse_build_script(&format!(
+            "schema_version = 1\n[filesystem]\nsize_mib = 64\n[container]\nimage = {{ name = \"debian\", tag = \"bookworm\" }}\n{extra_toml}"
+        ))
+    }
+
+    #[tokio::test]
+    async fn copy_in_beyond_the_image_size_fails_early() {
+        let staging_path = get_tmp_path();
+        tokio::fs::create_dir_all(&staging_path).await.unwrap();
+        tokio::fs::write(staging_path.join("staged"), vec![0; 36 * 1024 * 1024])
+            .await
+            .unwrap();
+        let build_script = build_script("");
+        let copy_in_space = CopyInSpace {
+            mount_path: &staging_path,
+            filesystem: &build_script.filesystem,
+            staged: true,
+        };
+
+        check_copy_in_space(&copy_in_space, 20 * 1024 * 1024, "overlays")
+            .await
+            .unwrap();
+        let result = check_copy_in_space(&copy_in_space, 64 * 1024 * 1024, "overlays").await;
+        tokio::fs::remove_dir_all(&staging_path).await.unwrap();
+
+        let err = result.expect_err("Too large a copy-in was not refused");
+        assert_eq!(
+            err.to_string(),
+            "The image is too small for the overlays: needed ~36 MiB more than the 28 MiB still free in the 64 MiB filesystem"
+        );
+    }
+
+    #[tokio::test]
+    async fn secrets_are_only_present_for_their_step() {
+        let mock = MockContainerEngine::default().with_exec("", 0).with_exec("", 1);
+        let container_engine: Box<dyn ContainerEngine> = Box::new(mock.clone());
+        let build_script = build_script(
+            "[container.secrets.npm]\nsource = \"/tmp/npmrc\"\ndestination = \"/root/.npmrc\"\n[[commands]]\ncommand = \"npm ci\"\nmounts = [\"secret:npm\"]\n[[commands]]\ncommand = \"true\"\n",
+        );
+
+        run_commands_in_container(
+            &CommandMounts::new(&HashMap::new(), &build_script.container),
+            build_script.commands,
+            "mock-container-id",
+            "mock-container",
+            &container_engine,
+            true,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let calls = mock
+            .calls()
+            .into_iter()
+            .filter(|call| matches!(call, MockCall::Exec(_) | MockCall::UploadFile(_)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            calls,
+            vec![
+                MockCall::Exec("mkdir -p /root".to_string()),
+                MockCall::Exec("test -e /root/.npmrc".to_string()),
+                MockCall::UploadFile(PathBuf::from("/root/.npmrc")),
+                MockCall::Exec("npm ci".to_string()),
+                MockCall::Exec("rm -rf /root/.npmrc".to_string()),
+                MockCall::Exec("true".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn commands_are_run_in_order_after_start() {
+        let mock = MockContainerEngine::default().with_exec("hello", 0);
+        let container_engine: Box<dyn ContainerEngine> = Box::new(mock.clone());
+        let build_script = build_script("[[commands]]\ncommand = \"echo hello\"\n[[commands]]\ncommand = \"true\"\n");
+        let unpack_path = get_tmp_path();
+
+        let (container_id, container_name, inline_mount_paths) =
+            pull_and_start_container(&container_engine, &build_script, &unpack_path, false)
+                .await
+                .unwrap();
+        let failure = run_commands_in_container(
+            &CommandMounts::new(&inline_mount_paths, &build_script.container),
+            build_script.commands,
+            &container_id,
+            &container_name,
+            &container_engine,
+            true,
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert!(failure.is_none());
+        assert_eq!(
+            mock.calls(),
+            vec![
+                MockCall::PullImage("debian:bookworm".to_string()),
+                MockCall::StartContainer {
+                    image: "debian:bookworm".to_string(),
+                    volumes: HashMap::new(),
+                },
+                MockCall::Exec("echo hello".to_string()),
+                MockCall::InspectExec("mock-exec-0".to_string()),
+                MockCall::InspectContainer,
+                MockCall::Exec("true".to_string()),
+                MockCall::InspectExec("mock-exec-1".to_string()),
+                MockCall::InspectContainer,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_pulls_fall_back_to_the_next_mirror() {
+        let mock = MockContainerEngine::default().with_pull_error("connection refused");
+        let container_engine: Box<dyn ContainerEngine> = Box::new(mock.clone());
+        let mut build_script = build_script("");
+        build_script.container.image.mirrors = vec!["mirror.internal/library/debian".to_string()];
+
+        pull_and_start_container(&container_engine, &build_script, &get_tmp_path(), false)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            mock.calls(),
+            vec![
+                MockCall::PullImage("mirror.internal/library/debian:bookworm".to_string()),
+                MockCall::PullImage("debian:bookworm".to_string()),
+                MockCall::StartContainer {
+                    image: "debian:bookworm".to_string(),
+                    volumes: HashMap::new(),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn offline_runs_do_not_pull_the_image() {
+        let mock = MockContainerEngine::default();
+        let container_engine: Box<dyn ContainerEngine> = Box::new(mock.clone());
+        let build_script = build_script("");
+
+        pull_and_start_container(&container_engine, &build_script, &get_tmp_path(), true)
+            .await
+            .unwrap();
+
+        assert!(!mock
+            .calls()
+            .iter()
+            .any(|call| matches!(call, MockCall::PullImage(_) | MockCall::LoadImage(_))));
+    }
+
+    #[tokio::test]
+    async fn inline_scripts_are_bind_mounted() {
+        let mock = MockContainerEngine::default();
+        let container_engine: Box<dyn ContainerEngine> = Box::new(mock.clone());
+        let build_script = build_script("[[commands]]\nscript_inline = \"echo inline\"\n");
+        let unpack_path = get_tmp_path();
+
+        let (_, _, inline_mount_paths) =
+            pull_and_start_container(&container_engine, &build_script, &unpack_path, false)
+                .await
+                .unwrap();
+        let (host_path, mount_path) = inline_mount_paths
+            .get("echo inline")
+            .expect("Inline script was not mounted")
+            .clone();
+
+        assert_eq!(tokio::fs::read_to_string(&host_path).await.unwrap(), "echo inline");
+        assert!(mount_path.starts_with("/__scripts"));
+        assert!(mock.calls().contains(&MockCall::StartContainer {
+            image: "debian:bookworm".to_string(),
+            volumes: HashMap::from([(host_path.clone(), mount_path)]),
+        }));
+
+        tokio::fs::remove_file(host_path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn identical_inline_scripts_share_one_mount() {
+        let mock = MockContainerEngine::default();
+        let container_engine: Box<dyn ContainerEngine> = Box::new(mock);
+        let build_script = build_script(
+            "[[commands]]\nscript_inline = \"make\"\nenv = { TARGET = \"a\" }\n[[commands]]\nscript_inline = \"make\"\nuid = 1000\n[[commands]]\nscript_inline = \"make\"\ninterpreter = \"bash\"\n",
+        );
+
+        let (_, _, inline_mount_paths) =
+            pull_and_start_container(&container_engine, &build_script, &get_tmp_path(), false)
+                .await
+                .unwrap();
+
+        assert_eq!(inline_mount_paths.len(), 2);
+        for (host_path, _) in inline_mount_paths.into_values() {
+            tokio::fs::remove_file(host_path).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn inline_scripts_get_a_shebang_for_their_interpreter() {
+        let mock = MockContainerEngine::default();
+        let container_engine: Box<dyn ContainerEngine> = Box::new(mock);
+        let build_script =
+            build_script("[[commands]]\nscript_inline = \"print('inline')\"\ninterpreter = \"python3\"\n");
+
+        let (_, _, inline_mount_paths) =
+            pull_and_start_container(&container_engine, &build_script, &get_tmp_path(), false)
+                .await
+                .unwrap();
+        let (host_path, _) = inline_mount_paths
+            .get("#!/usr/bin/env python3\nprint('inline')")
+            .unwrap();
+
+        assert_eq!(
+            tokio::fs::read_to_string(host_path).await.unwrap(),
+            "#!/usr/bin/env python3\nprint('inline')"
+        );
+
+        tokio::fs::remove_file(host_path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn image_is_copied_to_every_additional_output() {
+        let output_path = get_tmp_path();
+        let copy_paths = vec![get_tmp_path(), get_tmp_path()];
+        tokio::fs::write(&output_path, "image").await.unwrap();
+
+        copy_image(&output_path, &copy_paths, &AuditLog::default())
             .await
-            .expect("Could not copy overlayed file");
+            .unwrap();
+
+        for copy_path in copy_paths {
+            assert_eq!(tokio::fs::read_to_string(&copy_path).await.unwrap(), "image");
+            tokio::fs::remove_file(copy_path).await.unwrap();
         }
+        tokio::fs::remove_file(output_path).await.unwrap();
+    }
+
+    #[test]
+    fn sparse_images_stay_sparse_when_copied() {
+        let (source_path, destination_path) = (get_tmp_path(), get_tmp_path());
+        let source_file = std::fs::File::create(&source_path).unwrap();
+        source_file.set_len(64 * 1024 * 1024).unwrap();
+        source_file.write_all_at(b"superblock", 32 * 1024 * 1024).unwrap();
+
+        copy_sparse(&source_path, &destination_path).unwrap();
+
+        let destination_metadata = std::fs::metadata(&destination_path).unwrap();
+        let mut data = [0; 10];
+        std::fs::File::open(&destination_path)
+            .unwrap()
+            .read_exact_at(&mut data, 32 * 1024 * 1024)
+            .unwrap();
+        assert_eq!(&data, b"superblock");
+        assert_eq!(destination_metadata.len(), 64 * 1024 * 1024);
+        assert!(destination_metadata.blocks() * 512 < 1024 * 1024);
+
+        std::fs::remove_file(source_path).unwrap();
+        std::fs::remove_file(destination_path).unwrap();
+    }
 
-        if let Some(source_inline) = overlay.source_inline {
-            let mut file = tokio::fs::File::options()
-                .create_new(true)
-                .write(true)
-                .open(destination_path.adjoin_absolute(&overlay.destination))
+    #[test]
+    fn command_env_overrides_container_env() {
+        let container_env = HashMap::from([
+            ("LANG".to_string(), "C.UTF-8".to_string()),
+            ("DEBIAN_FRONTEND".to_string(), "dialog".to_string()),
+        ]);
+        let command_env = HashMap::from([("DEBIAN_FRONTEND".to_string(), "noninteractive".to_string())]);
+
+        assert_eq!(
+            get_effective_env(&container_env, command_env),
+            HashMap::from([
+                ("LANG".to_string(), "C.UTF-8".to_string()),
+                ("DEBIAN_FRONTEND".to_string(), "noninteractive".to_string()),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn attached_container_receives_uploaded_scripts() {
+        let mock = MockContainerEngine::default();
+        let container_engine: Box<dyn ContainerEngine> = Box::new(mock.clone());
+        let build_script = build_script("attach_to = \"sidecar\"\n[[commands]]\nscript_inline = \"echo inline\"\n");
+        let unpack_path = get_tmp_path();
+
+        let (_, container_name, inline_mount_paths) =
+            pull_and_start_container(&container_engine, &build_script, &unpack_path, false)
                 .await
-                .expect("Could not create and open overlayed inline file");
-            file.write_all(source_inline.as_bytes())
+                .unwrap();
+        let (host_path, mount_path) = inline_mount_paths
+            .get("echo inline")
+            .expect("Inline script was not uploaded")
+            .clone();
+
+        assert_eq!(container_name, "sidecar");
+        assert_eq!(
+            mock.calls(),
+            vec![
+                MockCall::AttachContainer("sidecar".to_string()),
+                MockCall::UploadFile(mount_path),
+            ]
+        );
+
+        tokio::fs::remove_file(host_path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn attached_container_is_not_removed() {
+        let mock = MockContainerEngine::default();
+        let container_engine: Box<dyn ContainerEngine> = Box::new(mock.clone());
+
+        let container_rootfs_path = export_and_remove_container(
+            &container_engine,
+            "sidecar",
+            false,
+            &get_tmp_path(),
+            HashMap::new(),
+            &build_script("attach_to = \"sidecar\"\n").container,
+            false,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(mock.calls(), vec![MockCall::ExportContainer]);
+        tokio::fs::remove_dir_all(container_rootfs_path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn committed_image_is_pushed_under_its_tag() {
+        let mock = MockContainerEngine::default();
+        let build_script = build_script(
+            "[post_build]\ncommit_image = \"registry.example.com:5000/me/rootfs-builder:v2\"\npush = true\n",
+        );
+        let commit_image = build_script.post_build.get_commit_image().unwrap();
+        assert_eq!(commit_image.name, "registry.example.com:5000/me/rootfs-builder");
+
+        commit_and_push_image(&mock, "builder", &commit_image, build_script.post_build.push)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            mock.calls(),
+            vec![
+                MockCall::CommitContainer("registry.example.com:5000/me/rootfs-builder:v2".to_string()),
+                MockCall::PushImage("registry.example.com:5000/me/rootfs-builder:v2".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn userspace_backend_populates_ext4_image() {
+        if which::which("mkfs.ext4").is_err() || which::which("debugfs").is_err() {
+            eprintln!(
+                "Skipping userspace backend test, since \"mkfs.ext4\" or \"debugfs\" could not be located in PATH"
+            );
+            return;
+        }
+
+        let staging_path = get_tmp_path();
+        tokio::fs::create_dir_all(staging_path.join("etc")).await.unwrap();
+        tokio::fs::write(staging_path.join("etc/hostname"), "userspace\n")
+            .await
+            .unwrap();
+        let image_path = get_tmp_path();
+        tokio::fs::File::create(&image_path)
+            .await
+            .unwrap()
+            .set_len(16 * 1024 * 1024)
+            .await
+            .unwrap();
+
+        populate_ext4(
+            &build_script("").filesystem,
+            &staging_path,
+            &image_path,
+            true,
+            &ToolsConfig::default(),
+            &AuditLog::default(),
+        )
+        .await
+        .unwrap();
+
+        let debugfs_output = tokio::process::Command::new("debugfs")
+            .arg("-R")
+            .arg("cat /etc/hostname")
+            .arg(&image_path)
+            .output()
+            .await
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&debugfs_output.stdout), "userspace\n");
+        assert!(tokio::fs::metadata(&staging_path).await.is_err());
+
+        tokio::fs::remove_file(image_path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn kept_staging_retains_exported_tarball() {
+        let mock = MockContainerEngine::default().with_rootfs_file("/etc/os-release", "ID=mock\n");
+        let container_engine: Box<dyn ContainerEngine> = Box::new(mock);
+
+        let container_rootfs_path = export_and_remove_container(
+            &container_engine,
+            "mock-container",
+            false,
+            &get_tmp_path(),
+            HashMap::new(),
+            &build_script("").container,
+            true,
+        )
+        .await
+        .unwrap();
+
+        let container_rootfs_tar_path = container_rootfs_path.with_extension("tar");
+        assert!(tokio::fs::metadata(&container_rootfs_tar_path).await.is_ok());
+
+        tokio::fs::remove_file(container_rootfs_tar_path).await.unwrap();
+        tokio::fs::remove_dir_all(container_rootfs_path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stopped_container_produces_failure_report() {
+        let mock = MockContainerEngine::default()
+            .with_inspection(Some(ContainerInspection {
+                status: "exited".to_string(),
+                running: false,
+                exit_code: Some(137),
+                oom_killed: true,
+                error: None,
+            }))
+            .with_logs("out of memory\n");
+        let container_engine: Box<dyn ContainerEngine> = Box::new(mock.clone());
+        let build_script = build_script("[[commands]]\ncommand = \"make\"\n[[commands]]\ncommand = \"true\"\n");
+
+        let failure = run_commands_in_container(
+            &CommandMounts::new(&HashMap::new(), &build_script.container),
+            build_script.commands,
+            "mock-container-id",
+            "mock-container",
+            &container_engine,
+            true,
+            None,
+        )
+        .await
+        .unwrap()
+        .expect("Failure was not reported");
+
+        assert_eq!(failure.cmd, "make");
+        assert_eq!(failure.logs_excerpt, "out of memory\n");
+        assert!(failure.inspection.is_some_and(|inspection| inspection.oom_killed));
+        assert!(!mock.calls().contains(&MockCall::Exec("true".to_string())));
+    }
+
+    #[tokio::test]
+    async fn non_zero_exit_fails_unless_allowed() {
+        let mock = MockContainerEngine::default()
+            .with_exec("", 1)
+            .with_exec("E: Unable to locate package nope\n", 100);
+        let container_engine: Box<dyn ContainerEngine> = Box::new(mock.clone());
+        let build_script = build_script(
+            "[[commands]]\ncommand = \"grep -q x /etc/hosts\"\nallow_failure = true\n[[commands]]\ncommand = \"apt install nope\"\n[[commands]]\ncommand = \"true\"\n",
+        );
+
+        let failure = run_commands_in_container(
+            &CommandMounts::new(&HashMap::new(), &build_script.container),
+            build_script.commands,
+            "mock-container-id",
+            "mock-container",
+            &container_engine,
+            true,
+            None,
+        )
+        .await
+        .unwrap()
+        .expect("Failure was not reported");
+
+        assert_eq!(failure.cmd, "apt install nope");
+        assert_eq!(failure.reason, "The command exited with code 100");
+        assert_eq!(failure.logs_excerpt, "E: Unable to locate package nope\n");
+        assert!(!mock.calls().contains(&MockCall::Exec("true".to_string())));
+    }
+
+    #[tokio::test]
+    async fn output_is_saved_and_checked_against_expected_regex() {
+        let mock = MockContainerEngine::default().with_exec("openjdk 17.0.2\n", 0);
+        let container_engine: Box<dyn ContainerEngine> = Box::new(mock);
+        let save_output_path = get_tmp_path().join("logs/java.txt");
+        let build_script = build_script(&format!(
+            "[[commands]]\ncommand = \"java -version\"\nexpect_output_regex = \"^openjdk 21\"\nsave_output_to = {save_output_path:?}\n"
+        ));
+
+        let failure = run_commands_in_container(
+            &CommandMounts::new(&HashMap::new(), &build_script.container),
+            build_script.commands,
+            "mock-container-id",
+            "mock-container",
+            &container_engine,
+            true,
+            None,
+        )
+        .await
+        .unwrap()
+        .expect("Failure was not reported");
+
+        assert!(failure.reason.contains("did not match the expected regex"));
+        assert_eq!(failure.logs_excerpt, "openjdk 17.0.2\n");
+        assert_eq!(
+            tokio::fs::read_to_string(&save_output_path).await.unwrap(),
+            "openjdk 17.0.2\n"
+        );
+
+        tokio::fs::remove_dir_all(save_output_path.parent().unwrap().parent().unwrap())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn report_contains_only_new_changes_per_step() {
+        let change = |path: &str| ContainerChange {
+            path: PathBuf::from(path),
+            kind: ContainerChangeKind::Added,
+        };
+        let mock = MockContainerEngine::default()
+            .with_changes(vec![])
+            .with_changes(vec![change("/a")])
+            .with_changes(vec![change("/a")])
+            .with_changes(vec![change("/a"), change("/b")])
+            .with_changes(vec![change("/b")])
+            .with_changes(vec![change("/a"), change("/b")]);
+        let container_engine: Box<dyn ContainerEngine> = Box::new(mock);
+        let build_script = build_script(
+            "[[commands]]\ncommand = \"touch /a\"\n[[commands]]\ncommand = \"touch /b && rm /a\"\n[[commands]]\ncommand = \"touch /a\"\n",
+        );
+        let mut report = super::BuildReport::default();
+
+        run_commands_in_container(
+            &CommandMounts::new(&HashMap::new(), &build_script.container),
+            build_script.commands,
+            "mock-container-id",
+            "mock-container",
+            &container_engine,
+            true,
+            Some(&mut report),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.steps.len(), 3);
+        assert_eq!(report.steps[0].changes, Some(vec![change("/a")]));
+        assert_eq!(report.steps[1].changes, Some(vec![change("/b")]));
+        assert_eq!(report.steps[2].changes, Some(vec![change("/a")]));
+    }
+
+    #[tokio::test]
+    async fn report_contains_resource_usage_per_step() {
+        let mock = MockContainerEngine::default()
+            .with_stats(1_000_000_000, 64 * 1024 * 1024)
+            .with_stats(3_500_000_000, 16 * 1024 * 1024);
+        let container_engine: Box<dyn ContainerEngine> = Box::new(mock);
+        let build_script = build_script("[[commands]]\ncommand = \"make\"\n");
+        let mut report = super::BuildReport::default();
+
+        run_commands_in_container(
+            &CommandMounts::new(&HashMap::new(), &build_script.container),
+            build_script.commands,
+            "mock-container-id",
+            "mock-container",
+            &container_engine,
+            true,
+            Some(&mut report),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            report.steps[0].resources,
+            Some(StepResources {
+                cpu_time_ms: 2500,
+                peak_memory_bytes: 64 * 1024 * 1024,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn report_contains_exit_code_and_output_size_per_step() {
+        let mock = MockContainerEngine::default().with_exec("no such target\n", 2);
+        let container_engine: Box<dyn ContainerEngine> = Box::new(mock);
+        let build_script = build_script("[[commands]]\ncommand = \"make\"\n");
+        let mut report = super::BuildReport::default();
+
+        run_commands_in_container(
+            &CommandMounts::new(&HashMap::new(), &build_script.container),
+            build_script.commands,
+            "mock-container-id",
+            "mock-container",
+            &container_engine,
+            true,
+            Some(&mut report),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.steps[0].outcome.exit_code, Some(2));
+        assert_eq!(report.steps[0].outcome.stdout_bytes, 15);
+        assert_eq!(report.steps[0].outcome.stderr_bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn exported_rootfs_is_unpacked_and_container_removed() {
+        let mock = MockContainerEngine::default().with_rootfs_file("/etc/os-release", "ID=mock\n");
+        let container_engine: Box<dyn ContainerEngine> = Box::new(mock.clone());
+
+        let container_rootfs_path = export_and_remove_container(
+            &container_engine,
+            "mock-container",
+            false,
+            &get_tmp_path(),
+            HashMap::new(),
+            &build_script("").container,
+            false,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            tokio::fs::read_to_string(container_rootfs_path.join("etc/os-release"))
                 .await
-                .expect("Could not write overlayed inline file's contents");
+                .unwrap(),
+            "ID=mock\n"
+        );
+        assert_eq!(mock.calls(), vec![MockCall::ExportContainer, MockCall::RemoveContainer]);
+
+        tokio::fs::remove_dir_all(container_rootfs_path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn early_export_is_stale_when_exported_paths_change() {
+        let change = |path: &str| ContainerChange {
+            path: PathBuf::from(path),
+            kind: ContainerChangeKind::Added,
+        };
+        let mock = MockContainerEngine::default()
+            .with_changes(vec![change("/etc/hostname"), change("/var/cache/apt")])
+            .with_exec("/etc/hostname\n", 0);
+        let container_engine: Box<dyn ContainerEngine> = Box::new(mock.clone());
+        let build_script = build_script("[export.files]\ninclude = [\"/etc/hostname\"]\n");
+
+        assert!(!is_early_export_current(
+            container_engine.as_ref(),
+            "mock-container-id",
+            "mock-container",
+            &build_script.export,
+            HashSet::from([change("/etc/hostname")]),
+        )
+        .await
+        .unwrap());
+        assert_eq!(
+            mock.calls(),
+            vec![
+                MockCall::DiffContainer,
+                MockCall::Exec("find '/etc/hostname' -newer /.buildfs-early-export".to_string()),
+                MockCall::InspectExec("mock-exec-0".to_string()),
+                MockCall::Exec("rm -f /.buildfs-early-export".to_string()),
+                MockCall::InspectExec("mock-exec-1".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn overlays_and_exports_are_applied() {
+        let source_path = get_tmp_path();
+        let destination_path = get_tmp_path();
+        tokio::fs::create_dir_all(source_path.join("usr/bin")).await.unwrap();
+        tokio::fs::create_dir_all(source_path.join("etc")).await.unwrap();
+        tokio::fs::create_dir_all(&destination_path).await.unwrap();
+        tokio::fs::write(source_path.join("usr/bin/tool"), "binary")
+            .await
+            .unwrap();
+        tokio::fs::write(source_path.join("etc/hosts"), "127.0.0.1 localhost\n")
+            .await
+            .unwrap();
+
+        let build_script = build_script(
+            r#"
+[[overlays]]
+source_inline = "inline overlay"
+destination = "/etc/motd"
+
+[export.directories]
+include = ["/usr/bin"]
+create = ["/tmp", "/proc"]
+
+[export.files]
+include = ["/etc/hosts"]
+create = ["/etc/fstab"]
+"#,
+        );
+
+        apply_overlays_and_finalize(
+            Arc::new(source_path.clone()),
+            Arc::new(destination_path.clone()),
+            build_script.overlays,
+            build_script.export,
+            build_script.guest,
+            Arc::new(get_tmp_path()),
+            &AuditLog::default(),
+        )
+        .await
+        .unwrap();
+
+        let read = |path: &str| std::fs::read_to_string(destination_path.join(path)).unwrap();
+        assert_eq!(read("etc/motd"), "inline overlay");
+        assert_eq!(read("usr/bin/tool"), "binary");
+        assert_eq!(read("etc/hosts"), "127.0.0.1 localhost\n");
+        assert_eq!(read("etc/fstab"), "");
+        assert!(destination_path.join("tmp").is_dir());
+        assert!(destination_path.join("proc").is_dir());
+
+        tokio::fs::remove_dir_all(source_path).await.unwrap();
+        tokio::fs::remove_dir_all(destination_path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn discarded_rootfs_preparation_removes_the_image_and_staging_path() {
+        let (output_path, staging_path) = (get_tmp_path(), get_tmp_path());
+        tokio::fs::write(&output_path, "partial image").await.unwrap();
+        tokio::fs::create_dir(&staging_path).await.unwrap();
+        tokio::fs::write(staging_path.join("file"), "staged").await.unwrap();
+
+        let mut rootfs_preparation = RootfsPreparation {
+            task: tokio::spawn({
+                let staging_path = staging_path.clone();
+                async move { Ok((staging_path, RootfsHandle::Staged, None)) }
+            }),
+            output_path: output_path.clone(),
+        };
+        while !rootfs_preparation.task.is_finished() {
+            tokio::task::yield_now().await;
         }
+        let result = rootfs_preparation
+            .discard_on_error::<()>(Err(BuildfsError::CommandFailed("failed".to_string())))
+            .await;
+
+        assert!(result.is_err());
+        assert!(!output_path.exists());
+        assert!(!staging_path.exists());
     }
 }
 
-fn get_tmp_path() -> PathBuf {
-    PathBuf::from(format!("/tmp/{}", Uuid::new_v4()))
-}
+#[cfg(test)]
+mod golden_tests;
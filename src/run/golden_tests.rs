@@ -0,0 +1,265 @@
+use std::{
+    fs::Permissions,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tokio::process::Command;
+
+use crate::{audit::AuditLog, schema::parse_build_script};
+
+use super::{apply_overlays_and_finalize, get_tmp_path};
+
+static IMAGE_SIZE_MIB: u64 = 32;
+
+struct GoldenInode {
+    mode: u32,
+    size: u64,
+    blocks: u64,
+}
+
+struct GoldenImage {
+    image_path: PathBuf,
+}
+
+impl GoldenImage {
+    async fn build(fixture_path: &Path, package_path: &Path, build_script_toml: &str) -> Option<Self> {
+        let (Ok(mkfs_path), Ok(_)) = (which::which("mkfs.ext4"), which::which("debugfs")) else {
+            eprintln!("Skipping golden-image test, since \"mkfs.ext4\" or \"debugfs\" could not be located in PATH");
+            return None;
+        };
+
+        let build_script = parse_build_script(&format!(
+            "schema_version = 1\n[filesystem]\nsize_mib = {IMAGE_SIZE_MIB}\n[container]\nimage = {{ name = \"debian\", tag = \"bookworm\" }}\n{build_script_toml}"
+        ));
+        let staging_path = get_tmp_path();
+        tokio::fs::create_dir(&staging_path).await.unwrap();
+
+        apply_overlays_and_finalize(
+            Arc::new(fixture_path.to_path_buf()),
+            Arc::new(staging_path.clone()),
+            build_script.overlays,
+            build_script.export,
+            build_script.guest,
+            Arc::new(package_path.to_path_buf()),
+            &AuditLog::default(),
+        )
+        .await
+        .unwrap();
+
+        let image_path = get_tmp_path();
+        tokio::fs::File::create(&image_path)
+            .await
+            .unwrap()
+            .set_len(IMAGE_SIZE_MIB * 1024 * 1024)
+            .await
+            .unwrap();
+        let mkfs_output = Command::new(mkfs_path)
+            .arg("-q")
+            .arg("-d")
+            .arg(&staging_path)
+            .arg(&image_path)
+            .output()
+            .await
+            .unwrap();
+        assert!(
+            mkfs_output.status.success(),
+            "mkfs.ext4 failed: {}",
+            String::from_utf8_lossy(&mkfs_output.stderr)
+        );
+
+        tokio::fs::remove_dir_all(staging_path).await.unwrap();
+        Some(Self { image_path })
+    }
+
+    async fn debugfs(&self, request: String) -> (String, String) {
+        let output = Command::new("debugfs")
+            .arg("-R")
+            .arg(request)
+            .arg(&self.image_path)
+            .output()
+            .await
+            .unwrap();
+        (
+            String::from_utf8_lossy(&output.stdout).to_string(),
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        )
+    }
+
+    async fn read(&self, path: &str) -> Option<String> {
+        let (stdout, stderr) = self.debugfs(format!("cat {path}")).await;
+        match stderr.contains("not found") {
+            true => None,
+            false => Some(stdout),
+        }
+    }
+
+    async fn stat(&self, path: &str) -> Option<GoldenInode> {
+        let (stdout, stderr) = self.debugfs(format!("stat {path}")).await;
+        if stderr.contains("not found") {
+            return None;
+        }
+
+        let field = |name: &str| {
+            stdout
+                .split_whitespace()
+                .skip_while(|word| *word != name)
+                .nth(1)
+                .unwrap_or_else(|| panic!("debugfs stat output is missing {name}"))
+                .to_string()
+        };
+        Some(GoldenInode {
+            mode: u32::from_str_radix(&field("Mode:"), 8).unwrap(),
+            size: field("Size:").parse().unwrap(),
+            blocks: field("Blockcount:").parse().unwrap(),
+        })
+    }
+}
+
+impl Drop for GoldenImage {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.image_path);
+    }
+}
+
+struct Fixture {
+    rootfs_path: PathBuf,
+    package_path: PathBuf,
+}
+
+impl Fixture {
+    fn new() -> Self {
+        let fixture = Self {
+            rootfs_path: get_tmp_path(),
+            package_path: get_tmp_path(),
+        };
+        std::fs::create_dir_all(&fixture.rootfs_path).unwrap();
+        std::fs::create_dir_all(&fixture.package_path).unwrap();
+        fixture
+    }
+
+    fn rootfs_file(self, path: &str, contents: &str, mode: u32) -> Self {
+        let path = self.rootfs_path.join(path.trim_start_matches('/'));
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, contents).unwrap();
+        std::fs::set_permissions(path, Permissions::from_mode(mode)).unwrap();
+        self
+    }
+
+    fn package_file(self, path: &str, contents: &str) -> Self {
+        let path = self.package_path.join(path.trim_start_matches('/'));
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+        self
+    }
+
+    async fn build(&self, build_script_toml: &str) -> Option<GoldenImage> {
+        GoldenImage::build(&self.rootfs_path, &self.package_path, build_script_toml).await
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.rootfs_path);
+        let _ = std::fs::remove_dir_all(&self.package_path);
+    }
+}
+
+#[tokio::test]
+async fn export_filters_only_copy_included_paths() {
+    let fixture = Fixture::new()
+        .rootfs_file("/usr/bin/tool", "tool", 0o755)
+        .rootfs_file("/usr/share/doc/README", "docs", 0o644)
+        .rootfs_file("/etc/hostname", "container", 0o644)
+        .rootfs_file("/etc/shadow", "secret", 0o600);
+    let Some(image) = fixture
+        .build("[export.directories]\ninclude = [\"/usr/bin\"]\ncreate = [\"/proc\"]\n[export.files]\ninclude = [\"/etc/hostname\"]\n")
+        .await
+    else {
+        return;
+    };
+
+    assert_eq!(image.read("/usr/bin/tool").await.as_deref(), Some("tool"));
+    assert_eq!(image.read("/etc/hostname").await.as_deref(), Some("container"));
+    assert!(image.stat("/proc").await.is_some());
+    assert!(image.stat("/usr/share/doc/README").await.is_none());
+    assert!(image.stat("/etc/shadow").await.is_none());
+}
+
+#[tokio::test]
+async fn permissions_are_preserved() {
+    let fixture = Fixture::new()
+        .rootfs_file("/opt/app/run.sh", "#!/bin/sh", 0o750)
+        .rootfs_file("/etc/secret", "secret", 0o600);
+    let Some(image) = fixture
+        .build("[export.directories]\ninclude = [\"/opt/app\"]\n[export.files]\ninclude = [\"/etc/secret\"]\n")
+        .await
+    else {
+        return;
+    };
+
+    assert_eq!(image.stat("/opt/app/run.sh").await.unwrap().mode, 0o750);
+    assert_eq!(image.stat("/etc/secret").await.unwrap().mode, 0o600);
+}
+
+#[tokio::test]
+async fn overlay_modes_are_applied_around_exports() {
+    let fixture = Fixture::new()
+        .rootfs_file("/etc/issue", "from container", 0o644)
+        .rootfs_file("/etc/motd", "from container", 0o644)
+        .package_file("/overlays/motd", "from mounted overlay");
+    let Some(image) = fixture
+        .build(
+            r#"
+[[overlays]]
+source_inline = "from non-mounted overlay"
+destination = "/etc/issue"
+
+[[overlays]]
+source_inline = "only from overlay"
+destination = "/etc/overlay-only"
+
+[[overlays]]
+source = "/overlays/motd"
+destination = "/etc/motd"
+mounted = true
+
+[export.files]
+include = ["/etc/issue", "/etc/motd"]
+"#,
+        )
+        .await
+    else {
+        return;
+    };
+
+    assert_eq!(image.read("/etc/issue").await.as_deref(), Some("from container"));
+    assert_eq!(image.read("/etc/motd").await.as_deref(), Some("from mounted overlay"));
+    assert_eq!(
+        image.read("/etc/overlay-only").await.as_deref(),
+        Some("only from overlay")
+    );
+}
+
+#[tokio::test]
+async fn sparse_files_stay_sparse() {
+    let fixture = Fixture::new().rootfs_file("/var/lib/sparse", "header", 0o644);
+    std::fs::File::options()
+        .write(true)
+        .open(fixture.rootfs_path.join("var/lib/sparse"))
+        .unwrap()
+        .set_len(8 * 1024 * 1024)
+        .unwrap();
+    let Some(image) = fixture.build("[export.directories]\ninclude = [\"/var/lib\"]\n").await else {
+        return;
+    };
+
+    let inode = image.stat("/var/lib/sparse").await.unwrap();
+    assert_eq!(inode.size, 8 * 1024 * 1024);
+    assert!(
+        inode.blocks < 8 * 1024 * 2,
+        "Sparse file was fully allocated with {} blocks",
+        inode.blocks
+    );
+}
@@ -0,0 +1,127 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::task::JoinHandle;
+
+use crate::{
+    epilogue::current_phase,
+    scheduler::{get_busy_jobs, get_jobs},
+};
+
+static SAMPLE_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone)]
+pub struct PhaseStats {
+    pub phase: String,
+    pub duration_ms: u128,
+    pub peak_os_threads: usize,
+    pub peak_alive_tasks: usize,
+    pub peak_busy_jobs: usize,
+    pub mean_global_queue_depth: f64,
+    samples: usize,
+    global_queue_depth_sum: usize,
+}
+
+pub struct RuntimeStatsSampler {
+    stopped: Arc<AtomicBool>,
+    handle: JoinHandle<Vec<PhaseStats>>,
+}
+
+impl RuntimeStatsSampler {
+    pub fn start() -> Self {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let task_stopped = stopped.clone();
+        let handle = tokio::spawn(async move {
+            let metrics = tokio::runtime::Handle::current().metrics();
+            let mut stats: Vec<PhaseStats> = Vec::new();
+            let mut phase_started = Instant::now();
+
+            while !task_stopped.load(Ordering::Relaxed) {
+                let phase = match current_phase() {
+                    Some(phase) => format!("{phase:?}"),
+                    None => "Setup".to_string(),
+                };
+                if stats.last().map_or(true, |last| last.phase != phase) {
+                    if let Some(last) = stats.last_mut() {
+                        last.duration_ms = phase_started.elapsed().as_millis();
+                    }
+                    phase_started = Instant::now();
+                    stats.push(PhaseStats {
+                        phase,
+                        duration_ms: 0,
+                        peak_os_threads: 0,
+                        peak_alive_tasks: 0,
+                        peak_busy_jobs: 0,
+                        mean_global_queue_depth: 0.0,
+                        samples: 0,
+                        global_queue_depth_sum: 0,
+                    });
+                }
+
+                let current = stats.last_mut().expect("Phase statistics are empty");
+                current.peak_os_threads = current.peak_os_threads.max(get_os_threads());
+                current.peak_alive_tasks = current.peak_alive_tasks.max(metrics.num_alive_tasks());
+                current.peak_busy_jobs = current.peak_busy_jobs.max(get_busy_jobs());
+                current.samples += 1;
+                current.global_queue_depth_sum += metrics.global_queue_depth();
+
+                tokio::time::sleep(SAMPLE_INTERVAL).await;
+            }
+
+            if let Some(last) = stats.last_mut() {
+                last.duration_ms = phase_started.elapsed().as_millis();
+            }
+            for phase_stats in &mut stats {
+                phase_stats.mean_global_queue_depth =
+                    phase_stats.global_queue_depth_sum as f64 / phase_stats.samples.max(1) as f64;
+            }
+            stats
+        });
+
+        Self { stopped, handle }
+    }
+
+    pub async fn finish(self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        let stats = self.handle.await.expect("Could not join on runtime stats sampler");
+        let metrics = tokio::runtime::Handle::current().metrics();
+
+        println!(
+            "Tokio runtime: {} worker thread(s), {} pipeline job(s)",
+            metrics.num_workers(),
+            get_jobs()
+        );
+        println!(
+            "{:<20} {:>10} {:>12} {:>12} {:>12} {:>12}",
+            "phase", "time (ms)", "OS threads", "tasks", "busy jobs", "queue depth"
+        );
+        for phase_stats in &stats {
+            println!(
+                "{:<20} {:>10} {:>12} {:>12} {:>12} {:>12.1}",
+                phase_stats.phase,
+                phase_stats.duration_ms,
+                phase_stats.peak_os_threads,
+                phase_stats.peak_alive_tasks,
+                phase_stats.peak_busy_jobs,
+                phase_stats.mean_global_queue_depth
+            );
+        }
+    }
+}
+
+fn get_os_threads() -> usize {
+    std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("Threads:"))
+                .and_then(|threads| threads.trim().parse().ok())
+        })
+        .unwrap_or_default()
+}
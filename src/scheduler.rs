@@ -0,0 +1,112 @@
+use std::{
+    future::Future,
+    sync::{Arc, OnceLock},
+};
+
+use tokio::{
+    sync::Semaphore,
+    task::{JoinError, JoinSet},
+};
+
+static JOBS: OnceLock<usize> = OnceLock::new();
+static JOB_SEMAPHORE: OnceLock<Arc<Semaphore>> = OnceLock::new();
+
+pub fn init_scheduler(jobs: Option<usize>) {
+    let jobs = jobs.unwrap_or_else(get_default_jobs).max(1);
+    if JOBS.set(jobs).is_err() {
+        panic!("The job scheduler was initialized more than once");
+    }
+    log::debug!("Job scheduler allows {jobs} concurrent job(s)");
+}
+
+pub fn get_jobs() -> usize {
+    *JOBS.get_or_init(get_default_jobs)
+}
+
+pub fn get_busy_jobs() -> usize {
+    get_jobs().saturating_sub(get_semaphore().available_permits())
+}
+
+fn get_default_jobs() -> usize {
+    // pipeline jobs are mostly file copies that wait on I/O, so twice the CPU count keeps the disk busy
+    let cpus = std::thread::available_parallelism().map(|cpus| cpus.get()).unwrap_or(1);
+    (cpus * 2).min(32)
+}
+
+fn get_semaphore() -> Arc<Semaphore> {
+    JOB_SEMAPHORE
+        .get_or_init(|| Arc::new(Semaphore::new(get_jobs())))
+        .clone()
+}
+
+pub struct JobSet<T> {
+    join_set: JoinSet<T>,
+}
+
+impl<T: Send + 'static> JobSet<T> {
+    pub fn new() -> Self {
+        Self {
+            join_set: JoinSet::new(),
+        }
+    }
+
+    pub fn spawn(&mut self, job: impl Future<Output = T> + Send + 'static) {
+        let semaphore = get_semaphore();
+        self.join_set.spawn(async move {
+            let _permit = semaphore.acquire_owned().await.expect("Job semaphore was closed");
+            job.await
+        });
+    }
+
+    pub fn spawn_blocking(&mut self, job: impl FnOnce() -> T + Send + 'static) {
+        let semaphore = get_semaphore();
+        self.join_set.spawn(async move {
+            let _permit = semaphore.acquire_owned().await.expect("Job semaphore was closed");
+            match tokio::task::spawn_blocking(job).await {
+                Ok(output) => output,
+                Err(err) => std::panic::resume_unwind(err.into_panic()),
+            }
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.join_set.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.join_set.is_empty()
+    }
+
+    pub async fn join_next(&mut self) -> Option<Result<T, JoinError>> {
+        self.join_set.join_next().await
+    }
+}
+
+impl<T: Send + 'static> Default for JobSet<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::JobSet;
+
+    #[tokio::test]
+    async fn job_set_runs_all_jobs() {
+        let mut job_set = JobSet::new();
+        for index in 0..64 {
+            if index % 2 == 0 {
+                job_set.spawn(async move { index });
+            } else {
+                job_set.spawn_blocking(move || index);
+            }
+        }
+
+        let mut sum = 0u32;
+        while let Some(result) = job_set.join_next().await {
+            sum += result.unwrap();
+        }
+        assert_eq!(sum, (0..64).sum::<u32>());
+    }
+}
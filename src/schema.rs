@@ -1,32 +1,345 @@
-use std::{collections::HashMap, fmt::Display, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+};
 
-use serde::Deserialize;
+use clap::ValueEnum;
+use schemars::{
+    schema::{RootSchema, Schema, SchemaObject},
+    schema_for, JsonSchema,
+};
+use serde::{Deserialize, Serialize};
 
-#[derive(Deserialize, Debug)]
+use crate::{
+    error::BuildfsError,
+    image_reference::{normalize_image_reference, parse_image_reference},
+    size::{deserialize_mib, deserialize_optional_kib, deserialize_optional_mib, SizeValue},
+    variables::interpolate_build_script,
+    SchemaArgs,
+};
+
+pub static CURRENT_SCHEMA_VERSION: u32 = 1;
+static SCHEMA_ALIASES: &[(&str, &str, &str)] = &[
+    ("BuildScriptFilesystem", "size_mib", "size"),
+    ("BuildScriptFilesystem", "block_size_mib", "block_size"),
+    ("BuildScriptSquashfs", "block_size_kib", "block_size"),
+    ("BuildScriptDedup", "min_size_kib", "min_size"),
+];
+static DEPRECATED_FIELDS: &[(&str, &str, &str)] = &[
+    ("/filesystem/size_mib", "filesystem.size_mib", "filesystem.size"),
+    (
+        "/filesystem/block_size_mib",
+        "filesystem.block_size_mib",
+        "filesystem.block_size",
+    ),
+    (
+        "/filesystem/squashfs/block_size_kib",
+        "filesystem.squashfs.block_size_kib",
+        "filesystem.squashfs.block_size",
+    ),
+    (
+        "/minimize/dedup/min_size_kib",
+        "minimize.dedup.min_size_kib",
+        "minimize.dedup.min_size",
+    ),
+];
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
 pub struct BuildScript {
+    #[serde(default)]
+    pub schema_version: Option<u32>,
+    #[serde(default)]
+    pub metadata: Option<BuildScriptMetadata>,
     pub filesystem: BuildScriptFilesystem,
     pub container: BuildScriptContainer,
     #[serde(default)]
+    pub packages: Vec<BuildScriptPackages>,
+    #[serde(default)]
     pub commands: Vec<BuildScriptCommand>,
     #[serde(default)]
     pub overlays: Vec<BuildScriptOverlay>,
     #[serde(default)]
+    pub first_boot: Vec<BuildScriptFirstBoot>,
+    #[serde(default)]
     pub export: BuildScriptExport,
+    #[serde(default)]
+    pub guest: BuildScriptGuest,
+    #[serde(default)]
+    pub minimize: BuildScriptMinimize,
+    #[serde(default)]
+    pub plugins: Vec<BuildScriptPlugin>,
+    #[serde(default)]
+    pub inventory: BuildScriptInventory,
+    #[serde(default)]
+    pub post_build: BuildScriptPostBuild,
+    #[serde(default)]
+    pub context: Option<BuildScriptContext>,
+    #[serde(default, rename = "use")]
+    pub uses: Vec<BuildScriptUse>,
+    #[serde(default)]
+    pub variables: HashMap<String, String>,
+    #[serde(skip)]
+    pub deprecated_fields: Vec<(&'static str, &'static str)>,
 }
 
-#[derive(Deserialize, Debug)]
+pub fn parse_build_script(build_script_toml: &str) -> BuildScript {
+    try_parse_build_script(build_script_toml, BuildScriptFormat::Toml, &HashMap::new())
+        .unwrap_or_else(|err| panic!("Build script validation failed: {err}"))
+}
+
+pub fn try_parse_build_script(
+    build_script_text: &str,
+    format: BuildScriptFormat,
+    variable_overrides: &HashMap<String, String>,
+) -> Result<BuildScript, String> {
+    let decode_error = |err: String| format!("could not decode build script from {format}: {err}");
+    let mut build_script_value = match format {
+        BuildScriptFormat::Toml => {
+            toml::from_str::<serde_json::Value>(build_script_text).map_err(|err| err.to_string())
+        }
+        BuildScriptFormat::Yaml => {
+            serde_yaml::from_str::<serde_json::Value>(build_script_text).map_err(|err| err.to_string())
+        }
+        BuildScriptFormat::Json => {
+            serde_json::from_str::<serde_json::Value>(build_script_text).map_err(|err| err.to_string())
+        }
+    }
+    .map_err(decode_error)?;
+    let deprecated_fields = DEPRECATED_FIELDS
+        .iter()
+        .filter(|(pointer, _, _)| build_script_value.pointer(pointer).is_some())
+        .map(|(_, field, replacement)| (*field, *replacement))
+        .collect();
+
+    let mut build_script = match build_script_value.get("variables").is_some() || !variable_overrides.is_empty() {
+        true => {
+            interpolate_build_script(&mut build_script_value, variable_overrides)?;
+            serde_json::from_value::<BuildScript>(build_script_value).map_err(|err| decode_error(err.to_string()))?
+        }
+        // without variables the text is decoded directly, since the TOML error's display form then points at the
+        // offending line, which decoding from an intermediate value can't
+        false => match format {
+            BuildScriptFormat::Toml => toml::from_str::<BuildScript>(build_script_text).map_err(|err| err.to_string()),
+            BuildScriptFormat::Yaml => {
+                serde_yaml::from_str::<BuildScript>(build_script_text).map_err(|err| err.to_string())
+            }
+            BuildScriptFormat::Json => {
+                serde_json::from_str::<BuildScript>(build_script_text).map_err(|err| err.to_string())
+            }
+        }
+        .map_err(decode_error)?,
+    };
+
+    match build_script.schema_version {
+        None => log::warn!(
+            "Build script does not specify a schema_version, assuming {CURRENT_SCHEMA_VERSION}. Add \"schema_version = {CURRENT_SCHEMA_VERSION}\" to its top to silence this warning"
+        ),
+        Some(0) => return Err("schema_version 0 is invalid, schema versions start at 1".to_string()),
+        Some(schema_version) if schema_version > CURRENT_SCHEMA_VERSION => return Err(format!(
+            "schema_version {schema_version} is required, but this version of buildfs only supports up to {CURRENT_SCHEMA_VERSION}. Upgrade buildfs to run it"
+        )),
+        Some(_) => {}
+    }
+
+    if let Err(err) = build_script.container.image.normalize() {
+        return Err(format!("container.image is not a valid image reference: {err}"));
+    }
+    build_script.deprecated_fields = deprecated_fields;
+
+    Ok(build_script)
+}
+
+pub async fn schema_command(schema_args: SchemaArgs) -> Result<(), BuildfsError> {
+    let schema_json =
+        serde_json::to_string_pretty(&get_build_script_schema()).expect("Could not encode JSON Schema into JSON");
+    match schema_args.output_path {
+        Some(ref output_path) => {
+            tokio::fs::write(output_path, schema_json)
+                .await
+                .map_err(BuildfsError::io("Could not write JSON Schema to its path"))?;
+            log::info!("Wrote the JSON Schema of the build script format to {output_path:?}");
+        }
+        None => println!("{schema_json}"),
+    }
+    Ok(())
+}
+
+pub fn get_build_script_schema() -> RootSchema {
+    let mut root_schema = schema_for!(BuildScript);
+    // schemars doesn't carry serde aliases over, so the short forms of size fields are added by hand
+    for (definition_name, field, alias) in SCHEMA_ALIASES {
+        let Some(Schema::Object(definition)) = root_schema.definitions.get_mut(*definition_name) else {
+            continue;
+        };
+        let object = definition.object();
+        let Some(property) = object.properties.get(*field).cloned() else {
+            continue;
+        };
+        object.properties.insert(alias.to_string(), property);
+
+        if object.required.remove(*field) {
+            definition.subschemas().any_of = Some(
+                [field, alias]
+                    .iter()
+                    .map(|name| {
+                        let mut required_schema = SchemaObject::default();
+                        required_schema.object().required.insert(name.to_string());
+                        Schema::Object(required_schema)
+                    })
+                    .collect(),
+            );
+        }
+    }
+    root_schema
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BuildScriptFormat {
+    #[default]
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl BuildScriptFormat {
+    pub fn from_path(path: &Path) -> Option<BuildScriptFormat> {
+        match path.extension()?.to_str()? {
+            "toml" => Some(BuildScriptFormat::Toml),
+            "yaml" | "yml" => Some(BuildScriptFormat::Yaml),
+            "json" => Some(BuildScriptFormat::Json),
+            _ => None,
+        }
+    }
+
+    pub fn get_build_script_filename(&self) -> &'static str {
+        match self {
+            BuildScriptFormat::Toml => "build.toml",
+            BuildScriptFormat::Yaml => "build.yaml",
+            BuildScriptFormat::Json => "build.json",
+        }
+    }
+}
+
+impl Display for BuildScriptFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildScriptFormat::Toml => write!(f, "TOML"),
+            BuildScriptFormat::Yaml => write!(f, "YAML"),
+            BuildScriptFormat::Json => write!(f, "JSON"),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[serde(untagged)]
+pub enum BuildScriptUse {
+    Reference(String),
+    Parameterized {
+        source: String,
+        #[serde(default)]
+        variables: HashMap<String, String>,
+    },
+}
+
+impl BuildScriptUse {
+    pub fn get_source(&self) -> &str {
+        match self {
+            BuildScriptUse::Reference(source) => source,
+            BuildScriptUse::Parameterized { source, .. } => source,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone)]
+pub struct BuildScriptMetadata {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub version: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
 pub struct BuildScriptFilesystem {
     #[serde(default, rename = "type")]
     pub filesystem_type: FilesystemType,
+    #[serde(alias = "size", deserialize_with = "deserialize_mib")]
+    #[schemars(with = "SizeValue")]
     pub size_mib: u32,
+    #[serde(default, alias = "block_size", deserialize_with = "deserialize_optional_mib")]
+    #[schemars(with = "Option<SizeValue>")]
     pub block_size_mib: Option<u32>,
     #[serde(default)]
     pub dd_args: Vec<String>,
     #[serde(default)]
     pub mkfs_args: Vec<String>,
+    #[serde(default)]
+    pub loop_device: Option<BuildScriptLoopDevice>,
+    #[serde(default)]
+    pub vfat: Option<BuildScriptVfat>,
+    #[serde(default)]
+    pub squashfs: Option<BuildScriptSquashfs>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone)]
+pub struct BuildScriptSquashfs {
+    #[serde(default)]
+    pub compression: Option<SquashfsCompression>,
+    #[serde(default)]
+    pub compression_level: Option<u32>,
+    #[serde(default, alias = "block_size", deserialize_with = "deserialize_optional_kib")]
+    #[schemars(with = "Option<SizeValue>")]
+    pub block_size_kib: Option<u32>,
+    #[serde(default)]
+    pub all_root: bool,
+    #[serde(default)]
+    pub pseudo_files: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy)]
+pub enum SquashfsCompression {
+    Gzip,
+    Lzo,
+    Lz4,
+    Xz,
+    Zstd,
+}
+
+impl Display for SquashfsCompression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SquashfsCompression::Gzip => write!(f, "gzip"),
+            SquashfsCompression::Lzo => write!(f, "lzo"),
+            SquashfsCompression::Lz4 => write!(f, "lz4"),
+            SquashfsCompression::Xz => write!(f, "xz"),
+            SquashfsCompression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone)]
+pub struct BuildScriptVfat {
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub codepage: Option<u16>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone)]
+pub struct BuildScriptLoopDevice {
+    #[serde(default)]
+    pub direct_io: bool,
+    #[serde(default)]
+    pub read_ahead_kib: Option<u32>,
+    #[serde(default)]
+    pub partition_scan: bool,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
 pub struct BuildScriptContainer {
     #[serde(default)]
     pub engine: ContainerEngineType,
@@ -51,21 +364,209 @@ pub struct BuildScriptContainer {
     pub cap_add: Option<Vec<String>>,
     #[serde(default)]
     pub cap_drop: Option<Vec<String>>,
+    #[serde(default)]
+    pub network_mode: Option<String>,
+    #[serde(default)]
+    pub ready_check: Option<BuildScriptReadyCheck>,
+    #[serde(default)]
+    pub entrypoint: Option<Vec<String>>,
+    #[serde(default)]
+    pub cmd: Option<Vec<String>>,
+    #[serde(default)]
+    pub init: Option<bool>,
+    #[serde(default)]
+    pub keep_alive: KeepAlivePolicy,
+    #[serde(default)]
+    pub pause_cmd: Option<Vec<String>>,
+    #[serde(default)]
+    pub direct_pull: DirectPullPolicy,
+    #[serde(default)]
+    pub attach_to: Option<String>,
+    #[serde(default)]
+    pub userns: Option<String>,
+    #[serde(default)]
+    pub uidmap: Vec<BuildScriptIdMap>,
+    #[serde(default)]
+    pub gidmap: Vec<BuildScriptIdMap>,
+    #[serde(default)]
+    pub keep_id: bool,
+    #[serde(default)]
+    pub caches: HashMap<String, BuildScriptStepMount>,
+    #[serde(default)]
+    pub secrets: HashMap<String, BuildScriptStepMount>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct BuildScriptStepMount {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy)]
+pub struct BuildScriptIdMap {
+    pub container_id: u32,
+    pub host_id: u32,
+    pub size: u32,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone, Copy)]
+pub enum KeepAlivePolicy {
+    Pause,
+    #[default]
+    Tty,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DirectPullPolicy {
+    #[default]
+    Never,
+    Fallback,
+    Always,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct BuildScriptReadyCheck {
+    pub command: String,
+    #[serde(default)]
+    pub retries: Option<u32>,
+    #[serde(default)]
+    pub interval_s: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
 pub struct BuildScriptContainerImage {
     pub name: String,
+    #[serde(default)]
     pub tag: String,
+    #[serde(default)]
+    pub digest: Option<String>,
+    #[serde(default)]
+    pub mirrors: Vec<String>,
+    #[serde(default)]
+    pub pull_timeout_s: Option<u64>,
+}
+
+impl BuildScriptContainer {
+    pub fn is_remote(&self) -> bool {
+        self.connection_uri
+            .as_ref()
+            .is_some_and(|connection_uri| connection_uri.starts_with("ssh://"))
+    }
 }
 
 impl BuildScriptContainerImage {
     pub fn full_name(&self) -> String {
-        format!("{}:{}", self.name, self.tag)
+        let mut full_name = self.name.clone();
+        if !self.tag.is_empty() {
+            full_name.push_str(&format!(":{}", self.tag));
+        }
+        if let Some(ref digest) = self.digest {
+            full_name.push_str(&format!("@{digest}"));
+        }
+        full_name
     }
+
+    pub fn normalize(&mut self) -> Result<(), String> {
+        let image_reference = normalize_image_reference(&self.name, &self.tag, self.digest.as_deref())?;
+        self.name = image_reference.name();
+        self.tag = image_reference.tag.unwrap_or_default();
+        self.digest = image_reference.digest;
+
+        // mirrors share the image's tag and digest, so they may only name a repository
+        for mirror in &self.mirrors {
+            let mirror_reference = parse_image_reference(mirror).map_err(|err| format!("mirror {mirror:?}: {err}"))?;
+            if mirror_reference.tag.is_some() || mirror_reference.digest.is_some() {
+                return Err(format!(
+                    "mirror {mirror:?} must not embed a tag or digest, since it shares the image's"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn get_registry(&self) -> Option<String> {
+        parse_image_reference(&self.name).ok()?.registry
+    }
+
+    pub fn get_pull_candidates(&self) -> Vec<BuildScriptContainerImage> {
+        // mirrors are tried in order, and the image's own name is the last resort unless it's listed among them
+        let mut names = self.mirrors.clone();
+        if !names.contains(&self.name) {
+            names.push(self.name.clone());
+        }
+
+        names
+            .into_iter()
+            .map(|name| BuildScriptContainerImage {
+                name,
+                tag: self.tag.clone(),
+                digest: self.digest.clone(),
+                mirrors: Vec::new(),
+                pull_timeout_s: self.pull_timeout_s,
+            })
+            .collect()
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone)]
+pub struct BuildScriptPostBuild {
+    #[serde(default)]
+    pub commit_image: Option<String>,
+    #[serde(default)]
+    pub push: bool,
+}
+
+impl BuildScriptPostBuild {
+    pub fn get_commit_image(&self) -> Option<BuildScriptContainerImage> {
+        let commit_image = self.commit_image.as_ref()?;
+        let image_reference = normalize_image_reference(commit_image, "", None)
+            .unwrap_or_else(|err| panic!("Could not parse post_build.commit_image {commit_image:?}: {err}"));
+
+        Some(BuildScriptContainerImage {
+            name: image_reference.name(),
+            tag: image_reference.tag.unwrap_or_default(),
+            digest: None,
+            mirrors: Vec::new(),
+            pull_timeout_s: None,
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone)]
+pub struct BuildScriptContext {
+    #[serde(default)]
+    pub destination: Option<PathBuf>,
+    #[serde(default)]
+    pub host_path: Option<PathBuf>,
 }
 
-#[derive(Deserialize, Debug)]
+impl BuildScriptContext {
+    pub fn get_destination(&self) -> PathBuf {
+        self.destination.clone().unwrap_or_else(|| PathBuf::from("/__context"))
+    }
+
+    pub fn get_host_path(&self) -> PathBuf {
+        self.host_path.clone().unwrap_or_else(|| PathBuf::from("context"))
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+pub struct BuildScriptPackages {
+    pub install: Vec<String>,
+    #[serde(default)]
+    pub manager: Option<PackageManager>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackageManager {
+    Apt,
+    Dnf,
+    Apk,
+    Zypper,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default)]
 pub struct BuildScriptCommand {
     // only one of these can be specified
     #[serde(default)]
@@ -85,9 +586,23 @@ pub struct BuildScriptCommand {
     pub privileged: Option<bool>,
     #[serde(default)]
     pub env: HashMap<String, String>,
+    #[serde(default)]
+    pub interpreter: Option<String>,
+    #[serde(default)]
+    pub shell: Option<String>,
+    #[serde(default)]
+    pub early_export: bool,
+    #[serde(default)]
+    pub expect_output_regex: Option<String>,
+    #[serde(default)]
+    pub save_output_to: Option<PathBuf>,
+    #[serde(default)]
+    pub mounts: Vec<String>,
+    #[serde(default)]
+    pub allow_failure: bool,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
 pub struct BuildScriptOverlay {
     #[serde(default)]
     pub source: Option<PathBuf>,
@@ -98,17 +613,91 @@ pub struct BuildScriptOverlay {
     pub is_directory: bool,
     #[serde(default)]
     pub mounted: bool,
+    #[serde(default)]
+    pub payload: BuildScriptPayload,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone)]
+pub struct BuildScriptPayload {
+    #[serde(default)]
+    pub compression: Option<PayloadCompression>,
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+impl BuildScriptPayload {
+    pub fn is_encoded(&self) -> bool {
+        self.compression.is_some() || self.encrypted
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy)]
+pub enum PayloadCompression {
+    Zstd,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+pub struct BuildScriptFirstBoot {
+    pub name: String,
+    // only one of these can be specified
+    #[serde(default)]
+    pub script_inline: Option<String>,
+    #[serde(default)]
+    pub script_path: Option<PathBuf>,
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default)]
 pub struct BuildScriptExport {
     #[serde(default)]
     pub files: Export,
     #[serde(default)]
     pub directories: Export,
+    #[serde(default)]
+    pub symlinks: SymlinkPolicy,
+    #[serde(default)]
+    pub fail_on_dangling_symlinks: bool,
+    #[serde(default)]
+    pub ownership: BuildScriptOwnership,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone)]
+pub struct BuildScriptOwnership {
+    #[serde(default)]
+    pub map: Vec<BuildScriptOwnershipMap>,
+    #[serde(default)]
+    pub rules: Vec<BuildScriptOwnershipRule>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy)]
+pub struct BuildScriptOwnershipMap {
+    pub from: u32,
+    pub to: u32,
+    pub size: u32,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct BuildScriptOwnershipRule {
+    pub path: PathBuf,
+    pub owner: OwnershipPolicy,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OwnershipPolicy {
+    #[default]
+    Mapped,
+    Root,
+    Preserve,
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    #[default]
+    Preserve,
+    RewriteRelative,
+    Dereference,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default)]
 pub struct Export {
     #[serde(default)]
     pub include: Vec<PathBuf>,
@@ -116,11 +705,124 @@ pub struct Export {
     pub create: Vec<PathBuf>,
 }
 
-#[derive(Deserialize, Debug, Default, Clone)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone)]
+pub struct BuildScriptMinimize {
+    #[serde(default)]
+    pub strip_docs: bool,
+    #[serde(default)]
+    pub strip_locales: Option<Vec<String>>,
+    #[serde(default)]
+    pub clean_package_cache: bool,
+    #[serde(default)]
+    pub strip_binaries: bool,
+    #[serde(default)]
+    pub dedup: Option<BuildScriptDedup>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone)]
+pub struct BuildScriptDedup {
+    #[serde(default, alias = "min_size", deserialize_with = "deserialize_optional_kib")]
+    #[schemars(with = "Option<SizeValue>")]
+    pub min_size_kib: Option<u64>,
+    #[serde(default)]
+    pub exclude: Option<Vec<PathBuf>>,
+}
+
+impl BuildScriptDedup {
+    pub fn get_min_size_kib(&self) -> u64 {
+        self.min_size_kib.unwrap_or(64)
+    }
+
+    // hardlinked files share every later write, so trees that are edited at runtime aren't deduplicated by default
+    pub fn get_exclude(&self) -> Vec<PathBuf> {
+        self.exclude
+            .clone()
+            .unwrap_or_else(|| vec![PathBuf::from("/etc"), PathBuf::from("/var")])
+    }
+}
+
+impl BuildScriptMinimize {
+    pub fn is_enabled(&self) -> bool {
+        self.strip_docs || self.strip_locales.is_some() || self.clean_package_cache || self.strip_binaries
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default)]
+pub struct BuildScriptInventory {
+    #[serde(default)]
+    pub report: bool,
+    #[serde(default)]
+    pub manifest: bool,
+}
+
+impl BuildScriptInventory {
+    pub fn is_enabled(&self) -> bool {
+        self.report || self.manifest
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default)]
+pub struct BuildScriptGuest {
+    #[serde(default)]
+    pub network: Option<BuildScriptGuestNetwork>,
+    #[serde(default)]
+    pub agent: Option<BuildScriptGuestAgent>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+pub struct BuildScriptGuestAgent {
+    pub source: PathBuf,
+    #[serde(default)]
+    pub vsock_port: Option<u32>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default)]
+pub struct BuildScriptGuestNetwork {
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub resolv_conf: ResolvConfPolicy,
+    #[serde(default)]
+    pub resolv_conf_inline: Option<String>,
+    #[serde(default)]
+    pub nsswitch_hosts: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone, Copy)]
+pub enum ResolvConfPolicy {
+    #[default]
+    Empty,
+    Inline,
+    SystemdResolved,
+    Keep,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+pub struct BuildScriptPlugin {
+    pub name: String,
+    pub hook: PluginHook,
+    #[serde(default)]
+    #[schemars(with = "HashMap<String, serde_json::Value>")]
+    pub config: toml::Table,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PluginHook {
+    PostStart,
+    PostCommands,
+    PostExport,
+    PreUnmount,
+    PostBuild,
+}
+
+#[derive(ValueEnum, Deserialize, Serialize, JsonSchema, Debug, Default, Clone)]
 pub enum ContainerEngineType {
     #[default]
     Docker,
     Podman,
+    Kubernetes,
+    #[cfg(any(test, feature = "mock-engine"))]
+    Mock,
 }
 
 impl Display for ContainerEngineType {
@@ -128,11 +830,14 @@ impl Display for ContainerEngineType {
         match self {
             ContainerEngineType::Docker => write!(f, "Docker"),
             ContainerEngineType::Podman => write!(f, "Podman"),
+            ContainerEngineType::Kubernetes => write!(f, "Kubernetes"),
+            #[cfg(any(test, feature = "mock-engine"))]
+            ContainerEngineType::Mock => write!(f, "Mock"),
         }
     }
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone, Copy)]
 pub enum FilesystemType {
     #[default]
     Ext4,
@@ -141,3 +846,71 @@ pub enum FilesystemType {
     Vfat,
     Xfs,
 }
+
+impl Display for FilesystemType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FilesystemType::Ext4 => write!(f, "Ext4"),
+            FilesystemType::Btrfs => write!(f, "Btrfs"),
+            FilesystemType::Squashfs => write!(f, "Squashfs"),
+            FilesystemType::Vfat => write!(f, "Vfat"),
+            FilesystemType::Xfs => write!(f, "Xfs"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::PathBuf};
+
+    use super::{get_build_script_schema, try_parse_build_script, BuildScriptFormat};
+
+    #[test]
+    fn yaml_and_json_build_scripts_are_equivalent() {
+        let yaml = "schema_version: 1\nfilesystem:\n  size: 1 GiB\ncontainer:\n  image: { name: debian, tag: bookworm }\ncommands:\n  - script_inline: |\n      #!/bin/sh\n      echo hi\n";
+        let json = r##"{"schema_version": 1, "filesystem": {"size_mib": 1024}, "container": {"image": {"name": "debian", "tag": "bookworm"}}, "commands": [{"script_inline": "#!/bin/sh\necho hi\n"}]}"##;
+
+        for (text, format) in [(yaml, BuildScriptFormat::Yaml), (json, BuildScriptFormat::Json)] {
+            let build_script = try_parse_build_script(text, format, &HashMap::new()).unwrap();
+            assert_eq!(build_script.filesystem.size_mib, 1024);
+            assert_eq!(build_script.container.image.name, "debian");
+            assert_eq!(
+                build_script.commands[0].script_inline.as_deref(),
+                Some("#!/bin/sh\necho hi\n")
+            );
+        }
+        assert!(
+            try_parse_build_script("filesystem: [", BuildScriptFormat::Yaml, &HashMap::new())
+                .unwrap_err()
+                .starts_with("could not decode build script from YAML")
+        );
+    }
+
+    #[test]
+    fn schema_accepts_size_aliases() {
+        let schema = serde_json::to_value(get_build_script_schema()).unwrap();
+        let filesystem = &schema["definitions"]["BuildScriptFilesystem"];
+        assert!(filesystem["properties"]["size"].is_object());
+        assert!(filesystem["properties"]["block_size"].is_object());
+        assert!(!filesystem["required"]
+            .as_array()
+            .is_some_and(|required| required.iter().any(|field| field == "size_mib")));
+        assert_eq!(filesystem["anyOf"].as_array().unwrap().len(), 2);
+        assert!(schema["properties"]["use"].is_object());
+        assert_eq!(schema["required"], serde_json::json!(["container", "filesystem"]));
+    }
+    #[test]
+    fn dedup_skips_small_and_mutable_files_by_default() {
+        let text = "schema_version: 1\nfilesystem:\n  size: 1 GiB\ncontainer:\n  image: { name: debian, tag: bookworm }\nminimize:\n  dedup: {}\n";
+        let build_script = try_parse_build_script(text, BuildScriptFormat::Yaml, &HashMap::new()).unwrap();
+        let dedup = build_script.minimize.dedup.unwrap();
+        assert_eq!(dedup.get_min_size_kib(), 64);
+        assert_eq!(dedup.get_exclude(), vec![PathBuf::from("/etc"), PathBuf::from("/var")]);
+
+        let text = text.replace("dedup: {}", "dedup: { min_size: 4 KiB, exclude: [] }");
+        let build_script = try_parse_build_script(&text, BuildScriptFormat::Yaml, &HashMap::new()).unwrap();
+        let dedup = build_script.minimize.dedup.unwrap();
+        assert_eq!(dedup.get_min_size_kib(), 4);
+        assert!(dedup.get_exclude().is_empty());
+    }
+}
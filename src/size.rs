@@ -0,0 +1,131 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer};
+
+static KIB: u64 = 1024;
+static MIB: u64 = 1024 * 1024;
+static SIZE_UNITS: [(&str, u64); 13] = [
+    ("B", 1),
+    ("K", 1024),
+    ("KIB", 1024),
+    ("KB", 1000),
+    ("M", 1024 * 1024),
+    ("MIB", 1024 * 1024),
+    ("MB", 1000 * 1000),
+    ("G", 1024 * 1024 * 1024),
+    ("GIB", 1024 * 1024 * 1024),
+    ("GB", 1000 * 1000 * 1000),
+    ("T", 1024 * 1024 * 1024 * 1024),
+    ("TIB", 1024 * 1024 * 1024 * 1024),
+    ("TB", 1000 * 1000 * 1000 * 1000),
+];
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum SizeValue {
+    Integer(u64),
+    Text(String),
+}
+
+pub fn deserialize_mib<'de, D: Deserializer<'de>, T: TryFrom<u64>>(deserializer: D) -> Result<T, D::Error> {
+    deserialize_size(deserializer, MIB, "MiB")
+}
+
+pub fn deserialize_optional_mib<'de, D: Deserializer<'de>, T: TryFrom<u64>>(
+    deserializer: D,
+) -> Result<Option<T>, D::Error> {
+    deserialize_size(deserializer, MIB, "MiB").map(Some)
+}
+
+pub fn deserialize_optional_kib<'de, D: Deserializer<'de>, T: TryFrom<u64>>(
+    deserializer: D,
+) -> Result<Option<T>, D::Error> {
+    deserialize_size(deserializer, KIB, "KiB").map(Some)
+}
+
+fn deserialize_size<'de, D: Deserializer<'de>, T: TryFrom<u64>>(
+    deserializer: D,
+    unit: u64,
+    unit_name: &str,
+) -> Result<T, D::Error> {
+    let size = match SizeValue::deserialize(deserializer)? {
+        // bare integers keep meaning the field's own unit, as they did before units were accepted
+        SizeValue::Integer(size) => size,
+        SizeValue::Text(text) => parse_size(&text, unit, unit_name).map_err(serde::de::Error::custom)?,
+    };
+    T::try_from(size).map_err(|_| serde::de::Error::custom(format!("size of {size} {unit_name} is too large")))
+}
+
+pub fn parse_size(text: &str, unit: u64, unit_name: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let number_end = text
+        .find(|character: char| !character.is_ascii_digit() && character != '.')
+        .unwrap_or(text.len());
+    let (number, suffix) = text.split_at(number_end);
+    let suffix = suffix.trim().to_uppercase();
+
+    let multiplier = match suffix.as_str() {
+        "" => unit,
+        suffix => SIZE_UNITS
+            .iter()
+            .find(|(name, _)| *name == suffix)
+            .map(|(_, multiplier)| *multiplier)
+            .ok_or_else(|| {
+                format!("size \"{text}\" has an unknown unit, expected one of B, KiB, MiB, GiB, TiB, KB, MB, GB or TB")
+            })?,
+    };
+
+    // the number is kept in fixed point so that e.g. "2.5 GiB" is exact instead of going through a float
+    let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+    if whole.is_empty() && fraction.is_empty() {
+        return Err(format!("size \"{text}\" does not start with a number"));
+    }
+    let scale = 10u128
+        .checked_pow(fraction.len() as u32)
+        .ok_or_else(|| format!("size \"{text}\" has too many decimal places"))?;
+    let parse_digits = |digits: &str| match digits.is_empty() {
+        true => Ok(0u128),
+        false => digits
+            .parse::<u128>()
+            .map_err(|_| format!("size \"{text}\" is not a valid number")),
+    };
+    let (whole, fraction) = (parse_digits(whole)?, parse_digits(fraction)?);
+    let scaled_bytes = whole
+        .checked_mul(scale)
+        .and_then(|scaled_whole| scaled_whole.checked_add(fraction))
+        .and_then(|scaled_number| scaled_number.checked_mul(multiplier as u128))
+        .ok_or_else(|| format!("size \"{text}\" is too large"))?;
+
+    if scaled_bytes % (scale * unit as u128) != 0 {
+        return Err(format!(
+            "size \"{text}\" is not a whole number of {unit_name}, note that KB, MB, GB and TB are powers of 1000 while KiB, MiB, GiB and TiB are powers of 1024"
+        ));
+    }
+    u64::try_from(scaled_bytes / (scale * unit as u128)).map_err(|_| format!("size \"{text}\" is too large"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schema::parse_build_script;
+
+    use super::parse_size;
+
+    #[test]
+    fn sizes_accept_units_and_bare_integers() {
+        assert_eq!(parse_size("2.5 GiB", 1024 * 1024, "MiB"), Ok(2560));
+        assert_eq!(parse_size("4M", 1024 * 1024, "MiB"), Ok(4));
+        assert_eq!(parse_size("128 KiB", 1024, "KiB"), Ok(128));
+        assert!(parse_size("2 GB", 1024 * 1024, "MiB").is_err());
+        assert!(parse_size("2 parsecs", 1024 * 1024, "MiB").is_err());
+
+        let build_script = parse_build_script(
+            "schema_version = 1\n[filesystem]\nsize = \"2.5 GiB\"\nblock_size = \"4 MiB\"\n[container]\nimage = { name = \"debian\", tag = \"bookworm\" }\n",
+        );
+        assert_eq!(build_script.filesystem.size_mib, 2560);
+        assert_eq!(build_script.filesystem.block_size_mib, Some(4));
+
+        let build_script = parse_build_script(
+            "schema_version = 1\n[filesystem]\nsize_mib = 64\n[container]\nimage = { name = \"debian\", tag = \"bookworm\" }\n",
+        );
+        assert_eq!(build_script.filesystem.size_mib, 64);
+    }
+}
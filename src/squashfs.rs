@@ -0,0 +1,164 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use crate::{
+    audit::{AuditAction, AuditLog},
+    error::BuildfsError,
+    schema::{BuildScriptFilesystem, BuildScriptSquashfs, SquashfsCompression},
+    tools::{get_tool_command, ToolsConfig},
+};
+
+pub fn get_mksquashfs_args(squashfs: &BuildScriptSquashfs) -> Vec<String> {
+    let mut args = vec!["-noappend".to_string()];
+
+    if let Some(compression) = squashfs.compression {
+        args.push("-comp".to_string());
+        args.push(compression.to_string());
+    }
+    if let Some(compression_level) = squashfs.compression_level {
+        args.push("-Xcompression-level".to_string());
+        args.push(compression_level.to_string());
+    }
+    if let Some(block_size_kib) = squashfs.block_size_kib {
+        args.push("-b".to_string());
+        args.push(format!("{block_size_kib}K"));
+    }
+    if squashfs.all_root {
+        args.push("-all-root".to_string());
+    }
+    for pseudo_file in &squashfs.pseudo_files {
+        args.push("-p".to_string());
+        args.push(pseudo_file.clone());
+    }
+
+    args
+}
+
+pub fn validate_squashfs(squashfs: &BuildScriptSquashfs) -> Result<(), BuildfsError> {
+    if let Some(compression_level) = squashfs.compression_level {
+        let max_level = match squashfs.compression {
+            None | Some(SquashfsCompression::Gzip) | Some(SquashfsCompression::Lzo) => 9,
+            Some(SquashfsCompression::Zstd) => 22,
+            Some(compression) => {
+                return Err(BuildfsError::Validation(format!(
+                    "the {compression} squashfs compressor does not accept a compression level"
+                )))
+            }
+        };
+
+        if compression_level == 0 || compression_level > max_level {
+            return Err(BuildfsError::Validation(format!(
+                "squashfs compression level must be between 1 and {max_level}"
+            )));
+        }
+    }
+
+    if let Some(block_size_kib) = squashfs.block_size_kib {
+        if !block_size_kib.is_power_of_two() || !(4..=1024).contains(&block_size_kib) {
+            return Err(BuildfsError::Validation(
+                "squashfs block size (KiB) must be a power of two between 4 and 1024".to_string(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn pack_squashfs(
+    filesystem: &BuildScriptFilesystem,
+    staging_path: &Path,
+    output_path: &PathBuf,
+    no_exec_logs: bool,
+    tools: &ToolsConfig,
+    audit_log: &AuditLog,
+) -> Result<(), BuildfsError> {
+    let output_parent_path = output_path.parent().unwrap_or(Path::new("."));
+    let mut mksquashfs_command = get_tool_command(tools, "mksquashfs", &[staging_path, output_parent_path])?;
+    mksquashfs_command.arg(staging_path).arg(output_path);
+    mksquashfs_command.args(get_mksquashfs_args(&filesystem.squashfs.clone().unwrap_or_default()));
+    mksquashfs_command.args(&filesystem.mkfs_args);
+    if no_exec_logs {
+        mksquashfs_command.stdout(Stdio::null());
+        mksquashfs_command.stderr(Stdio::null());
+    }
+    audit_log.record_with_args(
+        AuditAction::MakeFilesystem,
+        output_path,
+        &mksquashfs_command
+            .as_std()
+            .get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect::<Vec<_>>(),
+    );
+
+    let mksquashfs_exit_status = mksquashfs_command
+        .status()
+        .await
+        .map_err(BuildfsError::io("Failed to fork \"mksquashfs\" process"))?;
+    if !mksquashfs_exit_status.success() {
+        return Err(BuildfsError::Filesystem(format!(
+            "\"mksquashfs\" invocation failed with exit status: {mksquashfs_exit_status}"
+        )));
+    }
+
+    tokio::fs::remove_dir_all(staging_path)
+        .await
+        .map_err(BuildfsError::io("Could not remove squashfs staging directory"))?;
+    log::info!("Packed the staging directory into a squashfs image at {output_path:?}");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::BuildfsError,
+        schema::{BuildScriptSquashfs, SquashfsCompression},
+    };
+
+    use super::{get_mksquashfs_args, validate_squashfs};
+
+    #[test]
+    fn options_are_translated_into_mksquashfs_args() {
+        let squashfs = BuildScriptSquashfs {
+            compression: Some(SquashfsCompression::Zstd),
+            compression_level: Some(19),
+            block_size_kib: Some(256),
+            all_root: true,
+            pseudo_files: vec!["/dev/console c 600 0 0 5 1".to_string()],
+        };
+        validate_squashfs(&squashfs).unwrap();
+
+        assert_eq!(
+            get_mksquashfs_args(&squashfs),
+            [
+                "-noappend",
+                "-comp",
+                "zstd",
+                "-Xcompression-level",
+                "19",
+                "-b",
+                "256K",
+                "-all-root",
+                "-p",
+                "/dev/console c 600 0 0 5 1"
+            ]
+        );
+    }
+
+    #[test]
+    fn compression_level_is_rejected_for_xz() {
+        let result = validate_squashfs(&BuildScriptSquashfs {
+            compression: Some(SquashfsCompression::Xz),
+            compression_level: Some(6),
+            ..Default::default()
+        });
+
+        assert!(matches!(
+            result,
+            Err(BuildfsError::Validation(message)) if message.contains("does not accept a compression level")
+        ));
+    }
+}
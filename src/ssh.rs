@@ -0,0 +1,128 @@
+use std::{
+    path::PathBuf,
+    process::{Child, Stdio},
+    time::Duration,
+};
+
+use uuid::Uuid;
+
+use crate::schema::ContainerEngineType;
+
+static SSH_TUNNEL_ATTEMPTS: u32 = 100;
+
+pub struct SshTunnel {
+    child: Child,
+    socket_path: PathBuf,
+}
+
+impl Drop for SshTunnel {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_file(&self.socket_path);
+    }
+}
+
+pub async fn open_ssh_tunnel(connection_uri: &str, engine: &ContainerEngineType) -> (SshTunnel, String) {
+    let ssh_path = which::which("ssh").expect("Could not locate the \"ssh\" binary in PATH");
+    let (destination, remote_socket_path) = parse_ssh_uri(connection_uri, engine);
+    let socket_path = PathBuf::from(format!("/tmp/{}.sock", Uuid::new_v4()));
+
+    let mut command = std::process::Command::new(ssh_path);
+    command
+        .args(["-nNT", "-o", "ExitOnForwardFailure=yes", "-o", "BatchMode=yes"])
+        .arg("-L")
+        .arg(format!("{}:{remote_socket_path}", socket_path.to_string_lossy()));
+    if let Some(port) = destination.port {
+        command.arg("-p").arg(port);
+    }
+    let child = command
+        .arg(&destination.host)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("Could not invoke ssh to open a tunnel to the remote build host");
+    let mut tunnel = SshTunnel { child, socket_path };
+
+    for _ in 0..SSH_TUNNEL_ATTEMPTS {
+        if tunnel.socket_path.exists() {
+            log::info!(
+                "Opened SSH tunnel to {remote_socket_path} on {} via {:?}",
+                destination.host,
+                tunnel.socket_path
+            );
+            let local_uri = format!("unix://{}", tunnel.socket_path.to_string_lossy());
+            return (tunnel, local_uri);
+        }
+
+        if let Ok(Some(status)) = tunnel.child.try_wait() {
+            panic!(
+                "Could not open SSH tunnel to {}: ssh exited with {status}",
+                destination.host
+            );
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    panic!(
+        "Could not open SSH tunnel to {}: the forwarded socket never appeared",
+        destination.host
+    );
+}
+
+struct SshDestination {
+    host: String,
+    port: Option<String>,
+}
+
+fn parse_ssh_uri(connection_uri: &str, engine: &ContainerEngineType) -> (SshDestination, String) {
+    let uri = connection_uri.trim_start_matches("ssh://");
+    let (authority, remote_socket_path) = match uri.find('/') {
+        Some(index) => (&uri[..index], uri[index..].to_string()),
+        None => (
+            uri,
+            match engine {
+                ContainerEngineType::Podman => "/run/podman/podman.sock".to_string(),
+                _ => "/var/run/docker.sock".to_string(),
+            },
+        ),
+    };
+    let destination = match authority.rsplit_once(':') {
+        Some((host, port)) => SshDestination {
+            host: host.to_string(),
+            port: Some(port.to_string()),
+        },
+        None => SshDestination {
+            host: authority.to_string(),
+            port: None,
+        },
+    };
+
+    (destination, remote_socket_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schema::ContainerEngineType;
+
+    use super::parse_ssh_uri;
+
+    #[test]
+    fn uri_with_port_and_socket_is_parsed() {
+        let (destination, remote_socket_path) = parse_ssh_uri(
+            "ssh://builder@build-01:2222/run/user/1000/podman/podman.sock",
+            &ContainerEngineType::Podman,
+        );
+        assert_eq!(destination.host, "builder@build-01");
+        assert_eq!(destination.port.as_deref(), Some("2222"));
+        assert_eq!(remote_socket_path, "/run/user/1000/podman/podman.sock");
+    }
+
+    #[test]
+    fn uri_without_socket_uses_engine_default() {
+        let (destination, remote_socket_path) = parse_ssh_uri("ssh://build-01", &ContainerEngineType::Docker);
+        assert_eq!(destination.host, "build-01");
+        assert_eq!(destination.port, None);
+        assert_eq!(remote_socket_path, "/var/run/docker.sock");
+    }
+}
@@ -0,0 +1,157 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use crate::{
+    container_engine::ContainerEngine, error::BuildfsError, run::exec_and_collect, schema::BuildScriptStepMount,
+};
+
+pub static CACHE_MOUNTS_PATH: &str = "/__caches";
+static DISPLACED_SUFFIX: &str = ".buildfs-displaced";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepMountKind {
+    Cache,
+    Secret,
+}
+
+pub struct AttachedStepMount {
+    reference: String,
+    destination: PathBuf,
+    displaced: bool,
+}
+
+pub fn parse_step_mount(reference: &str) -> Option<(StepMountKind, &str)> {
+    match reference.split_once(':')? {
+        ("cache", name) => Some((StepMountKind::Cache, name)),
+        ("secret", name) => Some((StepMountKind::Secret, name)),
+        _ => None,
+    }
+}
+
+pub async fn attach_step_mounts(
+    container_engine: &dyn ContainerEngine,
+    container_id: &str,
+    container_name: &str,
+    caches: &HashMap<String, BuildScriptStepMount>,
+    secrets: &HashMap<String, BuildScriptStepMount>,
+    references: &[String],
+) -> Result<Vec<AttachedStepMount>, BuildfsError> {
+    let mut attached_step_mounts = Vec::with_capacity(references.len());
+    for reference in references {
+        let (kind, name) = parse_step_mount(reference).ok_or_else(|| {
+            BuildfsError::Validation(format!(
+                "Could not parse step mount \"{reference}\", expected \"cache:<name>\" or \"secret:<name>\""
+            ))
+        })?;
+        let step_mount = match kind {
+            StepMountKind::Cache => caches.get(name),
+            StepMountKind::Secret => secrets.get(name),
+        }
+        .ok_or_else(|| {
+            BuildfsError::Validation(format!(
+                "Could not find step mount \"{reference}\" in the container's caches or secrets"
+            ))
+        })?;
+        let destination = step_mount.destination.to_string_lossy();
+
+        if let Some(parent_path) = step_mount.destination.parent() {
+            exec_step_mount_cmd(
+                container_engine,
+                container_id,
+                container_name,
+                &format!("mkdir -p {}", parent_path.to_string_lossy()),
+            )
+            .await?;
+        }
+        // whatever the image has at the destination is moved aside for the step and restored afterwards
+        let (exit_code, _) = exec_and_collect(
+            container_engine,
+            container_id,
+            container_name,
+            &format!("test -e {destination}"),
+        )
+        .await?;
+        let displaced = exit_code == Some(0);
+        if displaced {
+            exec_step_mount_cmd(
+                container_engine,
+                container_id,
+                container_name,
+                &format!("mv {destination} {destination}{DISPLACED_SUFFIX}"),
+            )
+            .await?;
+        }
+
+        match kind {
+            StepMountKind::Cache => {
+                exec_step_mount_cmd(
+                    container_engine,
+                    container_id,
+                    container_name,
+                    &format!("ln -s {CACHE_MOUNTS_PATH}/{name} {destination}"),
+                )
+                .await?;
+            }
+            StepMountKind::Secret => {
+                container_engine
+                    .upload_file(container_name, &step_mount.source, &step_mount.destination)
+                    .await?;
+            }
+        }
+        log::debug!("Attached step mount \"{reference}\" at {:?}", step_mount.destination);
+
+        attached_step_mounts.push(AttachedStepMount {
+            reference: reference.clone(),
+            destination: step_mount.destination.clone(),
+            displaced,
+        });
+    }
+
+    Ok(attached_step_mounts)
+}
+
+pub async fn detach_step_mounts(
+    container_engine: &dyn ContainerEngine,
+    container_id: &str,
+    container_name: &str,
+    attached_step_mounts: Vec<AttachedStepMount>,
+) -> Result<(), BuildfsError> {
+    for attached_step_mount in attached_step_mounts.into_iter().rev() {
+        let destination = attached_step_mount.destination.to_string_lossy();
+        exec_step_mount_cmd(
+            container_engine,
+            container_id,
+            container_name,
+            &format!("rm -rf {destination}"),
+        )
+        .await?;
+        if attached_step_mount.displaced {
+            exec_step_mount_cmd(
+                container_engine,
+                container_id,
+                container_name,
+                &format!("mv {destination}{DISPLACED_SUFFIX} {destination}"),
+            )
+            .await?;
+        }
+        log::debug!("Detached step mount \"{}\"", attached_step_mount.reference);
+    }
+
+    Ok(())
+}
+
+async fn exec_step_mount_cmd(
+    container_engine: &dyn ContainerEngine,
+    container_id: &str,
+    container_name: &str,
+    cmd: &str,
+) -> Result<(), BuildfsError> {
+    let (exit_code, _) = exec_and_collect(container_engine, container_id, container_name, cmd).await?;
+    if exit_code != Some(0) {
+        return Err(BuildfsError::Engine {
+            context: "Could not attach or detach a step mount",
+            message: format!("\"{cmd}\" exited with code {exit_code:?}"),
+        });
+    }
+
+    Ok(())
+}
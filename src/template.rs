@@ -0,0 +1,130 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::schema::BuildScript;
+
+pub fn resolve_output_path(output_path: &Path, build_script: &BuildScript) -> PathBuf {
+    let template = output_path.to_string_lossy();
+    if !template.contains('{') {
+        return output_path.to_path_buf();
+    }
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time is before the UNIX epoch")
+        .as_secs();
+    let mut resolved = String::with_capacity(template.len());
+    let mut remaining = template.as_ref();
+
+    while let Some(start) = remaining.find('{') {
+        resolved.push_str(&remaining[..start]);
+        let end = remaining[start..]
+            .find('}')
+            .unwrap_or_else(|| panic!("Output path template {template:?} contains an unclosed placeholder"));
+        let placeholder = &remaining[start + 1..start + end];
+        resolved.push_str(&resolve_placeholder(placeholder, build_script, timestamp));
+        remaining = &remaining[start + end + 1..];
+    }
+    resolved.push_str(remaining);
+
+    log::debug!("Resolved output path template {template:?} to {resolved:?}");
+    PathBuf::from(resolved)
+}
+
+fn resolve_placeholder(placeholder: &str, build_script: &BuildScript, timestamp: u64) -> String {
+    let value = match placeholder {
+        "name" => build_script
+            .metadata
+            .as_ref()
+            .and_then(|metadata| metadata.name.clone())
+            .unwrap_or_else(|| {
+                panic!("Output path template uses {{name}}, but the build script has no metadata name. Pass -o explicitly or set it")
+            }),
+        "version" => build_script
+            .metadata
+            .as_ref()
+            .and_then(|metadata| metadata.version.clone())
+            .unwrap_or_else(|| {
+                panic!("Output path template uses {{version}}, but the build script has no metadata version. Pass -o explicitly or set it")
+            }),
+        "image.name" => build_script.container.image.name.clone(),
+        "image.tag" => build_script.container.image.tag.clone(),
+        "engine" => build_script.container.engine.to_string().to_lowercase(),
+        "filesystem.type" => build_script.filesystem.filesystem_type.to_string().to_lowercase(),
+        "date" => {
+            let (year, month, day) = get_civil_date(timestamp);
+            format!("{year:04}-{month:02}-{day:02}")
+        }
+        "time" => format!(
+            "{:02}{:02}{:02}",
+            timestamp / 3600 % 24,
+            timestamp / 60 % 60,
+            timestamp % 60
+        ),
+        "timestamp" => timestamp.to_string(),
+        _ => match placeholder.strip_prefix("env.") {
+            Some(variable) => std::env::var(variable).unwrap_or_else(|_| {
+                panic!("Output path template uses environment variable {variable}, which is not set")
+            }),
+            None => panic!("Output path template contains unknown placeholder {{{placeholder}}}"),
+        },
+    };
+
+    value.replace(['/', ':'], "-")
+}
+
+// converts days since the UNIX epoch into a UTC (year, month, day) date
+fn get_civil_date(timestamp: u64) -> (u64, u64, u64) {
+    let days = timestamp / 86400 + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::schema::parse_build_script;
+
+    use super::{get_civil_date, resolve_output_path};
+
+    #[test]
+    fn civil_dates_are_computed_in_utc() {
+        assert_eq!(get_civil_date(0), (1970, 1, 1));
+        assert_eq!(get_civil_date(951782400), (2000, 2, 29));
+        assert_eq!(get_civil_date(1735689599), (2024, 12, 31));
+    }
+
+    #[test]
+    fn placeholders_are_resolved_from_the_build_script() {
+        let build_script = parse_build_script(
+            "schema_version = 1\n[metadata]\nname = \"web\"\n[filesystem]\nsize_mib = 64\n[container]\nimage = { name = \"library/debian\", tag = \"bookworm\" }\n",
+        );
+
+        assert_eq!(
+            resolve_output_path(
+                &PathBuf::from("out/{name}-{image.name}-{image.tag}.ext4"),
+                &build_script
+            ),
+            PathBuf::from("out/web-library-debian-bookworm.ext4")
+        );
+        assert_eq!(
+            resolve_output_path(&PathBuf::from("out/plain.ext4"), &build_script),
+            PathBuf::from("out/plain.ext4")
+        );
+    }
+}
@@ -0,0 +1,119 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+use tokio::process::Command;
+
+use crate::error::BuildfsError;
+
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct ToolsConfig {
+    #[serde(default)]
+    pub container_image: Option<String>,
+    #[serde(default)]
+    pub container_cli: Option<String>,
+    #[serde(flatten)]
+    pub paths: HashMap<String, PathBuf>,
+}
+
+pub fn get_tool_path(tools: &ToolsConfig, name: &str) -> Option<PathBuf> {
+    if let Some(tool_path) = tools.paths.get(&get_tool_key(name)) {
+        log::debug!("Using configured \"{name}\" binary at: {tool_path:?}");
+        return Some(tool_path.clone());
+    }
+
+    let tool_path = which::which(name).ok()?;
+    log::debug!("Located \"{name}\" binary at: {tool_path:?}");
+    Some(tool_path)
+}
+
+pub fn get_tool_command(tools: &ToolsConfig, name: &str, shared_paths: &[&Path]) -> Result<Command, BuildfsError> {
+    if let Some(tool_path) = get_tool_path(tools, name) {
+        return Ok(Command::new(tool_path));
+    }
+
+    let key = get_tool_key(name);
+    let Some(ref container_image) = tools.container_image else {
+        return Err(BuildfsError::InvalidArguments(format!(
+            "Could not locate \"{name}\" binary in PATH, set tools.{key} or tools.container_image in the config"
+        )));
+    };
+    let container_cli = tools.container_cli.as_deref().unwrap_or("docker");
+    let container_cli_path = which::which(container_cli).map_err(|_| {
+        BuildfsError::InvalidArguments(format!(
+            "Could not locate the \"{container_cli}\" binary in PATH to run \"{name}\" in"
+        ))
+    })?;
+
+    let mut command = Command::new(container_cli_path);
+    command.args(["run", "--rm", "--network", "none"]);
+    for shared_path in shared_paths {
+        let shared_path =
+            std::path::absolute(shared_path).map_err(BuildfsError::io("Could not make a shared tool path absolute"))?;
+        let shared_path = shared_path.to_string_lossy();
+        command.arg("--volume").arg(format!("{shared_path}:{shared_path}"));
+    }
+    command.arg("--entrypoint").arg(name).arg(container_image);
+    log::info!("\"{name}\" is missing on the host, so it will run inside the {container_image} utility container");
+
+    Ok(command)
+}
+
+pub fn check_tools_available(tools: &ToolsConfig, names: &[&str]) -> Result<(), BuildfsError> {
+    let missing_names = names
+        .iter()
+        .filter(|name| get_tool_path(tools, name).is_none())
+        .collect::<Vec<_>>();
+    if missing_names.is_empty() {
+        return Ok(());
+    }
+
+    let Some(ref container_image) = tools.container_image else {
+        return Err(BuildfsError::InvalidArguments(format!(
+            "Could not locate {missing_names:?} in PATH, set tools.container_image in the config to an image with \
+             them to run them in a container (on macOS, inside the Docker Desktop or Colima VM)"
+        )));
+    };
+    let container_cli = tools.container_cli.as_deref().unwrap_or("docker");
+    if which::which(container_cli).is_err() {
+        return Err(BuildfsError::InvalidArguments(format!(
+            "Could not locate the \"{container_cli}\" binary in PATH to run {missing_names:?} inside {container_image}"
+        )));
+    }
+
+    Ok(())
+}
+
+fn get_tool_key(name: &str) -> String {
+    // config keys can't contain dots, so "mkfs.ext4" is looked up as "mkfs_ext4"
+    name.replace(['.', '-'], "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::PathBuf};
+
+    use super::{check_tools_available, get_tool_command, ToolsConfig};
+
+    #[test]
+    fn configured_path_takes_precedence() {
+        let tools = ToolsConfig {
+            paths: HashMap::from([("mkfs_ext4".to_string(), PathBuf::from("/opt/e2fsprogs/sbin/mkfs.ext4"))]),
+            ..Default::default()
+        };
+
+        let command = get_tool_command(&tools, "mkfs.ext4", &[]).unwrap();
+        assert_eq!(command.as_std().get_program(), "/opt/e2fsprogs/sbin/mkfs.ext4");
+    }
+
+    #[test]
+    fn missing_tools_without_a_container_image_are_an_error() {
+        let tools = ToolsConfig::default();
+        assert!(check_tools_available(&tools, &["sh"]).is_ok());
+        let err = check_tools_available(&tools, &["buildfs-missing-tool"]).unwrap_err();
+        assert!(err.to_string().contains("tools.container_image"));
+        assert_eq!(err.exit_code(), std::process::ExitCode::from(2));
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use sys_mount::{Mount, Unmount, UnmountDrop, UnmountFlags};
+
+use crate::{
+    error::BuildfsError,
+    privilege::HelperMount,
+    warnings::{WarningCollector, WarningKind},
+};
+
+static UNMOUNT_RETRIES: u32 = 5;
+static UNMOUNT_INITIAL_BACKOFF_MS: u64 = 100;
+
+pub enum ImageMount {
+    Kernel(UnmountDrop<Mount>),
+    Helper(HelperMount),
+}
+
+impl Unmount for ImageMount {
+    fn unmount(&self, flags: UnmountFlags) -> std::io::Result<()> {
+        match self {
+            ImageMount::Kernel(unmount_drop) => unmount_drop.unmount(flags),
+            ImageMount::Helper(helper_mount) => helper_mount.unmount(flags),
+        }
+    }
+}
+
+pub async fn unmount_rootfs(
+    unmount_drop: ImageMount,
+    mount_path: &Path,
+    warnings: &mut WarningCollector,
+) -> Result<(), BuildfsError> {
+    tokio::task::spawn_blocking(|| unsafe { libc::sync() })
+        .await
+        .expect("Join on blocking task failed");
+
+    let mut backoff = Duration::from_millis(UNMOUNT_INITIAL_BACKOFF_MS);
+    for attempt in 1..=UNMOUNT_RETRIES {
+        let error = match unmount_drop.unmount(UnmountFlags::empty()) {
+            Ok(()) => {
+                log::debug!("Unmounted {mount_path:?} on attempt {attempt}");
+                return Ok(());
+            }
+            Err(error) => error,
+        };
+
+        if error.raw_os_error() != Some(libc::EBUSY) {
+            return Err(BuildfsError::Filesystem(format!(
+                "Could not unmount {mount_path:?}: {error}"
+            )));
+        }
+
+        let busy_processes = find_busy_processes(mount_path.to_path_buf()).await;
+        log::warn!(
+            "Unmounting {mount_path:?} failed on attempt {attempt}/{UNMOUNT_RETRIES} as it is busy, retrying in {}ms. Offending processes: {}",
+            backoff.as_millis(),
+            format_busy_processes(&busy_processes)
+        );
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+    }
+
+    let busy_processes = find_busy_processes(mount_path.to_path_buf()).await;
+    unmount_drop
+        .unmount(UnmountFlags::DETACH)
+        .map_err(BuildfsError::io("Could not lazily unmount rootfs"))?;
+    log::error!(
+        "Unmounting {mount_path:?} failed {UNMOUNT_RETRIES} times, it has been lazily detached instead. The filesystem image may be incomplete until these processes exit: {}",
+        format_busy_processes(&busy_processes)
+    );
+    warnings.warn(
+        WarningKind::Cleanup,
+        format!(
+            "The filesystem had to be lazily unmounted because it was busy, offending processes: {}",
+            format_busy_processes(&busy_processes)
+        ),
+    );
+    Ok(())
+}
+
+async fn find_busy_processes(mount_path: PathBuf) -> Vec<(u32, String)> {
+    tokio::task::spawn_blocking(move || {
+        let Ok(proc_entries) = std::fs::read_dir("/proc") else {
+            return Vec::new();
+        };
+
+        proc_entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let pid = entry.file_name().to_str()?.parse::<u32>().ok()?;
+                let process_path = entry.path();
+                if !process_uses_path(&process_path, &mount_path) {
+                    return None;
+                }
+
+                let comm = std::fs::read_to_string(process_path.join("comm")).unwrap_or_default();
+                Some((pid, comm.trim().to_string()))
+            })
+            .collect()
+    })
+    .await
+    .expect("Join on blocking task failed")
+}
+
+fn process_uses_path(process_path: &Path, mount_path: &Path) -> bool {
+    let links_into_mount = |link_path: PathBuf| {
+        std::fs::read_link(link_path)
+            .map(|target| target.starts_with(mount_path))
+            .unwrap_or(false)
+    };
+
+    if ["cwd", "root", "exe"]
+        .iter()
+        .any(|link_name| links_into_mount(process_path.join(link_name)))
+    {
+        return true;
+    }
+
+    match std::fs::read_dir(process_path.join("fd")) {
+        Ok(fd_entries) => fd_entries
+            .filter_map(|entry| entry.ok())
+            .any(|entry| links_into_mount(entry.path())),
+        Err(_) => false,
+    }
+}
+
+fn format_busy_processes(busy_processes: &[(u32, String)]) -> String {
+    if busy_processes.is_empty() {
+        return "none found".to_string();
+    }
+
+    busy_processes
+        .iter()
+        .map(|(pid, comm)| format!("{pid} ({comm})"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+pub static VARIABLE_ENV_PREFIX: &str = "BUILDFS_VAR_";
+
+pub fn parse_variable(text: &str) -> Result<(String, String), String> {
+    match text.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+        _ => Err(format!("\"{text}\" is not of the form KEY=VALUE")),
+    }
+}
+
+pub fn interpolate_build_script(
+    build_script_value: &mut Value,
+    variable_overrides: &HashMap<String, String>,
+) -> Result<(), String> {
+    let Some(build_script_object) = build_script_value.as_object_mut() else {
+        return Ok(());
+    };
+
+    let mut variables = HashMap::new();
+    if let Some(declared_variables) = build_script_object.get("variables") {
+        let declared_variables = declared_variables
+            .as_object()
+            .ok_or_else(|| "variables must be a table of names to their default values".to_string())?;
+        for (name, default_value) in declared_variables {
+            let default_value = match default_value {
+                Value::String(text) => text.clone(),
+                Value::Number(number) => number.to_string(),
+                Value::Bool(boolean) => boolean.to_string(),
+                _ => {
+                    return Err(format!(
+                        "variable \"{name}\" must default to a string, number or boolean"
+                    ))
+                }
+            };
+            variables.insert(name.clone(), default_value);
+        }
+    }
+
+    for name in variable_overrides.keys() {
+        if !variables.contains_key(name) {
+            return Err(format!(
+                "variable \"{name}\" is set, but isn't declared in the build script's variables"
+            ));
+        }
+    }
+    // an explicit --set beats the environment, which beats the default declared in the build script
+    for (name, value) in variables.iter_mut() {
+        if let Some(override_value) = variable_overrides.get(name) {
+            *value = override_value.clone();
+        } else if let Ok(env_value) = std::env::var(format!("{VARIABLE_ENV_PREFIX}{name}")) {
+            *value = env_value;
+        }
+    }
+
+    for (key, field_value) in build_script_object.iter_mut() {
+        if key != "variables" {
+            interpolate_value(field_value, &variables);
+        }
+    }
+    build_script_object.insert(
+        "variables".to_string(),
+        Value::Object(
+            variables
+                .iter()
+                .map(|(name, value)| (name.clone(), Value::String(value.clone())))
+                .collect(),
+        ),
+    );
+    log::debug!("Interpolated {} variable(s) into the build script", variables.len());
+    Ok(())
+}
+
+fn interpolate_value(value: &mut Value, variables: &HashMap<String, String>) {
+    match value {
+        Value::String(text) => *text = interpolate_text(text, variables),
+        Value::Array(items) => items.iter_mut().for_each(|item| interpolate_value(item, variables)),
+        Value::Object(object) => {
+            *object = std::mem::take(object)
+                .into_iter()
+                .map(|(key, mut field_value)| {
+                    interpolate_value(&mut field_value, variables);
+                    (interpolate_text(&key, variables), field_value)
+                })
+                .collect();
+        }
+        _ => {}
+    }
+}
+
+pub fn interpolate_text(text: &str, variables: &HashMap<String, String>) -> String {
+    // only declared names are replaced, so that the shell's own ${...} expansions in scripts pass through untouched
+    let mut interpolated = String::with_capacity(text.len());
+    let mut remaining = text;
+
+    while let Some(start) = remaining.find('$') {
+        interpolated.push_str(&remaining[..start]);
+        let rest = &remaining[start..];
+        let (escaped, placeholder) = match rest.strip_prefix("$${") {
+            Some(placeholder) => (true, placeholder),
+            None => (false, rest.strip_prefix("${").unwrap_or_default()),
+        };
+
+        match placeholder
+            .find('}')
+            .map(|end| &placeholder[..end])
+            .filter(|name| variables.contains_key(*name))
+        {
+            Some(name) if escaped => interpolated.push_str(&format!("${{{name}}}")),
+            Some(name) => interpolated.push_str(&variables[name]),
+            None => {
+                interpolated.push('$');
+                remaining = &rest[1..];
+                continue;
+            }
+        }
+        remaining = &placeholder[placeholder.find('}').unwrap() + 1..];
+    }
+
+    interpolated.push_str(remaining);
+    interpolated
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::schema::{try_parse_build_script, BuildScriptFormat};
+
+    use super::interpolate_text;
+
+    #[test]
+    fn only_declared_variables_are_interpolated() {
+        let variables = HashMap::from([("RELEASE".to_string(), "bookworm".to_string())]);
+        assert_eq!(
+            interpolate_text("debian-${RELEASE} $${RELEASE} ${HOME} $$ ${RELEASE", &variables),
+            "debian-bookworm ${RELEASE} ${HOME} $$ ${RELEASE"
+        );
+    }
+
+    #[test]
+    fn variables_are_resolved_from_overrides_and_defaults() {
+        let build_script_toml = "schema_version = 1\n[variables]\nRELEASE = \"bookworm\"\nSIZE = \"1 GiB\"\n[filesystem]\nsize = \"${SIZE}\"\n[container]\nimage = { name = \"debian\", tag = \"${RELEASE}\" }\n";
+        let overrides = HashMap::from([("SIZE".to_string(), "2 GiB".to_string())]);
+
+        let build_script = try_parse_build_script(build_script_toml, BuildScriptFormat::Toml, &overrides).unwrap();
+        assert_eq!(build_script.container.image.tag, "bookworm");
+        assert_eq!(build_script.filesystem.size_mib, 2048);
+        assert_eq!(build_script.variables["SIZE"], "2 GiB");
+
+        let overrides = HashMap::from([("ARCH".to_string(), "arm64".to_string())]);
+        assert!(
+            try_parse_build_script(build_script_toml, BuildScriptFormat::Toml, &overrides)
+                .unwrap_err()
+                .contains("\"ARCH\" is set, but isn't declared")
+        );
+    }
+}
@@ -0,0 +1,181 @@
+use std::path::{Path, PathBuf};
+
+use sys_mount::{Mount, MountFlags, UnmountFlags};
+use uuid::Uuid;
+
+use crate::{
+    audit::{AuditAction, AuditLog},
+    cleanup::{register_mount, register_path, release_mount, release_path, MountKind},
+    dry_run::AdjoinAbsolute,
+    error::BuildfsError,
+    privilege::{self, mount_via_helper},
+    run::get_mount_fstype,
+    schema::{BuildScript, FilesystemType},
+    tools::{get_tool_command, ToolsConfig},
+    unmount::ImageMount,
+};
+
+static VERIFY_SAMPLE_SIZE: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct ExpectedPath {
+    pub path: PathBuf,
+    pub compare_size: bool,
+}
+
+pub fn plan_verification(build_script: &BuildScript) -> Vec<ExpectedPath> {
+    let export = &build_script.export;
+    let mut expected_paths = export
+        .files
+        .include
+        .iter()
+        .map(|path| ExpectedPath {
+            path: path.clone(),
+            compare_size: true,
+        })
+        .chain(
+            export
+                .directories
+                .include
+                .iter()
+                .chain(export.directories.create.iter())
+                .chain(export.files.create.iter())
+                .chain(build_script.overlays.iter().map(|overlay| &overlay.destination))
+                .map(|path| ExpectedPath {
+                    path: path.clone(),
+                    compare_size: false,
+                }),
+        )
+        .collect::<Vec<_>>();
+
+    if let Some(hostname_path) = build_script
+        .guest
+        .network
+        .as_ref()
+        .and_then(|network| network.hostname.as_ref())
+        .map(|_| PathBuf::from("/etc/hostname"))
+    {
+        expected_paths.push(ExpectedPath {
+            path: hostname_path,
+            compare_size: false,
+        });
+    }
+
+    if expected_paths.len() > VERIFY_SAMPLE_SIZE {
+        let step = expected_paths.len().div_ceil(VERIFY_SAMPLE_SIZE);
+        expected_paths = expected_paths.into_iter().step_by(step).collect();
+    }
+
+    expected_paths
+}
+
+pub async fn verify_rootfs(
+    filesystem_type: &FilesystemType,
+    image_path: &PathBuf,
+    staging_path: &Path,
+    expected_paths: Vec<ExpectedPath>,
+    tools: &ToolsConfig,
+    audit_log: &AuditLog,
+) -> Result<(), BuildfsError> {
+    let (fsck_name, fsck_args): (&str, &[&str]) = match filesystem_type {
+        FilesystemType::Ext4 => ("fsck.ext4", &["-n", "-f"]),
+        FilesystemType::Btrfs => ("btrfs", &["check", "--readonly"]),
+        FilesystemType::Squashfs => ("unsquashfs", &["-s"]),
+        FilesystemType::Vfat => ("fsck.vfat", &["-n"]),
+        FilesystemType::Xfs => ("xfs_repair", &["-n"]),
+    };
+    let image_parent_path = image_path.parent().unwrap_or(Path::new("."));
+    let fsck_exit_status = get_tool_command(tools, fsck_name, &[image_parent_path])?
+        .args(fsck_args)
+        .arg(image_path)
+        .status()
+        .await
+        .map_err(BuildfsError::io("Failed to fork fsck process"))?;
+    if !fsck_exit_status.success() {
+        return Err(BuildfsError::Filesystem(format!(
+            "Verification failed: \"{fsck_name}\" reported errors with exit status: {fsck_exit_status}"
+        )));
+    }
+    log::info!("Verification: \"{fsck_name}\" found no errors in the filesystem");
+
+    let verify_mount_path = PathBuf::from(format!("/tmp/{}", Uuid::new_v4()));
+    tokio::fs::create_dir(&verify_mount_path)
+        .await
+        .map_err(BuildfsError::io("Could not create verification mount point directory"))?;
+    register_path(&verify_mount_path);
+    let image_mount = match privilege::is_helper_connected() {
+        true => ImageMount::Helper(mount_via_helper(
+            image_path,
+            &verify_mount_path,
+            get_mount_fstype(filesystem_type),
+            "",
+            true,
+            None,
+        )?),
+        false => ImageMount::Kernel(
+            Mount::builder()
+                .fstype(get_mount_fstype(filesystem_type))
+                .flags(MountFlags::RDONLY)
+                .mount_autodrop(image_path, &verify_mount_path, UnmountFlags::empty())
+                .map_err(BuildfsError::io("Could not mount rootfs read-only for verification"))?,
+        ),
+    };
+    if let ImageMount::Kernel(_) = image_mount {
+        register_mount(&verify_mount_path, MountKind::Kernel);
+    }
+    audit_log.record_with_args(
+        AuditAction::Mount,
+        &verify_mount_path,
+        &[image_path.to_string_lossy().to_string(), "ro".to_string()],
+    );
+
+    let mut failures = Vec::new();
+    for expected_path in &expected_paths {
+        let image_metadata =
+            match tokio::fs::symlink_metadata(verify_mount_path.adjoin_absolute(&expected_path.path)).await {
+                Ok(metadata) => metadata,
+                Err(_) => {
+                    failures.push(format!("{:?} is missing from the image", expected_path.path));
+                    continue;
+                }
+            };
+
+        if expected_path.compare_size && image_metadata.is_file() {
+            if let Ok(staging_metadata) =
+                tokio::fs::metadata(staging_path.to_path_buf().adjoin_absolute(&expected_path.path)).await
+            {
+                if staging_metadata.len() != image_metadata.len() {
+                    failures.push(format!(
+                        "{:?} is {} byte(s) in the image, but {} byte(s) in the container rootfs",
+                        expected_path.path,
+                        image_metadata.len(),
+                        staging_metadata.len()
+                    ));
+                }
+            }
+        }
+    }
+
+    drop(image_mount);
+    release_mount(&verify_mount_path);
+    audit_log.record(AuditAction::Unmount, &verify_mount_path);
+    tokio::fs::remove_dir(&verify_mount_path)
+        .await
+        .map_err(BuildfsError::io("Could not remove verification mount point directory"))?;
+    release_path(&verify_mount_path);
+
+    if !failures.is_empty() {
+        return Err(BuildfsError::Filesystem(format!(
+            "Verification failed: {} of {} sampled path(s) are wrong:\n{}",
+            failures.len(),
+            expected_paths.len(),
+            failures.join("\n")
+        )));
+    }
+
+    log::info!(
+        "Verification: all {} sampled path(s) are present in the image",
+        expected_paths.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use std::fmt::Display;
+
+use serde::Serialize;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Warning {
+    pub kind: WarningKind,
+    pub message: String,
+}
+
+#[derive(Serialize, Debug, Clone, Copy)]
+pub enum WarningKind {
+    Deprecated,
+    SuspectValue,
+    EngineSpecific,
+    Cleanup,
+}
+
+impl Display for WarningKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WarningKind::Deprecated => write!(f, "Deprecated"),
+            WarningKind::SuspectValue => write!(f, "SuspectValue"),
+            WarningKind::EngineSpecific => write!(f, "EngineSpecific"),
+            WarningKind::Cleanup => write!(f, "Cleanup"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct WarningCollector {
+    warnings: Vec<Warning>,
+}
+
+impl WarningCollector {
+    pub fn warn(&mut self, kind: WarningKind, message: impl Into<String>) {
+        let message = message.into();
+        log::debug!("Collected {kind} warning: {message}");
+        self.warnings.push(Warning { kind, message });
+    }
+
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    pub fn surface(&self, json: bool) {
+        if json {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&self.warnings).expect("Could not encode warnings into JSON")
+            );
+            return;
+        }
+
+        for warning in &self.warnings {
+            log::warn!("[{}] {}", warning.kind, warning.message);
+        }
+
+        if !self.warnings.is_empty() {
+            log::warn!("{} warning(s) were emitted", self.warnings.len());
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::path::PathBuf;
+
+use serde::Deserialize;
+use wasmi::{Engine, Linker, Module, Store};
+
+use crate::schema::BuildScript;
+
+static WASM_PLUGIN_FUEL: u64 = 10_000_000_000;
+
+#[derive(Deserialize)]
+struct WasmPluginOutput {
+    #[serde(default)]
+    errors: Vec<String>,
+    #[serde(default)]
+    build_script: Option<BuildScript>,
+}
+
+pub async fn apply_wasm_plugins(plugin_paths: &[PathBuf], mut build_script: BuildScript) -> BuildScript {
+    for plugin_path in plugin_paths {
+        let wasm = tokio::fs::read(plugin_path)
+            .await
+            .expect("Could not read WASM plugin module");
+        let plugin_path = plugin_path.clone();
+
+        build_script = tokio::task::spawn_blocking(move || run_wasm_plugin(&plugin_path, &wasm, build_script))
+            .await
+            .expect("Join on blocking task failed");
+    }
+
+    build_script
+}
+
+fn run_wasm_plugin(plugin_path: &PathBuf, wasm: &[u8], build_script: BuildScript) -> BuildScript {
+    let input = serde_json::to_vec(&build_script).expect("Could not encode build script into JSON");
+
+    let mut engine_config = wasmi::Config::default();
+    engine_config.consume_fuel(true);
+    let engine = Engine::new(&engine_config);
+    let module = Module::new(&engine, wasm).expect("Could not compile WASM plugin module");
+    let mut store = Store::new(&engine, ());
+    store
+        .set_fuel(WASM_PLUGIN_FUEL)
+        .expect("Could not set fuel for WASM plugin");
+
+    let instance = Linker::<()>::new(&engine)
+        .instantiate(&mut store, &module)
+        .and_then(|instance_pre| instance_pre.start(&mut store))
+        .expect("Could not instantiate WASM plugin module, note that plugins cannot import anything");
+    let memory = instance
+        .get_memory(&store, "memory")
+        .expect("WASM plugin does not export its memory");
+    let alloc = instance
+        .get_typed_func::<i32, i32>(&store, "buildfs_alloc")
+        .expect("WASM plugin does not export \"buildfs_alloc\"");
+    let validate = instance
+        .get_typed_func::<(i32, i32), i64>(&store, "buildfs_validate")
+        .expect("WASM plugin does not export \"buildfs_validate\"");
+
+    let input_ptr = alloc
+        .call(&mut store, input.len() as i32)
+        .expect("WASM plugin trapped while allocating input");
+    memory
+        .write(&mut store, input_ptr as usize, &input)
+        .expect("Could not write input into WASM plugin memory");
+
+    let packed_output = validate
+        .call(&mut store, (input_ptr, input.len() as i32))
+        .expect("WASM plugin trapped while validating the build script");
+    let (output_ptr, output_len) = ((packed_output >> 32) as u32, packed_output as u32);
+    let mut output = vec![0u8; output_len as usize];
+    memory
+        .read(&store, output_ptr as usize, &mut output)
+        .expect("Could not read output from WASM plugin memory");
+
+    let output =
+        serde_json::from_slice::<WasmPluginOutput>(&output).expect("Could not decode output of WASM plugin from JSON");
+    if !output.errors.is_empty() {
+        panic!(
+            "WASM plugin {plugin_path:?} rejected the build script:\n{}",
+            output.errors.join("\n")
+        );
+    }
+
+    match output.build_script {
+        Some(build_script) => {
+            log::info!("WASM plugin {plugin_path:?} transformed the build script");
+            build_script
+        }
+        None => {
+            log::info!("WASM plugin {plugin_path:?} accepted the build script");
+            build_script
+        }
+    }
+}